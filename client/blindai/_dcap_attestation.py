@@ -17,9 +17,14 @@ import enum
 import hashlib
 import importlib
 import os
-from typing import Optional
+from typing import Optional, Tuple
 from typing_extensions import Self
 from dataclasses import dataclass
+from cryptography import x509
+from cryptography.exceptions import InvalidSignature
+from cryptography.hazmat.primitives import hashes, serialization
+from cryptography.hazmat.primitives.asymmetric import ec
+from cryptography.hazmat.primitives.asymmetric.utils import encode_dss_signature
 import sgx_dcap_quote_verify
 from sgx_dcap_quote_verify import VerificationStatus
 import toml
@@ -48,6 +53,33 @@ class Collateral:
                 )
 
 
+# Extension of RA-TLS certificates carrying the raw quote, as Gramine does
+RA_TLS_QUOTE_OID = x509.ObjectIdentifier("1.2.840.113741.1337.6")
+
+
+def ra_tls_evidence(
+    certificate: bytes,
+) -> Tuple[Optional[bytes], bytes]:
+    """Extracts the attestation evidence embedded in an enclave certificate.
+
+    Args:
+        certificate (bytes): DER encoded enclave TLS certificate
+    Returns:
+        The quote embedded in the certificate, None when absent, and the
+        enclave held data: the DER encoded public key of RA-TLS certificates,
+        and the certificate itself otherwise.
+    """
+    cert = x509.load_der_x509_certificate(certificate)
+    try:
+        quote = cert.extensions.get_extension_for_oid(RA_TLS_QUOTE_OID).value.value
+    except x509.ExtensionNotFound:
+        return None, certificate
+    public_key = cert.public_key().public_bytes(
+        serialization.Encoding.DER, serialization.PublicFormat.SubjectPublicKeyInfo
+    )
+    return quote, public_key
+
+
 class AttestationError(Exception):
     """This exception is raised when the attestation is invalid (enclave
     settings mismatching, debug mode unallowed...).
@@ -105,11 +137,132 @@ class IdentityError(QuoteValidationError):
         )
 
 
+# QE vendor id of the quotes simulated by BlindAI servers built without SGX
+SIMULATED_QE_VENDOR_ID = b"BLINDAISIMULATED"
+
+
+def is_simulated_quote(quote: bytes) -> bool:
+    """Whether the quote was simulated by a server built without SGX."""
+    return quote[12:28] == SIMULATED_QE_VENDOR_ID
+
+
+def _verify_p256_signature(public_key, signature: bytes, data: bytes):
+    """Verifies a raw (r || s) ECDSA P-256 signature, as found in quotes."""
+    der_signature = encode_dss_signature(
+        int.from_bytes(signature[:32], "big"), int.from_bytes(signature[32:], "big")
+    )
+    public_key.verify(der_signature, data, ec.ECDSA(hashes.SHA256()))
+
+
+def verify_simulated_quote(quote: bytes) -> bytes:
+    """Verifies a version 3 DCAP quote simulated by a server built without SGX,
+    trusting the root CA of the throwaway PKI it embeds, as the verifier of the
+    server does in test mode.
+
+    The certificate chain and the signatures are checked, so that the quote is
+    known to carry the report data it was simulated with. It proves nothing about
+    the server though, anyone can simulate quotes.
+
+    Args:
+        quote (bytes): Simulated SGX quote
+    Raises:
+        QuoteValidationError: The quote is not a valid simulated quote.
+    Returns:
+        The report data of the enclave report
+    """
+    u16 = lambda data, offset: int.from_bytes(data[offset : offset + 2], "little")
+    u32 = lambda data, offset: int.from_bytes(data[offset : offset + 4], "little")
+
+    # Header (48 bytes), enclave report (384 bytes), then the signature data
+    if len(quote) < 436 or u16(quote, 0) != 3 or u16(quote, 2) != 2:
+        raise QuoteValidationError("Unsupported quote format")
+    if not is_simulated_quote(quote):
+        raise QuoteValidationError("The quote is not simulated")
+    signed_data = quote[:432]
+    signature_data = quote[436 : 436 + u32(quote, 432)]
+
+    signature = signature_data[0:64]
+    attestation_key = signature_data[64:128]
+    qe_report = signature_data[128:512]
+    qe_report_signature = signature_data[512:576]
+    qe_auth_data = signature_data[578 : 578 + u16(signature_data, 576)]
+    offset = 578 + len(qe_auth_data)
+    if u16(signature_data, offset) != 5:
+        raise QuoteValidationError("The quote does not embed its PCK certificate chain")
+    certification_data = signature_data[
+        offset + 6 : offset + 6 + u32(signature_data, offset + 2)
+    ]
+
+    try:
+        chain = x509.load_pem_x509_certificates(certification_data)
+        if len(chain) != 3:
+            raise QuoteValidationError(
+                "The PCK certificate chain must be made of the PCK, PCK CA and root CA certificates"
+            )
+        pck, pck_ca, root_ca = chain
+        now = datetime.utcnow()
+        for certificate, issuer in [(pck, pck_ca), (pck_ca, root_ca), (root_ca, root_ca)]:
+            if certificate.issuer != issuer.subject:
+                raise QuoteValidationError("Invalid PCK certificate chain")
+            if not certificate.not_valid_before <= now <= certificate.not_valid_after:
+                raise QuoteValidationError("Expired PCK certificate chain")
+            issuer.public_key().verify(
+                certificate.signature,
+                certificate.tbs_certificate_bytes,
+                ec.ECDSA(certificate.signature_hash_algorithm),
+            )
+
+        # The PCK certifies the QE report, which binds the attestation key
+        _verify_p256_signature(pck.public_key(), qe_report_signature, qe_report)
+        if hashlib.sha256(attestation_key + qe_auth_data).digest() != qe_report[320:352]:
+            raise QuoteValidationError("The QE report does not bind the attestation key")
+        _verify_p256_signature(
+            ec.EllipticCurvePublicKey.from_encoded_point(
+                ec.SECP256R1(), b"\x04" + attestation_key
+            ),
+            signature,
+            signed_data,
+        )
+    except InvalidSignature:
+        raise QuoteValidationError("Invalid signature of the simulated quote")
+    except ValueError as e:
+        raise QuoteValidationError(f"Invalid simulated quote: {e}")
+
+    return signed_data[368:432]
+
+
+def _validate_report_data(
+    report_data: bytes,
+    enclave_held_data: bytes,
+    enclave_public_key: Optional[bytes],
+    nonce: Optional[bytes],
+):
+    if hashlib.sha256(enclave_held_data).digest() != report_data[:32]:
+        raise EnclaveHeldDataError(
+            expected=hashlib.sha256(enclave_held_data).digest(),
+            got=report_data[:32],
+        )
+
+    if nonce is not None and enclave_public_key is None:
+        raise QuoteValidationError("Fresh quotes can't be checked without the enclave key")
+
+    if enclave_public_key is not None:
+        expected = hashlib.sha256(enclave_public_key + (nonce or b"")).digest()
+        if expected != report_data[32:64]:
+            raise EnclaveHeldDataError(
+                expected=expected,
+                got=report_data[32:64],
+            )
+
+
 def validate_attestation(
     quote: bytes,
     collateral: Collateral,
     enclave_held_data: bytes,
     manifest_path: Optional[Path] = None,
+    enclave_public_key: Optional[bytes] = None,
+    nonce: Optional[bytes] = None,
+    allow_simulated: bool = False,
 ):
     """Verifies if the enclave evidence is valid.
 
@@ -118,11 +271,19 @@ def validate_attestation(
     * Validates if the SHA256 hash of Enclave Held Data (EHD) matches the first 32 bytes
         of reportData field in the enclave quote. After this check
         we can be sure that the EHD bytes are endorsed by the enclave.
+    * If given, validates if the SHA256 hash of the enclave X25519 public key, followed by
+        the nonce of a fresh quote, matches the last 32 bytes of reportData.
     Args:
         quote (bytes): SGX quote
         attestation_collateral (SgxCollateral): SGX collateral needed to assess the validity of the quote
             (collateral is signed by Intel)
         enclave_held_data (bytes): Enclave held data
+        enclave_public_key (Optional[bytes]): Enclave X25519 public key, used to seal inputs
+        nonce (Optional[bytes]): Nonce the quote was requested with, for fresh quotes
+        allow_simulated (bool): Test mode, also accept the quotes simulated by servers
+            built without SGX (see `verify_simulated_quote`). Their report data is
+            checked, but neither the collateral nor the manifest: a simulated
+            enclave has no identity.
     Raises:
         QuoteValidationError: The quote could not be validated.
         EnclaveHeldDataError: The enclave held data expected does not match the one in the quote. The expected enclave held data in BlindAI is a certificate to avoid man-in-the-middle attacks.
@@ -131,6 +292,15 @@ def validate_attestation(
         -
     """
 
+    if is_simulated_quote(quote):
+        if not allow_simulated:
+            raise QuoteValidationError(
+                "The quote is simulated, it was not produced by an SGX enclave"
+            )
+        report_data = verify_simulated_quote(quote)
+        _validate_report_data(report_data, enclave_held_data, enclave_public_key, nonce)
+        return
+
     # TODO: Handle the case where the retuned quote status is STATUS_TCB_SW_HARDENING_NEEDED
     # We must do more cautious checks in this case in order to determine whether or not to accept the quote
 
@@ -174,14 +344,12 @@ def validate_attestation(
         )
 
     assert attestation_result.enclave_report is not None
-    if (
-        hashlib.sha256(enclave_held_data).digest()
-        != attestation_result.enclave_report.report_data[:32]
-    ):
-        raise EnclaveHeldDataError(
-            expected=hashlib.sha256(enclave_held_data).digest(),
-            got=attestation_result.enclave_report.report_data[:32],
-        )
+    _validate_report_data(
+        attestation_result.enclave_report.report_data,
+        enclave_held_data,
+        enclave_public_key,
+        nonce,
+    )
 
     if manifest_path is None:
         manifest = EnclaveManifest.from_str(
@@ -14,7 +14,12 @@
 
 
 import pathlib
-from ._dcap_attestation import validate_attestation, AttestationError, Collateral
+from ._dcap_attestation import (
+    validate_attestation,
+    ra_tls_evidence,
+    AttestationError,
+    Collateral,
+)
 from .utils import *
 
 from dataclasses import dataclass
@@ -43,11 +48,19 @@ app_version = version("blindai")
 
 CONNECTION_TIMEOUT = 10
 
+# Version of the wire protocol spoken by this client
+PROTOCOL_VERSION = 2
+PROTOCOL_VERSION_HEADER = "X-BlindAI-Protocol-Version"
+
 
 class SimulationModeWarning(Warning):
     pass
 
 
+class ProtocolVersionError(Exception):
+    pass
+
+
 class ModelDatumType(IntEnum):
     F32 = 0
     F64 = 1
@@ -159,6 +172,9 @@ class UploadModel:
     model_name: str
     optimize: bool
     client_info: "_ClientInfo"
+    encrypted: bool
+    policy: dict
+    ttl: Optional[int]
 
     def __init__(
         self,
@@ -167,12 +183,18 @@ class UploadModel:
         client_info,
         model_name="",
         optimize=True,
+        encrypted=False,
+        policy=None,
+        ttl=None,
     ):
         self.model = model
         self.length = length
         self.model_name = model_name
         self.optimize = optimize
         self.client_info = client_info
+        self.encrypted = encrypted
+        self.policy = policy if policy is not None else {}
+        self.ttl = ttl
 
 
 @dataclass
@@ -181,20 +203,38 @@ class RunModel:
     model_hash: str
     inputs: List[Tensor]
     client_info: Optional["_ClientInfo"]
+    sealed_inputs: Optional[dict]
+    recipient: Optional[dict]
 
-    def __init__(self, model_id, model_hash, inputs, client_info=None):
+    def __init__(
+        self,
+        model_id,
+        model_hash,
+        inputs,
+        client_info=None,
+        sealed_inputs=None,
+        recipient=None,
+    ):
         self.model_id = model_id
         self.model_hash = model_hash
         self.inputs = inputs
         self.client_info = client_info
+        self.sealed_inputs = sealed_inputs
+        self.recipient = recipient
 
 
 @dataclass
 class DeleteModel:
     model_id: str
+    model_hash: Optional[str]
+    model_name: Optional[str]
+    tenant: Optional[str]
 
-    def __init__(self, model_id):
+    def __init__(self, model_id="", model_hash=None, model_name=None, tenant=None):
         self.model_id = model_id
+        self.model_hash = model_hash
+        self.model_name = model_name
+        self.tenant = tenant
 
 
 @dataclass
@@ -591,27 +631,59 @@ class BlindAiConnection(contextlib.AbstractContextManager):
                 "The BlindAI server is a mock. You can only connect to it in simulation mode."
             )
 
-        if not simulation_mode:
-            try:
-                quote = cbor.loads(s.get(f"{self._unattested_url}/quote").content)
-                collateral = cbor.loads(
-                    s.get(f"{self._unattested_url}/collateral").content
-                )
-                try:
-                    collateral = Collateral(**collateral)
-                except TypeError as e:
-                    raise AttestationError("Bad attestation collateral from the server")
-
-                validate_attestation(
-                    quote,
-                    collateral,
-                    cert,
-                    manifest_path=hazmat_manifest_path,
+        self._check_protocol_version(s)
+
+        # Servers predating sealed inputs don't publish their key
+        try:
+            self._enclave_public_key = cbor.loads(
+                s.get(f"{self._unattested_url}/enclave_key").content
+            )
+        except requests.exceptions.HTTPError:
+            self._enclave_public_key = None
+
+        # Simulated quotes are verified too in simulation mode, although they
+        # prove nothing about the server
+        try:
+            # RA-TLS certificates carry the quote, bound to their public
+            # key
+            quote, enclave_held_data = ra_tls_evidence(cert)
+
+            # Servers able to quote on demand bind a nonce of ours to the
+            # quote, proving that it was not replayed
+            nonce = None
+            if (
+                "fresh_quotes" in self._server_features
+                and self._enclave_public_key is not None
+            ):
+                nonce = os.urandom(32)
+                quote = cbor.loads(
+                    s.get(
+                        f"{self._unattested_url}/quote?nonce={nonce.hex()}"
+                    ).content
                 )
-            except AttestationError as e:
-                raise
-            except Exception as e:
-                raise AttestationError("Attestation verification failed")
+            elif quote is None:
+                quote = cbor.loads(s.get(f"{self._unattested_url}/quote").content)
+            collateral = cbor.loads(
+                s.get(f"{self._unattested_url}/collateral").content
+            )
+            try:
+                collateral = Collateral(**collateral)
+            except TypeError as e:
+                raise AttestationError("Bad attestation collateral from the server")
+
+            validate_attestation(
+                quote,
+                collateral,
+                enclave_held_data,
+                manifest_path=hazmat_manifest_path,
+                enclave_public_key=self._enclave_public_key,
+                nonce=nonce,
+                allow_simulated=simulation_mode,
+            )
+        except AttestationError as e:
+            raise
+        except Exception as e:
+            raise AttestationError("Attestation verification failed")
 
         # requests (http library) takes a path to a file containing the CA
         # there is no easy way to give the CA as a string/bytes directly
@@ -626,6 +698,7 @@ class BlindAiConnection(contextlib.AbstractContextManager):
         self.attested_cert_file = attested_server_cert_file
 
         attested_conn = requests.Session()
+        attested_conn.headers[PROTOCOL_VERSION_HEADER] = str(PROTOCOL_VERSION)
         attested_conn.verify = attested_server_cert_file.name
         attested_conn.mount(self._attested_url, CustomHostNameCheckingAdapter())
         attested_conn.mount(self._model_management_url, CustomHostNameCheckingAdapter())
@@ -638,11 +711,36 @@ class BlindAiConnection(contextlib.AbstractContextManager):
 
         self._conn = attested_conn
 
+    def _check_protocol_version(self, session: requests.Session):
+        """Check that the server speaks the protocol version of this client,
+        and record the features it advertises.
+
+        Servers predating protocol versioning don't advertise their capabilities
+        and are assumed to be compatible.
+        """
+        self._server_features = []
+        try:
+            capabilities = session.get(f"{self._unattested_url}/capabilities").json()
+        except requests.exceptions.HTTPError:
+            return
+        self._server_features = capabilities.get("features", [])
+        min_version = capabilities["min_protocol_version"]
+        max_version = capabilities["protocol_version"]
+        if not min_version <= PROTOCOL_VERSION <= max_version:
+            raise ProtocolVersionError(
+                f"The server speaks protocol versions {min_version} to {max_version} "
+                f"but this client speaks version {PROTOCOL_VERSION}, "
+                "please install a compatible version of the blindai client"
+            )
+
     def upload_model(
         self,
         model: str,
         model_name: Optional[str] = None,
         optimize: bool = True,
+        encrypted: bool = False,
+        policy: Optional[dict] = None,
+        ttl: Optional[int] = None,
     ) -> UploadResponse:
         """Upload an inference model to the server.
 
@@ -658,6 +756,11 @@ class BlindAiConnection(contextlib.AbstractContextManager):
                 Used for you to identify the model, but won't be used by the server (a random UUID will be assigned to your model for the inferences).
             optimize (bool): Whether tract (our inference engine) should optimize the model or not.
                 Optimzing should only be turned off when you are encountering issues loading your model.
+            encrypted (bool): Whether the model file is an encrypted model bundle (see `encrypt_model`).
+                The server will fetch the decryption key from its key broker and decrypt the model inside the enclave.
+            policy (Optional[dict]): Usage policy enforced by the server on the model. Accepted keys are
+                `allowed_clients`, `max_queries`, `max_queries_per_client`, `expires_at` and `allowed_outputs`.
+            ttl (Optional[int]): Time-to-live of the model in seconds. The server deletes the model once it has elapsed.
         Raises:
             HttpError: raised by the requests lib to relay server side errors
             ValueError: raised when inputs sanity checks fail
@@ -678,6 +781,9 @@ class BlindAiConnection(contextlib.AbstractContextManager):
             model_name=model_name,
             optimize=optimize,
             client_info=self.client_info.__dict__,
+            encrypted=encrypted,
+            policy=policy,
+            ttl=ttl,
         )
         bytes_data = cbor.dumps(data.__dict__)
         r = self._conn.post(f"{self._model_management_url}/upload", data=bytes_data)
@@ -695,6 +801,8 @@ class BlindAiConnection(contextlib.AbstractContextManager):
         input_tensors: Optional[Union[List, Dict]] = None,
         dtypes: Optional[List[ModelDatumType]] = None,
         shapes: Optional[Union[List[List[int]], List[int]]] = None,
+        seal_inputs: bool = False,
+        recipient_key: Optional[Any] = None,
     ) -> RunModelResponse:
         """Send data to the server to make a secure inference.
 
@@ -718,6 +826,11 @@ class BlindAiConnection(contextlib.AbstractContextManager):
             shapes (Union[List[List[int]], List[int]], optional): The shape of the data you want to upload.
                 Only required if you are uploading flat lists, will be ignored if you are uploading numpy
                 or tensors (this info will be extracted directly from the tensors/numpys).
+            seal_inputs (bool): Whether to seal the inputs to the enclave key published in the attestation,
+                so that a relay forwarding the query cannot read them.
+            recipient_key (Optional[Any]): X25519 or P-256 private key (from the `cryptography` library).
+                When set, the server seals the outputs to its public key and they are opened locally.
+                The inputs are then sealed too, bound to this key so that a relay cannot swap it.
         Raises:
             HttpError: raised by the requests lib to relay server side errors
             ValueError: raised when inputs sanity checks fail
@@ -734,26 +847,52 @@ class BlindAiConnection(contextlib.AbstractContextManager):
             )
 
         tensors = translate_tensors(input_tensors, dtypes, shapes)
+        recipient = (
+            sealing_recipient(recipient_key) if recipient_key is not None else None
+        )
+        sealed_inputs = None
+        if seal_inputs or recipient is not None:
+            if self._enclave_public_key is None:
+                raise ValueError("The server does not support sealed inputs")
+            sealed_inputs = seal_payload(
+                self._enclave_public_key, cbor.dumps(tensors), recipient
+            )
+            tensors = []
+
         run_data = RunModel(
             model_hash=model_hash,
             model_id=model_id,
             inputs=tensors,
             client_info=self.client_info.__dict__,
+            sealed_inputs=sealed_inputs,
+            recipient=recipient,
         )
         bytes_run_data = cbor.dumps(run_data.__dict__)
         r = self._conn.post(f"{self._attested_url}/run", data=bytes_run_data)
         r.raise_for_status()
         run_model_reply = RunModelReply(**cbor.loads(r.content))
 
+        outputs = run_model_reply.outputs
+        if recipient_key is not None:
+            outputs = cbor.loads(
+                open_sealed_payload(recipient_key, run_model_reply.sealed_outputs)
+            )
+
         ret = RunModelResponse(
             output=[
                 Tensor(TensorInfo(**output["info"]), output["bytes_data"])
-                for output in run_model_reply.outputs
+                for output in outputs
             ]
         )
         return ret
 
-    def delete_model(self, model_id: str):
+    def delete_model(
+        self,
+        model_id: str = "",
+        model_hash: Optional[str] = None,
+        model_name: Optional[str] = None,
+        tenant: Optional[str] = None,
+    ) -> List[str]:
         """Delete a model in the inference server.
 
         This may be used to free up some memory. If you did not specify that you
@@ -765,16 +904,26 @@ class BlindAiConnection(contextlib.AbstractContextManager):
             It doesn't relies on a session token or anything, hence if the `model_id` is known,
             it's deletion is possible.
 
+        Exactly one of the arguments must be provided.
+
         Args:
             model_id (str): The id of the model to remove.
+            model_hash (Optional[str]): Remove all the models with this hex encoded hash.
+            model_name (Optional[str]): Remove all the models with this name.
+            tenant (Optional[str]): Remove all the models uploaded by this caller identity.
         Raises:
             HttpError: raised by the requests lib to relay server side errors
             ValueError: raised when inputs sanity checks fail
+        Returns:
+            List[str]: The ids of the deleted models.
         """
-        delete_data = DeleteModel(model_id=model_id)
+        delete_data = DeleteModel(
+            model_id=model_id, model_hash=model_hash, model_name=model_name, tenant=tenant
+        )
         bytes_delete_data = cbor.dumps(delete_data.__dict__)
         r = self._conn.post(f"{self._model_management_url}/delete", bytes_delete_data)
         r.raise_for_status()
+        return cbor.loads(r.content)["model_ids"]
 
     def close(self):
         self._conn.close()
@@ -818,6 +967,8 @@ def connect(
             Caution: This parameter should never be set to True in production. Using a HTTPS connection is critical to
             get a graceful degradation in case of a failure of the Intel SGX attestation.
         simulation_mode (bool, optional): If set to True, BlindAI will work in simulation mode.
+            The quotes simulated by servers built without SGX are then accepted, once their signatures
+            and the data they bind (certificate, enclave key, nonce) are verified.
             Caution: In simulation, BlindAI does not provide any security since there is no SGX enclave.
             This mode SHOULD NEVER be enabled in production.
             Defaults to False (production mode)
@@ -13,8 +13,13 @@
 # limitations under the License.
 
 import re
+import cbor2 as cbor
 import cryptography.x509
-from cryptography.hazmat.primitives import serialization
+from cryptography.hazmat.primitives import hashes, serialization
+from cryptography.hazmat.primitives.asymmetric import ec, x25519
+from cryptography.hazmat.primitives.ciphers.aead import AESGCM
+from cryptography.hazmat.primitives.kdf.hkdf import HKDF
+from typing import Optional
 import torch
 import os
 
@@ -29,6 +34,117 @@ def cert_der_to_pem(cert: bytes) -> bytes:
     )
 
 
+def encrypt_model(model: str, key: bytes, key_id: str, output: str):
+    """Encrypt an ONNX model into a bundle that only the enclave can decrypt.
+
+    The model is sealed with AES-256-GCM. The key must be registered under
+    `key_id` in the key broker used by the server.
+
+    Args:
+        model (str): Path to the ONNX model file.
+        key (bytes): 32 bytes model key.
+        key_id (str): Identifier of the key in the key broker.
+        output (str): Path of the encrypted bundle to write.
+    """
+    with open(model, "rb") as f:
+        model_bytes = f.read()
+
+    nonce = os.urandom(12)
+    ciphertext = AESGCM(key).encrypt(nonce, model_bytes, key_id.encode())
+    with open(output, "wb") as f:
+        f.write(cbor.dumps({"key_id": key_id, "nonce": nonce, "ciphertext": ciphertext}))
+
+
+SEALED_OUTPUTS_INFO = b"blindai sealed outputs v1"
+SEALED_INPUTS_INFO = b"blindai sealed inputs v1"
+
+# Every key seals a single payload
+_SEALING_NONCE = bytes(12)
+
+
+def _payload_key(
+    shared_secret: bytes, encapsulated_key: bytes, public_key: bytes, info: bytes
+) -> bytes:
+    return HKDF(
+        algorithm=hashes.SHA256(),
+        length=32,
+        salt=encapsulated_key + public_key,
+        info=info,
+    ).derive(shared_secret)
+
+
+def sealing_recipient(private_key) -> dict:
+    """Describe the public key of an X25519 or P-256 private key, for the server to
+    seal the outputs to it."""
+    if isinstance(private_key, x25519.X25519PrivateKey):
+        return {
+            "kem": "x25519",
+            "public_key": private_key.public_key().public_bytes(
+                serialization.Encoding.Raw, serialization.PublicFormat.Raw
+            ),
+        }
+    if isinstance(private_key, ec.EllipticCurvePrivateKey) and isinstance(
+        private_key.curve, ec.SECP256R1
+    ):
+        return {
+            "kem": "p256",
+            "public_key": private_key.public_key().public_bytes(
+                serialization.Encoding.X962,
+                serialization.PublicFormat.UncompressedPoint,
+            ),
+        }
+    raise ValueError("Only X25519 and P-256 keys are supported")
+
+
+def seal_payload(
+    enclave_public_key: bytes, plaintext: bytes, recipient: Optional[dict] = None
+) -> dict:
+    """Seal a payload to the X25519 key of the enclave, bound to the `recipient` the
+    outputs are to be sealed to, if any."""
+    ephemeral_key = x25519.X25519PrivateKey.generate()
+    encapsulated_key = ephemeral_key.public_key().public_bytes(
+        serialization.Encoding.Raw, serialization.PublicFormat.Raw
+    )
+    shared_secret = ephemeral_key.exchange(
+        x25519.X25519PublicKey.from_public_bytes(enclave_public_key)
+    )
+    key = _payload_key(
+        shared_secret, encapsulated_key, enclave_public_key, SEALED_INPUTS_INFO
+    )
+    return {
+        "kem": "x25519",
+        "encapsulated_key": encapsulated_key,
+        "ciphertext": AESGCM(key).encrypt(
+            _SEALING_NONCE,
+            plaintext,
+            recipient["public_key"] if recipient is not None else None,
+        ),
+    }
+
+
+def open_sealed_payload(private_key, sealed: dict) -> bytes:
+    """Open outputs sealed by the enclave to the public key of `private_key`."""
+    recipient = sealing_recipient(private_key)
+    if sealed["kem"] != recipient["kem"]:
+        raise ValueError("The payload is sealed to another kind of key")
+    encapsulated_key = sealed["encapsulated_key"]
+    if recipient["kem"] == "x25519":
+        shared_secret = private_key.exchange(
+            x25519.X25519PublicKey.from_public_bytes(encapsulated_key)
+        )
+    else:
+        shared_secret = private_key.exchange(
+            ec.ECDH(),
+            ec.EllipticCurvePublicKey.from_encoded_point(
+                ec.SECP256R1(), encapsulated_key
+            ),
+        )
+    key = _payload_key(
+        shared_secret, encapsulated_key, recipient["public_key"], SEALED_OUTPUTS_INFO
+    )
+    return AESGCM(key).decrypt(_SEALING_NONCE, sealed["ciphertext"], None)
+
+
 def fetch_whisper_tiny_20_tokens():
     # TODO: Urgent
     # Remove this implementation and actually convert the model to ONNX
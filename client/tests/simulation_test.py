@@ -0,0 +1,75 @@
+"""Attestation in simulation mode, against a server built without SGX running on
+localhost."""
+
+import warnings
+
+import cbor2 as cbor
+import pytest
+import requests
+
+import blindai
+from blindai._dcap_attestation import (
+    Collateral,
+    EnclaveHeldDataError,
+    QuoteValidationError,
+    is_simulated_quote,
+    validate_attestation,
+)
+
+UNATTESTED_URL = "http://localhost:9923"
+
+
+def attestation_evidence():
+    get = lambda path: cbor.loads(requests.get(f"{UNATTESTED_URL}{path}").content)
+    certificate = get("/")
+    quote = get("/quote")
+    collateral = Collateral(**get("/collateral"))
+    enclave_public_key = get("/enclave_key")
+    return certificate, quote, collateral, enclave_public_key
+
+
+def test_connect_in_simulation_mode():
+    with warnings.catch_warnings():
+        warnings.simplefilter("ignore")
+        blindai.core.connect(
+            addr="localhost", hazmat_http_on_unattested_port=True, simulation_mode=True
+        )
+
+
+def test_simulated_quotes_are_verified():
+    certificate, quote, collateral, enclave_public_key = attestation_evidence()
+    assert is_simulated_quote(quote)
+
+    validate_attestation(
+        quote,
+        collateral,
+        certificate,
+        enclave_public_key=enclave_public_key,
+        allow_simulated=True,
+    )
+
+    # Only in test mode
+    with pytest.raises(QuoteValidationError):
+        validate_attestation(quote, collateral, certificate)
+
+    # The quote must bind the certificate and the enclave key
+    with pytest.raises(EnclaveHeldDataError):
+        validate_attestation(
+            quote, collateral, b"another certificate", allow_simulated=True
+        )
+    with pytest.raises(EnclaveHeldDataError):
+        validate_attestation(
+            quote,
+            collateral,
+            certificate,
+            enclave_public_key=bytes(32),
+            allow_simulated=True,
+        )
+
+    # The report data is signed
+    tampered = bytearray(quote)
+    tampered[400] ^= 1
+    with pytest.raises(QuoteValidationError):
+        validate_attestation(
+            bytes(tampered), collateral, certificate, allow_simulated=True
+        )
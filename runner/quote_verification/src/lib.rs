@@ -0,0 +1,403 @@
+//! Verification of SGX DCAP quotes and of their collateral, in Rust.
+//!
+//! This is the verification done by the Python client
+//! (`client/blindai/_dcap_attestation.py`) without the Intel QVL:
+//!
+//! * the PCK certificate chain embedded in the quote, or the PCK certificate
+//!   and signing chain of the collateral when the quote only identifies its
+//!   PCK certificate, is checked up to the Intel SGX root CA, against the
+//!   root CA and PCK CRLs;
+//! * the TCB info and the QE identity are checked with the TCB signing chain,
+//!   and must be valid at the verification time;
+//! * the QE report must match the QE identity and be signed by the PCK key,
+//!   and must bind the attestation key that signs the enclave report;
+//! * the enclave report can then be checked against an enclave manifest, and
+//!   its report data against the data the enclave claims to hold.
+//!
+//! Quotes simulated by servers built without SGX are rejected, unless the
+//! verifier is explicitly put in test mode with [`Verifier::allow_simulated`].
+//!
+//! The TCB status of the platform is evaluated by matching the TCB of its
+//! PCK certificate against the levels of the TCB info, and combined with the
+//! TCB status of the quoting enclave. It is returned with the verified quote
+//! for the caller to decide what is acceptable, but a revoked quoting enclave
+//! is always rejected.
+
+pub mod manifest;
+pub mod pki;
+pub mod quote;
+pub mod tcb;
+
+use anyhow::{bail, ensure, Context, Result};
+use manifest::Manifest;
+use pki::{check_not_revoked, parse_certificate, parse_crl, pem_blocks, PckExtensions};
+use quote::{Quote, ReportBody};
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tcb::{check_dates, parse_date, QeIdentity, TcbEvaluation, TcbInfo, TcbStatus};
+
+const INTEL_SGX_ROOT_CA: &str = include_str!("Intel_SGX_Provisioning_Certification_RootCA.pem");
+
+// "Native" Rust type for sgx_ql_qve_collateral_t, as served by the enclave
+// on `/collateral`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SgxCollateral {
+    pub version: u32,                  // version = 1.  PCK Cert chain is in the Quote.
+    pub pck_crl_issuer_chain: String,  // PCK CRL Issuer Chain in PEM format
+    pub root_ca_crl: String,           // Root CA CRL in PEM format
+    pub pck_crl: String,               // PCK Cert CRL in PEM format
+    pub tcb_info_issuer_chain: String, // PEM
+    pub tcb_info: String,              // TCB Info structure
+    pub qe_identity_issuer_chain: String, // PEM
+    pub qe_identity: String,           // QE Identity Structure
+    pub pck_certificate: String,       // PCK certificate in PEM format
+    pub pck_signing_chain: String,     // PCK signing chain in PEM format
+}
+
+impl SgxCollateral {
+    /// Earliest `nextUpdate` of the CRLs, the TCB info and the QE identity, as
+    /// a Unix timestamp. Nothing is verified, and missing pieces of
+    /// collateral are skipped.
+    pub fn next_update(&self) -> Result<Option<i64>> {
+        let mut next_updates = vec![
+            json_next_update(&self.tcb_info, "tcbInfo").context("Invalid TCB info")?,
+            json_next_update(&self.qe_identity, "enclaveIdentity")
+                .context("Invalid QE identity")?,
+        ];
+        for (name, crl) in [
+            ("root CA CRL", &self.root_ca_crl),
+            ("PCK CRL", &self.pck_crl),
+        ] {
+            if crl.trim_end_matches('\0').trim().is_empty() {
+                continue;
+            }
+            let der = crl_der(crl, name)?;
+            let crl = parse_crl(&der).with_context(|| format!("Invalid {}", name))?;
+            next_updates.push(crl.next_update().map(|date| date.timestamp()));
+        }
+        Ok(next_updates.into_iter().flatten().min())
+    }
+}
+
+/// `nextUpdate` of a signed JSON structure such as `{"tcbInfo": {...}}`
+fn json_next_update(json: &str, body: &str) -> Result<Option<i64>> {
+    let json = json.trim_end_matches('\0');
+    if json.is_empty() {
+        return Ok(None);
+    }
+    let value: serde_json::Value = serde_json::from_str(json)?;
+    // QE identities predating version 2 use `qeIdentity`
+    let body = value.get(body).or_else(|| value.get("qeIdentity"));
+    match body.and_then(|body| body.get("nextUpdate")) {
+        Some(serde_json::Value::String(next_update)) => Ok(Some(parse_date(next_update)?)),
+        Some(_) => bail!("Invalid nextUpdate"),
+        None => Ok(None),
+    }
+}
+
+/// DER encoding of a CRL of the collateral, which the quote provider library
+/// serves PEM or hex encoded
+fn crl_der(crl: &str, name: &str) -> Result<Vec<u8>> {
+    let crl = crl.trim_end_matches('\0').trim();
+    if crl.starts_with("-----BEGIN") {
+        let mut blocks = pem_blocks(crl)?;
+        return Ok(blocks.remove(0));
+    }
+    hex::decode(crl).with_context(|| format!("Invalid encoding of the {}", name))
+}
+
+/// PCK certificate of the collateral followed by its signing chain
+fn collateral_pck_chain(collateral: &SgxCollateral) -> Result<Vec<Vec<u8>>> {
+    ensure!(
+        !collateral.pck_certificate.trim_end_matches('\0').is_empty(),
+        "The quote doesn't embed the PCK certificate chain, and the collateral has no PCK certificate"
+    );
+    let mut chain = pem_blocks(&collateral.pck_certificate).context("Invalid PCK certificate")?;
+    chain.extend(pem_blocks(&collateral.pck_signing_chain).context("Invalid PCK signing chain")?);
+    Ok(chain)
+}
+
+/// Parse a hex encoded field of the QE identity
+fn identity_bytes<const N: usize>(value: &str, name: &str) -> Result<[u8; N]> {
+    hex::decode(value)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .with_context(|| {
+            format!(
+                "The {} of the QE identity must be {} hex encoded bytes",
+                name, N
+            )
+        })
+}
+
+/// Verifies quotes against a trusted root CA at a given time
+pub struct Verifier {
+    root_ca: Vec<u8>,
+    time: i64,
+    allow_simulated: bool,
+}
+
+impl Verifier {
+    /// Verifier trusting the Intel SGX root CA, at the current time
+    pub fn new() -> Self {
+        Self::with_root_ca_pem(INTEL_SGX_ROOT_CA).expect("Invalid Intel SGX root CA")
+    }
+
+    /// Verifier trusting another root CA, at the current time
+    pub fn with_root_ca_pem(root_ca: &str) -> Result<Self> {
+        let root_ca = pem_blocks(root_ca)?.remove(0);
+        parse_certificate(&root_ca).context("Invalid root CA")?;
+        let time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        Ok(Verifier {
+            root_ca,
+            time,
+            allow_simulated: false,
+        })
+    }
+
+    /// Verify as if the current time was `time`, a Unix timestamp
+    pub fn at(mut self, time: i64) -> Self {
+        self.time = time;
+        self
+    }
+
+    /// Test mode: also accept simulated quotes, trusting the root CA of the
+    /// throwaway PKI they embed. They prove nothing about the server.
+    pub fn allow_simulated(mut self) -> Self {
+        self.allow_simulated = true;
+        self
+    }
+
+    pub fn verify(&self, quote: &[u8], collateral: &SgxCollateral) -> Result<VerifiedQuote> {
+        let quote = Quote::parse(quote)?;
+        let time = self.time;
+
+        // PCK certificate chain, and the CRLs of the root CA and the PCK CA
+        let pck_chain = match quote.pck_cert_chain() {
+            Ok(chain) => pem_blocks(chain)?,
+            // The quote only identifies the PCK certificate, which the quote
+            // provider library fetched with the collateral
+            Err(_) => collateral_pck_chain(collateral)?,
+        };
+        let trusted_root_ca = if quote.is_simulated() {
+            ensure!(
+                self.allow_simulated,
+                "The quote is simulated, it was not produced by an SGX enclave"
+            );
+            pck_chain.last().unwrap()
+        } else {
+            &self.root_ca
+        };
+        pki::verify_chain(&pck_chain, trusted_root_ca, time)
+            .context("Invalid PCK certificate chain")?;
+        ensure!(
+            pck_chain.len() == 3,
+            "The PCK certificate chain must be made of the PCK, PCK CA and root CA certificates"
+        );
+        let pck = parse_certificate(&pck_chain[0])?;
+        let pck_ca = parse_certificate(&pck_chain[1])?;
+        let root_ca = parse_certificate(trusted_root_ca)?;
+
+        let root_ca_crl_der = crl_der(&collateral.root_ca_crl, "root CA CRL")?;
+        let root_ca_crl = parse_crl(&root_ca_crl_der)?;
+        pki::verify_crl(&root_ca_crl, &root_ca, time)?;
+        check_not_revoked(&root_ca_crl, &pck_ca)?;
+
+        let pck_crl_der = crl_der(&collateral.pck_crl, "PCK CRL")?;
+        let pck_crl = parse_crl(&pck_crl_der)?;
+        pki::verify_crl(&pck_crl, &pck_ca, time)?;
+        check_not_revoked(&pck_crl, &pck)?;
+
+        // TCB info and QE identity
+        let tcb_info_chain = pem_blocks(&collateral.tcb_info_issuer_chain)?;
+        pki::verify_chain(&tcb_info_chain, trusted_root_ca, time)
+            .context("Invalid TCB info issuer chain")?;
+        let tcb_signing = parse_certificate(&tcb_info_chain[0])?;
+        check_not_revoked(&root_ca_crl, &tcb_signing)?;
+        let tcb_info =
+            TcbInfo::parse_signed(&collateral.tcb_info, pki::public_key_point(&tcb_signing))?;
+        check_dates(
+            "TCB info",
+            &tcb_info.issue_date,
+            &tcb_info.next_update,
+            time,
+        )?;
+
+        let qe_identity_chain = pem_blocks(&collateral.qe_identity_issuer_chain)?;
+        pki::verify_chain(&qe_identity_chain, trusted_root_ca, time)
+            .context("Invalid QE identity issuer chain")?;
+        let qe_identity_signing = parse_certificate(&qe_identity_chain[0])?;
+        check_not_revoked(&root_ca_crl, &qe_identity_signing)?;
+        let qe_identity = QeIdentity::parse_signed(
+            &collateral.qe_identity,
+            pki::public_key_point(&qe_identity_signing),
+        )?;
+        check_dates(
+            "QE identity",
+            &qe_identity.issue_date,
+            &qe_identity.next_update,
+            time,
+        )?;
+
+        // The TCB info must be the one of the platform
+        let platform = PckExtensions::parse(&pck)?;
+        ensure!(
+            tcb_info
+                .fmspc
+                .eq_ignore_ascii_case(&hex::encode(platform.fmspc)),
+            "The TCB info is for the FMSPC {}, not the FMSPC {} of the platform",
+            tcb_info.fmspc,
+            hex::encode(platform.fmspc)
+        );
+        ensure!(
+            tcb_info
+                .pce_id
+                .eq_ignore_ascii_case(&hex::encode(platform.pce_id)),
+            "The TCB info is for the PCE-ID {}, not the PCE-ID {} of the platform",
+            tcb_info.pce_id,
+            hex::encode(platform.pce_id)
+        );
+
+        // The quoting enclave, and the attestation key it certifies
+        let qe_tcb_level = check_qe_report(&quote.qe_report, &qe_identity)?;
+        let tcb_evaluation = TcbEvaluation::new(tcb_info.tcb_level(&platform.tcb)?, qe_tcb_level);
+        pki::verify_p256(
+            pki::public_key_point(&pck),
+            quote.qe_report_raw,
+            &quote.qe_report_signature,
+        )
+        .context("Invalid signature of the QE report")?;
+        let mut key_and_auth_data = quote.attestation_key.to_vec();
+        key_and_auth_data.extend_from_slice(quote.qe_auth_data);
+        ensure!(
+            quote.qe_report.report_data[..32] == *digest(&SHA256, &key_and_auth_data).as_ref()
+                && quote.qe_report.report_data[32..] == [0; 32],
+            "The QE report does not bind the attestation key"
+        );
+
+        // The enclave report, signed by the attestation key
+        let mut attestation_key = vec![0x04];
+        attestation_key.extend_from_slice(&quote.attestation_key);
+        pki::verify_p256(&attestation_key, quote.signed_data, &quote.signature)
+            .context("Invalid signature of the quote")?;
+
+        let collateral_expiry = [
+            parse_date(&tcb_info.next_update)?,
+            parse_date(&qe_identity.next_update)?,
+            root_ca_crl
+                .next_update()
+                .map_or(i64::MAX, |date| date.timestamp()),
+            pck_crl
+                .next_update()
+                .map_or(i64::MAX, |date| date.timestamp()),
+        ]
+        .into_iter()
+        .min()
+        .unwrap();
+
+        Ok(VerifiedQuote {
+            simulated: quote.is_simulated(),
+            report: quote.report,
+            fmspc: platform.fmspc,
+            pce_id: platform.pce_id,
+            platform,
+            qe_tcb_status: qe_tcb_level.tcb_status,
+            qe_advisory_ids: qe_tcb_level.advisory_ids.clone(),
+            tcb_evaluation,
+            tcb_info,
+            collateral_expiry,
+        })
+    }
+}
+
+impl Default for Verifier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Check the QE report against the QE identity, returning its TCB level
+fn check_qe_report<'a>(
+    qe_report: &ReportBody,
+    qe_identity: &'a QeIdentity,
+) -> Result<&'a tcb::QeTcbLevel> {
+    let mr_signer: [u8; 32] = identity_bytes(&qe_identity.mrsigner, "MRSIGNER")?;
+    ensure!(
+        qe_report.mr_signer == mr_signer,
+        "The MRSIGNER of the QE does not match the QE identity"
+    );
+    ensure!(
+        qe_report.isv_prod_id == qe_identity.isvprodid,
+        "The product id of the QE does not match the QE identity"
+    );
+
+    let misc_select = u32::from_be_bytes(identity_bytes(&qe_identity.miscselect, "MISCSELECT")?);
+    let misc_mask = u32::from_be_bytes(identity_bytes(
+        &qe_identity.miscselect_mask,
+        "MISCSELECT mask",
+    )?);
+    ensure!(
+        qe_report.misc_select & misc_mask == misc_select & misc_mask,
+        "The MISCSELECT of the QE does not match the QE identity"
+    );
+
+    let attributes: [u8; 16] = identity_bytes(&qe_identity.attributes, "attributes")?;
+    let attributes_mask: [u8; 16] =
+        identity_bytes(&qe_identity.attributes_mask, "attributes mask")?;
+    let matches = qe_report
+        .attributes()
+        .iter()
+        .zip(attributes.iter().zip(attributes_mask))
+        .all(|(report, (identity, mask))| report & mask == identity & mask);
+    ensure!(
+        matches,
+        "The attributes of the QE do not match the QE identity"
+    );
+
+    let level = qe_identity.tcb_level(qe_report.isv_svn)?;
+    if level.tcb_status == TcbStatus::Revoked {
+        bail!("The TCB of the quoting enclave is revoked");
+    }
+    Ok(level)
+}
+
+/// Quote whose signatures and collateral were verified
+#[derive(Clone, Debug)]
+pub struct VerifiedQuote {
+    /// Whether the quote was simulated, only with
+    /// [`Verifier::allow_simulated`]
+    pub simulated: bool,
+    /// Report of the attested enclave
+    pub report: ReportBody,
+    pub fmspc: [u8; 6],
+    pub pce_id: [u8; 2],
+    /// SGX extensions of the PCK certificate: the TCB level and type of the
+    /// platform
+    pub platform: PckExtensions,
+    /// TCB info of the platform, to evaluate its TCB status
+    pub tcb_info: TcbInfo,
+    pub qe_tcb_status: TcbStatus,
+    pub qe_advisory_ids: Vec<String>,
+    /// TCB status of the platform combined with the one of the quoting
+    /// enclave, with their advisories
+    pub tcb_evaluation: TcbEvaluation,
+    /// When the first piece of collateral expires, as a Unix timestamp
+    pub collateral_expiry: i64,
+}
+
+impl VerifiedQuote {
+    pub fn check_manifest(&self, manifest: &Manifest) -> Result<()> {
+        manifest.check(&self.report)
+    }
+
+    /// Fail unless the first half of the report data is the SHA-256 of
+    /// `enclave_held_data`, the enclave TLS certificate for BlindAI enclaves
+    pub fn check_enclave_held_data(&self, enclave_held_data: &[u8]) -> Result<()> {
+        ensure!(
+            self.report.report_data[..32] == *digest(&SHA256, enclave_held_data).as_ref(),
+            "The report data does not match the enclave held data"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,194 @@
+use anyhow::{Context, Result};
+use quote_verification::manifest::Manifest;
+use quote_verification::pki::ra_tls_evidence;
+use quote_verification::{SgxCollateral, Verifier};
+use serde::de::DeserializeOwned;
+use serde_bytes::ByteBuf;
+use std::fs;
+use std::io::Read;
+
+fn usage(name: &str) {
+    println!(
+        "Usage: \n\
+         {name} --quote <quote.bin> --collateral <collateral.json> [options]\n\
+         {name} --server <http://host:9923> [options]\n\
+         \n\
+         Options:\n  \
+           --manifest <manifest.toml>  check the enclave against a manifest\n  \
+           --certificate <cert.der>    check the enclave held data, the\n  \
+                                       public key of RA-TLS certificates\n  \
+           --root-ca <root_ca.pem>     trust another root CA than the Intel one\n  \
+           --time <unix timestamp>     verify at another time than now\n  \
+           --allow-simulated           test mode, accept the simulated quotes of\n  \
+                                       servers built without SGX"
+    );
+}
+
+#[derive(Default)]
+struct Args {
+    quote: Option<String>,
+    collateral: Option<String>,
+    server: Option<String>,
+    manifest: Option<String>,
+    certificate: Option<String>,
+    root_ca: Option<String>,
+    time: Option<i64>,
+    allow_simulated: bool,
+}
+
+fn parse_args() -> Result<Args, ()> {
+    let args: Vec<String> = std::env::args().collect();
+    let mut parsed = Args::default();
+
+    let mut valid = true;
+    let mut rest = args[1..].iter();
+    while let Some(flag) = rest.next() {
+        if flag == "--allow-simulated" {
+            parsed.allow_simulated = true;
+            continue;
+        }
+        let value = match rest.next() {
+            Some(value) => Some(value.to_owned()),
+            None => {
+                valid = false;
+                break;
+            }
+        };
+        match flag.as_str() {
+            "--quote" => parsed.quote = value,
+            "--collateral" => parsed.collateral = value,
+            "--server" => parsed.server = value,
+            "--manifest" => parsed.manifest = value,
+            "--certificate" => parsed.certificate = value,
+            "--root-ca" => parsed.root_ca = value,
+            "--time" => {
+                parsed.time = value.and_then(|time| time.parse().ok());
+                valid &= parsed.time.is_some();
+            }
+            _ => valid = false,
+        }
+    }
+    valid &= matches!(
+        (&parsed.quote, &parsed.collateral, &parsed.server),
+        (Some(_), Some(_), None) | (None, None, Some(_))
+    );
+
+    if !valid {
+        usage(&args[0]);
+        return Err(());
+    }
+    Ok(parsed)
+}
+
+fn get_cbor<T: DeserializeOwned>(url: &str) -> Result<T> {
+    let mut body = Vec::new();
+    ureq::get(url)
+        .call()
+        .with_context(|| format!("Could not get {}", url))?
+        .into_reader()
+        .read_to_end(&mut body)?;
+    Ok(serde_cbor::from_slice(&body)?)
+}
+
+fn verify(args: Args) -> Result<()> {
+    let (quote, collateral, mut certificate) = match &args.server {
+        Some(server) => {
+            let server = server.trim_end_matches('/');
+            let certificate: ByteBuf = get_cbor(&format!("{server}/"))?;
+            // RA-TLS certificates carry the quote
+            let quote = match ra_tls_evidence(&certificate)?.0 {
+                Some(quote) => quote,
+                None => get_cbor::<ByteBuf>(&format!("{server}/quote"))?.into_vec(),
+            };
+            let collateral: SgxCollateral = get_cbor(&format!("{server}/collateral"))?;
+            (quote, collateral, Some(certificate.into_vec()))
+        }
+        None => {
+            let quote =
+                fs::read(args.quote.as_ref().unwrap()).context("Could not read the quote")?;
+            let collateral = fs::read_to_string(args.collateral.as_ref().unwrap())
+                .context("Could not read the collateral")?;
+            (quote, serde_json::from_str(&collateral)?, None)
+        }
+    };
+    if let Some(path) = &args.certificate {
+        certificate = Some(fs::read(path).context("Could not read the certificate")?);
+    }
+
+    let mut verifier = match &args.root_ca {
+        Some(path) => Verifier::with_root_ca_pem(&fs::read_to_string(path)?)?,
+        None => Verifier::new(),
+    };
+    if let Some(time) = args.time {
+        verifier = verifier.at(time);
+    }
+    if args.allow_simulated {
+        verifier = verifier.allow_simulated();
+    }
+
+    let verified = verifier.verify(&quote, &collateral)?;
+    println!("Quote and collateral are valid");
+    if verified.simulated {
+        println!("WARNING: the quote is SIMULATED, it was not produced by an SGX enclave");
+    }
+    println!(
+        "MRENCLAVE:        {}",
+        hex::encode(verified.report.mr_enclave)
+    );
+    println!(
+        "MRSIGNER:         {}",
+        hex::encode(verified.report.mr_signer)
+    );
+    println!("FMSPC:            {}", hex::encode(verified.fmspc));
+    println!(
+        "Platform:         {:?}, {}",
+        verified.platform.sgx_type,
+        if verified.platform.is_multi_package() {
+            "multi-package"
+        } else {
+            "single-package"
+        }
+    );
+    println!(
+        "Platform TCB:     components {:?}, PCE SVN {}",
+        verified.platform.tcb.sgx_components, verified.platform.tcb.pce_svn
+    );
+    println!(
+        "TCB status:       {:?} {:?}",
+        verified.tcb_evaluation.status, verified.tcb_evaluation.advisory_ids
+    );
+    println!(
+        "QE TCB status:    {:?} {:?}",
+        verified.qe_tcb_status, verified.qe_advisory_ids
+    );
+    println!(
+        "Collateral valid until {} (Unix time)",
+        verified.collateral_expiry
+    );
+
+    if let Some(path) = &args.manifest {
+        let manifest = Manifest::from_toml(&fs::read_to_string(path)?)?;
+        verified.check_manifest(&manifest)?;
+        println!("The enclave matches the manifest");
+    }
+    match certificate {
+        Some(certificate) => {
+            let (_, enclave_held_data) = ra_tls_evidence(&certificate)?;
+            verified.check_enclave_held_data(&enclave_held_data)?;
+            println!("The report data matches the enclave certificate");
+        }
+        None => println!("The report data was not checked, no enclave certificate given"),
+    }
+    Ok(())
+}
+
+fn main() {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(()) => std::process::exit(2),
+    };
+    if let Err(e) = verify(args) {
+        println!("Verification failed: {e:#}");
+        std::process::exit(1);
+    }
+}
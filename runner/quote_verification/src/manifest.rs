@@ -0,0 +1,90 @@
+//! Enclave manifest, the `manifest.toml` also shipped with the Python client,
+//! describing the enclave a verified quote must come from
+
+use crate::quote::{ReportBody, ATTRIBUTE_DEBUG, ATTRIBUTE_INIT};
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+struct ManifestFile {
+    mr_enclave: String,
+    allow_debug: bool,
+    attributes_flags_hex: String,
+    attributes_mask_flags_hex: String,
+    attributes_xfrm_hex: String,
+    attributes_mask_xfrm_hex: String,
+    misc_select_hex: String,
+    misc_mask_hex: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Manifest {
+    pub mr_enclave: [u8; 32],
+    pub allow_debug: bool,
+    pub attributes_flags: u64,
+    pub attributes_mask_flags: u64,
+    pub attributes_xfrm: u64,
+    pub attributes_mask_xfrm: u64,
+    pub misc_select: u32,
+    pub misc_mask: u32,
+}
+
+fn parse_hex(value: &str) -> Result<u64> {
+    u64::from_str_radix(value.trim_start_matches("0x"), 16)
+        .with_context(|| format!("Invalid hex value {:?}", value))
+}
+
+impl Manifest {
+    pub fn from_toml(toml: &str) -> Result<Self> {
+        let file: ManifestFile = toml::from_str(toml).context("Invalid enclave manifest")?;
+        Ok(Manifest {
+            mr_enclave: hex::decode(&file.mr_enclave)
+                .ok()
+                .and_then(|mr_enclave| mr_enclave.try_into().ok())
+                .context("The MRENCLAVE of the manifest must be 32 hex encoded bytes")?,
+            allow_debug: file.allow_debug,
+            attributes_flags: parse_hex(&file.attributes_flags_hex)?,
+            attributes_mask_flags: parse_hex(&file.attributes_mask_flags_hex)?,
+            attributes_xfrm: parse_hex(&file.attributes_xfrm_hex)?,
+            attributes_mask_xfrm: parse_hex(&file.attributes_mask_xfrm_hex)?,
+            misc_select: parse_hex(&file.misc_select_hex)?.try_into()?,
+            misc_mask: parse_hex(&file.misc_mask_hex)?.try_into()?,
+        })
+    }
+
+    /// Fail unless `report` is the report of the enclave described by the
+    /// manifest
+    pub fn check(&self, report: &ReportBody) -> Result<()> {
+        if report.mr_enclave != self.mr_enclave {
+            bail!(
+                "The MRENCLAVE {} does not match the manifest, expected {}",
+                hex::encode(report.mr_enclave),
+                hex::encode(self.mr_enclave)
+            );
+        }
+        if report.attributes_flags & ATTRIBUTE_DEBUG != 0 && !self.allow_debug {
+            bail!("The enclave is running in debug mode but the manifest forbids debug mode");
+        }
+        if report.attributes_flags & self.attributes_mask_flags
+            != self.attributes_flags | ATTRIBUTE_INIT
+        {
+            bail!(
+                "The attributes flags {:#x} do not conform to the manifest",
+                report.attributes_flags
+            );
+        }
+        if report.attributes_xfrm & self.attributes_mask_xfrm != self.attributes_xfrm {
+            bail!(
+                "The attributes XFRM {:#x} do not conform to the manifest",
+                report.attributes_xfrm
+            );
+        }
+        if report.misc_select & self.misc_mask != self.misc_select {
+            bail!(
+                "The MISCSELECT {:#x} does not conform to the manifest",
+                report.misc_select
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,403 @@
+//! Certificate chains, CRLs and SGX extensions of the Intel SGX PKI, as
+//! described in the Intel SGX PCK Certificate and CRL Profile
+//! <https://api.trustedservices.intel.com/documents/Intel_SGX_PCK_Certificate_CRL_Spec-1.4.pdf>
+
+use crate::tcb::Tcb;
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use der_parser::ber::{BerObject, Tag};
+use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED};
+use serde::{Serialize, Serializer};
+use x509_parser::certificate::X509Certificate;
+use x509_parser::pem::Pem;
+use x509_parser::prelude::FromDer;
+use x509_parser::revocation_list::CertificateRevocationList;
+
+/// Extension of RA-TLS certificates carrying the raw quote, as used by Gramine
+/// and the sgx-ra-tls library
+pub const RA_TLS_QUOTE_OID: &str = "1.2.840.113741.1337.6";
+
+const SGX_EXTENSIONS_OID: &str = "1.2.840.113741.1.13.1";
+const PPID_OID: &str = "1.2.840.113741.1.13.1.1";
+/// Its entries are `TCB_OID.1` to `TCB_OID.16` for the SGX TCB components,
+/// `TCB_OID.17` for the PCE SVN and `TCB_OID.18` for the CPU SVN
+const TCB_OID: &str = "1.2.840.113741.1.13.1.2";
+const PCE_ID_OID: &str = "1.2.840.113741.1.13.1.3";
+const FMSPC_OID: &str = "1.2.840.113741.1.13.1.4";
+const SGX_TYPE_OID: &str = "1.2.840.113741.1.13.1.5";
+const PLATFORM_INSTANCE_ID_OID: &str = "1.2.840.113741.1.13.1.6";
+const CONFIGURATION_OID: &str = "1.2.840.113741.1.13.1.7";
+const DYNAMIC_PLATFORM_OID: &str = "1.2.840.113741.1.13.1.7.1";
+const CACHED_KEYS_OID: &str = "1.2.840.113741.1.13.1.7.2";
+const SMT_ENABLED_OID: &str = "1.2.840.113741.1.13.1.7.3";
+
+/// Decode the PEM blocks of `pem`, in order
+pub fn pem_blocks(pem: &str) -> Result<Vec<Vec<u8>>> {
+    let blocks = Pem::iter_from_buffer(pem.as_bytes())
+        .map(|block| Ok(block?.contents))
+        .collect::<Result<Vec<_>>>()?;
+    ensure!(!blocks.is_empty(), "No PEM block found");
+    Ok(blocks)
+}
+
+pub fn parse_certificate(der: &[u8]) -> Result<X509Certificate<'_>> {
+    let (_, certificate) = X509Certificate::from_der(der)?;
+    Ok(certificate)
+}
+
+pub fn parse_crl(der: &[u8]) -> Result<CertificateRevocationList<'_>> {
+    let (_, crl) = CertificateRevocationList::from_der(der)?;
+    Ok(crl)
+}
+
+fn common_name<'a>(certificate: &'a X509Certificate) -> &'a str {
+    certificate
+        .subject()
+        .iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+        .unwrap_or("<no common name>")
+}
+
+/// Verify that `chain`, leaf first, is issued by the trusted `root` and valid
+/// at `time` (a Unix timestamp)
+///
+/// The chain must end with the root certificate itself.
+pub fn verify_chain(chain: &[Vec<u8>], root: &[u8], time: i64) -> Result<()> {
+    ensure!(
+        chain.last().map(Vec::as_slice) == Some(root),
+        "The certificate chain does not end with the trusted root CA"
+    );
+    let certificates = chain
+        .iter()
+        .map(|der| parse_certificate(der))
+        .collect::<Result<Vec<_>>>()?;
+
+    for (i, certificate) in certificates.iter().enumerate() {
+        let issuer = certificates.get(i + 1).unwrap_or(certificate);
+        let name = common_name(certificate);
+        let validity = certificate.validity();
+        ensure!(
+            validity.not_before.timestamp() <= time && time <= validity.not_after.timestamp(),
+            "The certificate {:?} is not valid at this time",
+            name
+        );
+        ensure!(
+            certificate.issuer().as_raw() == issuer.subject().as_raw(),
+            "The certificate {:?} is not issued by {:?}",
+            name,
+            common_name(issuer)
+        );
+        certificate
+            .verify_signature(Some(issuer.public_key()))
+            .map_err(|e| anyhow!("Invalid signature of the certificate {:?}: {}", name, e))?;
+        if i > 0 {
+            let is_ca = certificate
+                .basic_constraints()?
+                .is_some_and(|constraints| constraints.value.ca);
+            ensure!(is_ca, "The certificate {:?} is not a CA", name);
+        }
+    }
+    Ok(())
+}
+
+/// Verify that `crl` is issued by `issuer` and valid at `time`
+pub fn verify_crl(
+    crl: &CertificateRevocationList,
+    issuer: &X509Certificate,
+    time: i64,
+) -> Result<()> {
+    let issuer_name = common_name(issuer);
+    ensure!(
+        crl.issuer().as_raw() == issuer.subject().as_raw(),
+        "The CRL is not issued by {:?}",
+        issuer_name
+    );
+    crl.verify_signature(issuer.public_key())
+        .map_err(|e| anyhow!("Invalid signature of the CRL of {:?}: {}", issuer_name, e))?;
+    let next_update = crl
+        .next_update()
+        .with_context(|| format!("The CRL of {:?} has no next update", issuer_name))?;
+    ensure!(
+        crl.last_update().timestamp() <= time && time <= next_update.timestamp(),
+        "The CRL of {:?} is not valid at this time",
+        issuer_name
+    );
+    Ok(())
+}
+
+/// Fail if `certificate` is revoked by `crl`
+pub fn check_not_revoked(
+    crl: &CertificateRevocationList,
+    certificate: &X509Certificate,
+) -> Result<()> {
+    let revoked = crl
+        .iter_revoked_certificates()
+        .any(|revoked| revoked.raw_serial() == certificate.raw_serial());
+    if revoked {
+        bail!("The certificate {:?} is revoked", common_name(certificate));
+    }
+    Ok(())
+}
+
+/// Verify a raw `r || s` ECDSA P-256 SHA-256 signature of `data`, with the
+/// public key given as an uncompressed point
+pub fn verify_p256(public_key: &[u8], data: &[u8], signature: &[u8]) -> Result<()> {
+    UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, public_key)
+        .verify(data, signature)
+        .map_err(|_| anyhow!("Invalid ECDSA signature"))
+}
+
+/// Uncompressed public key point of a certificate
+pub fn public_key_point<'a>(certificate: &'a X509Certificate) -> &'a [u8] {
+    &certificate.public_key().subject_public_key.data
+}
+
+/// Attestation evidence of an enclave TLS certificate, as the Python client
+/// extracts it: the quote embedded in RA-TLS certificates and the enclave held
+/// data, their DER encoded public key as the quote binds it, or the
+/// certificate itself when it embeds no quote
+pub fn ra_tls_evidence(certificate: &[u8]) -> Result<(Option<Vec<u8>>, Vec<u8>)> {
+    let parsed = parse_certificate(certificate)?;
+    let quote = parsed
+        .extensions()
+        .iter()
+        .find(|extension| extension.oid.to_id_string() == RA_TLS_QUOTE_OID);
+    Ok(match quote {
+        Some(quote) => (Some(quote.value.to_vec()), parsed.public_key().raw.to_vec()),
+        None => (None, certificate.to_vec()),
+    })
+}
+
+/// SGX type of a platform, from the PCK certificate
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum SgxType {
+    Standard,
+    Scalable,
+    ScalableWithIntegrity,
+}
+
+/// Configuration of a multi-package platform. Each flag is optional.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct PlatformConfiguration {
+    pub dynamic_platform: Option<bool>,
+    pub cached_keys: Option<bool>,
+    pub smt_enabled: Option<bool>,
+}
+
+/// Platform information found in the SGX extensions of a PCK certificate
+///
+/// Byte strings are serialized hex encoded, as in the TCB info.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct PckExtensions {
+    #[serde(serialize_with = "hex_bytes")]
+    pub ppid: [u8; 16],
+    /// SVNs of the SGX TCB components and PCE SVN the PCK certificate was
+    /// issued for, the TCB level of the platform
+    pub tcb: Tcb,
+    #[serde(serialize_with = "hex_bytes")]
+    pub cpu_svn: [u8; 16],
+    #[serde(serialize_with = "hex_bytes")]
+    pub pce_id: [u8; 2],
+    #[serde(serialize_with = "hex_bytes")]
+    pub fmspc: [u8; 6],
+    pub sgx_type: SgxType,
+    /// Only in the PCK certificates of multi-package platforms, issued by the
+    /// Platform CA
+    #[serde(serialize_with = "optional_hex_bytes")]
+    pub platform_instance_id: Option<[u8; 16]>,
+    pub configuration: Option<PlatformConfiguration>,
+}
+
+impl PckExtensions {
+    pub fn parse(certificate: &X509Certificate) -> Result<Self> {
+        let extension = certificate
+            .extensions()
+            .iter()
+            .find(|extension| extension.oid.to_id_string() == SGX_EXTENSIONS_OID)
+            .context("The PCK certificate has no SGX extensions")?;
+        Self::from_der(extension.value)
+    }
+
+    /// Parse the DER encoded value of the SGX extensions
+    pub fn from_der(der: &[u8]) -> Result<Self> {
+        let (rest, extensions) = der_parser::parse_der(der)?;
+        ensure!(rest.is_empty(), "Trailing data after the SGX extensions");
+
+        let mut ppid = None;
+        let mut tcb = None;
+        let mut pce_id = None;
+        let mut fmspc = None;
+        let mut sgx_type = None;
+        let mut platform_instance_id = None;
+        let mut configuration = None;
+        for entry in extensions.as_sequence()? {
+            let (oid, value) = sgx_extension(entry)?;
+            match oid.as_str() {
+                PPID_OID => set(&mut ppid, octet_string(value, "PPID")?, "PPID")?,
+                TCB_OID => set(&mut tcb, parse_tcb(value)?, "TCB")?,
+                PCE_ID_OID => set(&mut pce_id, octet_string(value, "PCE-ID")?, "PCE-ID")?,
+                FMSPC_OID => set(&mut fmspc, octet_string(value, "FMSPC")?, "FMSPC")?,
+                SGX_TYPE_OID => {
+                    let value = match enumerated(value, "SGX type")? {
+                        0 => SgxType::Standard,
+                        1 => SgxType::Scalable,
+                        2 => SgxType::ScalableWithIntegrity,
+                        other => bail!("Unknown SGX type {}", other),
+                    };
+                    set(&mut sgx_type, value, "SGX type")?
+                }
+                PLATFORM_INSTANCE_ID_OID => set(
+                    &mut platform_instance_id,
+                    octet_string(value, "platform instance ID")?,
+                    "platform instance ID",
+                )?,
+                CONFIGURATION_OID => set(
+                    &mut configuration,
+                    parse_configuration(value)?,
+                    "configuration",
+                )?,
+                // Extensions added by later versions of the specification
+                _ => {}
+            }
+        }
+        let (tcb, cpu_svn) = tcb.context("The SGX extensions have no TCB")?;
+        Ok(PckExtensions {
+            ppid: ppid.context("The SGX extensions have no PPID")?,
+            tcb,
+            cpu_svn,
+            pce_id: pce_id.context("The SGX extensions have no PCE-ID")?,
+            fmspc: fmspc.context("The SGX extensions have no FMSPC")?,
+            sgx_type: sgx_type.context("The SGX extensions have no SGX type")?,
+            platform_instance_id,
+            configuration,
+        })
+    }
+
+    /// Whether the platform has several CPU packages, as told by the
+    /// platform instance ID only multi-package platforms have
+    pub fn is_multi_package(&self) -> bool {
+        self.platform_instance_id.is_some()
+    }
+}
+
+/// `SEQUENCE` of the 16 SGX TCB component SVNs, the PCE SVN and the CPU SVN
+fn parse_tcb(value: &BerObject) -> Result<(Tcb, [u8; 16])> {
+    let mut sgx_components = [None; 16];
+    let mut pce_svn = None;
+    let mut cpu_svn = None;
+    for entry in value.as_sequence()? {
+        let (oid, value) = sgx_extension(entry)?;
+        let Some(arc) = oid
+            .strip_prefix(TCB_OID)
+            .and_then(|arc| arc.strip_prefix('.'))
+        else {
+            bail!("Unexpected extension {} in the TCB", oid);
+        };
+        match arc.parse::<usize>() {
+            Ok(i @ 1..=16) => {
+                let name = format!("SGX TCB component {} SVN", i);
+                let svn = integer(value, &name, u8::MAX.into())? as u8;
+                set(&mut sgx_components[i - 1], svn, &name)?
+            }
+            Ok(17) => set(
+                &mut pce_svn,
+                integer(value, "PCE SVN", u16::MAX.into())? as u16,
+                "PCE SVN",
+            )?,
+            Ok(18) => set(&mut cpu_svn, octet_string(value, "CPU SVN")?, "CPU SVN")?,
+            _ => {}
+        }
+    }
+
+    let mut svns = [0; 16];
+    for (i, (svn, component)) in svns.iter_mut().zip(sgx_components).enumerate() {
+        *svn = component.with_context(|| format!("The TCB has no SGX TCB component {}", i + 1))?;
+    }
+    let tcb = Tcb {
+        sgx_components: svns,
+        pce_svn: pce_svn.context("The TCB has no PCE SVN")?,
+    };
+    Ok((tcb, cpu_svn.context("The TCB has no CPU SVN")?))
+}
+
+/// `SEQUENCE` of the optional configuration flags
+fn parse_configuration(value: &BerObject) -> Result<PlatformConfiguration> {
+    let mut configuration = PlatformConfiguration::default();
+    for entry in value.as_sequence()? {
+        let (oid, value) = sgx_extension(entry)?;
+        let (flag, name) = match oid.as_str() {
+            DYNAMIC_PLATFORM_OID => (&mut configuration.dynamic_platform, "dynamic platform"),
+            CACHED_KEYS_OID => (&mut configuration.cached_keys, "cached keys"),
+            SMT_ENABLED_OID => (&mut configuration.smt_enabled, "SMT enabled"),
+            _ => continue,
+        };
+        let value = value
+            .as_bool()
+            .map_err(|_| anyhow!("The {} flag must be a boolean", name))?;
+        set(flag, value, name)?;
+    }
+    Ok(configuration)
+}
+
+/// Fill an extension, which must appear once
+fn set<T>(slot: &mut Option<T>, value: T, name: &str) -> Result<()> {
+    ensure!(slot.is_none(), "Duplicate {} in the SGX extensions", name);
+    *slot = Some(value);
+    Ok(())
+}
+
+/// Split an SGX extension `SEQUENCE { sGXExtensionId, sGXExtensionValue }`
+fn sgx_extension<'a>(entry: &'a BerObject<'a>) -> Result<(String, &'a BerObject<'a>)> {
+    match entry.as_sequence()?.as_slice() {
+        [oid, value] => Ok((oid.as_oid()?.to_id_string(), value)),
+        _ => bail!("Invalid SGX extension"),
+    }
+}
+
+fn octet_string<const N: usize>(value: &BerObject, name: &str) -> Result<[u8; N]> {
+    ensure!(
+        value.header.tag() == Tag::OctetString,
+        "The {} must be an octet string",
+        name
+    );
+    value
+        .as_slice()?
+        .try_into()
+        .map_err(|_| anyhow!("The {} must be {} bytes long", name, N))
+}
+
+fn integer(value: &BerObject, name: &str, max: u32) -> Result<u32> {
+    ensure!(
+        value.header.tag() == Tag::Integer,
+        "The {} must be an integer",
+        name
+    );
+    value
+        .as_u32()
+        .ok()
+        .filter(|&value| value <= max)
+        .with_context(|| format!("The {} must be between 0 and {}", name, max))
+}
+
+fn enumerated(value: &BerObject, name: &str) -> Result<u32> {
+    ensure!(
+        value.header.tag() == Tag::Enumerated,
+        "The {} must be enumerated",
+        name
+    );
+    Ok(value.as_u32()?)
+}
+
+fn hex_bytes<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&hex::encode(bytes))
+}
+
+fn optional_hex_bytes<S: Serializer>(
+    bytes: &Option<[u8; 16]>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match bytes {
+        Some(bytes) => hex_bytes(bytes, serializer),
+        None => serializer.serialize_none(),
+    }
+}
@@ -0,0 +1,388 @@
+//! Parsing of version 3 DCAP quotes, as laid out in the Intel SGX ECDSA Quote
+//! Library API reference (appendix A)
+//! <https://download.01.org/intel-sgx/latest/dcap-latest/linux/docs/Intel_SGX_ECDSA_QuoteLibReference_DCAP_API.pdf>
+
+use std::fmt;
+
+/// Version of the quotes produced by the DCAP quoting enclave
+pub const QUOTE_VERSION: u16 = 3;
+/// ECDSA-256-with-P-256 attestation key
+pub const ATTESTATION_KEY_TYPE_ECDSA_P256: u16 = 2;
+/// PPID in clear, with the CPU SVN, PCE SVN and PCE-ID
+pub const CERTIFICATION_DATA_PPID_CLEARTEXT: u16 = 1;
+/// PPID encrypted with RSA-2048-OAEP, with the CPU SVN, PCE SVN and PCE-ID
+pub const CERTIFICATION_DATA_PPID_RSA2048_OAEP: u16 = 2;
+/// PPID encrypted with RSA-3072-OAEP, with the CPU SVN, PCE SVN and PCE-ID
+pub const CERTIFICATION_DATA_PPID_RSA3072_OAEP: u16 = 3;
+/// Concatenated PCK certificate chain, PEM formatted
+pub const CERTIFICATION_DATA_PCK_CERT_CHAIN: u16 = 5;
+/// QE report certification data, wrapping another certification data
+pub const CERTIFICATION_DATA_QE_REPORT: u16 = 6;
+/// QE vendor id of the quotes simulated by BlindAI servers built without SGX
+pub const SIMULATED_QE_VENDOR_ID: [u8; 16] = *b"BLINDAISIMULATED";
+
+const HEADER_SIZE: usize = 48;
+pub const REPORT_BODY_SIZE: usize = 384;
+
+/// DEBUG bit of the report attributes flags
+pub const ATTRIBUTE_DEBUG: u64 = 1 << 1;
+/// INIT bit of the report attributes flags, set in every report
+pub const ATTRIBUTE_INIT: u64 = 1 << 0;
+
+/// Why a quote could not be parsed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum QuoteError {
+    /// The quote ends before the given field
+    Truncated(&'static str),
+    UnsupportedVersion(u16),
+    UnsupportedAttestationKeyType(u16),
+    UnsupportedCertificationDataType(u16),
+    InvalidCertificationData {
+        data_type: u16,
+        reason: String,
+    },
+    /// The certification data of the given type does not embed the PCK
+    /// certificate chain
+    NoPckCertChain(u16),
+    InvalidReportBodySize(usize),
+}
+
+impl fmt::Display for QuoteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QuoteError::Truncated(field) => {
+                write!(f, "The quote is truncated, could not read the {}", field)
+            }
+            QuoteError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported quote version {}", version)
+            }
+            QuoteError::UnsupportedAttestationKeyType(key_type) => {
+                write!(f, "Unsupported attestation key type {}", key_type)
+            }
+            QuoteError::UnsupportedCertificationDataType(data_type) => {
+                write!(f, "Unsupported certification data type {}", data_type)
+            }
+            QuoteError::InvalidCertificationData { data_type, reason } => write!(
+                f,
+                "Invalid certification data of type {}: {}",
+                data_type, reason
+            ),
+            QuoteError::NoPckCertChain(data_type) => write!(
+                f,
+                "The certification data of type {} does not embed the PCK certificate chain (type {})",
+                data_type, CERTIFICATION_DATA_PCK_CERT_CHAIN
+            ),
+            QuoteError::InvalidReportBodySize(size) => write!(
+                f,
+                "A report body is {} bytes long, got {}",
+                REPORT_BODY_SIZE, size
+            ),
+        }
+    }
+}
+
+impl std::error::Error for QuoteError {}
+
+/// Reads the little-endian fields of a quote
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize, field: &'static str) -> Result<&'a [u8], QuoteError> {
+        if self.0.len() < len {
+            return Err(QuoteError::Truncated(field));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self, field: &'static str) -> Result<[u8; N], QuoteError> {
+        Ok(self.take(N, field)?.try_into().unwrap())
+    }
+
+    fn u16(&mut self, field: &'static str) -> Result<u16, QuoteError> {
+        Ok(u16::from_le_bytes(self.array(field)?))
+    }
+
+    fn u32(&mut self, field: &'static str) -> Result<u32, QuoteError> {
+        Ok(u32::from_le_bytes(self.array(field)?))
+    }
+
+    fn u64(&mut self, field: &'static str) -> Result<u64, QuoteError> {
+        Ok(u64::from_le_bytes(self.array(field)?))
+    }
+}
+
+/// SGX report body (`sgx_report_body_t`)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReportBody {
+    pub cpu_svn: [u8; 16],
+    pub misc_select: u32,
+    pub attributes_flags: u64,
+    pub attributes_xfrm: u64,
+    pub mr_enclave: [u8; 32],
+    pub mr_signer: [u8; 32],
+    pub isv_prod_id: u16,
+    pub isv_svn: u16,
+    pub report_data: [u8; 64],
+}
+
+impl ReportBody {
+    pub fn parse(data: &[u8]) -> Result<Self, QuoteError> {
+        if data.len() != REPORT_BODY_SIZE {
+            return Err(QuoteError::InvalidReportBodySize(data.len()));
+        }
+        let mut reader = Reader(data);
+        let cpu_svn = reader.array("CPU SVN")?;
+        let misc_select = reader.u32("MISCSELECT")?;
+        reader.take(28, "reserved bytes")?;
+        let attributes_flags = reader.u64("attributes")?;
+        let attributes_xfrm = reader.u64("attributes")?;
+        let mr_enclave = reader.array("MRENCLAVE")?;
+        reader.take(32, "reserved bytes")?;
+        let mr_signer = reader.array("MRSIGNER")?;
+        reader.take(96, "reserved bytes and config id")?;
+        let isv_prod_id = reader.u16("ISV product id")?;
+        let isv_svn = reader.u16("ISV SVN")?;
+        reader.take(60, "reserved bytes and ISV family id")?;
+        let report_data = reader.array("report data")?;
+
+        Ok(ReportBody {
+            cpu_svn,
+            misc_select,
+            attributes_flags,
+            attributes_xfrm,
+            mr_enclave,
+            mr_signer,
+            isv_prod_id,
+            isv_svn,
+            report_data,
+        })
+    }
+
+    /// Attributes as laid out in the report, flags then XFRM
+    pub fn attributes(&self) -> [u8; 16] {
+        let mut attributes = [0; 16];
+        attributes[..8].copy_from_slice(&self.attributes_flags.to_le_bytes());
+        attributes[8..].copy_from_slice(&self.attributes_xfrm.to_le_bytes());
+        attributes
+    }
+}
+
+/// Encryption of the PPID in the certification data
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PpidEncryption {
+    Cleartext,
+    Rsa2048Oaep,
+    Rsa3072Oaep,
+}
+
+/// Identifier of the PCK certificate of a platform, with which a PCCS serves
+/// the certificate
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PckCertId<'a> {
+    pub ppid: &'a [u8],
+    pub ppid_encryption: PpidEncryption,
+    pub cpu_svn: [u8; 16],
+    pub pce_svn: u16,
+    /// As laid out in the quote, little-endian
+    pub pce_id: [u8; 2],
+}
+
+/// Certification data of the QE report, which identifies the PCK key that
+/// signed it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CertificationData<'a> {
+    /// Types 1 to 3
+    PckCertId(PckCertId<'a>),
+    /// Type 5, leaf first
+    PckCertChain(&'a str),
+    /// Type 6
+    QeReport(Box<QeReportCertificationData<'a>>),
+}
+
+/// A QE report and the certification data of the key that signed it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QeReportCertificationData<'a> {
+    pub qe_report: ReportBody,
+    pub qe_report_signature: [u8; 64],
+    pub qe_auth_data: &'a [u8],
+    pub certification_data: CertificationData<'a>,
+}
+
+impl<'a> CertificationData<'a> {
+    pub fn parse(data_type: u16, data: &'a [u8]) -> Result<Self, QuoteError> {
+        let invalid = |reason: String| QuoteError::InvalidCertificationData { data_type, reason };
+        match data_type {
+            CERTIFICATION_DATA_PPID_CLEARTEXT
+            | CERTIFICATION_DATA_PPID_RSA2048_OAEP
+            | CERTIFICATION_DATA_PPID_RSA3072_OAEP => {
+                let (ppid_encryption, ppid_len) = match data_type {
+                    CERTIFICATION_DATA_PPID_CLEARTEXT => (PpidEncryption::Cleartext, 16),
+                    CERTIFICATION_DATA_PPID_RSA2048_OAEP => (PpidEncryption::Rsa2048Oaep, 256),
+                    _ => (PpidEncryption::Rsa3072Oaep, 384),
+                };
+                // PPID || CPU SVN || PCE SVN || PCE-ID
+                if data.len() != ppid_len + 20 {
+                    return Err(invalid(format!(
+                        "expected {} bytes, got {}",
+                        ppid_len + 20,
+                        data.len()
+                    )));
+                }
+                let mut reader = Reader(data);
+                Ok(CertificationData::PckCertId(PckCertId {
+                    ppid: reader.take(ppid_len, "PPID")?,
+                    ppid_encryption,
+                    cpu_svn: reader.array("CPU SVN")?,
+                    pce_svn: reader.u16("PCE SVN")?,
+                    pce_id: reader.array("PCE-ID")?,
+                }))
+            }
+            CERTIFICATION_DATA_PCK_CERT_CHAIN => std::str::from_utf8(data)
+                .map(|chain| CertificationData::PckCertChain(chain.trim_end_matches('\0')))
+                .map_err(|_| invalid("the PCK certificate chain is not valid PEM".to_string())),
+            CERTIFICATION_DATA_QE_REPORT => {
+                let mut reader = Reader(data);
+                let qe_report = ReportBody::parse(reader.take(REPORT_BODY_SIZE, "QE report")?)?;
+                let qe_report_signature = reader.array("QE report signature")?;
+                let qe_auth_data_len = reader.u16("QE authentication data length")? as usize;
+                let qe_auth_data = reader.take(qe_auth_data_len, "QE authentication data")?;
+                let inner_type = reader.u16("certification data type")?;
+                let inner_len = reader.u32("certification data length")? as usize;
+                let inner_data = reader.take(inner_len, "certification data")?;
+                if inner_type == CERTIFICATION_DATA_QE_REPORT {
+                    return Err(invalid("nested QE report certification data".to_string()));
+                }
+                Ok(CertificationData::QeReport(Box::new(
+                    QeReportCertificationData {
+                        qe_report,
+                        qe_report_signature,
+                        qe_auth_data,
+                        certification_data: CertificationData::parse(inner_type, inner_data)?,
+                    },
+                )))
+            }
+            _ => Err(QuoteError::UnsupportedCertificationDataType(data_type)),
+        }
+    }
+
+    pub fn data_type(&self) -> u16 {
+        match self {
+            CertificationData::PckCertId(id) => match id.ppid_encryption {
+                PpidEncryption::Cleartext => CERTIFICATION_DATA_PPID_CLEARTEXT,
+                PpidEncryption::Rsa2048Oaep => CERTIFICATION_DATA_PPID_RSA2048_OAEP,
+                PpidEncryption::Rsa3072Oaep => CERTIFICATION_DATA_PPID_RSA3072_OAEP,
+            },
+            CertificationData::PckCertChain(_) => CERTIFICATION_DATA_PCK_CERT_CHAIN,
+            CertificationData::QeReport(_) => CERTIFICATION_DATA_QE_REPORT,
+        }
+    }
+
+    /// Certification data of the PCK key, unwrapping the QE report
+    /// certification data
+    pub fn pck(&self) -> &Self {
+        match self {
+            CertificationData::QeReport(qe_report) => &qe_report.certification_data,
+            other => other,
+        }
+    }
+}
+
+/// Version 3 quote with an ECDSA P-256 signature
+#[derive(Clone, Debug)]
+pub struct Quote<'a> {
+    pub version: u16,
+    pub attestation_key_type: u16,
+    pub qe_svn: u16,
+    pub pce_svn: u16,
+    pub qe_vendor_id: [u8; 16],
+    /// First bytes of the user data of the header, with which the Intel
+    /// quoting library identifies the quoting enclave to the PCCS
+    pub qe_id: [u8; 16],
+    pub report: ReportBody,
+    /// Header and enclave report, as signed by the attestation key
+    pub signed_data: &'a [u8],
+    /// Raw `r || s` ECDSA signature of `signed_data`
+    pub signature: [u8; 64],
+    /// Raw `x || y` coordinates of the attestation public key
+    pub attestation_key: [u8; 64],
+    pub qe_report: ReportBody,
+    /// QE report, as signed by the PCK key
+    pub qe_report_raw: &'a [u8],
+    pub qe_report_signature: [u8; 64],
+    pub qe_auth_data: &'a [u8],
+    pub certification_data: CertificationData<'a>,
+}
+
+impl<'a> Quote<'a> {
+    pub fn parse(quote: &'a [u8]) -> Result<Self, QuoteError> {
+        let mut reader = Reader(quote);
+        let version = reader.u16("version")?;
+        if version != QUOTE_VERSION {
+            return Err(QuoteError::UnsupportedVersion(version));
+        }
+        let attestation_key_type = reader.u16("attestation key type")?;
+        if attestation_key_type != ATTESTATION_KEY_TYPE_ECDSA_P256 {
+            return Err(QuoteError::UnsupportedAttestationKeyType(
+                attestation_key_type,
+            ));
+        }
+        reader.take(4, "reserved bytes")?;
+        let qe_svn = reader.u16("QE SVN")?;
+        let pce_svn = reader.u16("PCE SVN")?;
+        let qe_vendor_id = reader.array("QE vendor id")?;
+        let qe_id = reader.array("QE id")?;
+        reader.take(4, "user data")?;
+        let report = ReportBody::parse(reader.take(REPORT_BODY_SIZE, "enclave report")?)?;
+        let signed_data = &quote[..HEADER_SIZE + REPORT_BODY_SIZE];
+
+        let signature_data_len = reader.u32("signature data length")? as usize;
+        let mut reader = Reader(reader.take(signature_data_len, "signature data")?);
+        let signature = reader.array("quote signature")?;
+        let attestation_key = reader.array("attestation key")?;
+        let qe_report_raw = reader.take(REPORT_BODY_SIZE, "QE report")?;
+        let qe_report = ReportBody::parse(qe_report_raw)?;
+        let qe_report_signature = reader.array("QE report signature")?;
+        let qe_auth_data_len = reader.u16("QE authentication data length")? as usize;
+        let qe_auth_data = reader.take(qe_auth_data_len, "QE authentication data")?;
+        let certification_data_type = reader.u16("certification data type")?;
+        let certification_data_len = reader.u32("certification data length")? as usize;
+        let certification_data = CertificationData::parse(
+            certification_data_type,
+            reader.take(certification_data_len, "certification data")?,
+        )?;
+
+        Ok(Quote {
+            version,
+            attestation_key_type,
+            qe_svn,
+            pce_svn,
+            qe_vendor_id,
+            qe_id,
+            report,
+            signed_data,
+            signature,
+            attestation_key,
+            qe_report,
+            qe_report_raw,
+            qe_report_signature,
+            qe_auth_data,
+            certification_data,
+        })
+    }
+
+    /// Whether the quote was simulated in software rather than produced by a
+    /// quoting enclave
+    pub fn is_simulated(&self) -> bool {
+        self.qe_vendor_id == SIMULATED_QE_VENDOR_ID
+    }
+
+    /// PEM encoded PCK certificate chain embedded in the quote, leaf first
+    pub fn pck_cert_chain(&self) -> Result<&'a str, QuoteError> {
+        match self.certification_data.pck() {
+            CertificationData::PckCertChain(chain) => Ok(chain),
+            other => Err(QuoteError::NoPckCertChain(other.data_type())),
+        }
+    }
+}
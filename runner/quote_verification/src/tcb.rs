@@ -0,0 +1,247 @@
+//! TCB info and QE identity, as served by the Intel PCS
+//! <https://api.portal.trustedservices.intel.com/documentation>
+//!
+//! Both are JSON documents such as `{"tcbInfo": {...}, "signature": "..."}`,
+//! whose signature covers the exact bytes of the body.
+
+use crate::pki::verify_p256;
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TcbStatus {
+    UpToDate,
+    SWHardeningNeeded,
+    ConfigurationNeeded,
+    ConfigurationAndSWHardeningNeeded,
+    OutOfDate,
+    OutOfDateConfigurationNeeded,
+    Revoked,
+}
+
+/// TCB of a platform: the SVNs of its 16 SGX TCB components and its PCE SVN
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "Map<String, Value>")]
+pub struct Tcb {
+    pub sgx_components: [u8; 16],
+    pub pce_svn: u16,
+}
+
+impl TryFrom<Map<String, Value>> for Tcb {
+    type Error = anyhow::Error;
+
+    /// Version 2 lists the components as `sgxtcbcomp01svn` to
+    /// `sgxtcbcomp16svn`, version 3 as `sgxtcbcomponents: [{"svn": ..}, ..]`
+    fn try_from(tcb: Map<String, Value>) -> Result<Self> {
+        let svn = |value: Option<&Value>, name: &str| -> Result<u64> {
+            value
+                .and_then(Value::as_u64)
+                .with_context(|| format!("Invalid or missing {} in a TCB level", name))
+        };
+
+        let mut sgx_components = [0; 16];
+        match tcb.get("sgxtcbcomponents") {
+            Some(Value::Array(components)) if components.len() == 16 => {
+                for (svn_out, component) in sgx_components.iter_mut().zip(components) {
+                    *svn_out = svn(component.get("svn"), "component SVN")?.try_into()?;
+                }
+            }
+            Some(_) => bail!("A TCB level must have 16 SGX TCB components"),
+            None => {
+                for (i, svn_out) in sgx_components.iter_mut().enumerate() {
+                    let name = format!("sgxtcbcomp{:02}svn", i + 1);
+                    *svn_out = svn(tcb.get(&name), &name)?.try_into()?;
+                }
+            }
+        }
+        Ok(Tcb {
+            sgx_components,
+            pce_svn: svn(tcb.get("pcesvn"), "pcesvn")?.try_into()?,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TcbLevel {
+    pub tcb: Tcb,
+    pub tcb_date: String,
+    pub tcb_status: TcbStatus,
+    #[serde(default, rename = "advisoryIDs")]
+    pub advisory_ids: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TcbInfo {
+    pub version: u32,
+    pub issue_date: String,
+    pub next_update: String,
+    pub fmspc: String,
+    pub pce_id: String,
+    pub tcb_evaluation_data_number: u32,
+    pub tcb_levels: Vec<TcbLevel>,
+}
+
+impl TcbInfo {
+    /// Parse the TCB info, checking its signature with the uncompressed
+    /// public key of the TCB signing certificate
+    pub fn parse_signed(json: &str, signing_key: &[u8]) -> Result<Self> {
+        let tcb_info: TcbInfo = parse_signed(json, "tcbInfo", signing_key)?;
+        if !(2..=3).contains(&tcb_info.version) {
+            bail!("Unsupported TCB info version {}", tcb_info.version);
+        }
+        Ok(tcb_info)
+    }
+    /// TCB level of a platform with the TCB `tcb`, as found in its PCK
+    /// certificate: the first level whose SGX TCB components and PCE SVN it
+    /// all reaches, the levels being sorted from the most recent
+    pub fn tcb_level(&self, tcb: &Tcb) -> Result<&TcbLevel> {
+        self.tcb_levels
+            .iter()
+            .find(|level| {
+                tcb.sgx_components
+                    .iter()
+                    .zip(level.tcb.sgx_components)
+                    .all(|(&svn, level_svn)| svn >= level_svn)
+                    && tcb.pce_svn >= level.tcb.pce_svn
+            })
+            .ok_or_else(|| anyhow!("The TCB of the platform is below every TCB level"))
+    }
+}
+
+/// TCB status of a platform and of its quoting enclave, as the Intel QVL
+/// reports it
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TcbEvaluation {
+    pub status: TcbStatus,
+    /// Date of the TCB level of the platform
+    pub tcb_date: String,
+    /// Advisories of the platform TCB level, then of the QE TCB level
+    pub advisory_ids: Vec<String>,
+}
+
+impl TcbEvaluation {
+    /// Combine the TCB levels of the platform and of its quoting enclave: an
+    /// out of date quoting enclave makes the platform out of date
+    pub fn new(platform: &TcbLevel, qe: &QeTcbLevel) -> Self {
+        use TcbStatus::*;
+        let status = match (platform.tcb_status, qe.tcb_status) {
+            (_, Revoked) => Revoked,
+            (UpToDate | SWHardeningNeeded, OutOfDate) => OutOfDate,
+            (ConfigurationNeeded | ConfigurationAndSWHardeningNeeded, OutOfDate) => {
+                OutOfDateConfigurationNeeded
+            }
+            (status, _) => status,
+        };
+        let mut advisory_ids = platform.advisory_ids.clone();
+        for id in &qe.advisory_ids {
+            if !advisory_ids.contains(id) {
+                advisory_ids.push(id.clone());
+            }
+        }
+        TcbEvaluation {
+            status,
+            tcb_date: platform.tcb_date.clone(),
+            advisory_ids,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct QeTcb {
+    pub isvsvn: u16,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QeTcbLevel {
+    pub tcb: QeTcb,
+    pub tcb_date: String,
+    pub tcb_status: TcbStatus,
+    #[serde(default, rename = "advisoryIDs")]
+    pub advisory_ids: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QeIdentity {
+    pub version: u32,
+    pub issue_date: String,
+    pub next_update: String,
+    pub tcb_evaluation_data_number: u32,
+    pub miscselect: String,
+    pub miscselect_mask: String,
+    pub attributes: String,
+    pub attributes_mask: String,
+    pub mrsigner: String,
+    pub isvprodid: u16,
+    pub tcb_levels: Vec<QeTcbLevel>,
+}
+
+impl QeIdentity {
+    /// Parse the QE identity, checking its signature with the uncompressed
+    /// public key of the TCB signing certificate
+    pub fn parse_signed(json: &str, signing_key: &[u8]) -> Result<Self> {
+        let qe_identity: QeIdentity = parse_signed(json, "enclaveIdentity", signing_key)?;
+        if qe_identity.version != 2 {
+            bail!("Unsupported QE identity version {}", qe_identity.version);
+        }
+        Ok(qe_identity)
+    }
+
+    /// TCB level of a quoting enclave with the SVN `isv_svn`: the first level
+    /// it reaches, the levels being sorted from the most recent
+    pub fn tcb_level(&self, isv_svn: u16) -> Result<&QeTcbLevel> {
+        self.tcb_levels
+            .iter()
+            .find(|level| isv_svn >= level.tcb.isvsvn)
+            .ok_or_else(|| anyhow!("The QE SVN {} is below every TCB level", isv_svn))
+    }
+}
+
+/// Check the signature of a signed JSON document and parse its body
+fn parse_signed<T: for<'de> Deserialize<'de>>(
+    json: &str,
+    body_name: &str,
+    signing_key: &[u8],
+) -> Result<T> {
+    let document: HashMap<&str, &RawValue> =
+        serde_json::from_str(json.trim_end_matches('\0')).context("Invalid JSON document")?;
+    let body = document
+        .get(body_name)
+        .with_context(|| format!("No {} in the document", body_name))?
+        .get();
+    let signature: &str = document
+        .get("signature")
+        .map(|signature| serde_json::from_str(signature.get()))
+        .context("The document is not signed")??;
+    let signature = hex::decode(signature).context("Invalid signature encoding")?;
+
+    verify_p256(signing_key, body.as_bytes(), &signature)
+        .with_context(|| format!("Invalid signature of the {}", body_name))?;
+    serde_json::from_str(body).with_context(|| format!("Invalid {}", body_name))
+}
+
+/// Parse a date such as `2023-06-14T10:11:26Z` to a Unix timestamp
+pub fn parse_date(date: &str) -> Result<i64> {
+    Ok(OffsetDateTime::parse(date, &Rfc3339)
+        .with_context(|| format!("Invalid date {:?}", date))?
+        .unix_timestamp())
+}
+
+/// Fail unless `time` is between `issue_date` and `next_update`
+pub fn check_dates(name: &str, issue_date: &str, next_update: &str, time: i64) -> Result<()> {
+    if time < parse_date(issue_date)? {
+        bail!("The {} is not valid yet", name);
+    }
+    if time > parse_date(next_update)? {
+        bail!("The {} is expired since {}", name, next_update);
+    }
+    Ok(())
+}
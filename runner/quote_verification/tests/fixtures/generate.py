@@ -0,0 +1,378 @@
+"""Generate the quote and collateral fixtures of the quote verification tests.
+
+No SGX hardware is needed: every key of the Intel PKI (root CA, PCK CA, PCK,
+TCB signing) and of the quoting enclave is replaced by a test key, and the
+structures are laid out as the DCAP quote library and the Intel PCS produce
+them. The tests trust `root_ca.pem` instead of the Intel root.
+
+Usage: python3 generate.py (requires the `cryptography` package)
+"""
+
+import datetime
+import hashlib
+import json
+import struct
+from pathlib import Path
+
+from cryptography import x509
+from cryptography.hazmat.primitives import hashes, serialization
+from cryptography.hazmat.primitives.asymmetric import ec
+from cryptography.hazmat.primitives.asymmetric.utils import decode_dss_signature
+from cryptography.x509.oid import NameOID
+
+OUT = Path(__file__).parent
+
+ISSUE_DATE = datetime.datetime(2024, 1, 1, tzinfo=datetime.timezone.utc)
+NEXT_UPDATE = datetime.datetime(2024, 1, 31, tzinfo=datetime.timezone.utc)
+NOT_AFTER = datetime.datetime(2040, 1, 1, tzinfo=datetime.timezone.utc)
+
+FMSPC = bytes.fromhex("00906ED50000")
+PCE_ID = bytes.fromhex("0000")
+CPU_SVN = bytes([5, 5, 2, 4, 1, 128, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0])
+PCE_SVN = 13
+PPID = bytes.fromhex("a1b2c3d4e5f60718293a4b5c6d7e8f90")
+QE_MRSIGNER = bytes.fromhex(
+    "8c4f5775d796503e96137f77c68a829a0056ac8ded70140b081b094490c57bff"
+)
+MR_ENCLAVE = hashlib.sha256(b"blindai test enclave").digest()
+MR_SIGNER = hashlib.sha256(b"blindai test signer").digest()
+
+SGX_EXTENSIONS = "1.2.840.113741.1.13.1"
+
+
+# Minimal DER encoder for the SGX extensions
+
+
+def der(tag: int, content: bytes) -> bytes:
+    length = len(content)
+    if length < 0x80:
+        return bytes([tag, length]) + content
+    encoded = length.to_bytes((length.bit_length() + 7) // 8, "big")
+    return bytes([tag, 0x80 | len(encoded)]) + encoded + content
+
+
+def der_oid(oid: str) -> bytes:
+    arcs = [int(arc) for arc in oid.split(".")]
+    body = bytes([40 * arcs[0] + arcs[1]])
+    for arc in arcs[2:]:
+        chunk = [arc & 0x7F]
+        arc >>= 7
+        while arc:
+            chunk.append(0x80 | (arc & 0x7F))
+            arc >>= 7
+        body += bytes(reversed(chunk))
+    return der(0x06, body)
+
+
+def der_int(value: int) -> bytes:
+    return der(0x02, value.to_bytes(value.bit_length() // 8 + 1, "big"))
+
+
+def sgx_extensions() -> bytes:
+    def entry(arc: str, value: bytes) -> bytes:
+        return der(0x30, der_oid(f"{SGX_EXTENSIONS}.{arc}") + value)
+
+    tcb = b"".join(
+        entry(f"2.{i + 1}", der_int(svn)) for i, svn in enumerate(CPU_SVN)
+    )
+    tcb += entry("2.17", der_int(PCE_SVN))
+    tcb += entry("2.18", der(0x04, CPU_SVN))
+    return der(
+        0x30,
+        entry("1", der(0x04, PPID))
+        + entry("2", der(0x30, tcb))
+        + entry("3", der(0x04, PCE_ID))
+        + entry("4", der(0x04, FMSPC))
+        + entry("5", der(0x0A, b"\x00")),
+    )
+
+
+# Test PKI
+
+
+def name(common_name: str) -> x509.Name:
+    return x509.Name(
+        [
+            x509.NameAttribute(NameOID.COMMON_NAME, common_name),
+            x509.NameAttribute(NameOID.ORGANIZATION_NAME, "BlindAI test PKI"),
+        ]
+    )
+
+
+def certificate(subject, key, issuer, issuer_key, ca, extensions=()):
+    builder = (
+        x509.CertificateBuilder()
+        .subject_name(subject)
+        .issuer_name(issuer)
+        .public_key(key.public_key())
+        .serial_number(x509.random_serial_number())
+        .not_valid_before(datetime.datetime(2023, 1, 1, tzinfo=datetime.timezone.utc))
+        .not_valid_after(NOT_AFTER)
+        .add_extension(
+            x509.BasicConstraints(ca=ca, path_length=None), critical=True
+        )
+    )
+    for extension in extensions:
+        builder = builder.add_extension(extension, critical=False)
+    return builder.sign(issuer_key, hashes.SHA256())
+
+
+def crl(issuer, issuer_key, revoked=()):
+    builder = (
+        x509.CertificateRevocationListBuilder()
+        .issuer_name(issuer)
+        .last_update(ISSUE_DATE)
+        .next_update(NEXT_UPDATE)
+    )
+    for serial in revoked:
+        builder = builder.add_revoked_certificate(
+            x509.RevokedCertificateBuilder()
+            .serial_number(serial)
+            .revocation_date(ISSUE_DATE)
+            .build()
+        )
+    return builder.sign(issuer_key, hashes.SHA256())
+
+
+def pem(obj) -> str:
+    return obj.public_bytes(serialization.Encoding.PEM).decode()
+
+
+def raw_signature(key, data: bytes) -> bytes:
+    r, s = decode_dss_signature(key.sign(data, ec.ECDSA(hashes.SHA256())))
+    return r.to_bytes(32, "big") + s.to_bytes(32, "big")
+
+
+def raw_public_key(key) -> bytes:
+    point = key.public_key().public_bytes(
+        serialization.Encoding.X962, serialization.PublicFormat.UncompressedPoint
+    )
+    return point[1:]
+
+
+def signed_json(body_name: str, body: dict, key) -> str:
+    # Signatures cover the exact bytes of the body, as served by the PCS
+    body = json.dumps(body, separators=(",", ":"))
+    signature = raw_signature(key, body.encode()).hex()
+    return f'{{"{body_name}":{body},"signature":"{signature}"}}'
+
+
+def date(value: datetime.datetime) -> str:
+    return value.strftime("%Y-%m-%dT%H:%M:%SZ")
+
+
+# Quote
+
+
+def report_body(
+    cpu_svn=bytes(16),
+    misc_select=0,
+    attributes=bytes(16),
+    mr_enclave=bytes(32),
+    mr_signer=bytes(32),
+    isv_prod_id=0,
+    isv_svn=0,
+    report_data=bytes(64),
+) -> bytes:
+    body = (
+        cpu_svn
+        + struct.pack("<I", misc_select)
+        + bytes(12)  # reserved
+        + bytes(16)  # isv_ext_prod_id
+        + attributes
+        + mr_enclave
+        + bytes(32)  # reserved
+        + mr_signer
+        + bytes(32)  # reserved
+        + bytes(64)  # config_id
+        + struct.pack("<HHH", isv_prod_id, isv_svn, 0)
+        + bytes(42)  # reserved
+        + bytes(16)  # isv_family_id
+        + report_data
+    )
+    assert len(body) == 384
+    return body
+
+
+def main():
+    root_key = ec.generate_private_key(ec.SECP256R1())
+    root_name = name("Test SGX Root CA")
+    root = certificate(root_name, root_key, root_name, root_key, ca=True)
+
+    pck_ca_key = ec.generate_private_key(ec.SECP256R1())
+    pck_ca_name = name("Test SGX PCK Processor CA")
+    pck_ca = certificate(pck_ca_name, pck_ca_key, root_name, root_key, ca=True)
+
+    pck_key = ec.generate_private_key(ec.SECP256R1())
+    pck = certificate(
+        name("Test SGX PCK Certificate"),
+        pck_key,
+        pck_ca_name,
+        pck_ca_key,
+        ca=False,
+        extensions=[
+            x509.UnrecognizedExtension(
+                x509.ObjectIdentifier(SGX_EXTENSIONS), sgx_extensions()
+            )
+        ],
+    )
+
+    tcb_signing_key = ec.generate_private_key(ec.SECP256R1())
+    tcb_signing = certificate(
+        name("Test SGX TCB Signing"), tcb_signing_key, root_name, root_key, ca=False
+    )
+
+    # Enclave TLS certificate, whose hash is the enclave held data
+    enclave_key = ec.generate_private_key(ec.SECP256R1())
+    enclave_certificate = certificate(
+        name("blindai-srv"), enclave_key, name("blindai-srv"), enclave_key, ca=False
+    ).public_bytes(serialization.Encoding.DER)
+
+    qe_isv_svn = 8
+    attestation_key = ec.generate_private_key(ec.SECP256R1())
+    qe_auth_data = bytes(range(32))
+    qe_report = report_body(
+        cpu_svn=CPU_SVN,
+        attributes=bytes.fromhex("11000000000000000700000000000000"),
+        mr_enclave=hashlib.sha256(b"test quoting enclave").digest(),
+        mr_signer=QE_MRSIGNER,
+        isv_prod_id=1,
+        isv_svn=qe_isv_svn,
+        report_data=hashlib.sha256(raw_public_key(attestation_key) + qe_auth_data).digest()
+        + bytes(32),
+    )
+
+    enclave_report = report_body(
+        cpu_svn=CPU_SVN,
+        # INIT | MODE64BIT, XFRM 0x3
+        attributes=bytes.fromhex("05000000000000000300000000000000"),
+        mr_enclave=MR_ENCLAVE,
+        mr_signer=MR_SIGNER,
+        report_data=hashlib.sha256(enclave_certificate).digest()
+        + hashlib.sha256(b"enclave X25519 public key").digest(),
+    )
+
+    # version 3, ECDSA-256-with-P-256 attestation key, Intel QE vendor id
+    header = struct.pack("<HHIHH", 3, 2, 0, qe_isv_svn, PCE_SVN)
+    header += bytes.fromhex("939a7233f79c4ca9940a0db3957f0607") + bytes(20)
+    assert len(header) == 48
+
+    cert_chain = (pem(pck) + pem(pck_ca) + pem(root)).encode()
+    signature_data = (
+        raw_signature(attestation_key, header + enclave_report)
+        + raw_public_key(attestation_key)
+        + qe_report
+        + raw_signature(pck_key, qe_report)
+        + struct.pack("<H", len(qe_auth_data))
+        + qe_auth_data
+        + struct.pack("<HI", 5, len(cert_chain))
+        + cert_chain
+    )
+    quote = header + enclave_report + struct.pack("<I", len(signature_data)) + signature_data
+
+    tcb_info = signed_json(
+        "tcbInfo",
+        {
+            "id": "SGX",
+            "version": 3,
+            "issueDate": date(ISSUE_DATE),
+            "nextUpdate": date(NEXT_UPDATE),
+            "fmspc": FMSPC.hex().upper(),
+            "pceId": PCE_ID.hex().upper(),
+            "tcbType": 0,
+            "tcbEvaluationDataNumber": 16,
+            "tcbLevels": [
+                {
+                    "tcb": {
+                        "sgxtcbcomponents": [{"svn": svn} for svn in CPU_SVN],
+                        "pcesvn": PCE_SVN,
+                    },
+                    "tcbDate": "2023-08-09T00:00:00Z",
+                    "tcbStatus": "UpToDate",
+                },
+                {
+                    "tcb": {
+                        "sgxtcbcomponents": [{"svn": 0} for _ in CPU_SVN],
+                        "pcesvn": 5,
+                    },
+                    "tcbDate": "2018-01-04T00:00:00Z",
+                    "tcbStatus": "OutOfDate",
+                    "advisoryIDs": ["INTEL-SA-00106", "INTEL-SA-00115"],
+                },
+            ],
+        },
+        tcb_signing_key,
+    )
+    qe_identity = signed_json(
+        "enclaveIdentity",
+        {
+            "id": "QE",
+            "version": 2,
+            "issueDate": date(ISSUE_DATE),
+            "nextUpdate": date(NEXT_UPDATE),
+            "tcbEvaluationDataNumber": 16,
+            "miscselect": "00000000",
+            "miscselectMask": "FFFFFFFF",
+            "attributes": "11000000000000000000000000000000",
+            "attributesMask": "FBFFFFFFFFFFFFFF0000000000000000",
+            "mrsigner": QE_MRSIGNER.hex().upper(),
+            "isvprodid": 1,
+            "tcbLevels": [
+                {
+                    "tcb": {"isvsvn": 8},
+                    "tcbDate": "2023-08-09T00:00:00Z",
+                    "tcbStatus": "UpToDate",
+                },
+                {
+                    "tcb": {"isvsvn": 0},
+                    "tcbDate": "2018-08-15T00:00:00Z",
+                    "tcbStatus": "OutOfDate",
+                    "advisoryIDs": ["INTEL-SA-00202"],
+                },
+            ],
+        },
+        tcb_signing_key,
+    )
+
+    def collateral(pck_crl):
+        return {
+            "version": 3,
+            "pck_crl_issuer_chain": pem(pck_ca) + pem(root),
+            "root_ca_crl": pem(crl(root_name, root_key)),
+            "pck_crl": pem(pck_crl),
+            "tcb_info_issuer_chain": pem(tcb_signing) + pem(root),
+            "tcb_info": tcb_info,
+            "qe_identity_issuer_chain": pem(tcb_signing) + pem(root),
+            "qe_identity": qe_identity,
+            "pck_certificate": pem(pck),
+            "pck_signing_chain": pem(pck_ca) + pem(root),
+        }
+
+    (OUT / "root_ca.pem").write_text(pem(root))
+    (OUT / "quote.bin").write_bytes(quote)
+    (OUT / "certificate.der").write_bytes(enclave_certificate)
+    (OUT / "collateral.json").write_text(
+        json.dumps(collateral(crl(pck_ca_name, pck_ca_key)), indent=2) + "\n"
+    )
+    (OUT / "collateral_revoked.json").write_text(
+        json.dumps(
+            collateral(crl(pck_ca_name, pck_ca_key, revoked=[pck.serial_number])),
+            indent=2,
+        )
+        + "\n"
+    )
+    (OUT / "manifest.toml").write_text(
+        f"""mr_enclave = "{MR_ENCLAVE.hex()}"
+allow_debug = false
+attributes_flags_hex = "0x4"
+attributes_mask_flags_hex = "0xfffffffffffffffd"
+attributes_xfrm_hex = "0x3"
+attributes_mask_xfrm_hex = "0xffffffffffffff1b"
+misc_select_hex = "0x0"
+misc_mask_hex = "0xffffffff"
+"""
+    )
+
+
+if __name__ == "__main__":
+    main()
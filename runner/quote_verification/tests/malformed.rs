@@ -0,0 +1,292 @@
+//! Malformed quotes and every certification data type, built from the quote
+//! of `fixtures/generate.py`: parsing must fail with a typed error, never
+//! panic
+
+use quote_verification::quote::{
+    CertificationData, PpidEncryption, Quote, QuoteError, CERTIFICATION_DATA_PCK_CERT_CHAIN,
+    CERTIFICATION_DATA_QE_REPORT,
+};
+use quote_verification::{SgxCollateral, VerifiedQuote, Verifier};
+
+const QUOTE: &[u8] = include_bytes!("fixtures/quote.bin");
+const COLLATERAL: &str = include_str!("fixtures/collateral.json");
+const ROOT_CA: &str = include_str!("fixtures/root_ca.pem");
+
+/// Offset of the signature data length, after the header and the report
+const SIGNATURE_DATA_LEN_OFFSET: usize = 48 + 384;
+/// Offset of the QE authentication data length
+const QE_AUTH_DATA_LEN_OFFSET: usize = SIGNATURE_DATA_LEN_OFFSET + 4 + 64 + 64 + 384 + 64;
+
+fn collateral() -> SgxCollateral {
+    serde_json::from_str(COLLATERAL).unwrap()
+}
+
+fn verify_with(quote: &[u8], collateral: &SgxCollateral) -> anyhow::Result<VerifiedQuote> {
+    Verifier::with_root_ca_pem(ROOT_CA)
+        .unwrap()
+        .at(1705276800)
+        .verify(quote, collateral)
+}
+
+fn verify(quote: &[u8]) -> anyhow::Result<()> {
+    verify_with(quote, &collateral()).map(|_| ())
+}
+
+/// The fixture quote with other certification data
+fn with_certification_data(data_type: u16, data: &[u8]) -> Vec<u8> {
+    let auth_data_len = u16::from_le_bytes(
+        QUOTE[QE_AUTH_DATA_LEN_OFFSET..QE_AUTH_DATA_LEN_OFFSET + 2]
+            .try_into()
+            .unwrap(),
+    ) as usize;
+    let mut quote = QUOTE[..QE_AUTH_DATA_LEN_OFFSET + 2 + auth_data_len].to_vec();
+    quote.extend_from_slice(&data_type.to_le_bytes());
+    quote.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    quote.extend_from_slice(data);
+
+    let signature_data_len = (quote.len() - SIGNATURE_DATA_LEN_OFFSET - 4) as u32;
+    quote[SIGNATURE_DATA_LEN_OFFSET..SIGNATURE_DATA_LEN_OFFSET + 4]
+        .copy_from_slice(&signature_data_len.to_le_bytes());
+    quote
+}
+
+/// PPID || CPU SVN || PCE SVN || PCE-ID
+fn pck_cert_id(ppid_len: usize) -> Vec<u8> {
+    let mut data = vec![0xaa; ppid_len];
+    data.extend_from_slice(&[0x11; 16]);
+    data.extend_from_slice(&13u16.to_le_bytes());
+    data.extend_from_slice(&[0, 0]);
+    data
+}
+
+/// The fixture quote identifying its PCK certificate by an encrypted PPID
+/// instead of embedding its chain, as the Intel quoting library does by
+/// default
+fn quote_with_pck_cert_id() -> Vec<u8> {
+    with_certification_data(3, &pck_cert_id(384))
+}
+
+/// QE report certification data wrapping `data_type` and `data`
+fn qe_report_certification_data(data_type: u16, data: &[u8]) -> Vec<u8> {
+    let quote = Quote::parse(QUOTE).unwrap();
+    let mut qe_report = quote.qe_report_raw.to_vec();
+    qe_report.extend_from_slice(&quote.qe_report_signature);
+    qe_report.extend_from_slice(&(quote.qe_auth_data.len() as u16).to_le_bytes());
+    qe_report.extend_from_slice(quote.qe_auth_data);
+    qe_report.extend_from_slice(&data_type.to_le_bytes());
+    qe_report.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    qe_report.extend_from_slice(data);
+    qe_report
+}
+
+#[test]
+fn truncated_quotes_are_rejected() {
+    for len in 0..QUOTE.len() {
+        let error = Quote::parse(&QUOTE[..len]).unwrap_err();
+        assert!(
+            matches!(error, QuoteError::Truncated(_)),
+            "{} bytes: {}",
+            len,
+            error
+        );
+        assert!(verify(&QUOTE[..len]).is_err());
+    }
+}
+
+#[test]
+fn corrupted_quotes_do_not_panic() {
+    // xorshift, to corrupt the same bytes on every run
+    let mut state: u64 = 0x2545f4914f6cdd1d;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    for _ in 0..2000 {
+        let mut quote = QUOTE.to_vec();
+        for _ in 0..1 + next() % 4 {
+            let i = (next() % quote.len() as u64) as usize;
+            quote[i] = next() as u8;
+        }
+        let _ = verify(&quote);
+    }
+}
+
+#[test]
+fn oversized_lengths_are_rejected() {
+    let mut quote = QUOTE.to_vec();
+    quote[SIGNATURE_DATA_LEN_OFFSET..SIGNATURE_DATA_LEN_OFFSET + 4]
+        .copy_from_slice(&u32::MAX.to_le_bytes());
+    assert_eq!(
+        Quote::parse(&quote).unwrap_err(),
+        QuoteError::Truncated("signature data")
+    );
+
+    let mut quote = QUOTE.to_vec();
+    quote[QE_AUTH_DATA_LEN_OFFSET..QE_AUTH_DATA_LEN_OFFSET + 2]
+        .copy_from_slice(&u16::MAX.to_le_bytes());
+    assert_eq!(
+        Quote::parse(&quote).unwrap_err(),
+        QuoteError::Truncated("QE authentication data")
+    );
+}
+
+#[test]
+fn unsupported_headers_are_rejected() {
+    let mut quote = QUOTE.to_vec();
+    quote[0] = 4;
+    assert_eq!(
+        Quote::parse(&quote).unwrap_err(),
+        QuoteError::UnsupportedVersion(4)
+    );
+
+    let mut quote = QUOTE.to_vec();
+    quote[2] = 3;
+    assert_eq!(
+        Quote::parse(&quote).unwrap_err(),
+        QuoteError::UnsupportedAttestationKeyType(3)
+    );
+}
+
+#[test]
+fn pck_cert_ids_are_parsed() {
+    for (data_type, ppid_len, ppid_encryption) in [
+        (1, 16, PpidEncryption::Cleartext),
+        (2, 256, PpidEncryption::Rsa2048Oaep),
+        (3, 384, PpidEncryption::Rsa3072Oaep),
+    ] {
+        let quote = with_certification_data(data_type, &pck_cert_id(ppid_len));
+        let parsed = Quote::parse(&quote).unwrap();
+        match &parsed.certification_data {
+            CertificationData::PckCertId(id) => {
+                assert_eq!(id.ppid, &[0xaa; 384][..ppid_len]);
+                assert_eq!(id.ppid_encryption, ppid_encryption);
+                assert_eq!(id.cpu_svn, [0x11; 16]);
+                assert_eq!(id.pce_svn, 13);
+                assert_eq!(id.pce_id, [0, 0]);
+            }
+            other => panic!("Unexpected certification data {:?}", other),
+        }
+        assert_eq!(parsed.certification_data.data_type(), data_type);
+        assert_eq!(
+            parsed.pck_cert_chain().unwrap_err(),
+            QuoteError::NoPckCertChain(data_type)
+        );
+        // The PCK certificate chain is then taken from the collateral
+        verify(&quote).unwrap();
+
+        // Every other length is invalid
+        for len in [0, ppid_len, ppid_len + 19, ppid_len + 21, 404 + 1] {
+            let quote = with_certification_data(data_type, &vec![0; len]);
+            assert!(
+                matches!(
+                    Quote::parse(&quote).unwrap_err(),
+                    QuoteError::InvalidCertificationData { .. }
+                ),
+                "type {} with {} bytes",
+                data_type,
+                len
+            );
+        }
+    }
+}
+
+#[test]
+fn qe_report_certification_data_is_unwrapped() {
+    let chain = Quote::parse(QUOTE).unwrap().pck_cert_chain().unwrap();
+    let quote = with_certification_data(
+        CERTIFICATION_DATA_QE_REPORT,
+        &qe_report_certification_data(CERTIFICATION_DATA_PCK_CERT_CHAIN, chain.as_bytes()),
+    );
+    let parsed = Quote::parse(&quote).unwrap();
+    assert_eq!(
+        parsed.certification_data.data_type(),
+        CERTIFICATION_DATA_QE_REPORT
+    );
+    assert_eq!(parsed.pck_cert_chain().unwrap(), chain);
+
+    let pck_cert_id = with_certification_data(
+        CERTIFICATION_DATA_QE_REPORT,
+        &qe_report_certification_data(3, &pck_cert_id(384)),
+    );
+    assert!(matches!(
+        Quote::parse(&pck_cert_id).unwrap().certification_data.pck(),
+        CertificationData::PckCertId(_)
+    ));
+}
+
+#[test]
+fn pck_certificates_of_the_collateral_are_verified() {
+    let verified = verify_with(&quote_with_pck_cert_id(), &collateral()).unwrap();
+    assert_eq!(verified.fmspc, [0x00, 0x90, 0x6e, 0xd5, 0x00, 0x00]);
+    assert_eq!(verified.platform.tcb.pce_svn, 13);
+
+    // Also when wrapped in QE report certification data
+    let wrapped = with_certification_data(
+        CERTIFICATION_DATA_QE_REPORT,
+        &qe_report_certification_data(3, &pck_cert_id(384)),
+    );
+    verify(&wrapped).unwrap();
+
+    let mut without_pck = collateral();
+    without_pck.pck_certificate.clear();
+    let error = verify_with(&quote_with_pck_cert_id(), &without_pck).unwrap_err();
+    assert!(error.to_string().contains("no PCK certificate"));
+
+    // The PCK certificate must chain up to the root CA, and be the one that
+    // signed the QE report
+    let mut unsigned = collateral();
+    unsigned.pck_signing_chain = unsigned.pck_certificate.clone();
+    assert!(verify_with(&quote_with_pck_cert_id(), &unsigned).is_err());
+    let mut pck_ca = collateral();
+    pck_ca.pck_certificate = pck_ca.pck_signing_chain.clone();
+    assert!(verify_with(&quote_with_pck_cert_id(), &pck_ca).is_err());
+
+    // A chain embedded in the quote takes precedence
+    verify_with(QUOTE, &without_pck).unwrap();
+}
+
+#[test]
+fn malformed_qe_report_certification_data_is_rejected() {
+    let nested = qe_report_certification_data(
+        CERTIFICATION_DATA_QE_REPORT,
+        &qe_report_certification_data(1, &pck_cert_id(16)),
+    );
+    let quote = with_certification_data(CERTIFICATION_DATA_QE_REPORT, &nested);
+    assert!(matches!(
+        Quote::parse(&quote).unwrap_err(),
+        QuoteError::InvalidCertificationData { .. }
+    ));
+
+    let data = qe_report_certification_data(1, &pck_cert_id(16));
+    for len in 0..data.len() {
+        let quote = with_certification_data(CERTIFICATION_DATA_QE_REPORT, &data[..len]);
+        let error = Quote::parse(&quote).unwrap_err();
+        assert!(
+            matches!(
+                error,
+                QuoteError::Truncated(_) | QuoteError::InvalidCertificationData { .. }
+            ),
+            "{} bytes: {}",
+            len,
+            error
+        );
+    }
+}
+
+#[test]
+fn unknown_certification_data_types_are_rejected() {
+    for data_type in [0, 4, 7, u16::MAX] {
+        let quote = with_certification_data(data_type, b"data");
+        assert_eq!(
+            Quote::parse(&quote).unwrap_err(),
+            QuoteError::UnsupportedCertificationDataType(data_type)
+        );
+    }
+    let quote = with_certification_data(CERTIFICATION_DATA_PCK_CERT_CHAIN, &[0xff, 0xfe]);
+    assert!(matches!(
+        Quote::parse(&quote).unwrap_err(),
+        QuoteError::InvalidCertificationData { .. }
+    ));
+}
@@ -0,0 +1,207 @@
+//! SGX extensions of PCK certificates: the one of `fixtures/generate.py`, and
+//! multi-package and malformed ones encoded here
+
+use quote_verification::pki::{
+    parse_certificate, pem_blocks, PckExtensions, PlatformConfiguration, SgxType,
+};
+use quote_verification::SgxCollateral;
+use yasna::models::ObjectIdentifier;
+use yasna::DERWriter;
+
+const COLLATERAL: &str = include_str!("fixtures/collateral.json");
+const CPU_SVN: [u8; 16] = [5, 5, 2, 4, 1, 128, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+/// `SEQUENCE { 1.2.840.113741.1.13.1.<arcs>, value }`
+fn entry(arcs: &[u64], value: impl FnOnce(DERWriter)) -> Vec<u8> {
+    let oid = ObjectIdentifier::from_slice(&[&[1, 2, 840, 113741, 1, 13, 1], arcs].concat());
+    yasna::construct_der(|writer| {
+        writer.write_sequence(|writer| {
+            writer.next().write_oid(&oid);
+            value(writer.next());
+        })
+    })
+}
+
+fn sequence(elements: &[Vec<u8>]) -> Vec<u8> {
+    yasna::construct_der(|writer| {
+        writer.write_sequence(|writer| {
+            for element in elements {
+                writer.next().write_der(element);
+            }
+        })
+    })
+}
+
+fn tcb(cpu_svn: &[u8; 16], pce_svn: u8) -> Vec<u8> {
+    let mut tcb = cpu_svn
+        .iter()
+        .enumerate()
+        .map(|(i, &svn)| entry(&[2, i as u64 + 1], |writer| writer.write_u8(svn)))
+        .collect::<Vec<_>>();
+    tcb.push(entry(&[2, 17], |writer| writer.write_u8(pce_svn)));
+    tcb.push(entry(&[2, 18], |writer| writer.write_bytes(cpu_svn)));
+    entry(&[2], |writer| writer.write_der(&sequence(&tcb)))
+}
+
+/// SGX extensions of a multi-package platform, with `extra` entries
+fn multi_package(extra: &[Vec<u8>]) -> Vec<u8> {
+    let configuration = sequence(&[
+        entry(&[7, 1], |writer| writer.write_bool(true)),
+        entry(&[7, 2], |writer| writer.write_bool(false)),
+    ]);
+    let mut entries = vec![
+        entry(&[1], |writer| writer.write_bytes(&[0x11; 16])),
+        tcb(&[9; 16], 11),
+        entry(&[3], |writer| writer.write_bytes(&[0, 0])),
+        entry(&[4], |writer| {
+            writer.write_bytes(&[0x30, 0x60, 0x6a, 0, 0, 0])
+        }),
+        entry(&[5], |writer| writer.write_enum(1)),
+        entry(&[6], |writer| writer.write_bytes(&[0x22; 16])),
+        entry(&[7], |writer| writer.write_der(&configuration)),
+    ];
+    entries.extend_from_slice(extra);
+    sequence(&entries)
+}
+
+#[test]
+fn pck_certificate_extensions_are_parsed() {
+    let collateral: SgxCollateral = serde_json::from_str(COLLATERAL).unwrap();
+    let der = pem_blocks(&collateral.pck_certificate).unwrap().remove(0);
+    let platform = PckExtensions::parse(&parse_certificate(&der).unwrap()).unwrap();
+
+    assert_eq!(
+        platform.ppid,
+        [
+            0xa1, 0xb2, 0xc3, 0xd4, 0xe5, 0xf6, 0x07, 0x18, 0x29, 0x3a, 0x4b, 0x5c, 0x6d, 0x7e,
+            0x8f, 0x90
+        ]
+    );
+    assert_eq!(platform.tcb.sgx_components, CPU_SVN);
+    assert_eq!(platform.tcb.pce_svn, 13);
+    assert_eq!(platform.cpu_svn, CPU_SVN);
+    assert_eq!(platform.pce_id, [0, 0]);
+    assert_eq!(platform.fmspc, [0x00, 0x90, 0x6e, 0xd5, 0x00, 0x00]);
+    assert_eq!(platform.sgx_type, SgxType::Standard);
+    assert!(!platform.is_multi_package());
+    assert_eq!(platform.configuration, None);
+
+    let json = serde_json::to_value(&platform).unwrap();
+    assert_eq!(json["fmspc"], "00906ed50000");
+    assert_eq!(json["tcb"]["pce_svn"], 13);
+    assert_eq!(json["sgx_type"], "Standard");
+    assert!(json["platform_instance_id"].is_null());
+}
+
+#[test]
+fn multi_package_extensions_are_parsed() {
+    let platform = PckExtensions::from_der(&multi_package(&[])).unwrap();
+    assert_eq!(platform.tcb.sgx_components, [9; 16]);
+    assert_eq!(platform.tcb.pce_svn, 11);
+    assert_eq!(platform.sgx_type, SgxType::Scalable);
+    assert!(platform.is_multi_package());
+    assert_eq!(platform.platform_instance_id, Some([0x22; 16]));
+    assert_eq!(
+        platform.configuration,
+        Some(PlatformConfiguration {
+            dynamic_platform: Some(true),
+            cached_keys: Some(false),
+            smt_enabled: None,
+        })
+    );
+
+    // Extensions of later versions of the specification are ignored
+    let extended = multi_package(&[entry(&[8], |writer| writer.write_null())]);
+    assert_eq!(PckExtensions::from_der(&extended).unwrap(), platform);
+}
+
+#[test]
+fn malformed_extensions_are_rejected() {
+    let entries = || {
+        vec![
+            entry(&[1], |writer| writer.write_bytes(&[0x11; 16])),
+            tcb(&[9; 16], 11),
+            entry(&[3], |writer| writer.write_bytes(&[0, 0])),
+            entry(&[4], |writer| writer.write_bytes(&[0; 6])),
+            entry(&[5], |writer| writer.write_enum(0)),
+        ]
+    };
+    let with = |i: usize, replacement: Vec<u8>| {
+        let mut entries = entries();
+        entries[i] = replacement;
+        sequence(&entries)
+    };
+    assert!(PckExtensions::from_der(&sequence(&entries())).is_ok());
+
+    let mut incomplete_tcb = (1..16)
+        .map(|i| entry(&[2, i], |writer| writer.write_u8(9)))
+        .collect::<Vec<_>>();
+    incomplete_tcb.push(entry(&[2, 17], |writer| writer.write_u8(11)));
+    incomplete_tcb.push(entry(&[2, 18], |writer| writer.write_bytes(&[9; 16])));
+
+    for (what, extensions) in [
+        (
+            "missing PPID",
+            with(0, entry(&[9], |writer| writer.write_null())),
+        ),
+        (
+            "short PPID",
+            with(0, entry(&[1], |writer| writer.write_bytes(&[0x11; 15]))),
+        ),
+        (
+            "PPID as an integer",
+            with(0, entry(&[1], |writer| writer.write_u8(1))),
+        ),
+        (
+            "missing TCB component",
+            with(
+                1,
+                entry(&[2], |writer| writer.write_der(&sequence(&incomplete_tcb))),
+            ),
+        ),
+        (
+            "TCB component SVN above 255",
+            with(
+                1,
+                entry(&[2], |writer| {
+                    writer.write_der(&sequence(&[entry(&[2, 1], |writer| writer.write_u16(256))]))
+                }),
+            ),
+        ),
+        (
+            "short FMSPC",
+            with(3, entry(&[4], |writer| writer.write_bytes(&[0; 5]))),
+        ),
+        (
+            "unknown SGX type",
+            with(4, entry(&[5], |writer| writer.write_enum(3))),
+        ),
+        (
+            "SGX type as an integer",
+            with(4, entry(&[5], |writer| writer.write_u8(0))),
+        ),
+        (
+            "duplicate FMSPC",
+            multi_package(&[entry(&[4], |writer| writer.write_bytes(&[0; 6]))]),
+        ),
+        (
+            "configuration flag as an integer",
+            sequence(
+                &[
+                    entries(),
+                    vec![entry(&[7], |writer| {
+                        writer.write_der(&sequence(&[entry(&[7, 3], |writer| writer.write_u8(1))]))
+                    })],
+                ]
+                .concat(),
+            ),
+        ),
+    ] {
+        assert!(PckExtensions::from_der(&extensions).is_err(), "{}", what);
+    }
+
+    let extensions = multi_package(&[]);
+    for len in 0..extensions.len() {
+        assert!(PckExtensions::from_der(&extensions[..len]).is_err());
+    }
+}
@@ -0,0 +1,56 @@
+//! Verification of a quote and collateral simulated by a BlindAI server built
+//! without SGX (`src/simulation.rs` of the server), with the report data
+//! binding the SHA-256 of `simulated enclave held data`
+
+use quote_verification::tcb::TcbStatus;
+use quote_verification::{SgxCollateral, Verifier};
+
+const QUOTE: &[u8] = include_bytes!("fixtures/simulated_quote.bin");
+const COLLATERAL: &str = include_str!("fixtures/simulated_collateral.json");
+
+/// 2026-10-19, while the simulated collateral is valid
+const TIME: i64 = 1792368000;
+
+fn collateral() -> SgxCollateral {
+    serde_json::from_str(COLLATERAL).unwrap()
+}
+
+#[test]
+fn simulated_quotes_are_rejected_by_default() {
+    let error = Verifier::new()
+        .at(TIME)
+        .verify(QUOTE, &collateral())
+        .unwrap_err();
+    assert!(error.to_string().contains("simulated"), "{error}");
+}
+
+#[test]
+fn simulated_quotes_are_verified_in_test_mode() {
+    let verified = Verifier::new()
+        .at(TIME)
+        .allow_simulated()
+        .verify(QUOTE, &collateral())
+        .unwrap();
+
+    assert!(verified.simulated);
+    assert_eq!(verified.report.mr_enclave, [0; 32]);
+    assert_eq!(verified.qe_tcb_status, TcbStatus::UpToDate);
+    verified
+        .check_enclave_held_data(b"simulated enclave held data")
+        .unwrap();
+}
+
+#[test]
+fn real_quotes_are_not_simulated() {
+    let root_ca = include_str!("fixtures/root_ca.pem");
+    let verified = Verifier::with_root_ca_pem(root_ca)
+        .unwrap()
+        .at(1705276800)
+        .allow_simulated()
+        .verify(
+            include_bytes!("fixtures/quote.bin"),
+            &serde_json::from_str(include_str!("fixtures/collateral.json")).unwrap(),
+        )
+        .unwrap();
+    assert!(!verified.simulated);
+}
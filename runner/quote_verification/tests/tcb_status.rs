@@ -0,0 +1,125 @@
+//! TCB status of platforms, against the TCB info of `fixtures/generate.py`
+
+use quote_verification::tcb::{QeTcbLevel, Tcb, TcbEvaluation, TcbInfo, TcbLevel, TcbStatus};
+use quote_verification::{SgxCollateral, Verifier};
+
+const QUOTE: &[u8] = include_bytes!("fixtures/quote.bin");
+const COLLATERAL: &str = include_str!("fixtures/collateral.json");
+const ROOT_CA: &str = include_str!("fixtures/root_ca.pem");
+const CPU_SVN: [u8; 16] = [5, 5, 2, 4, 1, 128, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+/// TCB info of the fixture collateral, its signature left unchecked
+fn tcb_info() -> TcbInfo {
+    let collateral: SgxCollateral = serde_json::from_str(COLLATERAL).unwrap();
+    let document: serde_json::Value = serde_json::from_str(&collateral.tcb_info).unwrap();
+    serde_json::from_value(document["tcbInfo"].clone()).unwrap()
+}
+
+fn level(tcb_status: TcbStatus, advisory_ids: &[&str]) -> TcbLevel {
+    TcbLevel {
+        tcb: Tcb {
+            sgx_components: [0; 16],
+            pce_svn: 0,
+        },
+        tcb_date: "2023-08-09T00:00:00Z".to_string(),
+        tcb_status,
+        advisory_ids: advisory_ids.iter().map(|id| id.to_string()).collect(),
+    }
+}
+
+fn qe_level(tcb_status: TcbStatus, advisory_ids: &[&str]) -> QeTcbLevel {
+    serde_json::from_value(serde_json::json!({
+        "tcb": { "isvsvn": 0 },
+        "tcbDate": "2023-08-09T00:00:00Z",
+        "tcbStatus": tcb_status,
+        "advisoryIDs": advisory_ids,
+    }))
+    .unwrap()
+}
+
+#[test]
+fn verified_quotes_have_a_tcb_status() {
+    let verified = Verifier::with_root_ca_pem(ROOT_CA)
+        .unwrap()
+        .at(1705276800)
+        .verify(QUOTE, &serde_json::from_str(COLLATERAL).unwrap())
+        .unwrap();
+    assert_eq!(
+        verified.tcb_evaluation,
+        TcbEvaluation {
+            status: TcbStatus::UpToDate,
+            tcb_date: "2023-08-09T00:00:00Z".to_string(),
+            advisory_ids: Vec::new(),
+        }
+    );
+}
+
+#[test]
+fn platforms_get_the_first_tcb_level_they_reach() {
+    let tcb_info = tcb_info();
+    let tcb = |sgx_components, pce_svn| Tcb {
+        sgx_components,
+        pce_svn,
+    };
+
+    let up_to_date = tcb_info.tcb_level(&tcb(CPU_SVN, 13)).unwrap();
+    assert_eq!(up_to_date.tcb_status, TcbStatus::UpToDate);
+    let mut newer = CPU_SVN;
+    newer[15] = 1;
+    assert_eq!(
+        tcb_info.tcb_level(&tcb(newer, 14)).unwrap().tcb_status,
+        TcbStatus::UpToDate
+    );
+
+    // A single component or the PCE SVN below the level is enough
+    let mut older = CPU_SVN;
+    older[5] = 127;
+    for tcb in [tcb(older, 13), tcb(CPU_SVN, 12)] {
+        let level = tcb_info.tcb_level(&tcb).unwrap();
+        assert_eq!(level.tcb_status, TcbStatus::OutOfDate);
+        assert_eq!(level.advisory_ids, ["INTEL-SA-00106", "INTEL-SA-00115"]);
+    }
+
+    assert!(tcb_info.tcb_level(&tcb(CPU_SVN, 4)).is_err());
+}
+
+#[test]
+fn out_of_date_quoting_enclaves_make_the_platform_out_of_date() {
+    use TcbStatus::*;
+    for (platform, qe, expected) in [
+        (UpToDate, UpToDate, UpToDate),
+        (SWHardeningNeeded, UpToDate, SWHardeningNeeded),
+        (ConfigurationNeeded, UpToDate, ConfigurationNeeded),
+        (UpToDate, OutOfDate, OutOfDate),
+        (SWHardeningNeeded, OutOfDate, OutOfDate),
+        (ConfigurationNeeded, OutOfDate, OutOfDateConfigurationNeeded),
+        (
+            ConfigurationAndSWHardeningNeeded,
+            OutOfDate,
+            OutOfDateConfigurationNeeded,
+        ),
+        (OutOfDate, OutOfDate, OutOfDate),
+        (Revoked, UpToDate, Revoked),
+        (UpToDate, Revoked, Revoked),
+    ] {
+        let evaluation = TcbEvaluation::new(&level(platform, &[]), &qe_level(qe, &[]));
+        assert_eq!(evaluation.status, expected, "{:?} and {:?}", platform, qe);
+    }
+}
+
+#[test]
+fn advisories_of_the_platform_and_quoting_enclave_are_merged() {
+    let evaluation = TcbEvaluation::new(
+        &level(
+            TcbStatus::SWHardeningNeeded,
+            &["INTEL-SA-00334", "INTEL-SA-00615"],
+        ),
+        &qe_level(TcbStatus::OutOfDate, &["INTEL-SA-00615", "INTEL-SA-00202"]),
+    );
+    assert_eq!(evaluation.status, TcbStatus::OutOfDate);
+    assert_eq!(
+        evaluation.advisory_ids,
+        ["INTEL-SA-00334", "INTEL-SA-00615", "INTEL-SA-00202"]
+    );
+    assert_eq!(evaluation.tcb_date, "2023-08-09T00:00:00Z");
+}
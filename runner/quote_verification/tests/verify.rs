@@ -0,0 +1,159 @@
+//! Verification of the quote and collateral generated by
+//! `fixtures/generate.py` with a test PKI in place of the Intel one
+
+use quote_verification::manifest::Manifest;
+use quote_verification::pki::{pem_blocks, ra_tls_evidence};
+use quote_verification::tcb::TcbStatus;
+use quote_verification::{SgxCollateral, Verifier};
+
+const QUOTE: &[u8] = include_bytes!("fixtures/quote.bin");
+const CERTIFICATE: &[u8] = include_bytes!("fixtures/certificate.der");
+const COLLATERAL: &str = include_str!("fixtures/collateral.json");
+const COLLATERAL_REVOKED: &str = include_str!("fixtures/collateral_revoked.json");
+const MANIFEST: &str = include_str!("fixtures/manifest.toml");
+const ROOT_CA: &str = include_str!("fixtures/root_ca.pem");
+
+/// 2024-01-15, while the collateral is valid
+const TIME: i64 = 1705276800;
+
+fn verifier() -> Verifier {
+    Verifier::with_root_ca_pem(ROOT_CA).unwrap().at(TIME)
+}
+
+fn collateral() -> SgxCollateral {
+    serde_json::from_str(COLLATERAL).unwrap()
+}
+
+#[test]
+fn valid_quote_is_verified() {
+    let verified = verifier().verify(QUOTE, &collateral()).unwrap();
+
+    assert_eq!(verified.fmspc, [0x00, 0x90, 0x6e, 0xd5, 0x00, 0x00]);
+    assert_eq!(verified.pce_id, [0, 0]);
+    assert_eq!(verified.qe_tcb_status, TcbStatus::UpToDate);
+    assert!(verified.qe_advisory_ids.is_empty());
+    assert_eq!(verified.tcb_info.tcb_levels.len(), 2);
+    // 2024-01-31, the next update of every piece of collateral
+    assert_eq!(verified.collateral_expiry, 1706659200);
+
+    verified
+        .check_manifest(&Manifest::from_toml(MANIFEST).unwrap())
+        .unwrap();
+    verified.check_enclave_held_data(CERTIFICATE).unwrap();
+    assert!(verified
+        .check_enclave_held_data(b"another certificate")
+        .is_err());
+}
+
+#[test]
+fn tampered_quotes_are_rejected() {
+    // MRENCLAVE of the enclave report
+    let mut quote = QUOTE.to_vec();
+    quote[48 + 64] ^= 1;
+    let error = verifier().verify(&quote, &collateral()).unwrap_err();
+    assert!(error.to_string().contains("signature of the quote"));
+
+    // Report data of the QE report
+    let mut quote = QUOTE.to_vec();
+    quote[436 + 128 + 320] ^= 1;
+    assert!(verifier().verify(&quote, &collateral()).is_err());
+
+    assert!(verifier().verify(&QUOTE[..1000], &collateral()).is_err());
+    assert!(verifier().verify(&[], &collateral()).is_err());
+}
+
+#[test]
+fn certificates_without_a_quote_are_the_enclave_held_data() {
+    let (quote, enclave_held_data) = ra_tls_evidence(CERTIFICATE).unwrap();
+    assert!(quote.is_none());
+    assert_eq!(enclave_held_data, CERTIFICATE);
+
+    assert!(ra_tls_evidence(b"not a certificate").is_err());
+}
+
+#[test]
+fn revoked_pck_is_rejected() {
+    let collateral = serde_json::from_str(COLLATERAL_REVOKED).unwrap();
+    let error = verifier().verify(QUOTE, &collateral).unwrap_err();
+    assert!(error.to_string().contains("revoked"));
+}
+
+#[test]
+fn expired_collateral_is_rejected() {
+    // 2024-03-01
+    let verifier = Verifier::with_root_ca_pem(ROOT_CA).unwrap().at(1709251200);
+    assert!(verifier.verify(QUOTE, &collateral()).is_err());
+}
+
+#[test]
+fn untrusted_root_is_rejected() {
+    let verifier = Verifier::new().at(TIME);
+    let error = verifier.verify(QUOTE, &collateral()).unwrap_err();
+    assert!(format!("{:#}", error).contains("trusted root CA"));
+}
+
+#[test]
+fn tampered_tcb_info_is_rejected() {
+    let mut collateral = collateral();
+    collateral.tcb_info = collateral.tcb_info.replacen("OutOfDate", "UpToDate", 1);
+    let error = verifier().verify(QUOTE, &collateral).unwrap_err();
+    assert!(format!("{:#}", error).contains("signature of the tcbInfo"));
+}
+
+#[test]
+fn manifest_mismatches_are_rejected() {
+    let verified = verifier().verify(QUOTE, &collateral()).unwrap();
+    let manifest = Manifest::from_toml(MANIFEST).unwrap();
+
+    let mut other_enclave = manifest.clone();
+    other_enclave.mr_enclave[0] ^= 1;
+    assert!(verified.check_manifest(&other_enclave).is_err());
+
+    let mut other_xfrm = manifest.clone();
+    other_xfrm.attributes_xfrm = 0x7;
+    assert!(verified.check_manifest(&other_xfrm).is_err());
+
+    let mut debug_report = verified.report.clone();
+    debug_report.attributes_flags |= quote_verification::quote::ATTRIBUTE_DEBUG;
+    assert!(manifest.check(&debug_report).is_err());
+    let allow_debug = Manifest {
+        allow_debug: true,
+        ..manifest
+    };
+    assert!(allow_debug.check(&debug_report).is_ok());
+}
+
+#[test]
+fn next_update_is_the_earliest_one() {
+    // 2024-01-31, the next update of every piece of collateral
+    assert_eq!(collateral().next_update().unwrap(), Some(1706659200));
+
+    // CRLs may also be hex encoded DER
+    let mut hex_crl = collateral();
+    hex_crl.pck_crl = hex::encode(pem_blocks(&hex_crl.pck_crl).unwrap().remove(0));
+    assert_eq!(hex_crl.next_update().unwrap(), Some(1706659200));
+
+    let with_tcb_next_update = |next_update: &str| {
+        let mut collateral = collateral();
+        collateral.tcb_info = collateral
+            .tcb_info
+            .replace("2024-01-31T00:00:00Z", next_update);
+        collateral.next_update()
+    };
+    // 2024-01-20T10:11:26Z
+    assert_eq!(
+        with_tcb_next_update("2024-01-20T10:11:26.5Z").unwrap(),
+        Some(1705745486)
+    );
+    assert!(with_tcb_next_update("2024-02-31T00:00:00Z").is_err());
+    assert!(with_tcb_next_update("2024-01-20 10:11:26").is_err());
+
+    let mut truncated = collateral();
+    truncated.pck_crl = hex_crl.pck_crl[..40].to_string();
+    assert!(truncated.next_update().is_err());
+
+    let mut without_crls = collateral();
+    without_crls.root_ca_crl.clear();
+    without_crls.pck_crl = "\0".to_string();
+    assert_eq!(without_crls.next_update().unwrap(), Some(1706659200));
+}
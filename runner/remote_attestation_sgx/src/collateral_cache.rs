@@ -0,0 +1,456 @@
+//! On-disk cache of the quote verification collateral, per FMSPC and CA.
+//!
+//! Every piece of collateral fetched from the upstream, the collateral source,
+//! is written to `BLINDAI_COLLATERAL_CACHE_DIR`
+//! (`collateral_cache` by default) with the date it was fetched and the date
+//! it expires, the earliest `nextUpdate` of its CRLs, TCB info and QE
+//! identity. The cached collateral is served for as long as it has not
+//! expired, so that an enclave can restart without network access: the
+//! upstream is only contacted on cache misses and by the refresher.
+//!
+//! Cached collateral is refreshed in the background every
+//! `BLINDAI_COLLATERAL_CACHE_REFRESH` seconds (1 hour by default). With
+//! `BLINDAI_COLLATERAL_OFFLINE` set, the upstream is never contacted and only
+//! the cache is used: air-gapped deployments pre-seed it with
+//! `runner collateral fetch` on a connected machine, or `runner collateral
+//! import`.
+
+use crate::collateral_source::CollateralSource;
+use crate::quote_verification_collateral::SgxQlQveCollateral;
+use anyhow::{anyhow, bail, Context, Result};
+use log::{error, info, warn};
+use quote_verification::tcb::parse_date;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsStr;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use x509_parser::prelude::{CertificateRevocationList, FromDer, X509Certificate};
+
+/// Collateral of a platform with its validity, as stored in the cache
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CachedCollateral {
+    /// Hex encoded
+    pub fmspc: String,
+    /// `processor` or `platform`, the CA that issued the PCK certificates
+    pub ca: String,
+    /// Unix timestamps, in seconds
+    pub fetched_at: u64,
+    pub expires_at: u64,
+    pub collateral: SgxQlQveCollateral,
+}
+
+impl CachedCollateral {
+    fn new(fmspc: &[u8; 6], ca: &str, collateral: SgxQlQveCollateral) -> Result<Self> {
+        Ok(CachedCollateral {
+            fmspc: hex::encode(fmspc),
+            ca: ca.to_string(),
+            fetched_at: unix_now(),
+            expires_at: expiry(&collateral)?,
+            collateral,
+        })
+    }
+
+    pub fn is_expired(&self) -> bool {
+        unix_now() >= self.expires_at
+    }
+}
+
+pub struct CollateralCache {
+    dir: PathBuf,
+    source: Box<dyn CollateralSource>,
+    offline: bool,
+    refresh_interval: Duration,
+}
+
+impl CollateralCache {
+    pub fn new(
+        dir: PathBuf,
+        source: Box<dyn CollateralSource>,
+        offline: bool,
+        refresh_interval: Duration,
+    ) -> Self {
+        CollateralCache {
+            dir,
+            source,
+            offline,
+            refresh_interval,
+        }
+    }
+
+    /// Configure the cache with `BLINDAI_COLLATERAL_CACHE_DIR`,
+    /// `BLINDAI_COLLATERAL_OFFLINE` and `BLINDAI_COLLATERAL_CACHE_REFRESH`
+    pub fn from_env(source: Box<dyn CollateralSource>) -> Result<Self> {
+        let dir = std::env::var("BLINDAI_COLLATERAL_CACHE_DIR")
+            .unwrap_or_else(|_| "collateral_cache".to_string());
+        let refresh_interval = match std::env::var("BLINDAI_COLLATERAL_CACHE_REFRESH") {
+            Ok(secs) => Duration::from_secs(
+                secs.parse()
+                    .context("Invalid BLINDAI_COLLATERAL_CACHE_REFRESH")?,
+            ),
+            Err(_) => Duration::from_secs(3600),
+        };
+        Ok(Self::new(
+            dir.into(),
+            source,
+            std::env::var("BLINDAI_COLLATERAL_OFFLINE").is_ok(),
+            refresh_interval,
+        ))
+    }
+
+    fn path(&self, fmspc: &str, ca: &str) -> PathBuf {
+        self.dir
+            .join(format!("{}_{}.json", fmspc.to_lowercase(), ca))
+    }
+
+    /// Cached collateral of a platform, expired or not
+    pub fn load(&self, fmspc: &[u8; 6], ca: &str) -> Result<Option<CachedCollateral>> {
+        let path = self.path(&hex::encode(fmspc), ca);
+        let json = match fs::read_to_string(&path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Could not read {:?}", path)),
+        };
+        let cached = serde_json::from_str(&json)
+            .with_context(|| format!("Invalid cache file {:?}", path))?;
+        Ok(Some(cached))
+    }
+
+    /// Write the collateral to the cache, replacing the previous one at once
+    fn store(&self, cached: &CachedCollateral) -> Result<()> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Could not create the cache directory {:?}", self.dir))?;
+        let path = self.path(&cached.fmspc, &cached.ca);
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_vec_pretty(cached)?)
+            .with_context(|| format!("Could not write {:?}", tmp_path))?;
+        fs::rename(&tmp_path, &path).with_context(|| format!("Could not write {:?}", path))?;
+        Ok(())
+    }
+
+    /// Upstream of the cache
+    pub fn source(&self) -> &dyn CollateralSource {
+        &*self.source
+    }
+
+    /// Fetch the collateral of a platform from the upstream and cache it
+    pub fn fetch(&self, fmspc: &[u8; 6], ca: &str) -> Result<CachedCollateral> {
+        let cached = CachedCollateral::new(fmspc, ca, self.source.collateral(fmspc, ca)?)?;
+        self.store(&cached)?;
+        Ok(cached)
+    }
+
+    /// Collateral of a platform: from the cache until it expires, fetched
+    /// from the upstream otherwise, unless the cache is offline
+    pub fn get(&self, fmspc: &[u8; 6], ca: &str) -> Result<SgxQlQveCollateral> {
+        let cached = self.load(fmspc, ca).unwrap_or_else(|e| {
+            error!("Could not read the collateral cache: {:?}", e);
+            None
+        });
+        let cache_miss = match cached {
+            Some(cached) if !cached.is_expired() => return Ok(cached.collateral),
+            Some(cached) => format!(
+                "The cached collateral for the FMSPC {} and the {} CA expired at {} (Unix time)",
+                cached.fmspc, cached.ca, cached.expires_at
+            ),
+            None => format!(
+                "No cached collateral for the FMSPC {} and the {} CA",
+                hex::encode(fmspc),
+                ca
+            ),
+        };
+        if self.offline {
+            return Err(anyhow!("The collateral cache is offline").context(cache_miss));
+        }
+        let cached = self.fetch(fmspc, ca).context(cache_miss)?;
+        Ok(cached.collateral)
+    }
+
+    /// Add collateral obtained elsewhere, such as the JSON served on
+    /// `/get_collateral`, to the cache. The FMSPC is the one of its TCB info,
+    /// and the CA the one that issues its PCK CRL.
+    pub fn import(&self, json: &str) -> Result<CachedCollateral> {
+        let collateral: SgxQlQveCollateral =
+            serde_json::from_str(json).context("Invalid collateral")?;
+        let tcb_info: serde_json::Value =
+            serde_json::from_str(&collateral.tcb_info).context("Invalid TCB info")?;
+        let fmspc: [u8; 6] = tcb_info["tcbInfo"]["fmspc"]
+            .as_str()
+            .and_then(|fmspc| hex::decode(fmspc).ok())
+            .and_then(|fmspc| fmspc.try_into().ok())
+            .context("Invalid FMSPC in the TCB info")?;
+        let ca = pck_crl_ca(&collateral.pck_crl_issuer_chain)?;
+
+        let cached = CachedCollateral::new(&fmspc, ca, collateral)?;
+        self.store(&cached)?;
+        Ok(cached)
+    }
+
+    /// Every cached collateral
+    pub fn entries(&self) -> Result<Vec<CachedCollateral>> {
+        let dir = match fs::read_dir(&self.dir) {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Could not read {:?}", self.dir));
+            }
+        };
+        let mut entries = Vec::new();
+        for entry in dir {
+            let path = entry?.path();
+            if path.extension() != Some(OsStr::new("json")) {
+                continue;
+            }
+            let json = fs::read_to_string(&path)?;
+            match serde_json::from_str(&json) {
+                Ok(cached) => entries.push(cached),
+                Err(e) => warn!("Ignoring the invalid cache file {:?}: {}", path, e),
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Fetch every cached collateral again
+    fn refresh(&self) -> Result<()> {
+        for cached in self.entries()? {
+            let fmspc: [u8; 6] = hex::decode(&cached.fmspc)
+                .ok()
+                .and_then(|fmspc| fmspc.try_into().ok())
+                .context("Invalid FMSPC in the collateral cache")?;
+            match self.fetch(&fmspc, &cached.ca) {
+                Ok(_) => info!(
+                    "Collateral for the FMSPC {} and the {} CA refreshed",
+                    cached.fmspc, cached.ca
+                ),
+                Err(e) => error!(
+                    "Could not refresh the collateral for the FMSPC {} and the {} CA, valid until {} (Unix time): {:?}",
+                    cached.fmspc, cached.ca, cached.expires_at, e
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    /// Refresh the cached collateral in the background, unless offline
+    pub fn spawn_refresher(self: &Arc<Self>) {
+        if self.offline {
+            return;
+        }
+        let cache = Arc::clone(self);
+        thread::spawn(move || loop {
+            thread::sleep(cache.refresh_interval);
+            if let Err(e) = cache.refresh() {
+                error!("Could not refresh the collateral cache: {:?}", e);
+            }
+        });
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Earliest `nextUpdate` of the CRLs, the TCB info and the QE identity
+fn expiry(collateral: &SgxQlQveCollateral) -> Result<u64> {
+    let mut next_updates = vec![
+        json_next_update(&collateral.tcb_info, "tcbInfo").context("Invalid TCB info")?,
+        json_next_update(&collateral.qe_identity, "enclaveIdentity")
+            .context("Invalid QE identity")?,
+    ];
+    for (name, crl) in [
+        ("root CA CRL", &collateral.root_ca_crl),
+        ("PCK CRL", &collateral.pck_crl),
+    ] {
+        next_updates.push(crl_next_update(crl).with_context(|| format!("Invalid {}", name))?);
+    }
+    let expires_at = next_updates
+        .into_iter()
+        .flatten()
+        .min()
+        .context("The collateral has no nextUpdate")?;
+    Ok(expires_at.max(0) as u64)
+}
+
+/// `nextUpdate` of a signed JSON structure such as `{"tcbInfo": {...}}`
+fn json_next_update(json: &str, body: &str) -> Result<Option<i64>> {
+    let json = json.trim_end_matches('\0');
+    if json.is_empty() {
+        return Ok(None);
+    }
+    let value: serde_json::Value = serde_json::from_str(json)?;
+    match value.get(body).and_then(|body| body.get("nextUpdate")) {
+        Some(serde_json::Value::String(next_update)) => Ok(Some(parse_date(next_update)?)),
+        Some(_) => bail!("Invalid nextUpdate"),
+        None => Ok(None),
+    }
+}
+
+/// `nextUpdate` of a PEM encoded CRL
+fn crl_next_update(crl: &str) -> Result<Option<i64>> {
+    if crl.trim_end_matches('\0').is_empty() {
+        return Ok(None);
+    }
+    let pem = pem::parse(crl)?;
+    let (_, crl) = CertificateRevocationList::from_der(pem.contents())?;
+    Ok(crl.next_update().map(|next_update| next_update.timestamp()))
+}
+
+/// CA of the PCK certificates, from the PCK CRL issuer chain
+fn pck_crl_ca(pck_crl_issuer_chain: &str) -> Result<&'static str> {
+    let issuer = pem::parse_many(pck_crl_issuer_chain)?
+        .into_iter()
+        .next()
+        .context("Empty PCK CRL issuer chain")?;
+    let (_, issuer) = X509Certificate::from_der(issuer.contents())?;
+    let common_name = issuer
+        .subject()
+        .iter_common_name()
+        .next()
+        .context("No common name in the PCK CRL issuer")?
+        .as_str()?;
+    if common_name.contains("Processor") {
+        Ok("processor")
+    } else if common_name.contains("Platform") {
+        Ok("platform")
+    } else {
+        bail!(
+            "Found the PCK CRL issuer {:?}, expected a processor or platform CA",
+            common_name
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    const FMSPC: [u8; 6] = [0x00, 0x90, 0x6e, 0xd5, 0x00, 0x00];
+
+    fn collateral(next_update: &str) -> SgxQlQveCollateral {
+        SgxQlQveCollateral {
+            version: 3,
+            pck_crl_issuer_chain: String::new(),
+            root_ca_crl: String::new(),
+            pck_crl: String::new(),
+            tcb_info_issuer_chain: String::new(),
+            tcb_info: format!(
+                r#"{{"tcbInfo":{{"fmspc":"00906ed50000","nextUpdate":"{}"}},"signature":""}}"#,
+                next_update
+            ),
+            qe_identity_issuer_chain: String::new(),
+            qe_identity: format!(
+                r#"{{"enclaveIdentity":{{"nextUpdate":"{}"}},"signature":""}}"#,
+                next_update
+            ),
+        }
+    }
+
+    /// Cache in a fresh directory whose upstream serves collateral expiring
+    /// at `next_update`, unless `upstream_up` is cleared
+    fn cache(name: &str, next_update: &'static str) -> (CollateralCache, Arc<AtomicBool>) {
+        let dir = std::env::temp_dir().join(format!(
+            "blindai_collateral_cache_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        let upstream_up = Arc::new(AtomicBool::new(true));
+        let up = Arc::clone(&upstream_up);
+        let fetch = Box::new(move |_: &[u8; 6], _: &str| {
+            if up.load(Ordering::SeqCst) {
+                Ok(collateral(next_update))
+            } else {
+                bail!("The upstream is unreachable")
+            }
+        });
+        let cache = CollateralCache::new(dir, fetch, false, Duration::from_secs(3600));
+        (cache, upstream_up)
+    }
+
+    #[test]
+    fn cached_collateral_is_served_when_the_upstream_is_down() {
+        let (cache, upstream_up) = cache("served", "2099-01-01T00:00:00Z");
+        assert!(cache.load(&FMSPC, "processor").unwrap().is_none());
+        cache.get(&FMSPC, "processor").unwrap();
+
+        let cached = cache.load(&FMSPC, "processor").unwrap().unwrap();
+        assert_eq!(cached.fmspc, "00906ed50000");
+        assert_eq!(cached.expires_at, 4070908800);
+
+        upstream_up.store(false, Ordering::SeqCst);
+        cache.get(&FMSPC, "processor").unwrap();
+        // Cached per FMSPC and CA
+        assert!(cache.get(&FMSPC, "platform").is_err());
+        assert_eq!(cache.entries().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn expired_collateral_is_not_served() {
+        let (cache, upstream_up) = cache("expired", "2020-01-01T00:00:00Z");
+        cache.get(&FMSPC, "processor").unwrap();
+        assert!(cache
+            .load(&FMSPC, "processor")
+            .unwrap()
+            .unwrap()
+            .is_expired());
+
+        upstream_up.store(false, Ordering::SeqCst);
+        let error = cache.get(&FMSPC, "processor").unwrap_err();
+        assert!(format!("{:?}", error).contains("expired"));
+
+        // Fetched again once expired
+        upstream_up.store(true, Ordering::SeqCst);
+        cache.get(&FMSPC, "processor").unwrap();
+    }
+
+    #[test]
+    fn fresh_collateral_is_served_from_the_cache() {
+        let (cache, _) = cache("fresh", "2099-01-01T00:00:00Z");
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&fetches);
+        let cache = CollateralCache {
+            source: Box::new(move |_: &[u8; 6], _: &str| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Ok(collateral("2099-01-01T00:00:00Z"))
+            }),
+            ..cache
+        };
+        cache.get(&FMSPC, "processor").unwrap();
+        cache.get(&FMSPC, "processor").unwrap();
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        cache.refresh().unwrap();
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn offline_cache_only_serves_cached_collateral() {
+        let (cache, _) = cache("offline", "2099-01-01T00:00:00Z");
+        let cache = CollateralCache {
+            offline: true,
+            ..cache
+        };
+        assert!(cache.get(&FMSPC, "processor").is_err());
+
+        cache.fetch(&FMSPC, "processor").unwrap();
+        cache.get(&FMSPC, "processor").unwrap();
+    }
+
+    #[test]
+    fn imported_collateral_is_cached_by_fmspc_and_ca() {
+        let (cache, _) = cache("import", "2099-01-01T00:00:00Z");
+        let collateral = include_str!("../../quote_verification/tests/fixtures/collateral.json");
+        let cached = cache.import(collateral).unwrap();
+        assert_eq!(cached.fmspc, "00906ed50000");
+        assert_eq!(cached.ca, "processor");
+        // 2024-01-31, the next update of every piece of collateral
+        assert_eq!(cached.expires_at, 1706659200);
+        assert!(cache.load(&FMSPC, "processor").unwrap().is_some());
+    }
+}
@@ -0,0 +1,553 @@
+//! Sources of the quote verification collateral of a platform.
+//!
+//! The source is selected with `BLINDAI_COLLATERAL_SOURCE`:
+//! * `qpl` (default): the Quote Provider Library, as configured in
+//!   `/etc/sgx_default_qcnl.conf`.
+//! * `pccs`: a PCCS at `BLINDAI_PCCS_URL` (`https://localhost:8081` by
+//!   default).
+//! * `intel_pcs`: Intel PCS at `BLINDAI_INTEL_PCS_URL`
+//!   (`https://api.trustedservices.intel.com` by default), with the API key
+//!   `BLINDAI_INTEL_PCS_API_KEY` if set.
+//! * `azure_thim`: the Trusted Hardware Identity Management service of Azure
+//!   VMs, at `BLINDAI_AZURE_THIM_URL`.
+//!
+//! The HTTP sources all speak the v4 API of Intel PCS. With the `qpl` source
+//! and `BLINDAI_AZURE_DCSV3_PATCH` set, the TCB info and the QE identity come
+//! from Intel PCS instead, for the Azure DCsv3 and DCdsv3-series VMs whose
+//! QPL serves outdated ones.
+
+use crate::quote_verification_collateral::{
+    pcs_crl_to_pem, sgx_get_quote_verification_collateral, SgxQlQveCollateral,
+};
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+use quote_verification::quote::{PckCertId, PpidEncryption};
+use std::ffi::CString;
+use std::io::Read;
+use std::time::Duration;
+
+/// PCCS of the default QPL configuration
+pub const DEFAULT_PCCS_URL: &str = "https://localhost:8081";
+pub const DEFAULT_INTEL_PCS_URL: &str = "https://api.trustedservices.intel.com";
+/// THIM endpoint of the Azure Instance Metadata Service
+pub const DEFAULT_AZURE_THIM_URL: &str = "http://169.254.169.254/metadata/THIM";
+/// Intel SGX Root CA CRL, which Intel PCS does not serve
+const INTEL_ROOT_CA_CRL_URL: &str =
+    "https://certificates.trustedservices.intel.com/IntelSGXRootCA.der";
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Collateral of the platforms with a given FMSPC and PCK CA (`processor` or
+/// `platform`)
+pub trait CollateralSource: Send + Sync {
+    fn collateral(&self, fmspc: &[u8; 6], ca: &str) -> Result<SgxQlQveCollateral>;
+
+    /// PCK certificate of the platform identified by a quote, with its issuer
+    /// chain
+    fn pck_certificate(
+        &self,
+        _qe_id: &[u8; 16],
+        _pck_cert_id: &PckCertId,
+    ) -> Result<(String, String)> {
+        bail!("The collateral source does not serve PCK certificates")
+    }
+}
+
+impl<F> CollateralSource for F
+where
+    F: Fn(&[u8; 6], &str) -> Result<SgxQlQveCollateral> + Send + Sync,
+{
+    fn collateral(&self, fmspc: &[u8; 6], ca: &str) -> Result<SgxQlQveCollateral> {
+        self(fmspc, ca)
+    }
+}
+
+/// Collateral source selected by `BLINDAI_COLLATERAL_SOURCE`
+pub fn from_env() -> Result<Box<dyn CollateralSource>> {
+    from_name(&std::env::var("BLINDAI_COLLATERAL_SOURCE").unwrap_or_else(|_| "qpl".to_string()))
+}
+
+/// Collateral source named as in `BLINDAI_COLLATERAL_SOURCE`
+fn from_name(name: &str) -> Result<Box<dyn CollateralSource>> {
+    let source: Box<dyn CollateralSource> = match name {
+        "qpl" if azure_dcsv3_patch() => {
+            info!("The patch for Azure DCsv3 and DCdsv3-series VMs is enabled. Requesting the TCB info and QE identity directly from Intel, bypassing the PCCS.");
+            Box::new(AzureDcsv3Patch {
+                intel_pcs: PcsApi::intel_pcs_from_env(),
+            })
+        }
+        "qpl" => Box::new(Qpl),
+        "pccs" => Box::new(PcsApi::pccs_from_env()),
+        "intel_pcs" => Box::new(PcsApi::intel_pcs_from_env()),
+        "azure_thim" => Box::new(PcsApi::azure_thim(
+            &std::env::var("BLINDAI_AZURE_THIM_URL")
+                .unwrap_or_else(|_| DEFAULT_AZURE_THIM_URL.to_string()),
+        )),
+        other => bail!(
+            "Unknown BLINDAI_COLLATERAL_SOURCE {:?}, expected qpl, pccs, intel_pcs or azure_thim",
+            other
+        ),
+    };
+    Ok(source)
+}
+
+/// `BLINDAI_AZURE_DCSV3_PATCH`, or the `BLINDAI_AZURE_DCS3_PATCH` that
+/// earlier versions of the runner read
+fn azure_dcsv3_patch() -> bool {
+    if std::env::var("BLINDAI_AZURE_DCSV3_PATCH").is_ok() {
+        return true;
+    }
+    if std::env::var("BLINDAI_AZURE_DCS3_PATCH").is_ok() {
+        warn!("BLINDAI_AZURE_DCS3_PATCH is deprecated, set BLINDAI_AZURE_DCSV3_PATCH instead");
+        return true;
+    }
+    false
+}
+
+/// The Quote Provider Library
+pub struct Qpl;
+
+impl CollateralSource for Qpl {
+    fn collateral(&self, fmspc: &[u8; 6], ca: &str) -> Result<SgxQlQveCollateral> {
+        sgx_get_quote_verification_collateral(fmspc, &CString::new(ca)?)
+    }
+
+    /// The QPL doesn't expose the PCK certificates it fetches for quotes, they
+    /// come from its PCCS (`BLINDAI_PCCS_URL`)
+    fn pck_certificate(
+        &self,
+        qe_id: &[u8; 16],
+        pck_cert_id: &PckCertId,
+    ) -> Result<(String, String)> {
+        PcsApi::pccs_from_env().pck_certificate(qe_id, pck_cert_id)
+    }
+}
+
+/// The QPL, with the TCB info and the QE identity from Intel PCS
+pub struct AzureDcsv3Patch {
+    pub intel_pcs: PcsApi,
+}
+
+impl CollateralSource for AzureDcsv3Patch {
+    fn collateral(&self, fmspc: &[u8; 6], ca: &str) -> Result<SgxQlQveCollateral> {
+        let (tcb_info_issuer_chain, tcb_info) = self.intel_pcs.tcb_info(fmspc)?;
+        let (qe_identity_issuer_chain, qe_identity) = self.intel_pcs.qe_identity()?;
+        Ok(SgxQlQveCollateral {
+            tcb_info_issuer_chain,
+            tcb_info,
+            qe_identity_issuer_chain,
+            qe_identity,
+            ..Qpl.collateral(fmspc, ca)?
+        })
+    }
+
+    fn pck_certificate(
+        &self,
+        qe_id: &[u8; 16],
+        pck_cert_id: &PckCertId,
+    ) -> Result<(String, String)> {
+        Qpl.pck_certificate(qe_id, pck_cert_id)
+    }
+}
+
+/// HTTP client of the services, whose requests can't hang for longer than
+/// [`REQUEST_TIMEOUT`]
+fn agent() -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout_connect(CONNECT_TIMEOUT)
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+}
+
+/// A service implementing the v4 API of Intel PCS: a PCCS, Intel PCS itself,
+/// or Azure THIM
+pub struct PcsApi {
+    /// For error messages
+    pub name: &'static str,
+    /// Up to `/sgx/certification/v4`
+    pub base_url: String,
+    pub root_ca_crl_url: String,
+    /// Sent with every request
+    pub headers: Vec<(&'static str, String)>,
+    agent: ureq::Agent,
+}
+
+impl PcsApi {
+    pub fn pccs(url: &str) -> Self {
+        let base_url = format!("{}/sgx/certification/v4", url.trim_end_matches('/'));
+        PcsApi {
+            name: "the PCCS",
+            root_ca_crl_url: format!("{}/rootcacrl", base_url),
+            base_url,
+            headers: Vec::new(),
+            agent: agent(),
+        }
+    }
+
+    pub fn pccs_from_env() -> Self {
+        Self::pccs(
+            &std::env::var("BLINDAI_PCCS_URL").unwrap_or_else(|_| DEFAULT_PCCS_URL.to_string()),
+        )
+    }
+
+    pub fn intel_pcs(url: &str, api_key: Option<String>) -> Self {
+        PcsApi {
+            name: "Intel PCS",
+            base_url: format!("{}/sgx/certification/v4", url.trim_end_matches('/')),
+            root_ca_crl_url: INTEL_ROOT_CA_CRL_URL.to_string(),
+            headers: api_key
+                .map(|api_key| ("Ocp-Apim-Subscription-Key", api_key))
+                .into_iter()
+                .collect(),
+            agent: agent(),
+        }
+    }
+
+    pub fn intel_pcs_from_env() -> Self {
+        Self::intel_pcs(
+            &std::env::var("BLINDAI_INTEL_PCS_URL")
+                .unwrap_or_else(|_| DEFAULT_INTEL_PCS_URL.to_string()),
+            std::env::var("BLINDAI_INTEL_PCS_API_KEY").ok(),
+        )
+    }
+
+    pub fn azure_thim(url: &str) -> Self {
+        PcsApi {
+            name: "Azure THIM",
+            base_url: format!("{}/sgx/certification/v4", url.trim_end_matches('/')),
+            root_ca_crl_url: INTEL_ROOT_CA_CRL_URL.to_string(),
+            headers: vec![("Metadata", "true".to_string())],
+            agent: agent(),
+        }
+    }
+
+    fn get(&self, url: &str, query: &[(&str, &str)], what: &str) -> Result<ureq::Response> {
+        let mut request = self.agent.get(url);
+        for (name, value) in &self.headers {
+            request = request.set(name, value);
+        }
+        for (name, value) in query {
+            request = request.query(name, value);
+        }
+        request
+            .call()
+            .with_context(|| format!("Could not get the {} from {}", what, self.name))
+    }
+
+    /// URL decoded issuer chain of a response, under the first of `headers`
+    /// that is present
+    fn issuer_chain(&self, response: &ureq::Response, headers: &[&str]) -> Result<String> {
+        let header = headers
+            .iter()
+            .find_map(|header| response.header(header))
+            .with_context(|| format!("{} did not send the {} header", self.name, headers[0]))?;
+        Ok(urlencoding::decode(header)?.into_owned())
+    }
+
+    fn crl(&self, response: ureq::Response) -> Result<String> {
+        let mut crl = Vec::new();
+        response.into_reader().read_to_end(&mut crl)?;
+        Ok(pcs_crl_to_pem(&crl))
+    }
+
+    /// TCB info of an FMSPC, with its issuer chain
+    pub fn tcb_info(&self, fmspc: &[u8; 6]) -> Result<(String, String)> {
+        let response = self.get(
+            &format!("{}/tcb", self.base_url),
+            &[("fmspc", &hex::encode(fmspc))],
+            "TCB info",
+        )?;
+        // The v3 API names it SGX-TCB-Info-Issuer-Chain, as some PCCS still do
+        let issuer_chain = self.issuer_chain(
+            &response,
+            &["TCB-Info-Issuer-Chain", "SGX-TCB-Info-Issuer-Chain"],
+        )?;
+        Ok((issuer_chain, response.into_string()?))
+    }
+
+    /// Identity of the quoting enclave, with its issuer chain
+    pub fn qe_identity(&self) -> Result<(String, String)> {
+        let response = self.get(
+            &format!("{}/qe/identity", self.base_url),
+            &[],
+            "QE identity",
+        )?;
+        let issuer_chain = self.issuer_chain(&response, &["SGX-Enclave-Identity-Issuer-Chain"])?;
+        Ok((issuer_chain, response.into_string()?))
+    }
+
+    /// PCK CRL of a CA in PEM format, with its issuer chain
+    pub fn pck_crl(&self, ca: &str) -> Result<(String, String)> {
+        let response = self.get(
+            &format!("{}/pckcrl", self.base_url),
+            &[("ca", ca)],
+            "PCK CRL",
+        )?;
+        let issuer_chain = self.issuer_chain(&response, &["SGX-PCK-CRL-Issuer-Chain"])?;
+        Ok((issuer_chain, self.crl(response)?))
+    }
+
+    /// Root CA CRL in PEM format
+    pub fn root_ca_crl(&self) -> Result<String> {
+        let response = self.get(&self.root_ca_crl_url, &[], "root CA CRL")?;
+        self.crl(response)
+    }
+}
+
+impl CollateralSource for PcsApi {
+    fn collateral(&self, fmspc: &[u8; 6], ca: &str) -> Result<SgxQlQveCollateral> {
+        let (pck_crl_issuer_chain, pck_crl) = self.pck_crl(ca)?;
+        let (tcb_info_issuer_chain, tcb_info) = self.tcb_info(fmspc)?;
+        let (qe_identity_issuer_chain, qe_identity) = self.qe_identity()?;
+        Ok(SgxQlQveCollateral {
+            // Collateral of the v3 and v4 APIs
+            version: 3,
+            pck_crl_issuer_chain,
+            root_ca_crl: self.root_ca_crl()?,
+            pck_crl,
+            tcb_info_issuer_chain,
+            tcb_info,
+            qe_identity_issuer_chain,
+            qe_identity,
+        })
+    }
+
+    /// PCK certificate of the platform identified by a quote, with its issuer
+    /// chain. A cleartext PPID is not sent: the service must know the
+    /// certificate for the QE id.
+    fn pck_certificate(
+        &self,
+        qe_id: &[u8; 16],
+        pck_cert_id: &PckCertId,
+    ) -> Result<(String, String)> {
+        let cpu_svn = hex::encode(pck_cert_id.cpu_svn);
+        let pce_svn = hex::encode(pck_cert_id.pce_svn.to_le_bytes());
+        let pce_id = hex::encode(pck_cert_id.pce_id);
+        let qe_id = hex::encode(qe_id);
+        let encrypted_ppid = hex::encode(pck_cert_id.ppid);
+        let mut query = vec![
+            ("qeid", qe_id.as_str()),
+            ("cpusvn", &cpu_svn),
+            ("pcesvn", &pce_svn),
+            ("pceid", &pce_id),
+        ];
+        if pck_cert_id.ppid_encryption != PpidEncryption::Cleartext {
+            query.push(("encrypted_ppid", &encrypted_ppid));
+        }
+        let response = self.get(
+            &format!("{}/pckcert", self.base_url),
+            &query,
+            "PCK certificate",
+        )?;
+        let issuer_chain = self.issuer_chain(&response, &["SGX-PCK-Certificate-Issuer-Chain"])?;
+        Ok((issuer_chain, response.into_string()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    const COLLATERAL: &str =
+        include_str!("../../quote_verification/tests/fixtures/collateral.json");
+    const FMSPC: [u8; 6] = [0x00, 0x90, 0x6e, 0xd5, 0x00, 0x00];
+
+    /// Response of a stand-in to requests whose path starts with a prefix
+    struct Route {
+        path: &'static str,
+        headers: Vec<(&'static str, String)>,
+        body: Vec<u8>,
+    }
+
+    /// Serve `routes.len()` requests as a PCS API service would, returning
+    /// the lowercase request lines and headers of each
+    fn stand_in(routes: Vec<Route>) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for _ in 0..routes.len() {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut request = String::new();
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    request.push_str(&line.to_lowercase());
+                    line.clear();
+                }
+
+                let path = request.split(' ').nth(1).unwrap().to_string();
+                let stream = reader.get_mut();
+                match routes.iter().find(|route| path.starts_with(route.path)) {
+                    Some(route) => {
+                        write!(stream, "HTTP/1.1 200 OK\r\n").unwrap();
+                        for (name, value) in &route.headers {
+                            write!(stream, "{}: {}\r\n", name, value).unwrap();
+                        }
+                        write!(
+                            stream,
+                            "Content-Length: {}\r\nConnection: close\r\n\r\n",
+                            route.body.len()
+                        )
+                        .unwrap();
+                        stream.write_all(&route.body).unwrap();
+                    }
+                    None => write!(
+                        stream,
+                        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    )
+                    .unwrap(),
+                }
+                requests.push(request);
+            }
+            requests
+        });
+        (url, handle)
+    }
+
+    fn fixture() -> SgxQlQveCollateral {
+        serde_json::from_str(COLLATERAL).unwrap()
+    }
+
+    /// The fixture collateral as served by the v4 API, with the PCK CRL in
+    /// DER and the root CA CRL in hex encoded DER
+    fn routes(tcb_info_issuer_chain_header: &'static str) -> Vec<Route> {
+        let collateral = fixture();
+        let encode = |chain: &str| urlencoding::encode(chain).into_owned();
+        vec![
+            Route {
+                path: "/sgx/certification/v4/pckcrl?ca=processor",
+                headers: vec![(
+                    "SGX-PCK-CRL-Issuer-Chain",
+                    encode(&collateral.pck_crl_issuer_chain),
+                )],
+                body: pem::parse(&collateral.pck_crl).unwrap().contents().to_vec(),
+            },
+            Route {
+                path: "/sgx/certification/v4/tcb?fmspc=00906ed50000",
+                headers: vec![(
+                    tcb_info_issuer_chain_header,
+                    encode(&collateral.tcb_info_issuer_chain),
+                )],
+                body: collateral.tcb_info.into_bytes(),
+            },
+            Route {
+                path: "/sgx/certification/v4/qe/identity",
+                headers: vec![(
+                    "SGX-Enclave-Identity-Issuer-Chain",
+                    encode(&collateral.qe_identity_issuer_chain),
+                )],
+                body: collateral.qe_identity.into_bytes(),
+            },
+            Route {
+                path: "/root_ca.crl",
+                headers: Vec::new(),
+                body: hex::encode(pem::parse(&collateral.root_ca_crl).unwrap().contents())
+                    .into_bytes(),
+            },
+        ]
+    }
+
+    fn assert_fixture(collateral: &SgxQlQveCollateral) {
+        let expected = fixture();
+        let der = |crl: &str| pem::parse(crl).unwrap().contents().to_vec();
+        assert_eq!(collateral.version, 3);
+        assert_eq!(
+            collateral.pck_crl_issuer_chain,
+            expected.pck_crl_issuer_chain
+        );
+        assert_eq!(der(&collateral.pck_crl), der(&expected.pck_crl));
+        assert_eq!(der(&collateral.root_ca_crl), der(&expected.root_ca_crl));
+        assert_eq!(
+            collateral.tcb_info_issuer_chain,
+            expected.tcb_info_issuer_chain
+        );
+        assert_eq!(collateral.tcb_info, expected.tcb_info);
+        assert_eq!(
+            collateral.qe_identity_issuer_chain,
+            expected.qe_identity_issuer_chain
+        );
+        assert_eq!(collateral.qe_identity, expected.qe_identity);
+    }
+
+    #[test]
+    fn collateral_is_fetched_from_a_pccs() {
+        let (url, pccs) = stand_in(routes("TCB-Info-Issuer-Chain"));
+        let mut source = PcsApi::pccs(&url);
+        source.root_ca_crl_url = format!("{}/root_ca.crl", url);
+
+        assert_fixture(&source.collateral(&FMSPC, "processor").unwrap());
+        assert_eq!(pccs.join().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn collateral_is_fetched_from_intel_pcs_with_the_api_key() {
+        let (url, pcs) = stand_in(routes("TCB-Info-Issuer-Chain"));
+        let mut source = PcsApi::intel_pcs(&url, Some("api key".to_string()));
+        source.root_ca_crl_url = format!("{}/root_ca.crl", url);
+
+        assert_fixture(&source.collateral(&FMSPC, "processor").unwrap());
+        for request in pcs.join().unwrap() {
+            assert!(
+                request.contains("\r\nocp-apim-subscription-key: api key\r\n"),
+                "{}",
+                request
+            );
+        }
+    }
+
+    #[test]
+    fn collateral_is_fetched_from_azure_thim() {
+        let (url, thim) = stand_in(routes("SGX-TCB-Info-Issuer-Chain"));
+        let mut source = PcsApi::azure_thim(&url);
+        source.root_ca_crl_url = format!("{}/root_ca.crl", url);
+
+        assert_fixture(&source.collateral(&FMSPC, "processor").unwrap());
+        for request in thim.join().unwrap() {
+            assert!(request.contains("\r\nmetadata: true\r\n"), "{}", request);
+        }
+    }
+
+    #[test]
+    fn missing_issuer_chains_are_errors() {
+        let mut routes = routes("TCB-Info-Issuer-Chain");
+        routes.truncate(2);
+        routes[1].headers.clear();
+        let (url, pccs) = stand_in(routes);
+
+        let error = PcsApi::pccs(&url).tcb_info(&FMSPC).unwrap_err();
+        assert!(
+            error.to_string().contains("TCB-Info-Issuer-Chain"),
+            "{}",
+            error
+        );
+        // Not served
+        let error = PcsApi::pccs(&url).pck_crl("platform").unwrap_err();
+        assert!(error.to_string().contains("PCK CRL"), "{}", error);
+        pccs.join().unwrap();
+    }
+
+    #[test]
+    fn unknown_sources_are_rejected() {
+        for name in ["qpl", "pccs", "intel_pcs", "azure_thim"] {
+            assert!(from_name(name).is_ok(), "{}", name);
+        }
+        assert!(from_name("pcs").is_err());
+        assert!(from_name("").is_err());
+    }
+
+    #[test]
+    fn requests_time_out() {
+        // Accepts connections, never answers
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut source = PcsApi::pccs(&format!("http://{}", listener.local_addr().unwrap()));
+        source.agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_millis(100))
+            .build();
+        assert!(source.qe_identity().is_err());
+    }
+}
@@ -1,25 +1,43 @@
 #![feature(type_alias_impl_trait)]
 
+pub mod collateral_cache;
+pub mod collateral_source;
 mod quote_generation;
 mod quote_verification_collateral;
 
 use anyhow::Result;
+use collateral_cache::CollateralCache;
 use quote_generation::QuoteProvider;
 use quote_verification_collateral::get_quote_verification_collateral;
 
 use axum::{extract::State, http::StatusCode, response::IntoResponse, routing::post, Json, Router};
 use log::info;
+use quote_verification::quote::QuoteError;
 use serde::Deserialize;
 use serde_json::json;
 use sgx_isa::Report;
 use std::{net::SocketAddr, sync::Arc};
 
+/// Cache of the collateral fetched from the collateral source, both
+/// configured from the environment
+pub fn collateral_cache() -> Result<CollateralCache> {
+    CollateralCache::from_env(collateral_source::from_env()?)
+}
+
 #[tokio::main(flavor = "current_thread")]
 pub async fn start_remote_attestation() {
+    let collateral_cache = Arc::new(collateral_cache().unwrap());
+    collateral_cache.spawn_refresher();
+
     let app = Router::new()
         .route("/get_target_info", post(get_target_info))
         .route("/get_quote", post(get_quote))
-        .route("/get_collateral", post(get_collateral))
+        .route(
+            "/get_collateral",
+            post(move |request: Json<GetCollateralRequest>| {
+                get_collateral(collateral_cache, request)
+            }),
+        )
         .with_state(Arc::new(QuoteProvider::init().unwrap()));
 
     let addr = SocketAddr::from(([127, 0, 0, 1], 11000));
@@ -38,16 +56,29 @@ struct WebError(anyhow::Error);
 
 impl IntoResponse for WebError {
     fn into_response(self) -> axum::response::Response {
+        // Malformed quotes are the fault of the caller
+        let status = if self.0.downcast_ref::<QuoteError>().is_some() {
+            StatusCode::BAD_REQUEST
+        } else {
+            StatusCode::INTERNAL_SERVER_ERROR
+        };
         // its often easiest to implement `IntoResponse` by calling other implementations
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Something went wrong :{}", self.0),
-        )
-            .into_response()
+        (status, format!("Something went wrong :{}", self.0)).into_response()
     }
 }
 
 type WebResult = Result<impl IntoResponse, WebError>;
+
+/// Run blocking work (AESM calls, requests to the collateral source) off the
+/// single threaded runtime, so that it doesn't stall the other requests
+async fn blocking<T, F>(f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f).await?
+}
+
 impl<E> From<E> for WebError
 where
     E: Into<anyhow::Error>,
@@ -65,7 +96,8 @@ async fn get_quote(
     State(quote_provider): State<Arc<QuoteProvider>>,
     Json(GetQuoteRequest { enclave_report }): Json<GetQuoteRequest>,
 ) -> WebResult {
-    Ok(Json(json! { quote_provider.get_quote(enclave_report)? }))
+    let quote = blocking(move || quote_provider.get_quote(enclave_report)).await?;
+    Ok(Json(json! { quote }))
 }
 
 #[derive(Deserialize)]
@@ -74,9 +106,10 @@ struct GetCollateralRequest {
 }
 
 async fn get_collateral(
+    collateral_cache: Arc<CollateralCache>,
     Json(GetCollateralRequest { quote }): Json<GetCollateralRequest>,
 ) -> WebResult {
-    let x = get_quote_verification_collateral(&quote)?;
+    let x = blocking(move || get_quote_verification_collateral(&quote, &collateral_cache)).await?;
     info!("Sending collateral!");
     Ok(Json(json! { x }))
 }
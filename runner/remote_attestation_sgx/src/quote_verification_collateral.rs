@@ -1,20 +1,17 @@
-use anyhow::{anyhow, ensure, Context, Error, Result};
-use dcap_ql::{
-    quote::{Qe3CertDataPckCertChain, Quote, Quote3SignatureEcdsaP256},
-    Quote3Error,
-};
-use der_parser::der::{
-    parse_der_octetstring, parse_der_oid, parse_der_sequence_defined_g, parse_der_sequence_of_v,
-};
-use der_parser::error::{BerError, BerResult};
-use der_parser::nom::combinator::map;
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use dcap_ql::Quote3Error;
+use quote_verification::pki::{parse_certificate, PckExtensions};
+use quote_verification::quote::{CertificationData, PckCertId, Quote};
 use serde::{Deserialize, Serialize};
 
+use crate::collateral_cache::CollateralCache;
+use crate::collateral_source::CollateralSource;
+
 use std::{
     ffi::{c_char, CString},
     ptr, slice, str,
 };
-use x509_parser::{oid_registry::Oid, prelude::FromDer, prelude::X509Certificate};
+
 /// Get SGX ECDSA attestation collateral from an SGX quote
 ///
 /// The verification collateral is the data required needed by the client to
@@ -26,50 +23,27 @@ use x509_parser::{oid_registry::Oid, prelude::FromDer, prelude::X509Certificate}
 /// * The signing cert chain for the QEIdentity structure
 /// * The TCBInfo structure
 /// * The QEIdentity structure
-pub fn get_quote_verification_collateral(quote: &[u8]) -> Result<SgxCollateral> {
+///
+/// The collateral of the platform comes from `cache`, the PCK certificate and
+/// its signing chain from the quote, or from the collateral source of `cache`
+/// if the quote only identifies the PCK certificate.
+pub fn get_quote_verification_collateral(
+    quote: &[u8],
+    cache: &CollateralCache,
+) -> Result<SgxCollateral> {
     let (fmspc, ca_from_quote, pck_certificate, pck_signing_chain) =
-        get_fmspc_ca_from_quote(quote)?;
+        get_fmspc_ca_from_quote(quote, cache.source())?;
 
     let SgxQlQveCollateral {
         version,
         pck_crl_issuer_chain,
         root_ca_crl,
         pck_crl,
-        mut tcb_info_issuer_chain,
-        mut tcb_info,
-        mut qe_identity_issuer_chain,
-        mut qe_identity,
-    } = sgx_get_quote_verification_collateral(&fmspc, &ca_from_quote)?;
-
-    if std::env::var("BLINDAI_AZURE_DCS3_PATCH").is_ok() {
-        println!("The patch for Azure DCsv3 and DCdsv3-series VMs is enabled. Requesting collateral directly from Intel, bypassing the PCS.");
-
-        let api_tcb_info_response =
-            ureq::get("https://api.trustedservices.intel.com/sgx/certification/v3/tcb")
-                .query("fmspc", &hex::encode(fmspc))
-                .call()?;
-
-        tcb_info_issuer_chain = urlencoding::decode(
-            api_tcb_info_response
-                .header("SGX-TCB-Info-Issuer-Chain")
-                .unwrap(),
-        )?
-        .to_string();
-
-        tcb_info = api_tcb_info_response.into_string()?;
-
-        let api_qe_identity_response =
-            ureq::get("https://api.trustedservices.intel.com/sgx/certification/v3/qe/identity")
-                .call()?;
-        qe_identity_issuer_chain = urlencoding::decode(
-            api_qe_identity_response
-                .header("SGX-Enclave-Identity-Issuer-Chain")
-                .unwrap(),
-        )?
-        .to_string();
-
-        qe_identity = api_qe_identity_response.into_string()?;
-    }
+        tcb_info_issuer_chain,
+        tcb_info,
+        qe_identity_issuer_chain,
+        qe_identity,
+    } = cache.get(&fmspc, ca_from_quote.to_str()?)?;
 
     Ok(SgxCollateral {
         version,
@@ -118,29 +92,6 @@ extern "C" {
     ) -> Quote3Error;
 }
 
-#[derive(Debug)]
-enum SgxExtension {
-    Fmspc([u8; 6]), // FMSPC ::= OCTET STRING (SIZE (6))
-}
-
-const FMSPC_SIZE: usize = 6; // FMSPC is 6 bytes long
-
-/// Parse the SGX extension from X509 certificate extension for SGX
-///
-/// # Arguments
-/// * `i` - DER encoded SGXExtentions as defined in Chapter 1.5.1 Appendix A of
-///  <https://api.trustedservices.intel.com/documents/Intel_SGX_PCK_Certificate_CRL_Spec-1.4.pdf>
-fn parse_sgx_extensions(
-    i: &[u8],
-) -> Result<(&[u8], Vec<SgxExtension>), der_parser::nom::Err<BerError>> {
-    // SGXExtentions ::= SEQUENCE SIZE (1..MAX) OF SEQUENCE {
-    // sGXExtensionId SGXExtensionId,
-    // sGXExtensionValue ANY DEFINED BY sGXExtensionId }
-    map(parse_der_sequence_of_v(parse_sgx_extension), |l| {
-        l.into_iter().flatten().collect()
-    })(i)
-}
-
 /// Convert any PCS CRL version to the V1/V2 PEM format
 ///
 /// Motivation :
@@ -152,7 +103,7 @@ fn parse_sgx_extensions(
 ///   0 or 1. A minor_verion of 0 indicates the CRL’s are formatted in Base16
 ///   encoded DER. A minor version of 1 indicates the CRL’s are formatted in raw
 ///   binary DER.
-fn pcs_crl_to_pem(crl: &[u8]) -> String {
+pub(crate) fn pcs_crl_to_pem(crl: &[u8]) -> String {
     // if it is already in PEM format (format V1/V2)
     if pem::parse(crl).is_ok() {
         return str::from_utf8(crl).unwrap().to_string();
@@ -169,41 +120,6 @@ fn pcs_crl_to_pem(crl: &[u8]) -> String {
     pem::encode(&pem::Pem::new("X509 CRL".to_string(), raw_bytes_crl))
 }
 
-/// Parse an SGX extension
-///
-/// Only the FMSPC value is extracted, the other extensions are ignored.
-///
-/// # Arguments
-/// * `i` - DER encoded sGXExtensionValue as defined in Chapter 1.5.1 Appendix A
-///   of <https://api.trustedservices.intel.com/documents/Intel_SGX_PCK_Certificate_CRL_Spec-1.4.pdf>
-fn parse_sgx_extension(i: &[u8]) -> BerResult<Option<SgxExtension>> {
-    parse_der_sequence_defined_g(|i: &[u8], _| {
-        let (i, a) = parse_der_oid(i)?;
-        let sgx_extension_id = a.as_oid()?;
-
-        let sgx_extension_fmspc: Oid =
-            Oid::from(sgx_pkix::oid::SGX_EXTENSION_FMSPC.components()).unwrap();
-        let sgx_value = if sgx_extension_id == &sgx_extension_fmspc {
-            let (_, fmspc) = parse_der_octetstring(i)?;
-            let fmspc = fmspc.content.as_slice()?;
-
-            assert!(
-                fmspc.len() == FMSPC_SIZE,
-                "FMSPC size should be {}, got {}",
-                FMSPC_SIZE,
-                fmspc.len()
-            );
-
-            Some(SgxExtension::Fmspc(
-                fmspc.try_into().map_err(|_| BerError::BerValueError)?,
-            ))
-        } else {
-            None
-        };
-        Ok((i, sgx_value))
-    })(i)
-}
-
 // "Native" Rust type for sgx_ql_qve_collateral_t
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SgxCollateral {
@@ -219,7 +135,7 @@ pub struct SgxCollateral {
     pub pck_signing_chain: String,     // PCK signing chain in PEM format
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct SgxQlQveCollateral {
     pub version: u32,                  // version = 1.  PCK Cert chain is in the Quote.
     pub pck_crl_issuer_chain: String,  // PCK CRL Issuer Chain in PEM format
@@ -353,35 +269,52 @@ pub fn sgx_get_quote_verification_collateral(
     })
 }
 
-/// Function to extract the FMSPc and CA from a given quote with cert type 5
-pub fn get_fmspc_ca_from_quote(quote: &[u8]) -> Result<([u8; 6], CString, String, String)> {
+/// Split a PEM certificate chain into its certificates
+fn split_pem_chain(chain: &str) -> Vec<String> {
+    chain
+        .split_inclusive("-----END CERTIFICATE-----")
+        .map(str::trim)
+        .filter(|certificate| !certificate.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Get the PCK certificate chain of a platform from the collateral source, as
+/// the QPL does when the quote only identifies the PCK certificate
+fn fetch_pck_cert_chain(
+    source: &dyn CollateralSource,
+    qe_id: &[u8; 16],
+    pck_cert_id: &PckCertId,
+) -> Result<Vec<String>> {
+    let (issuer_chain, pck_certificate) = source.pck_certificate(qe_id, pck_cert_id)?;
+    Ok([
+        split_pem_chain(&pck_certificate),
+        split_pem_chain(&issuer_chain),
+    ]
+    .concat())
+}
+
+/// Function to extract the FMSPC and CA from a given quote
+///
+/// The PCK certificate chain is the one embedded in the quote (certification
+/// data type 5, possibly wrapped in type 6), or the one `source` serves for
+/// the platform identified by the quote (types 1 to 3).
+pub fn get_fmspc_ca_from_quote(
+    quote: &[u8],
+    source: &dyn CollateralSource,
+) -> Result<([u8; 6], CString, String, String)> {
     // The following is basically what the internal QVL function
     // get_fmspc_ca_from_quote does :
     // <https://github.com/intel/SGXDataCenterAttestationPrimitives/blob/master/QuoteVerification/QvE/Enclave/qve.cpp#L478>
-    let quote = Quote::parse(quote).map_err(|_| anyhow!("Quote parsing failed!"))?;
-
-    // qe_certification_data is the Certification Data Variable Byte Array Data
-    // required to verify the QE Report Signature depending on the value of the
-    // Certification Data Type: . * 5: Concatenated PCK Cert Chain (PEM
-    // formatted). PCK Leaf Cert||Intermediate CA Cert|| Root CA Cert
-
-    // In Fortanix the certChain function doesn't exist. And thus must be implemented
-    // let cert_chain_sig = cert_chain.parse(EcdsaP256, quote.signature)
-    // The certification data is in quote.certification_data()
-    // It returns the PCK (platform certification public key) in DER format
-    let signature: Quote3SignatureEcdsaP256 = quote.signature().unwrap();
-
-    //ajouter un match pour s'assurer du certification_type ici dans le code final du collateral
-    let certification_data = signature.certification_data::<Qe3CertDataPckCertChain>();
-
-    //transforming the certification into pems
-    let cert_chain_data = certification_data.unwrap();
-    let cert_chain: Vec<String> = cert_chain_data
-        .certs
-        .iter()
-        .map(|c| c.to_string())
-        .collect();
-
+    let quote = Quote::parse(quote)?;
+
+    let cert_chain = match quote.certification_data.pck() {
+        CertificationData::PckCertChain(chain) => split_pem_chain(chain),
+        CertificationData::PckCertId(pck_cert_id) => {
+            fetch_pck_cert_chain(source, &quote.qe_id, pck_cert_id)?
+        }
+        CertificationData::QeReport(_) => bail!("Nested QE report certification data"),
+    };
     ensure!(
         cert_chain.len() == 3,
         "Wrong number of certificates in the CertChain"
@@ -391,32 +324,8 @@ pub fn get_fmspc_ca_from_quote(quote: &[u8]) -> Result<([u8; 6], CString, String
     let pck_signing_chain = cert_chain[1..].join("\n");
 
     let pck_cert_der = pem::parse(pck_certificate)?;
-
-    let (_, pck_cert) = X509Certificate::from_der(pck_cert_der.contents())?;
-
-    let sgx_extension_oid: Oid = Oid::from(sgx_pkix::oid::SGX_EXTENSION.components())
-        .map_err(|e| Error::msg(format!("{e:?})")))?;
-    let sgx_ext = pck_cert
-        .extensions()
-        .iter()
-        .find(|ext| ext.oid == sgx_extension_oid)
-        .context(
-            "SGX extension not found in the X509 Certificate, hint: is it the wrong certificate \
-            (expecting the PCK cert but maybe got the Root CA, or the Intermediate CA cert instead) ?",
-        )?
-        .value;
-
-    let (_, extension) = parse_sgx_extensions(sgx_ext)?;
-    let fmspc = extension
-        .iter()
-        .find_map(|v| {
-            #[allow(unreachable_patterns)]
-            match v {
-                SgxExtension::Fmspc(fmspc) => Some(fmspc),
-                _ => None,
-            }
-        })
-        .context("SGX FMSPC not found in the SGX extensions")?;
+    let pck_cert = parse_certificate(pck_cert_der.contents())?;
+    let fmspc = PckExtensions::parse(&pck_cert)?.fmspc;
 
     let issuer_cn = pck_cert
         .issuer()
@@ -438,9 +347,121 @@ pub fn get_fmspc_ca_from_quote(quote: &[u8]) -> Result<([u8; 6], CString, String
 
     let ca_from_quote = CString::new(ca_from_quote)?;
     Ok((
-        fmspc.to_owned(),
+        fmspc,
         ca_from_quote,
         pck_certificate.to_string(),
         pck_signing_chain,
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collateral_source::PcsApi;
+    use quote_verification::quote::QuoteError;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    const QUOTE: &[u8] = include_bytes!("../../quote_verification/tests/fixtures/quote.bin");
+    const FMSPC: [u8; 6] = [0x00, 0x90, 0x6e, 0xd5, 0x00, 0x00];
+
+    /// The fixture quote with the PCK certificate identified by an encrypted
+    /// PPID (certification data type 3) instead of embedded
+    fn quote_with_pck_cert_id() -> Vec<u8> {
+        let parsed = Quote::parse(QUOTE).unwrap();
+        let certification_data_offset = QUOTE.len() - parsed.pck_cert_chain().unwrap().len() - 6;
+        let mut quote = QUOTE[..certification_data_offset].to_vec();
+        quote.extend_from_slice(&3u16.to_le_bytes());
+        quote.extend_from_slice(&404u32.to_le_bytes());
+        quote.extend_from_slice(&[0xaa; 384]);
+        quote.extend_from_slice(&[0x11; 16]);
+        quote.extend_from_slice(&[0x0d, 0x00, 0x00, 0x00]);
+
+        let signature_data_len = (quote.len() - 436) as u32;
+        quote[432..436].copy_from_slice(&signature_data_len.to_le_bytes());
+        quote
+    }
+
+    /// Serve one PCK certificate request as a PCCS would, returning the
+    /// request line
+    fn pccs_stand_in(chain: Vec<String>) -> (String, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+
+            let body = &chain[0];
+            let issuer_chain = urlencoding::encode(&chain[1..].join("\n")).into_owned();
+            write!(
+                reader.get_mut(),
+                "HTTP/1.1 200 OK\r\nSGX-PCK-Certificate-Issuer-Chain: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                issuer_chain,
+                body.len(),
+                body
+            )
+            .unwrap();
+            request_line
+        });
+        (url, handle)
+    }
+
+    /// Source of quotes embedding their PCK certificate chain
+    fn no_source(_: &[u8; 6], _: &str) -> Result<SgxQlQveCollateral> {
+        bail!("No collateral source")
+    }
+
+    #[test]
+    fn embedded_pck_cert_chains_are_used() {
+        let (fmspc, ca, pck_certificate, pck_signing_chain) =
+            get_fmspc_ca_from_quote(QUOTE, &no_source).unwrap();
+        assert_eq!(fmspc, FMSPC);
+        assert_eq!(ca.to_str().unwrap(), "processor");
+        assert!(pck_certificate.starts_with("-----BEGIN CERTIFICATE-----"));
+        assert_eq!(split_pem_chain(&pck_signing_chain).len(), 2);
+    }
+
+    #[test]
+    fn malformed_quotes_are_errors() {
+        for len in 0..QUOTE.len() {
+            let error = get_fmspc_ca_from_quote(&QUOTE[..len], &no_source).unwrap_err();
+            assert!(error.downcast_ref::<QuoteError>().is_some(), "{}", error);
+        }
+        // Corrupting the PCK certificate chain
+        let chain_offset = QUOTE.len() - 1200;
+        for i in (chain_offset..QUOTE.len()).step_by(7) {
+            let mut quote = QUOTE.to_vec();
+            quote[i] ^= 0x55;
+            let _ = get_fmspc_ca_from_quote(&quote, &no_source);
+        }
+    }
+
+    #[test]
+    fn pck_cert_chains_are_fetched_from_the_pccs() {
+        let chain = split_pem_chain(Quote::parse(QUOTE).unwrap().pck_cert_chain().unwrap());
+        let (url, pccs) = pccs_stand_in(chain.clone());
+
+        let (fmspc, ca, pck_certificate, pck_signing_chain) =
+            get_fmspc_ca_from_quote(&quote_with_pck_cert_id(), &PcsApi::pccs(&url)).unwrap();
+        assert_eq!(fmspc, FMSPC);
+        assert_eq!(ca.to_str().unwrap(), "processor");
+        assert_eq!(pck_certificate, chain[0]);
+        assert_eq!(pck_signing_chain, chain[1..].join("\n"));
+
+        let request_line = pccs.join().unwrap();
+        assert!(request_line.starts_with("GET /sgx/certification/v4/pckcert?qeid="));
+        assert!(request_line.contains(&format!("&cpusvn={}", "11".repeat(16))));
+        assert!(request_line.contains("&pcesvn=0d00&pceid=0000"));
+        assert!(request_line.contains(&format!("&encrypted_ppid={}", "aa".repeat(384))));
+
+        // Sources that don't serve PCK certificates
+        assert!(get_fmspc_ca_from_quote(&quote_with_pck_cert_id(), &no_source).is_err());
+    }
+}
@@ -0,0 +1,187 @@
+//! Bounded connections to the enclave.
+//!
+//! The enclave serves HTTP with a thread per connection: the Fortanix target
+//! has no readiness based I/O, and the read and write timeouts of its sockets
+//! have no effect. Unbounded, idle or slow clients would take every thread of
+//! the enclave. The listening sockets of the enclave are thus bound by the
+//! runner, whose I/O is event driven, and bounded there:
+//!
+//! * at most `BLINDAI_MAX_CONNECTIONS` connections are open at once on each
+//!   port (16 by default). The connections over this limit are closed as soon
+//!   as they are accepted, before the enclave sees them
+//! * a connection on which nothing was read nor written for
+//!   `BLINDAI_IDLE_TIMEOUT` seconds (30 by default) fails with a timeout,
+//!   which closes it in the enclave too
+//!
+//! The enclave sizes its connection threads with the same variables.
+
+use enclave_runner::usercalls::{AsyncListener, AsyncStream, UsercallExtension};
+use futures::FutureExt;
+use std::future::Future;
+use std::io::{self, ErrorKind};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{self, Delay, Instant};
+
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionLimits {
+    pub max_connections: usize,
+    pub idle_timeout: Duration,
+}
+
+impl ConnectionLimits {
+    pub fn from_env() -> Result<Self, String> {
+        fn var(name: &str, default: u64) -> Result<u64, String> {
+            match std::env::var(name) {
+                Ok(value) => value.parse().map_err(|e| format!("Invalid {name}: {e}")),
+                Err(_) => Ok(default),
+            }
+        }
+        Ok(ConnectionLimits {
+            max_connections: var("BLINDAI_MAX_CONNECTIONS", 16)? as usize,
+            idle_timeout: Duration::from_secs(var("BLINDAI_IDLE_TIMEOUT", 30)?),
+        })
+    }
+}
+
+/// Binds the listening sockets of the enclave, bounded by the limits
+#[derive(Debug)]
+pub struct BoundedConnections(pub ConnectionLimits);
+
+impl UsercallExtension for BoundedConnections {
+    fn bind_stream<'future>(
+        &'future self,
+        addr: &'future str,
+        local_addr: Option<&'future mut String>,
+    ) -> Pin<Box<dyn Future<Output = io::Result<Option<Box<dyn AsyncListener>>>> + 'future>> {
+        async move {
+            let listener = TcpListener::bind(addr).await?;
+            if let Some(local_addr) = local_addr {
+                *local_addr = listener.local_addr()?.to_string();
+            }
+            let listener: Box<dyn AsyncListener> = Box::new(BoundedListener {
+                listener,
+                limits: self.0,
+                open: Arc::new(AtomicUsize::new(0)),
+            });
+            Ok(Some(listener))
+        }
+        .boxed_local()
+    }
+}
+
+struct BoundedListener {
+    listener: TcpListener,
+    limits: ConnectionLimits,
+    /// Connections of this listener still open
+    open: Arc<AtomicUsize>,
+}
+
+impl AsyncListener for BoundedListener {
+    fn poll_accept(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        local_addr: Option<&mut String>,
+        peer_addr: Option<&mut String>,
+    ) -> Poll<io::Result<Option<Box<dyn AsyncStream>>>> {
+        loop {
+            let (stream, peer) = match self.listener.poll_accept(cx) {
+                Poll::Ready(Ok(accepted)) => accepted,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+            if self.open.fetch_add(1, Ordering::SeqCst) >= self.limits.max_connections {
+                // Dropping the stream closes it
+                self.open.fetch_sub(1, Ordering::SeqCst);
+                continue;
+            }
+
+            if let Some(local_addr) = local_addr {
+                *local_addr = stream.local_addr()?.to_string();
+            }
+            if let Some(peer_addr) = peer_addr {
+                *peer_addr = peer.to_string();
+            }
+            let idle_timeout = self.limits.idle_timeout;
+            let stream: Box<dyn AsyncStream> = Box::new(BoundedStream {
+                stream,
+                idle: time::delay_for(idle_timeout),
+                idle_timeout,
+                _slot: Slot(Arc::clone(&self.open)),
+            });
+            return Poll::Ready(Ok(Some(stream)));
+        }
+    }
+}
+
+/// An open connection, counted until dropped
+struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+struct BoundedStream {
+    stream: TcpStream,
+    /// Elapses once the connection has been idle for `idle_timeout`
+    idle: Delay,
+    idle_timeout: Duration,
+    _slot: Slot,
+}
+
+impl BoundedStream {
+    /// Restart the idle timer on progress, or fail once it elapsed
+    fn track<T>(&mut self, cx: &mut Context, poll: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        match poll {
+            Poll::Pending => match Pin::new(&mut self.idle).poll(cx) {
+                Poll::Ready(()) => Poll::Ready(Err(io::Error::new(
+                    ErrorKind::TimedOut,
+                    "The connection was idle for too long",
+                ))),
+                Poll::Pending => Poll::Pending,
+            },
+            ready => {
+                self.idle.reset(Instant::now() + self.idle_timeout);
+                ready
+            }
+        }
+    }
+}
+
+impl AsyncRead for BoundedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.stream).poll_read(cx, buf);
+        self.track(cx, poll)
+    }
+}
+
+impl AsyncWrite for BoundedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.stream).poll_write(cx, buf);
+        self.track(cx, poll)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.stream).poll_flush(cx);
+        self.track(cx, poll)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
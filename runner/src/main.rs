@@ -1,5 +1,9 @@
+mod connections;
+
 use aesm_client::AesmClient;
+use connections::{BoundedConnections, ConnectionLimits};
 use enclave_runner::EnclaveBuilder;
+use remote_attestation_sgx::collateral_cache::CachedCollateral;
 use sgxs_loaders::isgx::Device as IsgxDevice;
 use std::{
     collections::hash_map::DefaultHasher,
@@ -8,22 +12,85 @@ use std::{
 };
 
 fn usage(name: String) {
-    println!("Usage: \n{name} <path_to_sgxs_file>");
+    println!(
+        "Usage: \n\
+         {name} <path_to_sgxs_file>\n\
+         {name} collateral list\n\
+         {name} collateral fetch <fmspc> <processor|platform>\n\
+         {name} collateral import <collateral.json>"
+    );
+}
+
+enum Command {
+    RunEnclave(String),
+    Collateral(CollateralCommand),
 }
 
-fn parse_args() -> Result<String, ()> {
+/// Administration of the collateral cache
+enum CollateralCommand {
+    List,
+    Fetch([u8; 6], String),
+    Import(String),
+}
+
+fn parse_args() -> Result<Command, ()> {
     let args: Vec<String> = std::env::args().collect();
+    let args_str: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let command = match args_str[1..] {
+        [path] => Some(Command::RunEnclave(path.to_owned())),
+        ["collateral", "list"] => Some(Command::Collateral(CollateralCommand::List)),
+        ["collateral", "fetch", fmspc, ca @ ("processor" | "platform")] => hex::decode(fmspc)
+            .ok()
+            .and_then(|fmspc| fmspc.try_into().ok())
+            .map(|fmspc| Command::Collateral(CollateralCommand::Fetch(fmspc, ca.to_owned()))),
+        ["collateral", "import", path] => Some(Command::Collateral(CollateralCommand::Import(
+            path.to_owned(),
+        ))),
+        _ => None,
+    };
+    command.ok_or_else(|| usage(args[0].to_owned()))
+}
+
+fn print_collateral(cached: &CachedCollateral) {
+    println!(
+        "FMSPC {} ({} CA): fetched at {}, valid until {} (Unix time){}",
+        cached.fmspc,
+        cached.ca,
+        cached.fetched_at,
+        cached.expires_at,
+        if cached.is_expired() { ", EXPIRED" } else { "" }
+    );
+}
 
-    match args.len() {
-        2 => Ok(args[1].to_owned()),
-        _ => {
-            usage(args[0].to_owned());
-            Err(())
-        }
+fn run_collateral_command(command: CollateralCommand) {
+    let result = remote_attestation_sgx::collateral_cache().and_then(|cache| match command {
+        CollateralCommand::List => cache.entries().map(|entries| {
+            for cached in &entries {
+                print_collateral(cached);
+            }
+        }),
+        CollateralCommand::Fetch(fmspc, ca) => cache
+            .fetch(&fmspc, &ca)
+            .map(|cached| print_collateral(&cached)),
+        CollateralCommand::Import(path) => std::fs::read_to_string(path)
+            .map_err(Into::into)
+            .and_then(|json| cache.import(&json))
+            .map(|cached| print_collateral(&cached)),
+    });
+    if let Err(e) = result {
+        println!("Error on the collateral cache \n {e:#}.");
+        std::process::exit(1);
     }
 }
 
 fn main() {
+    let file = match parse_args() {
+        Ok(Command::RunEnclave(file)) => file,
+        Ok(Command::Collateral(command)) => return run_collateral_command(command),
+        Err(()) => std::process::exit(2),
+    };
+
     // Running the remote attestation thread
     let remote_att_sgx = thread::spawn(remote_attestation_sgx::start_remote_attestation);
 
@@ -39,13 +106,17 @@ fn main() {
     };
     let custom_agent_id = std::env::var("CUSTOM_AGENT_ID").unwrap_or_default();
     // Running the enclave
-    let file = parse_args().unwrap();
     let aesm_client = AesmClient::new();
     let mut device = IsgxDevice::new()
         .unwrap()
         .einittoken_provider(aesm_client)
         .build();
     let mut enclave_builder = EnclaveBuilder::new(file.as_ref());
+    let limits = ConnectionLimits::from_env().unwrap_or_else(|e| {
+        println!("{e}");
+        std::process::exit(2)
+    });
+    enclave_builder.usercall_extension(BoundedConnections(limits));
 
     fn make_arg(arg_name: &str, arg_value: &str) -> Vec<u8> {
         let mut arg = arg_name.as_bytes().to_vec();
@@ -0,0 +1,286 @@
+// Copyright 2022 Mithril Security. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tamper-evident log of the model lifecycle events.
+//!
+//! Each entry carries the CBOR encoded event in `data` and is chained to the
+//! previous one:
+//!
+//! `hash = SHA-256(previous_hash || index as u64 big endian || data)`
+//!
+//! The first entry uses 32 zero bytes as `previous_hash`. The head of the log
+//! is signed with the enclave TLS key (ECDSA P-256 with SHA-256, ASN.1
+//! signature) over `size as u64 big endian || hash`, so that anyone holding
+//! the attested enclave certificate can check it.
+//!
+//! The log only lives in enclave memory and starts empty at each boot. Only
+//! its last `BLINDAI_AUDIT_LOG_MAX_ENTRIES` entries (100 000 by default) are
+//! kept: the `previous_hash` of the oldest entry kept checkpoints the dropped
+//! ones, and the signed head still covers the whole log.
+//!
+//! The server has no model aliases: a model keeps the name given at upload,
+//! and a new version of a model replaces it under the same id and name
+//! (recorded as `Replace`), so no event moves a name to another model.
+
+use anyhow::{anyhow, bail, Result};
+use log::info;
+use ring::digest;
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+use serde_derive::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) enum AuditEvent {
+    Upload {
+        model_id: String,
+        model_hash: String,
+        model_name: Option<String>,
+        caller: Option<String>,
+    },
+    Delete {
+        model_id: String,
+        model_hash: String,
+        caller: Option<String>,
+    },
+    Expire {
+        model_id: String,
+        model_hash: String,
+    },
+    Replace {
+        model_id: String,
+        previous_model_hash: String,
+        model_hash: String,
+        caller: Option<String>,
+    },
+    PolicyChange {
+        model_id: String,
+        model_hash: String,
+        caller: Option<String>,
+    },
+}
+
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    timestamp: u64,
+    event: &'a AuditEvent,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct AuditEntry {
+    pub index: u64,
+    #[serde(with = "crate::encoding::binary")]
+    pub previous_hash: Vec<u8>,
+    /// CBOR encoded `{ timestamp, event }`
+    #[serde(with = "crate::encoding::binary")]
+    pub data: Vec<u8>,
+    #[serde(with = "crate::encoding::binary")]
+    pub hash: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct SignedHead {
+    pub size: u64,
+    #[serde(with = "crate::encoding::binary")]
+    pub hash: Vec<u8>,
+    #[serde(with = "crate::encoding::binary")]
+    pub signature: Vec<u8>,
+}
+
+pub(crate) struct AuditLog {
+    /// Last entries, the oldest ones first
+    entries: Mutex<VecDeque<AuditEntry>>,
+    max_entries: usize,
+    signing_key: EcdsaKeyPair,
+    rng: SystemRandom,
+}
+
+fn entry_hash(previous_hash: &[u8], index: u64, data: &[u8]) -> Vec<u8> {
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    ctx.update(previous_hash);
+    ctx.update(&index.to_be_bytes());
+    ctx.update(data);
+    ctx.finish().as_ref().to_vec()
+}
+
+/// Size of the whole log and hash of its last entry
+fn head(entries: &VecDeque<AuditEntry>) -> (u64, Vec<u8>) {
+    match entries.back() {
+        Some(entry) => (entry.index + 1, entry.hash.clone()),
+        None => (0, vec![0u8; digest::SHA256_OUTPUT_LEN]),
+    }
+}
+
+impl AuditLog {
+    /// Create an empty log signed with the PKCS#8 encoded enclave TLS key,
+    /// keeping its last `max_entries` entries
+    pub fn new(enclave_private_key_der: &[u8], max_entries: usize) -> Result<Self> {
+        if max_entries == 0 {
+            bail!("The audit log must keep at least one entry");
+        }
+        let signing_key =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, enclave_private_key_der)
+                .map_err(|e| anyhow!("Invalid enclave signing key: {}", e))?;
+        Ok(AuditLog {
+            entries: Mutex::new(VecDeque::new()),
+            max_entries,
+            signing_key,
+            rng: SystemRandom::new(),
+        })
+    }
+
+    /// Keep `BLINDAI_AUDIT_LOG_MAX_ENTRIES` entries (100 000 by default)
+    pub fn from_env(enclave_private_key_der: &[u8]) -> Result<Self> {
+        let max_entries = match std::env::var("BLINDAI_AUDIT_LOG_MAX_ENTRIES") {
+            Ok(value) => value.parse()?,
+            Err(_) => 100_000,
+        };
+        Self::new(enclave_private_key_der, max_entries)
+    }
+
+    pub fn record(&self, event: AuditEvent) -> Result<()> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let data = serde_cbor::to_vec(&AuditRecord {
+            timestamp,
+            event: &event,
+        })?;
+
+        let mut entries = self.entries.lock().unwrap();
+        let (index, previous_hash) = head(&entries);
+        let hash = entry_hash(&previous_hash, index, &data);
+
+        info!("Audit log entry {}: {:?}", index, event);
+        if entries.len() == self.max_entries {
+            entries.pop_front();
+        }
+        entries.push_back(AuditEntry {
+            index,
+            previous_hash,
+            data,
+            hash,
+        });
+        Ok(())
+    }
+
+    /// Entries starting at index `from`, or at the oldest entry kept
+    pub fn entries(&self, from: u64) -> Vec<AuditEntry> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .iter()
+            .filter(|entry| entry.index >= from)
+            .cloned()
+            .collect()
+    }
+
+    pub fn signed_head(&self) -> Result<SignedHead> {
+        let (size, hash) = head(&self.entries.lock().unwrap());
+
+        let message = [&size.to_be_bytes()[..], &hash].concat();
+        let signature = self
+            .signing_key
+            .sign(&self.rng, &message)
+            .map_err(|_| anyhow!("Could not sign the audit log head"))?;
+
+        Ok(SignedHead {
+            size,
+            hash,
+            signature: signature.as_ref().to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1};
+
+    fn upload_event(model_id: &str) -> AuditEvent {
+        AuditEvent::Upload {
+            model_id: model_id.into(),
+            model_hash: "00".into(),
+            model_name: None,
+            caller: None,
+        }
+    }
+
+    #[test]
+    fn entries_are_chained() {
+        let certificate = crate::identity::create_tls_certificate().unwrap();
+        let log = AuditLog::new(&certificate.serialize_private_key_der(), 10).unwrap();
+        log.record(upload_event("a")).unwrap();
+        log.record(upload_event("b")).unwrap();
+        log.record(upload_event("c")).unwrap();
+
+        let entries = log.entries(0);
+        assert_eq!(entries.len(), 3);
+        let mut previous_hash = vec![0u8; 32];
+        for entry in &entries {
+            assert_eq!(entry.previous_hash, previous_hash);
+            assert_eq!(
+                entry.hash,
+                entry_hash(&previous_hash, entry.index, &entry.data)
+            );
+            previous_hash = entry.hash.clone();
+        }
+        assert_eq!(log.entries(2).len(), 1);
+        assert_eq!(log.entries(3).len(), 0);
+    }
+
+    #[test]
+    fn oldest_entries_are_dropped() {
+        let certificate = crate::identity::create_tls_certificate().unwrap();
+        let log = AuditLog::new(&certificate.serialize_private_key_der(), 2).unwrap();
+        log.record(upload_event("a")).unwrap();
+        let dropped = log.entries(0).remove(0);
+        log.record(upload_event("b")).unwrap();
+        log.record(upload_event("c")).unwrap();
+
+        let entries = log.entries(0);
+        assert_eq!(
+            entries.iter().map(|entry| entry.index).collect::<Vec<_>>(),
+            [1, 2]
+        );
+        // Still chained to the dropped entry
+        assert_eq!(entries[0].previous_hash, dropped.hash);
+        assert_eq!(entries[1].previous_hash, entries[0].hash);
+        assert_eq!(log.entries(2).len(), 1);
+
+        let head = log.signed_head().unwrap();
+        assert_eq!(head.size, 3);
+        assert_eq!(head.hash, entries[1].hash);
+
+        assert!(AuditLog::new(&certificate.serialize_private_key_der(), 0).is_err());
+    }
+
+    #[test]
+    fn head_is_signed_with_the_tls_key() {
+        let certificate = crate::identity::create_tls_certificate().unwrap();
+        let log = AuditLog::new(&certificate.serialize_private_key_der(), 10).unwrap();
+        log.record(upload_event("a")).unwrap();
+
+        let head = log.signed_head().unwrap();
+        assert_eq!(head.size, 1);
+        assert_eq!(head.hash, log.entries(0)[0].hash);
+
+        let message = [&head.size.to_be_bytes()[..], &head.hash].concat();
+        UnparsedPublicKey::new(
+            &ECDSA_P256_SHA256_ASN1,
+            certificate.get_key_pair().public_key_raw(),
+        )
+        .verify(&message, &head.signature)
+        .unwrap();
+    }
+}
@@ -12,17 +12,32 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::model::ModelDatumType;
+use crate::audit_log::{AuditEntry, AuditEvent, AuditLog, SignedHead};
+use crate::compute::{ComputePool, Workload};
+use crate::encoding::{
+    binary, is_multipart, read_body, read_multipart, read_raw_body, Compression, Encoding,
+};
+use crate::identity::EnclaveKey;
+use crate::key_broker::KeyBroker;
+use crate::metrics::Metrics;
+use crate::model::{check_owner, InferenceModel, ModelDatumType};
 use crate::model_store::ModelStore;
+use crate::policy::ModelPolicy;
+use crate::protocol::{
+    parse_request, reject_newer_fields, UnsupportedVersion, Versioned, PROTOCOL_VERSION,
+    PROTOCOL_VERSION_HEADER,
+};
+use crate::sealing::{self, Recipient, SealedPayload, INPUTS_INFO, OUTPUTS_INFO};
 use crate::telemetry::{self, TelemetryEventProps};
 use anyhow::{Error, Result};
 use log::{error, info};
+use ring::digest;
 use serde_derive::{Deserialize, Serialize};
-use std::io::Read;
-use std::mem::size_of;
+use std::borrow::Cow;
+use std::fmt;
 use std::str::FromStr;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -35,54 +50,180 @@ pub struct TensorInfo {
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct SerializedTensor {
     pub info: TensorInfo,
-    #[serde(with = "serde_bytes")]
+    #[serde(with = "binary")]
     pub bytes_data: Vec<u8>,
 }
 
+/// Header set by clients whose tensor data is gzip compressed, the output
+/// tensors are then compressed the same way
+pub(crate) const TENSOR_ENCODING_HEADER: &str = "X-Tensor-Encoding";
+
+#[derive(Debug)]
+pub(crate) struct ModelNotFound;
+
+impl fmt::Display for ModelNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Model doesn't exist")
+    }
+}
+
+impl std::error::Error for ModelNotFound {}
+
 #[derive(Clone)]
 pub(crate) struct Exchanger {
     model_store: Arc<ModelStore>,
     max_model_size: usize,
     max_input_size: usize,
+    key_broker: Option<Arc<KeyBroker>>,
+    audit_log: Arc<AuditLog>,
+    metrics: Arc<Metrics>,
+    enclave_key: Arc<EnclaveKey>,
+    compute: Arc<ComputePool>,
 }
 
+/// Exactly one of the deletion criteria must be provided
 #[derive(Deserialize)]
 struct DeleteModel {
+    #[serde(default)]
+    model_id: String,
+    #[serde(default)]
+    model_hash: Option<String>,
+    #[serde(default)]
+    model_name: Option<String>,
+    /// Identity of the caller who uploaded the models
+    #[serde(default)]
+    tenant: Option<String>,
+    #[serde(default)]
+    protocol_version: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct SetPolicy {
+    model_id: String,
+    policy: ModelPolicy,
+    #[serde(default)]
+    protocol_version: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct GetPolicy {
+    #[serde(default)]
     model_id: String,
+    #[serde(default)]
+    model_hash: String,
+    #[serde(default)]
+    protocol_version: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct GetAuditLog {
+    #[serde(default)]
+    from: u64,
+    #[serde(default)]
+    protocol_version: Option<u32>,
 }
 
 #[derive(Deserialize)]
 pub(crate) struct RunModel {
     model_id: String,
     model_hash: String,
+    #[serde(default)]
     pub inputs: Vec<SerializedTensor>,
+    /// CBOR encoded inputs sealed to the enclave key, in place of `inputs`
+    #[serde(default)]
+    sealed_inputs: Option<SealedPayload>,
+    /// When set, the outputs are sealed to this key instead of being sent in
+    /// the clear. The inputs must then be sealed, bound to this key.
+    #[serde(default)]
+    recipient: Option<Recipient>,
+    #[serde(default)]
     client_info: ClientInfo,
+    #[serde(default)]
+    protocol_version: Option<u32>,
 }
 
+/// A model upload, either as a single CBOR or JSON body, or as a
+/// `multipart/form-data` body with the ONNX file in the `model` field and the
+/// other fields as a JSON object in the `metadata` field
 #[derive(Debug, Deserialize)]
 struct UploadModel {
-    #[serde(with = "serde_bytes")]
+    #[serde(with = "binary", default)]
     model: Vec<u8>,
+    #[serde(default)]
     length: u64,
+    #[serde(default)]
     model_name: String,
+    #[serde(default = "default_optimize")]
     optimize: bool,
+    #[serde(default)]
     client_info: ClientInfo,
+    #[serde(default)]
+    policy: ModelPolicy,
+    /// Time-to-live of the model in seconds
+    #[serde(default)]
+    ttl: Option<u64>,
+    /// When set, `model` is an encrypted model bundle whose key is held by
+    /// the key broker
+    #[serde(default)]
+    encrypted: bool,
+    #[serde(default)]
+    protocol_version: Option<u32>,
+}
+
+fn default_optimize() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+struct ReplaceModel {
+    model_id: String,
+    #[serde(with = "binary")]
+    model: Vec<u8>,
+    length: u64,
+    optimize: bool,
+    #[serde(default)]
+    encrypted: bool,
+    #[serde(default)]
+    protocol_version: Option<u32>,
 }
 
 #[derive(Serialize)]
 pub(crate) struct SendModelReply {
-    #[serde(with = "serde_bytes")]
+    #[serde(with = "binary")]
     hash: Vec<u8>,
     model_id: String,
 }
 
-#[derive(Default, Serialize)]
+#[derive(Serialize)]
+pub(crate) struct DeletionReceipt {
+    model_ids: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct AuditLogReply {
+    entries: Vec<AuditEntry>,
+    head: SignedHead,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub(crate) struct RunModelReply {
-    outputs: Vec<SerializedTensor>,
+    pub outputs: Vec<SerializedTensor>,
+    /// CBOR encoded outputs sealed to the recipient key, `outputs` being then
+    /// empty
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sealed_outputs: Option<SealedPayload>,
+}
+
+/// A parsed `/run` query along with what is needed to run it outside of the
+/// HTTP request
+pub(crate) struct RunRequest {
+    body: RunModel,
+    caller: Option<String>,
+    tensor_compression: Compression,
 }
 
 /// This model represents the ClientInfo used for telemetry
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub(crate) struct ClientInfo {
     pub uid: String,
     pub platform_name: String,
@@ -94,12 +235,104 @@ pub(crate) struct ClientInfo {
     pub is_colab: bool,
 }
 
+impl Versioned for DeleteModel {
+    fn protocol_version(&self) -> Option<u32> {
+        self.protocol_version
+    }
+
+    fn check_fields(&self, version: u32) -> Result<()> {
+        reject_newer_fields(
+            version,
+            2,
+            &[
+                ("model_hash", self.model_hash.is_some()),
+                ("model_name", self.model_name.is_some()),
+                ("tenant", self.tenant.is_some()),
+            ],
+        )
+    }
+}
+
+impl Versioned for RunModel {
+    fn protocol_version(&self) -> Option<u32> {
+        self.protocol_version
+    }
+
+    fn check_fields(&self, version: u32) -> Result<()> {
+        reject_newer_fields(
+            version,
+            2,
+            &[
+                ("sealed_inputs", self.sealed_inputs.is_some()),
+                ("recipient", self.recipient.is_some()),
+            ],
+        )
+    }
+}
+
+impl Versioned for UploadModel {
+    fn protocol_version(&self) -> Option<u32> {
+        self.protocol_version
+    }
+
+    fn check_fields(&self, version: u32) -> Result<()> {
+        reject_newer_fields(
+            version,
+            2,
+            &[
+                ("policy", self.policy != ModelPolicy::default()),
+                ("ttl", self.ttl.is_some()),
+                ("encrypted", self.encrypted),
+            ],
+        )
+    }
+}
+
+// These requests were introduced with version 2 and have no older fields
+impl Versioned for ReplaceModel {
+    fn protocol_version(&self) -> Option<u32> {
+        self.protocol_version
+    }
+}
+
+impl Versioned for SetPolicy {
+    fn protocol_version(&self) -> Option<u32> {
+        self.protocol_version
+    }
+}
+
+impl Versioned for GetPolicy {
+    fn protocol_version(&self) -> Option<u32> {
+        self.protocol_version
+    }
+}
+
+impl Versioned for GetAuditLog {
+    fn protocol_version(&self) -> Option<u32> {
+        self.protocol_version
+    }
+}
+
 impl Exchanger {
-    pub fn new(model_store: Arc<ModelStore>, max_model_size: usize, max_input_size: usize) -> Self {
+    pub fn new(
+        model_store: Arc<ModelStore>,
+        max_model_size: usize,
+        max_input_size: usize,
+        key_broker: Option<Arc<KeyBroker>>,
+        audit_log: Arc<AuditLog>,
+        metrics: Arc<Metrics>,
+        enclave_key: Arc<EnclaveKey>,
+        compute: Arc<ComputePool>,
+    ) -> Self {
         Self {
             model_store,
             max_model_size,
             max_input_size,
+            key_broker,
+            audit_log,
+            metrics,
+            enclave_key,
+            compute,
         }
     }
 
@@ -107,14 +340,7 @@ impl Exchanger {
         // Start the timer for the telemetry event
         let start_time = Instant::now();
 
-        let upload_model_body: UploadModel = {
-            let mut data: Vec<u8> = vec![];
-            request
-                .data()
-                .expect("Could not get input")
-                .read_to_end(&mut data)?;
-            serde_cbor::from_slice(&data)?
-        };
+        let upload_model_body = self.read_upload(request)?;
 
         let max_model_size = self.max_model_size;
         let mut model_size = 0usize;
@@ -137,12 +363,29 @@ impl Exchanger {
             return Err(Error::msg("Received no data".to_string()));
         }
 
+        if upload_model_body.ttl == Some(0) {
+            return Err(Error::msg("The time-to-live must be positive".to_string()));
+        }
+
+        let model_bytes =
+            self.plaintext_model(&upload_model_body.model, upload_model_body.encrypted)?;
+
         let (model_id, model_hash) = self.model_store.add_model(
-            &upload_model_body.model,
+            &model_bytes,
             model_name.clone(),
             upload_model_body.optimize,
+            upload_model_body.policy,
+            caller_identity(request),
+            upload_model_body.ttl.map(Duration::from_secs),
         )?;
 
+        self.audit_log.record(AuditEvent::Upload {
+            model_id: model_id.to_string(),
+            model_hash: hex::encode(model_hash),
+            model_name: model_name.clone(),
+            caller: caller_identity(request),
+        })?;
+
         // End the timer for the telemetry event
         let elapsed = start_time.elapsed();
 
@@ -164,144 +407,476 @@ impl Exchanger {
         })
     }
 
+    fn read_upload(&self, request: &rouille::Request) -> Result<UploadModel> {
+        if !is_multipart(request) {
+            return read_body(request, self.max_model_size);
+        }
+        if Compression::of_request(request)? != Compression::Identity {
+            return Err(Error::msg(
+                "Compressed multipart bodies are not supported".to_string(),
+            ));
+        }
+
+        let mut fields = read_multipart(request, self.max_model_size)?;
+        let model = fields
+            .remove("model")
+            .ok_or_else(|| Error::msg("Missing the model field".to_string()))?;
+        let metadata = fields.remove("metadata").unwrap_or_else(|| b"{}".to_vec());
+        let mut upload_model: UploadModel = parse_request(request, Encoding::Json, &metadata)?;
+        upload_model.length = model.len().try_into()?;
+        upload_model.model = model;
+        Ok(upload_model)
+    }
+
+    /// Decrypt the uploaded model if it is an encrypted model bundle
+    fn plaintext_model<'a>(&self, model: &'a [u8], encrypted: bool) -> Result<Cow<'a, [u8]>> {
+        if !encrypted {
+            return Ok(Cow::Borrowed(model));
+        }
+
+        let key_broker = self.key_broker.as_ref().ok_or_else(|| {
+            Error::msg("Encrypted models are not supported: no key broker configured")
+        })?;
+        let model = key_broker.decrypt_model(model)?;
+        if model.len() > self.max_model_size {
+            return Err(Error::msg("Model is too big".to_string()));
+        }
+        Ok(Cow::Owned(model))
+    }
+
+    /// Swap the model behind an existing id for a new version
+    pub fn replace_model(&self, request: &rouille::Request) -> Result<SendModelReply, Error> {
+        let replace_model_body: ReplaceModel = read_body(request, self.max_model_size)?;
+
+        let model_size: usize = replace_model_body.length.try_into()?;
+        if model_size > self.max_model_size {
+            return Err(Error::msg("Model is too big".to_string()));
+        }
+
+        if model_size == 0 {
+            return Err(Error::msg("Received no data".to_string()));
+        }
+
+        let model_id = Uuid::from_str(&replace_model_body.model_id)?;
+        let model_bytes =
+            self.plaintext_model(&replace_model_body.model, replace_model_body.encrypted)?;
+
+        let (model_hash, previous) =
+            self.model_store
+                .replace_model(model_id, &model_bytes, replace_model_body.optimize)?;
+
+        self.audit_log.record(AuditEvent::Replace {
+            model_id: model_id.to_string(),
+            previous_model_hash: hex::encode(previous.model_hash()),
+            model_hash: hex::encode(model_hash),
+            caller: caller_identity(request),
+        })?;
+
+        Ok(SendModelReply {
+            hash: model_hash.as_ref().to_vec(),
+            model_id: model_id.to_string(),
+        })
+    }
+
     pub fn run_model(&self, request: &rouille::Request) -> Result<RunModelReply, Error> {
-        let max_input_size = self.max_input_size;
+        let run_request = self.read_run_request(request)?;
+        self.run(Workload::Query, run_request)
+    }
 
-        let mut data_stream = request.data().expect("Could not get the input");
-        let mut data: Vec<u8> = vec![];
-        data_stream.read_to_end(&mut data)?;
+    /// Parse a `/run` query, to be run right away or later on
+    pub fn read_run_request(&self, request: &rouille::Request) -> Result<RunRequest> {
+        // The inputs make up the body, its size bounds theirs
+        let body: RunModel = read_body(request, self.max_input_size)?;
+
+        Ok(RunRequest {
+            body,
+            caller: caller_identity(request),
+            tensor_compression: Compression::from_header(request.header(TENSOR_ENCODING_HEADER))?,
+        })
+    }
 
-        let run_model_body: RunModel = serde_cbor::from_slice(&data)?;
+    pub fn run(&self, workload: Workload, run_request: RunRequest) -> Result<RunModelReply, Error> {
+        let RunRequest {
+            body:
+                RunModel {
+                    model_id,
+                    model_hash,
+                    inputs,
+                    sealed_inputs,
+                    recipient,
+                    client_info,
+                    ..
+                },
+            caller,
+            tensor_compression,
+        } = run_request;
 
         // Start the timer for the telemetry event
         let start_time = Instant::now();
 
-        if run_model_body.model_id.is_empty() && run_model_body.model_hash.is_empty() {
+        let uuid = self.resolve_model(&model_id, &model_hash)?;
+        // Unless bound to the sealed inputs, the recipient could be swapped
+        // by whoever relays the request
+        if recipient.is_some() && sealed_inputs.is_none() {
+            return Err(Error::msg(
+                "Outputs can only be sealed to a recipient bound to sealed inputs".to_string(),
+            ));
+        }
+        let inputs = match sealed_inputs {
+            Some(_) if !inputs.is_empty() => {
+                return Err(Error::msg(
+                    "You cannot provide inputs and sealed inputs in the same time".to_string(),
+                ))
+            }
+            Some(sealed_inputs) => serde_cbor::from_slice(&sealing::open(
+                &self.enclave_key,
+                INPUTS_INFO,
+                sealing::recipient_binding(recipient.as_ref()),
+                sealed_inputs,
+            )?)?,
+            None => inputs,
+        };
+        let inputs = self.decompress_tensors(tensor_compression, inputs)?;
+        let outputs: Vec<SerializedTensor> = self
+            .infer(workload, caller.as_deref(), uuid, inputs)?
+            .into_iter()
+            .map(|mut output| {
+                output.bytes_data = tensor_compression.compress(output.bytes_data)?;
+                Ok(output)
+            })
+            .collect::<Result<_>>()?;
+
+        // End the timer for the telemetry event
+        let elapsed = start_time.elapsed();
+
+        // Emit a telemetry event for `RunModel`
+        telemetry::add_event(
+            TelemetryEventProps::RunModel {
+                model_hash: Some(uuid.to_string()),
+                time_taken: elapsed.as_secs_f64(),
+            },
+            Some(client_info),
+            None,
+        );
+
+        match recipient {
+            Some(recipient) => Ok(RunModelReply {
+                outputs: vec![],
+                sealed_outputs: Some(sealing::seal(
+                    &recipient,
+                    OUTPUTS_INFO,
+                    &[],
+                    serde_cbor::to_vec(&outputs)?,
+                )?),
+            }),
+            None => Ok(RunModelReply {
+                outputs,
+                sealed_outputs: None,
+            }),
+        }
+    }
+
+    /// Decompress the data of the input tensors, all of them being bounded
+    /// by the maximum input size
+    fn decompress_tensors(
+        &self,
+        compression: Compression,
+        mut tensors: Vec<SerializedTensor>,
+    ) -> Result<Vec<SerializedTensor>> {
+        if compression == Compression::Identity {
+            return Ok(tensors);
+        }
+        let mut budget = self.max_input_size;
+        for tensor in &mut tensors {
+            tensor.bytes_data = compression.decompress(&tensor.bytes_data[..], budget)?;
+            budget -= tensor.bytes_data.len();
+        }
+        Ok(tensors)
+    }
+
+    pub fn max_input_size(&self) -> usize {
+        self.max_input_size
+    }
+
+    /// Read the body of an inference request, decompressing it if needed,
+    /// bounded by the maximum input size
+    pub fn read_input_body(&self, request: &rouille::Request) -> Result<Vec<u8>> {
+        read_raw_body(request, self.max_input_size)
+    }
+
+    /// Run the model `uuid` on `inputs` on behalf of `caller`, enforcing the
+    /// usage policy of the model
+    ///
+    /// Queries run on the compute pool, the calling thread waiting for them,
+    /// the other workloads on the calling thread.
+    pub fn infer(
+        &self,
+        workload: Workload,
+        caller: Option<&str>,
+        uuid: Uuid,
+        inputs: Vec<SerializedTensor>,
+    ) -> Result<Vec<SerializedTensor>> {
+        let start_time = Instant::now();
+
+        let model_store = Arc::clone(&self.model_store);
+        let caller = caller.map(str::to_string);
+        let res = self.compute.run_for(workload, move || {
+            model_store.use_model(uuid, |model| -> Result<_> {
+                model.admit(caller.as_deref())?;
+                // uncomment to run benches
+                // bench(3, 50, || {
+                //     model.run_inference(&mut inputs.to_vec()[..]);
+                // });
+                Ok(model
+                    .run_inference(&inputs)
+                    .map(|outputs| model.policy().filter_outputs(outputs)))
+            })
+        })?;
+
+        let result = match res {
+            Some(res) => res?,
+            None => {
+                error!("Error in model match");
+                return Err(ModelNotFound.into());
+            }
+        };
+
+        self.metrics
+            .observe_inference(&uuid.to_string(), start_time.elapsed());
+
+        match result {
+            Ok(outputs) => Ok(outputs),
+            Err(err) => {
+                error!("Error while running inference: {}", err);
+                Err(Error::msg("Unknown error".to_string()))
+            }
+        }
+    }
+
+    /// Find a model by id, or by name if only one model has this name
+    pub fn find_model(&self, name: &str) -> Result<Uuid> {
+        if let Ok(uuid) = Uuid::from_str(name) {
+            return Ok(uuid);
+        }
+        match self.model_store.get_uuids_from_name(name)[..] {
+            [uuid] => Ok(uuid),
+            [] => Err(ModelNotFound.into()),
+            _ => Err(Error::msg(format!(
+                "Several models are named {name:?}, use the model id instead"
+            ))),
+        }
+    }
+
+    pub fn with_model<U>(&self, uuid: Uuid, fun: impl Fn(&InferenceModel) -> U) -> Result<U> {
+        self.model_store
+            .use_model(uuid, fun)
+            .ok_or_else(|| ModelNotFound.into())
+    }
+
+    /// Find the model designated either by its id or by its hash
+    fn resolve_model(&self, model_id: &str, model_hash: &str) -> Result<Uuid> {
+        if model_id.is_empty() && model_hash.is_empty() {
             error!("Model_id and model_hash are empty");
             return Err(Error::msg(
                 "You must provide at least one model_id or model_hash".to_string(),
             ));
         }
 
-        if !run_model_body.model_id.is_empty() && !run_model_body.model_hash.is_empty() {
+        if !model_id.is_empty() && !model_hash.is_empty() {
             error!("Model_id and model_hash are NOT empty, cannot pick one over the other");
             return Err(Error::msg(
                 "You cannot provide a model_id and a model_hash in the same time".to_string(),
             ));
         }
 
-        if run_model_body.inputs.len() * size_of::<u8>() > max_input_size
-            || run_model_body.inputs.len() * size_of::<u8>() > max_input_size
-        {
-            return Err(Error::msg("Input too big".to_string()));
-        }
-
-        let uuid = if !run_model_body.model_hash.is_empty() {
-            match self
-                .model_store
-                .get_uuid_from_hash(&run_model_body.model_hash)
-            {
-                Some(uuid) => uuid,
+        if !model_hash.is_empty() {
+            match self.model_store.get_uuid_from_hash(model_hash)? {
+                Some(uuid) => Ok(uuid),
                 None => {
                     error!("Hash not found");
-                    return Err(Error::msg("Model doesn't exist".to_string()));
+                    Err(Error::msg("Model doesn't exist".to_string()))
                 }
             }
         } else {
-            match Uuid::from_str(&run_model_body.model_id) {
-                Ok(uuid) => uuid,
+            match Uuid::from_str(model_id) {
+                Ok(uuid) => Ok(uuid),
                 Err(_) => {
                     error!("Error in uuid");
-                    return Err(Error::msg("Model doesn't exist".to_string()));
+                    Err(Error::msg("Model doesn't exist".to_string()))
                 }
             }
-        };
+        }
+    }
 
-        let res = self.model_store.use_model(uuid, |model| {
-            // uncomment to run benches
-            // bench(3, 50, || {
-            //     model.run_inference(&mut run_model_body.inputs.clone()[..]);
-            // });
-            (
-                model.run_inference(run_model_body.inputs.as_slice()),
-                model.model_name().map(|s| s.to_string()),
-            )
-        });
-
-        let res = match res {
-            Some(res) => res,
+    pub fn set_policy(&self, request: &rouille::Request) -> Result<()> {
+        let set_policy_body: SetPolicy = read_body(request, self.max_input_size)?;
+        let model_id = Uuid::from_str(&set_policy_body.model_id)?;
+        let caller = caller_identity(request);
+
+        let model_hash = match self.model_store.set_policy(
+            model_id,
+            set_policy_body.policy,
+            caller.as_deref(),
+        )? {
+            Some(model_hash) => model_hash,
             None => {
-                error!("Error in model match");
+                error!("Model doesn't exist");
                 return Err(Error::msg("Model doesn't exist".to_string()));
             }
         };
 
-        let (result, _model_name) = res;
-
-        let outputs = match result {
-            Ok(res) => res,
-            Err(err) => {
-                error!("Error while running inference: {}", err);
-                return Err(Error::msg("Unknown error".to_string()));
-            }
-        };
+        self.audit_log.record(AuditEvent::PolicyChange {
+            model_id: model_id.to_string(),
+            model_hash: hex::encode(model_hash),
+            caller,
+        })
+    }
 
-        // End the timer for the telemetry event
-        let elapsed = start_time.elapsed();
+    /// Let data owners know the terms under which they query a model
+    pub fn get_policy(&self, request: &rouille::Request) -> Result<ModelPolicy> {
+        let get_policy_body: GetPolicy = read_body(request, self.max_input_size)?;
+        let uuid = self.resolve_model(&get_policy_body.model_id, &get_policy_body.model_hash)?;
 
-        // Emit a telemetry event for `RunModel`
-        telemetry::add_event(
-            TelemetryEventProps::RunModel {
-                model_hash: Some(uuid.to_string()),
-                time_taken: elapsed.as_secs_f64(),
-            },
-            Some(run_model_body.client_info),
-            None,
-        );
-
-        Ok(RunModelReply { outputs })
+        self.model_store
+            .use_model(uuid, |model| model.policy())
+            .ok_or_else(|| Error::msg("Model doesn't exist".to_string()))
     }
 
-    pub fn delete_model(&self, request: &rouille::Request) -> Result<()> {
-        let mut data_stream = request.data().expect("Could not get the input");
-        let mut data: Vec<u8> = vec![];
-        data_stream.read_to_end(&mut data)?;
+    pub fn delete_model(&self, request: &rouille::Request) -> Result<DeletionReceipt> {
+        let delete_model_body: DeleteModel = read_body(request, self.max_input_size)?;
+        let caller = caller_identity(request);
+
+        let criteria = [
+            !delete_model_body.model_id.is_empty(),
+            delete_model_body.model_hash.is_some(),
+            delete_model_body.model_name.is_some(),
+            delete_model_body.tenant.is_some(),
+        ];
+        match criteria.iter().filter(|c| **c).count() {
+            0 => {
+                return Err(Error::msg(
+                    "You must provide a model_id, model_hash, model_name or tenant".to_string(),
+                ))
+            }
+            1 => (),
+            _ => {
+                return Err(Error::msg(
+                    "You must provide only one of model_id, model_hash, model_name or tenant"
+                        .to_string(),
+                ))
+            }
+        }
 
-        let delete_model_body: DeleteModel = serde_cbor::from_slice(&data)?;
+        let deleted = if let Some(model_hash) = delete_model_body.model_hash {
+            let model_hash = hex::decode(model_hash)?;
+            self.model_store
+                .delete_models(|model| model.model_hash().as_ref() == model_hash.as_slice())
+        } else if let Some(model_name) = delete_model_body.model_name {
+            self.model_store
+                .delete_models(|model| model.model_name() == Some(model_name.as_str()))
+        } else if let Some(tenant) = delete_model_body.tenant {
+            // Tenants only delete their own models
+            check_owner(Some(&tenant), caller.as_deref())?;
+            self.model_store
+                .delete_models(|model| model.owner() == Some(tenant.as_str()))
+        } else {
+            let model_id = Uuid::from_str(&delete_model_body.model_id)?;
+            // Delete the model
+            match self.model_store.delete_model(model_id) {
+                Some(model) => vec![model],
+                None => {
+                    error!("Model doesn't exist");
+                    return Err(Error::msg("Model doesn't exist".to_string()));
+                }
+            }
+        };
 
-        if delete_model_body.model_id.is_empty() {
-            return Err(Error::msg("Model doesn't exist".to_string()));
+        for model in &deleted {
+            self.audit_log.record(AuditEvent::Delete {
+                model_id: model.model_id().to_string(),
+                model_hash: hex::encode(model.model_hash()),
+                caller: caller.clone(),
+            })?;
         }
 
-        let model_id = Uuid::from_str(&delete_model_body.model_id)?;
+        Ok(DeletionReceipt {
+            model_ids: deleted
+                .iter()
+                .map(|model| model.model_id().to_string())
+                .collect(),
+        })
+    }
 
-        // Delete the model
-        if self.model_store.delete_model(model_id).is_none() {
-            error!("Model doesn't exist");
-            return Err(Error::msg("Model doesn't exist".to_string()));
+    /// Remove the models whose time-to-live has elapsed
+    pub fn delete_expired_models(&self) {
+        for model in self.model_store.delete_expired_models() {
+            info!("Model {} has expired", model.model_id());
+            let event = AuditEvent::Expire {
+                model_id: model.model_id().to_string(),
+                model_hash: hex::encode(model.model_hash()),
+            };
+            if let Err(e) = self.audit_log.record(event) {
+                error!("Could not record the expiry of a model: {}", e);
+            }
         }
-        Ok(())
     }
 
+    pub fn get_audit_log(&self, request: &rouille::Request) -> Result<AuditLogReply> {
+        let get_audit_log_body: GetAuditLog = read_body(request, self.max_input_size)?;
+
+        Ok(AuditLogReply {
+            entries: self.audit_log.entries(get_audit_log_body.from),
+            head: self.audit_log.signed_head()?,
+        })
+    }
+
+    pub fn get_audit_log_head(&self) -> Result<SignedHead> {
+        self.audit_log.signed_head()
+    }
+
+    /// Encode the reply in the format asked for in the `Accept` header, and
+    /// compress it if the client accepts gzip
+    ///
+    /// Requests in an unsupported protocol version are rejected with a 400.
     pub fn respond<Reply: serde::Serialize>(
         &self,
-        _rq: &rouille::Request,
+        rq: &rouille::Request,
         reply: Result<Reply>,
     ) -> rouille::Response {
-        match reply {
-            Ok(reply) => rouille::Response::from_data(
-                "application/cbor",
-                serde_cbor::to_vec(&reply).unwrap(),
-            ),
-            Err(e) => rouille::Response::from_data(
-                "application/cbor",
-                serde_cbor::to_vec(&format!("{:?}", &e)).unwrap(),
-            )
-            .with_status_code(500),
+        let encoding = Encoding::accepted(rq);
+        let compression = Compression::accepted(rq);
+        let (data, status_code) = match reply {
+            Ok(reply) => (encoding.serialize(&reply).unwrap(), 200),
+            Err(e) if e.is::<UnsupportedVersion>() => {
+                (encoding.serialize(&e.to_string()).unwrap(), 400)
+            }
+            Err(e) => (encoding.serialize(&format!("{:?}", &e)).unwrap(), 500),
+        };
+        let response = rouille::Response::from_data(
+            encoding.content_type(),
+            compression.compress(data).unwrap(),
+        )
+        .with_status_code(status_code)
+        .with_additional_header(PROTOCOL_VERSION_HEADER, PROTOCOL_VERSION.to_string());
+        match compression {
+            Compression::Identity => response,
+            Compression::Gzip => {
+                response.with_additional_header("Content-Encoding", compression.header_value())
+            }
         }
     }
 }
 
+/// Identity of the caller, the hex encoded SHA-256 of the bearer token sent in
+/// the `Authorization` header
+pub(crate) fn caller_identity(request: &rouille::Request) -> Option<String> {
+    let token = request.header("Authorization")?.strip_prefix("Bearer ")?;
+    Some(hex::encode(digest::digest(
+        &digest::SHA256,
+        token.as_bytes(),
+    )))
+}
+
 #[allow(dead_code)]
 pub fn bench(repeats: usize, samples: usize, f: impl Fn()) -> Result<()> {
     let mut results = vec![];
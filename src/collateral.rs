@@ -0,0 +1,299 @@
+// Copyright 2022 Mithril Security. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Quote verification collateral, kept fresh while the server runs.
+//!
+//! The CRLs, the TCB info and the QE identity all carry a `nextUpdate` date
+//! after which verifiers reject them. The collateral is fetched from the
+//! runner at startup, then again every `BLINDAI_COLLATERAL_REFRESH` seconds
+//! and `BLINDAI_COLLATERAL_REFRESH_MARGIN` seconds before its earliest
+//! `nextUpdate`. A failed refresh keeps the current collateral and is retried
+//! a minute later. `/collateral` always serves the latest snapshot, and
+//! `/collateral/status` reports its age and expiry.
+
+use crate::SgxCollateral;
+use anyhow::Result;
+use log::{error, info};
+use serde_derive::Serialize;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Delay before retrying a failed refresh
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+
+type Fetch = dyn Fn() -> Result<SgxCollateral> + Send + Sync;
+
+/// Collateral as fetched at a given time
+pub(crate) struct Snapshot {
+    pub collateral: SgxCollateral,
+    pub fetched_at: SystemTime,
+    /// Earliest `nextUpdate` of the collateral, if any could be read
+    pub expires_at: Option<SystemTime>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct CollateralStatus {
+    /// Unix timestamps, in seconds
+    fetched_at: u64,
+    expires_at: Option<u64>,
+    age_secs: u64,
+    /// Negative once the collateral has expired
+    expires_in_secs: Option<i64>,
+    expired: bool,
+    last_refresh_error: Option<String>,
+}
+
+pub(crate) struct CollateralStore {
+    fetch: Box<Fetch>,
+    current: RwLock<Arc<Snapshot>>,
+    last_error: Mutex<Option<String>>,
+    refresh_interval: Duration,
+    refresh_margin: Duration,
+}
+
+impl CollateralStore {
+    /// Fetch the collateral a first time, failing if it can't be
+    pub fn new(
+        fetch: Box<Fetch>,
+        refresh_interval: Duration,
+        refresh_margin: Duration,
+    ) -> Result<Self> {
+        let snapshot = Self::snapshot(fetch()?);
+        Ok(CollateralStore {
+            fetch,
+            current: RwLock::new(Arc::new(snapshot)),
+            last_error: Mutex::new(None),
+            refresh_interval,
+            refresh_margin,
+        })
+    }
+
+    /// Refresh every `BLINDAI_COLLATERAL_REFRESH` seconds (1 hour by default)
+    /// and `BLINDAI_COLLATERAL_REFRESH_MARGIN` seconds before expiry (1 day by
+    /// default)
+    pub fn from_env(fetch: Box<Fetch>) -> Result<Self> {
+        fn var(name: &str, default: u64) -> Result<Duration> {
+            match std::env::var(name) {
+                Ok(value) => Ok(Duration::from_secs(value.parse()?)),
+                Err(_) => Ok(Duration::from_secs(default)),
+            }
+        }
+        Self::new(
+            fetch,
+            var("BLINDAI_COLLATERAL_REFRESH", 3600)?,
+            var("BLINDAI_COLLATERAL_REFRESH_MARGIN", 86400)?,
+        )
+    }
+
+    fn snapshot(collateral: SgxCollateral) -> Snapshot {
+        let expires_at = match expiry(&collateral) {
+            Ok(expires_at) => expires_at,
+            Err(e) => {
+                error!("Could not read the expiry of the collateral: {:?}", e);
+                None
+            }
+        };
+        Snapshot {
+            collateral,
+            fetched_at: SystemTime::now(),
+            expires_at,
+        }
+    }
+
+    /// Collateral currently served
+    pub fn current(&self) -> Arc<Snapshot> {
+        Arc::clone(&self.current.read().unwrap())
+    }
+
+    /// Fetch the collateral again and serve it from now on
+    pub fn refresh(&self) -> Result<()> {
+        let result = (self.fetch)();
+        *self.last_error.lock().unwrap() = result.as_ref().err().map(|e| format!("{:?}", e));
+        let snapshot = Self::snapshot(result?);
+        *self.current.write().unwrap() = Arc::new(snapshot);
+        Ok(())
+    }
+
+    /// When the current collateral should be refreshed
+    fn next_refresh(&self) -> SystemTime {
+        if self.last_error.lock().unwrap().is_some() {
+            return SystemTime::now() + RETRY_DELAY;
+        }
+        let current = self.current();
+        let mut next = current.fetched_at + self.refresh_interval;
+        if let Some(expires_at) = current.expires_at {
+            let before_expiry = expires_at
+                .checked_sub(self.refresh_margin)
+                .unwrap_or(UNIX_EPOCH);
+            next = next.min(before_expiry);
+        }
+        // Collateral expiring within the margin would be refreshed in a loop
+        next.max(current.fetched_at + RETRY_DELAY)
+    }
+
+    /// Refresh the collateral in the background for as long as the server runs
+    pub fn spawn_refresher(self: &Arc<Self>) {
+        let store = Arc::clone(self);
+        thread::spawn(move || loop {
+            let delay = store
+                .next_refresh()
+                .duration_since(SystemTime::now())
+                .unwrap_or_default();
+            thread::sleep(delay);
+            match store.refresh() {
+                Ok(()) => info!("Attestation : Collateral refreshed"),
+                Err(e) => error!("Attestation : Could not refresh the collateral: {:?}", e),
+            }
+        });
+    }
+
+    pub fn status(&self) -> CollateralStatus {
+        let current = self.current();
+        let now = SystemTime::now();
+        let expires_in_secs =
+            current
+                .expires_at
+                .map(|expires_at| match expires_at.duration_since(now) {
+                    Ok(remaining) => remaining.as_secs() as i64,
+                    Err(e) => -(e.duration().as_secs() as i64),
+                });
+        CollateralStatus {
+            fetched_at: unix_secs(current.fetched_at),
+            expires_at: current.expires_at.map(unix_secs),
+            age_secs: now
+                .duration_since(current.fetched_at)
+                .unwrap_or_default()
+                .as_secs(),
+            expires_in_secs,
+            expired: expires_in_secs.is_some_and(|secs| secs <= 0),
+            last_refresh_error: self.last_error.lock().unwrap().clone(),
+        }
+    }
+
+    pub fn respond_status(&self) -> rouille::Response {
+        rouille::Response::json(&self.status())
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Earliest `nextUpdate` of the CRLs, the TCB info and the QE identity
+pub(crate) fn expiry(collateral: &SgxCollateral) -> Result<Option<SystemTime>> {
+    Ok(collateral
+        .next_update()?
+        .map(|secs| UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::bail;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const COLLATERAL: &str =
+        include_str!("../runner/quote_verification/tests/fixtures/collateral.json");
+
+    /// Collateral without CRLs, expiring when its TCB info does
+    fn collateral(tcb_next_update: &str) -> SgxCollateral {
+        SgxCollateral {
+            version: 3,
+            pck_crl_issuer_chain: String::new(),
+            root_ca_crl: String::new(),
+            pck_crl: String::new(),
+            tcb_info_issuer_chain: String::new(),
+            tcb_info: format!(
+                r#"{{"tcbInfo":{{"version":3,"nextUpdate":"{}"}},"signature":""}}"#,
+                tcb_next_update
+            ),
+            qe_identity_issuer_chain: String::new(),
+            qe_identity: r#"{"enclaveIdentity":{"nextUpdate":"2030-01-01T00:00:00Z"}}"#.to_string(),
+            pck_certificate: String::new(),
+            pck_signing_chain: String::new(),
+        }
+    }
+
+    #[test]
+    fn expiry_is_the_earliest_next_update() {
+        let time = |secs| Some(UNIX_EPOCH + Duration::from_secs(secs));
+
+        // 2024-01-31T00:00:00Z, the next update of the CRLs and of the JSON
+        let fixture: SgxCollateral = serde_json::from_str(COLLATERAL).unwrap();
+        assert_eq!(expiry(&fixture).unwrap(), time(1706659200));
+
+        // 2023-06-14T10:11:26Z
+        let expires_at = expiry(&collateral("2023-06-14T10:11:26Z")).unwrap();
+        assert_eq!(expires_at, time(1686737486));
+        let expires_at = expiry(&collateral("2024-02-29T00:00:00.5Z")).unwrap();
+        assert_eq!(expires_at, time(1709164800));
+
+        assert!(expiry(&collateral("2023-06-14 10:11:26")).is_err());
+        assert!(expiry(&collateral("2023-02-31T00:00:00Z")).is_err());
+        let mut truncated = fixture;
+        truncated.pck_crl.truncate(200);
+        assert!(expiry(&truncated).is_err());
+    }
+
+    #[test]
+    fn refresh_swaps_the_served_collateral() {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let fetch = {
+            let fetches = Arc::clone(&fetches);
+            move || match fetches.fetch_add(1, Ordering::SeqCst) {
+                1 => bail!("The runner is unreachable"),
+                n => {
+                    let mut collateral = collateral("2030-01-01T00:00:00Z");
+                    collateral.version = n as u32;
+                    Ok(collateral)
+                }
+            }
+        };
+        let hour = Duration::from_secs(3600);
+        let store = CollateralStore::new(Box::new(fetch), hour, hour).unwrap();
+        let first = store.current();
+        assert_eq!(first.collateral.version, 0);
+        assert!(store.next_refresh() <= first.fetched_at + hour);
+
+        // A failed refresh keeps the collateral and is retried soon
+        assert!(store.refresh().is_err());
+        assert_eq!(store.current().collateral.version, 0);
+        assert!(store.status().last_refresh_error.is_some());
+        assert!(store.next_refresh() <= SystemTime::now() + RETRY_DELAY);
+
+        store.refresh().unwrap();
+        assert_eq!(store.current().collateral.version, 2);
+        let status = store.status();
+        assert!(status.last_refresh_error.is_none());
+        assert!(!status.expired);
+        assert_eq!(status.expires_at, Some(1893456000));
+        // The snapshot handed out before the refresh is left untouched
+        assert_eq!(first.collateral.version, 0);
+    }
+
+    #[test]
+    fn expired_collateral_is_reported() {
+        let fetch = || Ok(collateral("2023-06-14T10:11:26Z"));
+        let hour = Duration::from_secs(3600);
+        let store = CollateralStore::new(Box::new(fetch), hour, hour).unwrap();
+        let status = store.status();
+        assert!(status.expired);
+        assert!(status.expires_in_secs.unwrap() < 0);
+        // Refreshes are still spaced out
+        assert!(store.next_refresh() >= store.current().fetched_at + RETRY_DELAY);
+    }
+}
@@ -0,0 +1,236 @@
+// Copyright 2022 Mithril Security. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Dedicated pool of threads running the inferences.
+//!
+//! The HTTP servers hand every synchronous inference to this pool and wait
+//! for its result, so that the number of connection threads and the number of
+//! concurrent inferences are sized independently. Connections stuck on a slow
+//! client no longer take inference capacity away, and synchronous inferences
+//! never run on more threads than `BLINDAI_COMPUTE_THREADS`.
+//!
+//! Jobs and streams don't take slots of this pool: they run on the job worker
+//! or the stream thread asking for them, and are bounded by
+//! `BLINDAI_JOB_WORKERS` and `BLINDAI_MAX_STREAMS` instead, so that neither
+//! of them can starve the synchronous queries.
+//!
+//! The HTTP layer itself is still thread based. The Fortanix target has no
+//! readiness based I/O (no epoll nor mio support for EDP, and non-blocking
+//! sockets are unsupported) and a fixed number of threads set by `threads` in
+//! the enclave manifest, which the HTTP, compute, job and stream threads must
+//! all fit in, as checked at startup. The connections are bounded outside of the enclave instead:
+//! the runner binds the listening sockets of the enclave with its event
+//! driven I/O, closes the connections over `BLINDAI_MAX_CONNECTIONS` per port
+//! as soon as they are accepted, and times out the ones idle for
+//! `BLINDAI_IDLE_TIMEOUT` seconds, so that idle or slow clients hold a
+//! connection thread for a bounded time and never more of them than that.
+
+use anyhow::{anyhow, bail, Result};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+type Task = Box<dyn FnOnce() + Send>;
+
+fn var(name: &str, default: usize) -> Result<usize> {
+    match std::env::var(name) {
+        Ok(value) => Ok(value.parse()?),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Origin of an inference, deciding what bounds its concurrency
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Workload {
+    /// Synchronous queries, run on the compute pool
+    Query,
+    /// Jobs and streams, run on the thread of the job worker or the stream
+    Background,
+}
+
+pub(crate) struct ComputePool {
+    sender: SyncSender<Task>,
+    /// Tasks waiting for a worker
+    queued: Arc<AtomicUsize>,
+}
+
+impl ComputePool {
+    /// Start `threads` workers, at most `max_queued` tasks waiting for one
+    pub fn new(threads: usize, max_queued: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Task>(max_queued);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let queued = Arc::new(AtomicUsize::new(0));
+        for _ in 0..threads {
+            let receiver = Arc::clone(&receiver);
+            let queued = Arc::clone(&queued);
+            thread::spawn(move || Self::work(&receiver, &queued));
+        }
+        ComputePool { sender, queued }
+    }
+
+    /// Number of tasks waiting for a worker
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    /// Configure the pool with `BLINDAI_COMPUTE_THREADS` (4 by default) and
+    /// `BLINDAI_MAX_QUEUED_INFERENCES` (64 by default)
+    pub fn from_env() -> Result<Self> {
+        Ok(Self::new(
+            Self::threads_from_env()?,
+            var("BLINDAI_MAX_QUEUED_INFERENCES", 64)?,
+        ))
+    }
+
+    /// Number of workers the pool is configured with
+    pub fn threads_from_env() -> Result<usize> {
+        var("BLINDAI_COMPUTE_THREADS", 4)
+    }
+
+    fn work(receiver: &Mutex<Receiver<Task>>, queued: &AtomicUsize) {
+        loop {
+            // The lock is released as soon as a task is received
+            let received = receiver.lock().unwrap().recv();
+            match received {
+                // A panicking task must not take its worker down with it
+                Ok(task) => {
+                    queued.fetch_sub(1, Ordering::SeqCst);
+                    let _ = panic::catch_unwind(AssertUnwindSafe(task));
+                }
+                Err(_) => return,
+            }
+        }
+    }
+
+    /// Queue `task`, returning where its result will be sent
+    fn spawn<R: Send + 'static>(
+        &self,
+        task: impl FnOnce() -> R + Send + 'static,
+    ) -> Result<Receiver<R>> {
+        let (result_sender, result_receiver) = mpsc::sync_channel(1);
+        let task: Task = Box::new(move || {
+            let _ = result_sender.send(task());
+        });
+        // Counted before sending, a worker may pick the task up right away
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        match self.sender.try_send(task) {
+            Ok(()) => Ok(result_receiver),
+            Err(e) => {
+                self.queued.fetch_sub(1, Ordering::SeqCst);
+                match e {
+                    TrySendError::Full(_) => bail!("Too many queued inferences, retry later"),
+                    TrySendError::Disconnected(_) => bail!("The compute pool is not running"),
+                }
+            }
+        }
+    }
+
+    /// Run `task` on the pool and wait for its result
+    pub fn run<R: Send + 'static>(&self, task: impl FnOnce() -> R + Send + 'static) -> Result<R> {
+        // The result sender is dropped without a result if the task panicked
+        self.spawn(task)?
+            .recv()
+            .map_err(|_| anyhow!("The inference was aborted"))
+    }
+
+    /// Run `task` of `workload`, on the pool for queries and on the calling
+    /// thread otherwise
+    pub fn run_for<R: Send + 'static>(
+        &self,
+        workload: Workload,
+        task: impl FnOnce() -> R + Send + 'static,
+    ) -> Result<R> {
+        match workload {
+            Workload::Query => self.run(task),
+            // A panicking task must not take the job worker or the stream down
+            Workload::Background => panic::catch_unwind(AssertUnwindSafe(task))
+                .map_err(|_| anyhow!("The inference was aborted")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tasks_run_on_the_pool() {
+        let pool = ComputePool::new(1, 4);
+        let caller = thread::current().id();
+        let (result, worker) = pool.run(|| (21 * 2, thread::current().id())).unwrap();
+        assert_eq!(result, 42);
+        assert_ne!(worker, caller);
+        assert!(pool.run(|| panic!("inference panicked")).is_err());
+        assert_eq!(pool.run(|| 1).unwrap(), 1);
+    }
+
+    #[test]
+    fn background_tasks_run_on_their_thread() {
+        // The only worker is busy and its queue is full, background tasks
+        // don't wait for it
+        let pool = ComputePool::new(1, 1);
+        let (started, wait_started) = mpsc::channel();
+        let (unblock, blocked) = mpsc::channel::<()>();
+        let running = pool
+            .spawn(move || {
+                started.send(()).unwrap();
+                blocked.recv().unwrap();
+            })
+            .unwrap();
+        wait_started.recv().unwrap();
+        let queued = pool.spawn(|| ()).unwrap();
+
+        let caller = thread::current().id();
+        let worker = pool
+            .run_for(Workload::Background, || thread::current().id())
+            .unwrap();
+        assert_eq!(worker, caller);
+        assert!(pool
+            .run_for(Workload::Background, || panic!("inference panicked"))
+            .is_err());
+        assert!(pool.run_for(Workload::Query, || 1).is_err());
+
+        unblock.send(()).unwrap();
+        running.recv().unwrap();
+        queued.recv().unwrap();
+    }
+
+    #[test]
+    fn queued_tasks_are_bounded() {
+        let pool = ComputePool::new(1, 1);
+        let (started, wait_started) = mpsc::channel();
+        let (unblock, blocked) = mpsc::channel::<()>();
+        let running = pool
+            .spawn(move || {
+                started.send(()).unwrap();
+                blocked.recv().unwrap();
+            })
+            .unwrap();
+        wait_started.recv().unwrap();
+
+        assert_eq!(pool.queued(), 0);
+
+        // The worker is busy, a single task may wait for it
+        let queued = pool.spawn(|| 1).unwrap();
+        assert!(pool.spawn(|| 2).is_err());
+        assert_eq!(pool.queued(), 1);
+
+        unblock.send(()).unwrap();
+        running.recv().unwrap();
+        assert_eq!(queued.recv().unwrap(), 1);
+        assert_eq!(pool.queued(), 0);
+    }
+}
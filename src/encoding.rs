@@ -0,0 +1,348 @@
+// Copyright 2022 Mithril Security. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Encoding of the request and response bodies.
+//!
+//! CBOR remains the default. Clients may send JSON bodies instead by setting
+//! `Content-Type: application/json`, and ask for JSON replies with
+//! `Accept: application/json`. In JSON, binary fields (tensor data, models,
+//! hashes) are base64 encoded strings.
+//!
+//! Request bodies may be gzip compressed (`Content-Encoding: gzip`) and
+//! replies are compressed when the client sends `Accept-Encoding: gzip`.
+//! Decompression is bounded, so that a small compressed payload can't exhaust
+//! the enclave memory.
+
+use crate::protocol::{parse_request, Versioned};
+use anyhow::{bail, Result};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Encoding {
+    Cbor,
+    Json,
+}
+
+impl Encoding {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Encoding::Cbor => "application/cbor",
+            Encoding::Json => "application/json",
+        }
+    }
+
+    fn from_mime(mime: &str) -> Option<Self> {
+        match mime.split(';').next()?.trim() {
+            "application/cbor" | "*/*" | "application/*" => Some(Encoding::Cbor),
+            "application/json" => Some(Encoding::Json),
+            _ => None,
+        }
+    }
+
+    /// Encoding of the request body, CBOR when no `Content-Type` is given
+    pub fn of_request(request: &rouille::Request) -> Result<Self> {
+        match request.header("Content-Type") {
+            None => Ok(Encoding::Cbor),
+            Some(content_type) => match Self::from_mime(content_type) {
+                Some(encoding) => Ok(encoding),
+                None => bail!("Unsupported content type {:?}", content_type),
+            },
+        }
+    }
+
+    /// Encoding requested for the response through the `Accept` header
+    ///
+    /// The first supported media type wins, quality values are ignored.
+    pub fn accepted(request: &rouille::Request) -> Self {
+        request
+            .header("Accept")
+            .and_then(|accept| accept.split(',').find_map(Self::from_mime))
+            .unwrap_or(Encoding::Cbor)
+    }
+
+    pub fn serialize(self, value: &impl Serialize) -> Result<Vec<u8>> {
+        Ok(match self {
+            Encoding::Cbor => serde_cbor::to_vec(value)?,
+            Encoding::Json => serde_json::to_vec(value)?,
+        })
+    }
+
+    pub fn deserialize<T: DeserializeOwned>(self, data: &[u8]) -> Result<T> {
+        Ok(match self {
+            Encoding::Cbor => serde_cbor::from_slice(data)?,
+            Encoding::Json => serde_json::from_slice(data)?,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Compression {
+    Identity,
+    Gzip,
+}
+
+impl Compression {
+    /// Parse the value of a `Content-Encoding` like header
+    pub fn from_header(value: Option<&str>) -> Result<Self> {
+        match value.map(str::trim) {
+            None | Some("") | Some("identity") => Ok(Compression::Identity),
+            Some("gzip") | Some("x-gzip") => Ok(Compression::Gzip),
+            Some(value) => bail!("Unsupported encoding {:?}", value),
+        }
+    }
+
+    pub fn of_request(request: &rouille::Request) -> Result<Self> {
+        Self::from_header(request.header("Content-Encoding"))
+    }
+
+    /// Compression requested for the response through `Accept-Encoding`
+    pub fn accepted(request: &rouille::Request) -> Self {
+        let accepts_gzip = request.header("Accept-Encoding").is_some_and(|accept| {
+            accept.split(',').any(|coding| {
+                let mut params = coding.split(';').map(str::trim);
+                params.next() == Some("gzip") && !params.any(|p| p == "q=0" || p == "q=0.0")
+            })
+        });
+        if accepts_gzip {
+            Compression::Gzip
+        } else {
+            Compression::Identity
+        }
+    }
+
+    pub fn header_value(self) -> &'static str {
+        match self {
+            Compression::Identity => "identity",
+            Compression::Gzip => "gzip",
+        }
+    }
+
+    /// Read `input` to the end, failing if more than `max_size` bytes come out
+    /// of the decompression
+    pub fn decompress(self, input: impl Read, max_size: usize) -> Result<Vec<u8>> {
+        let mut data = vec![];
+        let input: Box<dyn Read> = match self {
+            Compression::Identity => Box::new(input),
+            Compression::Gzip => Box::new(GzDecoder::new(input)),
+        };
+        input.take(max_size as u64 + 1).read_to_end(&mut data)?;
+        if data.len() > max_size {
+            bail!("The payload is too big");
+        }
+        Ok(data)
+    }
+
+    pub fn compress(self, data: Vec<u8>) -> Result<Vec<u8>> {
+        match self {
+            Compression::Identity => Ok(data),
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(vec![], flate2::Compression::fast());
+                encoder.write_all(&data)?;
+                Ok(encoder.finish()?)
+            }
+        }
+    }
+}
+
+/// Read the body of a request, decompressing it according to its
+/// `Content-Encoding` up to `max_size` bytes
+pub(crate) fn read_raw_body(request: &rouille::Request, max_size: usize) -> Result<Vec<u8>> {
+    let compression = Compression::of_request(request)?;
+    compression.decompress(request.data().expect("Could not get input"), max_size)
+}
+
+/// Read and decode the body of a request according to its `Content-Type`,
+/// checking its protocol version
+pub(crate) fn read_body<T: DeserializeOwned + Versioned>(
+    request: &rouille::Request,
+    max_size: usize,
+) -> Result<T> {
+    let encoding = Encoding::of_request(request)?;
+    let body = read_raw_body(request, max_size)?;
+    parse_request(request, encoding, &body)
+}
+
+pub(crate) fn is_multipart(request: &rouille::Request) -> bool {
+    request
+        .header("Content-Type")
+        .is_some_and(|content_type| content_type.starts_with("multipart/form-data"))
+}
+
+/// Read the fields of a `multipart/form-data` body, each one being limited to
+/// `max_field_size` bytes
+pub(crate) fn read_multipart(
+    request: &rouille::Request,
+    max_field_size: usize,
+) -> Result<HashMap<String, Vec<u8>>> {
+    let mut multipart = rouille::input::multipart::get_multipart_input(request)?;
+    let mut fields = HashMap::new();
+    while let Some(mut field) = multipart.read_entry()? {
+        let mut data = vec![];
+        (&mut field.data)
+            .take(max_field_size as u64 + 1)
+            .read_to_end(&mut data)?;
+        if data.len() > max_field_size {
+            bail!("The multipart field {:?} is too big", &*field.headers.name);
+        }
+        fields.insert(field.headers.name.to_string(), data);
+    }
+    Ok(fields)
+}
+
+/// Serde helper for binary fields: raw bytes in CBOR, base64 strings in JSON
+pub(crate) mod binary {
+    use base64::prelude::{Engine, BASE64_STANDARD};
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&BASE64_STANDARD.encode(bytes))
+        } else {
+            serde_bytes::serialize(bytes, serializer)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        if deserializer.is_human_readable() {
+            let encoded = String::deserialize(deserializer)?;
+            BASE64_STANDARD.decode(encoded).map_err(D::Error::custom)
+        } else {
+            serde_bytes::deserialize(deserializer)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_derive::{Deserialize, Serialize};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Tensor {
+        #[serde(with = "binary")]
+        bytes_data: Vec<u8>,
+    }
+
+    impl Versioned for Tensor {
+        fn protocol_version(&self) -> Option<u32> {
+            None
+        }
+    }
+
+    fn request(headers: &[(&str, &str)], body: Vec<u8>) -> rouille::Request {
+        let headers = headers
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        rouille::Request::fake_http("POST", "/run", headers, body)
+    }
+
+    #[test]
+    fn binary_fields() {
+        let tensor = Tensor {
+            bytes_data: vec![0, 1, 2, 255],
+        };
+
+        let json = Encoding::Json.serialize(&tensor).unwrap();
+        assert_eq!(json, br#"{"bytes_data":"AAEC/w=="}"#);
+        assert_eq!(Encoding::Json.deserialize::<Tensor>(&json).unwrap(), tensor);
+
+        let cbor = Encoding::Cbor.serialize(&tensor).unwrap();
+        assert_eq!(
+            serde_cbor::from_slice::<serde_cbor::Value>(&cbor).unwrap(),
+            serde_cbor::Value::Map(
+                [(
+                    serde_cbor::Value::Text("bytes_data".into()),
+                    serde_cbor::Value::Bytes(vec![0, 1, 2, 255])
+                )]
+                .into_iter()
+                .collect()
+            )
+        );
+        assert_eq!(Encoding::Cbor.deserialize::<Tensor>(&cbor).unwrap(), tensor);
+    }
+
+    #[test]
+    fn content_negotiation() {
+        let rq = request(
+            &[],
+            serde_cbor::to_vec(&Tensor {
+                bytes_data: vec![1],
+            })
+            .unwrap(),
+        );
+        assert_eq!(Encoding::accepted(&rq), Encoding::Cbor);
+        assert_eq!(read_body::<Tensor>(&rq, 100).unwrap().bytes_data, vec![1]);
+
+        let rq = request(
+            &[
+                ("Content-Type", "application/json; charset=utf-8"),
+                ("Accept", "text/html, application/json;q=0.9, */*;q=0.8"),
+            ],
+            br#"{"bytes_data":"AQ=="}"#.to_vec(),
+        );
+        assert_eq!(Encoding::accepted(&rq), Encoding::Json);
+        assert_eq!(read_body::<Tensor>(&rq, 100).unwrap().bytes_data, vec![1]);
+
+        let rq = request(&[("Content-Type", "text/plain")], vec![]);
+        assert!(read_body::<Tensor>(&rq, 100).is_err());
+    }
+
+    #[test]
+    fn compressed_body() {
+        let body = br#"{"bytes_data":"AQ=="}"#.to_vec();
+        let compressed = Compression::Gzip.compress(body.clone()).unwrap();
+        let rq = request(
+            &[
+                ("Content-Type", "application/json"),
+                ("Content-Encoding", "gzip"),
+                ("Accept-Encoding", "deflate, gzip;q=0.5"),
+            ],
+            compressed,
+        );
+        assert_eq!(Compression::accepted(&rq), Compression::Gzip);
+        assert_eq!(read_raw_body(&rq, body.len()).unwrap(), body);
+        assert_eq!(read_body::<Tensor>(&rq, 100).unwrap().bytes_data, vec![1]);
+
+        let rq = request(&[("Accept-Encoding", "gzip;q=0, br")], vec![]);
+        assert_eq!(Compression::accepted(&rq), Compression::Identity);
+    }
+
+    #[test]
+    fn decompression_is_bounded() {
+        let bomb = Compression::Gzip.compress(vec![0u8; 10_000_000]).unwrap();
+        assert!(bomb.len() < 100_000);
+        assert!(Compression::Gzip.decompress(&bomb[..], 1_000_000).is_err());
+        let data = Compression::Gzip.decompress(&bomb[..], 10_000_000).unwrap();
+        assert_eq!(data.len(), 10_000_000);
+        assert!(Compression::from_header(Some("br")).is_err());
+    }
+
+    #[test]
+    fn uncompressed_bodies_are_bounded() {
+        let body = vec![0u8; 1000];
+        let data = Compression::Identity.decompress(&body[..], 1000).unwrap();
+        assert_eq!(data.len(), 1000);
+        assert!(Compression::Identity.decompress(&body[..], 999).is_err());
+
+        let rq = request(&[], body);
+        assert!(read_raw_body(&rq, 999).is_err());
+    }
+}
@@ -0,0 +1,232 @@
+// Copyright 2022 Mithril Security. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Liveness and readiness of the server, reported on the unattested port for
+//! orchestrators.
+//!
+//! The unattested port is bound before attestation starts, so that `/ready`
+//! reports the progress of the attestation. Until the attestation routes are
+//! set, the other routes answer 503.
+
+use crate::metrics::labelled_router;
+use crate::model_store::ModelStore;
+use serde_derive::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Health {
+    status: &'static str,
+    version: &'static str,
+    /// `sgx` inside an enclave, `mock` otherwise
+    mode: &'static str,
+    telemetry_enabled: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Readiness {
+    pub ready: bool,
+    quote_obtained: bool,
+    collateral_obtained: bool,
+    management_server_started: bool,
+    attested_server_started: bool,
+    models_loaded: usize,
+    version: &'static str,
+    mode: &'static str,
+    telemetry_enabled: bool,
+}
+
+/// Startup progress of the server
+pub(crate) struct ServerStatus {
+    model_store: Arc<ModelStore>,
+    telemetry_enabled: bool,
+    quote_obtained: AtomicBool,
+    collateral_obtained: AtomicBool,
+    management_server_started: AtomicBool,
+    attested_server_started: AtomicBool,
+}
+
+impl ServerStatus {
+    pub fn new(model_store: Arc<ModelStore>, telemetry_enabled: bool) -> Self {
+        ServerStatus {
+            model_store,
+            telemetry_enabled,
+            quote_obtained: AtomicBool::new(false),
+            collateral_obtained: AtomicBool::new(false),
+            management_server_started: AtomicBool::new(false),
+            attested_server_started: AtomicBool::new(false),
+        }
+    }
+
+    fn mode() -> &'static str {
+        if cfg!(target_env = "sgx") {
+            "sgx"
+        } else {
+            "mock"
+        }
+    }
+
+    pub fn set_quote_obtained(&self) {
+        self.quote_obtained.store(true, Ordering::SeqCst);
+    }
+
+    pub fn set_collateral_obtained(&self) {
+        self.collateral_obtained.store(true, Ordering::SeqCst);
+    }
+
+    pub fn set_management_server_started(&self) {
+        self.management_server_started.store(true, Ordering::SeqCst);
+    }
+
+    pub fn set_attested_server_started(&self) {
+        self.attested_server_started.store(true, Ordering::SeqCst);
+    }
+
+    pub fn health(&self) -> Health {
+        Health {
+            status: "ok",
+            version: env!("CARGO_PKG_VERSION"),
+            mode: Self::mode(),
+            telemetry_enabled: self.telemetry_enabled,
+        }
+    }
+
+    /// The server is ready once both TLS servers are up and, inside an
+    /// enclave, the quote and the collateral were obtained
+    pub fn readiness(&self) -> Readiness {
+        let quote_obtained = self.quote_obtained.load(Ordering::SeqCst);
+        let collateral_obtained = self.collateral_obtained.load(Ordering::SeqCst);
+        let management_server_started = self.management_server_started.load(Ordering::SeqCst);
+        let attested_server_started = self.attested_server_started.load(Ordering::SeqCst);
+        let attested = !cfg!(target_env = "sgx") || (quote_obtained && collateral_obtained);
+
+        Readiness {
+            ready: attested && management_server_started && attested_server_started,
+            quote_obtained,
+            collateral_obtained,
+            management_server_started,
+            attested_server_started,
+            models_loaded: self.model_store.model_count(),
+            version: env!("CARGO_PKG_VERSION"),
+            mode: Self::mode(),
+            telemetry_enabled: self.telemetry_enabled,
+        }
+    }
+
+    pub fn respond_health(&self) -> rouille::Response {
+        rouille::Response::json(&self.health())
+    }
+
+    pub fn respond_readiness(&self) -> rouille::Response {
+        let readiness = self.readiness();
+        let status_code = if readiness.ready { 200 } else { 503 };
+        rouille::Response::json(&readiness).with_status_code(status_code)
+    }
+}
+
+type Routes = dyn Fn(&rouille::Request) -> rouille::Response + Send + Sync;
+
+/// Routes of the unattested port, `/health` and `/ready` being served from
+/// startup
+pub(crate) struct UnattestedRoutes {
+    status: Arc<ServerStatus>,
+    attestation: RwLock<Option<Box<Routes>>>,
+}
+
+impl UnattestedRoutes {
+    pub fn new(status: Arc<ServerStatus>) -> Self {
+        UnattestedRoutes {
+            status,
+            attestation: RwLock::new(None),
+        }
+    }
+
+    /// Serve the routes that need the attestation to be done
+    pub fn set_attestation_routes(&self, routes: Box<Routes>) {
+        *self.attestation.write().unwrap() = Some(routes);
+    }
+
+    pub fn handle(&self, request: &rouille::Request) -> rouille::Response {
+        labelled_router!(request,
+            (GET) (/health) => { self.status.respond_health() },
+            (GET) (/ready) => { self.status.respond_readiness() },
+            _ => match &*self.attestation.read().unwrap() {
+                Some(routes) => routes(request),
+                None => {
+                    rouille::Response::text("The attestation is in progress").with_status_code(503)
+                }
+            }
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(routes: &UnattestedRoutes, url: &str) -> rouille::Response {
+        routes.handle(&rouille::Request::fake_http("GET", url, vec![], vec![]))
+    }
+
+    #[test]
+    fn ready_once_every_step_is_done() {
+        let status = ServerStatus::new(Arc::new(ModelStore::new()), false);
+        let steps: [fn(&ServerStatus); 4] = [
+            ServerStatus::set_quote_obtained,
+            ServerStatus::set_collateral_obtained,
+            ServerStatus::set_management_server_started,
+            ServerStatus::set_attested_server_started,
+        ];
+        for step in steps {
+            assert!(!status.readiness().ready);
+            assert_eq!(status.respond_readiness().status_code, 503);
+            step(&status);
+        }
+        assert!(status.readiness().ready);
+        assert_eq!(status.respond_readiness().status_code, 200);
+    }
+
+    #[test]
+    fn readiness_reports_the_steps() {
+        let status = ServerStatus::new(Arc::new(ModelStore::new()), true);
+        status.set_quote_obtained();
+        let readiness = serde_json::to_value(status.readiness()).unwrap();
+        assert_eq!(readiness["ready"], false);
+        assert_eq!(readiness["quote_obtained"], true);
+        assert_eq!(readiness["collateral_obtained"], false);
+        assert_eq!(readiness["models_loaded"], 0);
+        assert_eq!(readiness["telemetry_enabled"], true);
+
+        let health = serde_json::to_value(status.health()).unwrap();
+        assert_eq!(health["status"], "ok");
+        assert_eq!(health["mode"], "mock");
+    }
+
+    #[test]
+    fn health_is_served_during_attestation() {
+        let status = Arc::new(ServerStatus::new(Arc::new(ModelStore::new()), false));
+        let routes = UnattestedRoutes::new(Arc::clone(&status));
+        assert_eq!(get(&routes, "/health").status_code, 200);
+        assert_eq!(get(&routes, "/ready").status_code, 503);
+        assert_eq!(get(&routes, "/quote").status_code, 503);
+
+        routes.set_attestation_routes(Box::new(|_| rouille::Response::text("quote")));
+        status.set_quote_obtained();
+        status.set_collateral_obtained();
+        assert_eq!(get(&routes, "/quote").status_code, 200);
+        assert_eq!(get(&routes, "/health").status_code, 200);
+        // The TLS servers are not started yet
+        assert_eq!(get(&routes, "/ready").status_code, 503);
+    }
+}
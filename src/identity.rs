@@ -12,10 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use anyhow::Result;
-use rcgen::{Certificate, CertificateParams, SanType};
+use anyhow::{bail, Result};
+use rcgen::{Certificate, CertificateParams, CustomExtension, KeyPair, SanType};
+use ring::digest;
+use x25519_dalek::{PublicKey, SharedSecret, StaticSecret};
 
-pub(crate) fn create_tls_certificate() -> Result<Certificate> {
+/// OID of the extension carrying the raw SGX quote in RA-TLS certificates, as
+/// used by Gramine and the sgx-ra-tls library
+pub(crate) const RA_TLS_QUOTE_OID: &[u64] = &[1, 2, 840, 113741, 1337, 6];
+
+fn certificate_params() -> CertificateParams {
     // Generate a self signed certificate
     let subject_alt_names: &[_] = &["blindai-srv".to_string()];
     let subject_alt_names = subject_alt_names
@@ -25,6 +31,139 @@ pub(crate) fn create_tls_certificate() -> Result<Certificate> {
 
     let mut params = CertificateParams::default();
     params.subject_alt_names = subject_alt_names;
+    params
+}
+
+pub(crate) fn create_tls_certificate() -> Result<Certificate> {
+    Ok(Certificate::from_params(certificate_params())?)
+}
+
+/// Attestation evidence embedded in the enclave TLS certificate
+///
+/// With RA-TLS, the quote is carried by the certificate it attests, so the
+/// first half of its report data can't be the hash of the certificate. It is
+/// the SHA-256 of the DER encoded public key of the certificate instead, as
+/// RA-TLS verifiers expect.
+///
+/// The collateral is not embedded: the certificate lives as long as the
+/// enclave, while the collateral is refreshed and expires. Clients get it from
+/// `/collateral`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum RaTls {
+    Disabled,
+    Quote,
+}
+
+impl RaTls {
+    /// `BLINDAI_RA_TLS` is unset (the default) or `quote`
+    pub fn from_env() -> Result<Self> {
+        match std::env::var("BLINDAI_RA_TLS").as_deref() {
+            Err(_) => Ok(RaTls::Disabled),
+            Ok("quote") => Ok(RaTls::Quote),
+            Ok(value) => bail!("Invalid BLINDAI_RA_TLS {:?}, expected \"quote\"", value),
+        }
+    }
+
+    pub fn is_enabled(self) -> bool {
+        self != RaTls::Disabled
+    }
+
+    /// Certificate with the key pair of `certificate`, carrying `quote`
+    pub fn create_certificate(
+        self,
+        certificate: &Certificate,
+        quote: &[u8],
+    ) -> Result<Certificate> {
+        let mut params = certificate_params();
+        params.key_pair = Some(KeyPair::from_der(&certificate.serialize_private_key_der())?);
+        params
+            .custom_extensions
+            .push(CustomExtension::from_oid_content(
+                RA_TLS_QUOTE_OID,
+                quote.to_vec(),
+            ));
+        Ok(Certificate::from_params(params)?)
+    }
+}
+
+/// X25519 key pair generated at startup and never leaving the enclave.
+///
+/// Its public key is bound to the quote through the second half of the
+/// report data, so that third parties (such as a key broker) can encrypt
+/// secrets that only this enclave instance is able to decrypt.
+pub(crate) struct EnclaveKey {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl EnclaveKey {
+    pub fn generate() -> Self {
+        let secret = StaticSecret::random_from_rng(rand::rngs::OsRng);
+        let public = PublicKey::from(&secret);
+        EnclaveKey { secret, public }
+    }
+
+    pub fn public_key(&self) -> &[u8; 32] {
+        self.public.as_bytes()
+    }
+
+    pub fn diffie_hellman(&self, peer_public_key: [u8; 32]) -> SharedSecret {
+        self.secret
+            .diffie_hellman(&PublicKey::from(peer_public_key))
+    }
+}
+
+/// Build the report data embedded in the enclave quote
+///
+/// * bytes 0..32 : SHA-256 of the enclave held data, the DER encoded TLS
+///   certificate or its public key with RA-TLS (see [`RaTls`])
+/// * bytes 32..64 : SHA-256 of the enclave X25519 public key followed by the
+///   client data, which is empty except for fresh quotes. The client data is
+///   hashed rather than copied as is, so that fresh quotes also bind the
+///   enclave key.
+pub(crate) fn report_data(
+    enclave_held_data: &[u8],
+    enclave_key: &EnclaveKey,
+    client_data: &[u8],
+) -> [u8; 64] {
+    let mut report_data = [0u8; 64];
+    report_data[0..32].copy_from_slice(digest::digest(&digest::SHA256, enclave_held_data).as_ref());
+    let mut context = digest::Context::new(&digest::SHA256);
+    context.update(enclave_key.public_key());
+    context.update(client_data);
+    report_data[32..64].copy_from_slice(context.finish().as_ref());
+    report_data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quote_verification::pki::ra_tls_evidence;
+
+    #[test]
+    fn ra_tls_certificates_embed_the_quote() {
+        let certificate = create_tls_certificate().unwrap();
+        let quote = b"a quote, as returned by the runner".to_vec();
+
+        let with_quote = RaTls::Quote
+            .create_certificate(&certificate, &quote)
+            .unwrap();
+        let with_quote_der = with_quote.serialize_der().unwrap();
+        // The report data binds the public key, which is left unchanged
+        assert_eq!(
+            with_quote.get_key_pair().public_key_der(),
+            certificate.get_key_pair().public_key_der()
+        );
+
+        // As verifiers extract it
+        let (embedded_quote, enclave_held_data) = ra_tls_evidence(&with_quote_der).unwrap();
+        assert_eq!(embedded_quote, Some(quote));
+        assert_eq!(
+            enclave_held_data,
+            certificate.get_key_pair().public_key_der()
+        );
 
-    Ok(Certificate::from_params(params)?)
+        let der = certificate.serialize_der().unwrap();
+        assert_eq!(ra_tls_evidence(&der).unwrap(), (None, der));
+    }
 }
@@ -0,0 +1,433 @@
+// Copyright 2022 Mithril Security. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Asynchronous inference jobs.
+//!
+//! Jobs are run by a dedicated set of worker threads, their inferences
+//! running on the workers themselves rather than on the compute pool serving
+//! the synchronous queries, and at most `max_pending` jobs may wait in the
+//! queue. Results are sealed with AES-256-GCM under a key generated at
+//! startup until the client that submitted the job fetches them, or until
+//! they expire. Results held in enclave memory are bounded along with the
+//! jobs: at most `max_jobs` jobs may be pending, running or waiting for their
+//! result to be fetched, further submissions being rejected.
+
+use crate::client_communication::RunModelReply;
+use anyhow::{anyhow, bail, Result};
+use log::{error, info};
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_derive::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+type Runner<T> = dyn Fn(T) -> Result<RunModelReply> + Send + Sync;
+
+fn var(name: &str, default: u64) -> Result<u64> {
+    match std::env::var(name) {
+        Ok(value) => Ok(value.parse()?),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Number of workers the queue is configured with
+pub(crate) fn workers_from_env() -> Result<usize> {
+    Ok(var("BLINDAI_JOB_WORKERS", 2)?.try_into()?)
+}
+
+struct SealedResult {
+    nonce: [u8; aead::NONCE_LEN],
+    ciphertext: Vec<u8>,
+}
+
+enum JobState {
+    Pending,
+    Running,
+    Done(SealedResult),
+    Failed(String),
+}
+
+struct Job {
+    owner: Option<String>,
+    state: JobState,
+    /// Set once the job is finished
+    expires_at: Option<Instant>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct JobCreated {
+    job_id: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub(crate) enum JobStatus {
+    Pending,
+    Running,
+    Done(RunModelReply),
+    Failed { error: String },
+}
+
+pub(crate) struct JobQueue<T> {
+    jobs: Mutex<HashMap<Uuid, Job>>,
+    sender: SyncSender<(Uuid, T)>,
+    sealing_key: LessSafeKey,
+    nonce_counter: AtomicU64,
+    max_jobs: usize,
+    result_ttl: Duration,
+}
+
+impl<T: Send + 'static> JobQueue<T> {
+    /// Start `workers` threads running the jobs with `run`
+    pub fn new(
+        run: Arc<Runner<T>>,
+        workers: usize,
+        max_pending: usize,
+        max_jobs: usize,
+        result_ttl: Duration,
+    ) -> Result<Arc<Self>> {
+        let mut key = [0u8; 32];
+        SystemRandom::new()
+            .fill(&mut key)
+            .map_err(|_| anyhow!("Could not generate the job sealing key"))?;
+        let sealing_key = LessSafeKey::new(
+            UnboundKey::new(&aead::AES_256_GCM, &key)
+                .map_err(|_| anyhow!("Invalid job sealing key"))?,
+        );
+
+        let (sender, receiver) = mpsc::sync_channel(max_pending);
+        let queue = Arc::new(JobQueue {
+            jobs: Mutex::new(HashMap::new()),
+            sender,
+            sealing_key,
+            nonce_counter: AtomicU64::new(0),
+            max_jobs,
+            result_ttl,
+        });
+
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..workers {
+            let queue = Arc::clone(&queue);
+            let receiver = Arc::clone(&receiver);
+            let run = Arc::clone(&run);
+            thread::spawn(move || queue.work(&receiver, &*run));
+        }
+        Ok(queue)
+    }
+
+    /// Configure the queue with `BLINDAI_JOB_WORKERS` (2 by default),
+    /// `BLINDAI_MAX_PENDING_JOBS` (64 by default), `BLINDAI_MAX_JOBS` (256 by
+    /// default) and `BLINDAI_JOB_RESULT_TTL` in seconds (600 by default)
+    pub fn from_env(run: Arc<Runner<T>>) -> Result<Arc<Self>> {
+        Self::new(
+            run,
+            workers_from_env()?,
+            var("BLINDAI_MAX_PENDING_JOBS", 64)?.try_into()?,
+            var("BLINDAI_MAX_JOBS", 256)?.try_into()?,
+            Duration::from_secs(var("BLINDAI_JOB_RESULT_TTL", 600)?),
+        )
+    }
+
+    fn work(&self, receiver: &Mutex<Receiver<(Uuid, T)>>, run: &Runner<T>) {
+        loop {
+            // The lock is released as soon as a job is received
+            let received = receiver.lock().unwrap().recv();
+            let (job_id, task) = match received {
+                Ok(job) => job,
+                Err(_) => return,
+            };
+            self.set_state(job_id, JobState::Running);
+
+            let state = match run(task).and_then(|reply| self.seal(&reply)) {
+                Ok(sealed) => JobState::Done(sealed),
+                Err(e) => {
+                    error!("Job {} failed: {}", job_id, e);
+                    JobState::Failed(format!("{}", e))
+                }
+            };
+            self.set_state(job_id, state);
+        }
+    }
+
+    fn set_state(&self, job_id: Uuid, state: JobState) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(&job_id) {
+            if matches!(state, JobState::Done(_) | JobState::Failed(_)) {
+                job.expires_at = Some(Instant::now() + self.result_ttl);
+            }
+            job.state = state;
+        }
+    }
+
+    fn seal(&self, reply: &RunModelReply) -> Result<SealedResult> {
+        let mut nonce = [0u8; aead::NONCE_LEN];
+        let counter = self.nonce_counter.fetch_add(1, Ordering::SeqCst);
+        nonce[..8].copy_from_slice(&counter.to_be_bytes());
+
+        let mut ciphertext = serde_cbor::to_vec(reply)?;
+        self.sealing_key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::empty(),
+                &mut ciphertext,
+            )
+            .map_err(|_| anyhow!("Could not seal the job result"))?;
+        Ok(SealedResult { nonce, ciphertext })
+    }
+
+    fn open(&self, sealed: SealedResult) -> Result<RunModelReply> {
+        let mut ciphertext = sealed.ciphertext;
+        let plaintext = self
+            .sealing_key
+            .open_in_place(
+                Nonce::assume_unique_for_key(sealed.nonce),
+                Aad::empty(),
+                &mut ciphertext,
+            )
+            .map_err(|_| anyhow!("Could not open the job result"))?;
+        Ok(serde_cbor::from_slice(plaintext)?)
+    }
+
+    pub fn submit(&self, owner: Option<String>, task: T) -> Result<JobCreated> {
+        let job_id = Uuid::new_v4();
+        {
+            let mut jobs = self.jobs.lock().unwrap();
+            if jobs.len() >= self.max_jobs {
+                retain_unexpired(&mut jobs, Instant::now());
+            }
+            if jobs.len() >= self.max_jobs {
+                bail!("Too many job results waiting to be fetched, retry later");
+            }
+            jobs.insert(
+                job_id,
+                Job {
+                    owner,
+                    state: JobState::Pending,
+                    expires_at: None,
+                },
+            );
+        }
+
+        if let Err(e) = self.sender.try_send((job_id, task)) {
+            self.jobs.lock().unwrap().remove(&job_id);
+            match e {
+                TrySendError::Full(_) => bail!("Too many pending jobs, retry later"),
+                TrySendError::Disconnected(_) => bail!("The job workers are not running"),
+            }
+        }
+
+        info!("Job {} submitted", job_id);
+        Ok(JobCreated {
+            job_id: job_id.to_string(),
+        })
+    }
+
+    /// Number of jobs waiting for a worker
+    pub fn pending(&self) -> usize {
+        self.jobs
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|job| matches!(job.state, JobState::Pending))
+            .count()
+    }
+
+    /// Status of a job submitted by `owner`. The result of a finished job is
+    /// handed out only once.
+    pub fn status(&self, owner: Option<&str>, job_id: &str) -> Result<JobStatus> {
+        let job_id = Uuid::parse_str(job_id).map_err(|_| anyhow!("Job doesn't exist"))?;
+
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = match jobs.get(&job_id) {
+            Some(job) if job.owner.as_deref() == owner => job,
+            _ => bail!("Job doesn't exist"),
+        };
+        match job.state {
+            JobState::Pending => return Ok(JobStatus::Pending),
+            JobState::Running => return Ok(JobStatus::Running),
+            JobState::Done(_) | JobState::Failed(_) => (),
+        }
+
+        let job = jobs.remove(&job_id).unwrap();
+        drop(jobs);
+        match job.state {
+            JobState::Done(sealed) => Ok(JobStatus::Done(self.open(sealed)?)),
+            JobState::Failed(error) => Ok(JobStatus::Failed { error }),
+            JobState::Pending | JobState::Running => unreachable!(),
+        }
+    }
+
+    /// Drop the results that were not fetched in time
+    pub fn delete_expired_jobs(&self) {
+        retain_unexpired(&mut self.jobs.lock().unwrap(), Instant::now());
+    }
+}
+
+fn retain_unexpired(jobs: &mut HashMap<Uuid, Job>, now: Instant) {
+    jobs.retain(|_, job| match job.expires_at {
+        Some(expires_at) => now < expires_at,
+        None => true,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_communication::{SerializedTensor, TensorInfo};
+    use crate::model::ModelDatumType;
+
+    fn tensor(bytes_data: Vec<u8>) -> SerializedTensor {
+        SerializedTensor {
+            info: TensorInfo {
+                fact: vec![bytes_data.len()],
+                datum_type: ModelDatumType::U8,
+                node_name: None,
+            },
+            bytes_data,
+        }
+    }
+
+    fn wait_for_result(queue: &JobQueue<Vec<u8>>, owner: Option<&str>, job_id: &str) -> JobStatus {
+        loop {
+            match queue.status(owner, job_id).unwrap() {
+                JobStatus::Pending | JobStatus::Running => thread::sleep(Duration::from_millis(10)),
+                status => return status,
+            }
+        }
+    }
+
+    fn echo_queue(max_pending: usize) -> Arc<JobQueue<Vec<u8>>> {
+        JobQueue::new(
+            Arc::new(|data: Vec<u8>| {
+                if data.is_empty() {
+                    bail!("Empty input");
+                }
+                Ok(RunModelReply {
+                    outputs: vec![tensor(data)],
+                    ..Default::default()
+                })
+            }),
+            1,
+            max_pending,
+            max_pending + 2,
+            Duration::from_secs(60),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn job_results_are_fetched_once_by_their_owner() {
+        let queue = echo_queue(4);
+        let job_id = queue
+            .submit(Some("a".into()), vec![1, 2, 3])
+            .unwrap()
+            .job_id;
+
+        assert!(queue.status(Some("b"), &job_id).is_err());
+        assert!(queue.status(None, &job_id).is_err());
+        match wait_for_result(&queue, Some("a"), &job_id) {
+            JobStatus::Done(reply) => assert_eq!(reply.outputs[0].bytes_data, vec![1, 2, 3]),
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert!(queue.status(Some("a"), &job_id).is_err());
+
+        let job_id = queue.submit(None, vec![]).unwrap().job_id;
+        match wait_for_result(&queue, None, &job_id) {
+            JobStatus::Failed { error } => assert_eq!(error, "Empty input"),
+            status => panic!("unexpected status {:?}", status),
+        }
+    }
+
+    #[test]
+    fn pending_jobs_are_bounded() {
+        let (unblock, blocked) = mpsc::channel::<()>();
+        let blocked = Mutex::new(blocked);
+        let queue = JobQueue::new(
+            Arc::new(move |_: Vec<u8>| {
+                blocked.lock().unwrap().recv().unwrap();
+                Ok(RunModelReply::default())
+            }),
+            1,
+            1,
+            3,
+            Duration::from_secs(60),
+        )
+        .unwrap();
+
+        let running = queue.submit(None, vec![]).unwrap().job_id;
+        while !matches!(queue.status(None, &running).unwrap(), JobStatus::Running) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(queue.pending(), 0);
+        queue.submit(None, vec![]).unwrap();
+        assert!(queue.submit(None, vec![]).is_err());
+        assert_eq!(queue.pending(), 1);
+
+        unblock.send(()).unwrap();
+        unblock.send(()).unwrap();
+    }
+
+    #[test]
+    fn unfetched_results_are_bounded() {
+        let queue = echo_queue(2);
+        // Finished, the results are held until fetched
+        let jobs: Vec<_> = (1..=4)
+            .map(|i| {
+                let job_id = queue.submit(None, vec![i]).unwrap().job_id;
+                let uuid = Uuid::parse_str(&job_id).unwrap();
+                while queue.jobs.lock().unwrap()[&uuid].expires_at.is_none() {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                job_id
+            })
+            .collect();
+        assert_eq!(queue.pending(), 0);
+        assert!(queue.submit(None, vec![5]).is_err());
+
+        assert!(matches!(
+            queue.status(None, &jobs[0]).unwrap(),
+            JobStatus::Done(_)
+        ));
+        queue.submit(None, vec![5]).unwrap();
+    }
+
+    #[test]
+    fn results_expire() {
+        let queue = JobQueue::new(
+            Arc::new(|_: Vec<u8>| Ok(RunModelReply::default())),
+            1,
+            1,
+            1,
+            Duration::ZERO,
+        )
+        .unwrap();
+        let job_id = queue.submit(None, vec![1]).unwrap().job_id;
+        loop {
+            let done = matches!(
+                queue.jobs.lock().unwrap().values().next().unwrap().state,
+                JobState::Done(_)
+            );
+            if done {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        queue.delete_expired_jobs();
+        assert!(queue.status(None, &job_id).is_err());
+    }
+}
@@ -0,0 +1,304 @@
+// Copyright 2022 Mithril Security. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Retrieval of model decryption keys from a key broker.
+//!
+//! A model owner can encrypt a model with AES-256-GCM and hand the resulting
+//! bundle to the operator, who uploads it like any other model. The enclave
+//! then asks the key broker for the model key, presenting its quote, its
+//! collateral, its TLS certificate and its X25519 public key (both bound to
+//! the quote through the report data). The broker checks the evidence and
+//! replies with the model key wrapped for the enclave public key:
+//!
+//! * `ephemeral_public_key` : X25519 public key generated by the broker
+//! * `nonce` : 12 bytes AES-256-GCM nonce
+//! * `wrapped_key` : the 32 bytes model key sealed with AES-256-GCM, using
+//!   `key_id` as additional data
+//!
+//! The wrapping key is derived with HKDF-SHA256 from the X25519 shared secret,
+//! using `ephemeral_public_key || enclave_public_key` as salt and
+//! [`KEY_WRAPPING_INFO`] as info.
+
+use crate::collateral::CollateralStore;
+use crate::identity::EnclaveKey;
+use crate::SgxCollateral;
+use anyhow::{anyhow, ensure, Result};
+use log::{debug, info};
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey};
+use ring::hkdf;
+use serde_derive::{Deserialize, Serialize};
+use std::sync::Arc;
+
+pub(crate) const KEY_WRAPPING_INFO: &[u8] = b"blindai key broker v1";
+
+/// Attestation evidence presented to the key broker
+#[derive(Clone, Default)]
+pub(crate) struct AttestationEvidence {
+    /// Empty when the server is not running inside an enclave
+    pub quote: Vec<u8>,
+    /// The latest collateral is presented with every key request
+    pub collateral: Option<Arc<CollateralStore>>,
+    /// DER encoded enclave TLS certificate
+    pub certificate: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct KeyRequest {
+    pub key_id: String,
+    pub quote: Vec<u8>,
+    pub collateral: Option<SgxCollateral>,
+    pub certificate: Vec<u8>,
+    pub enclave_public_key: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct KeyResponse {
+    pub ephemeral_public_key: Vec<u8>,
+    pub nonce: Vec<u8>,
+    pub wrapped_key: Vec<u8>,
+}
+
+/// Encrypted model, as produced by the model owner
+///
+/// The bundle is CBOR encoded. The ciphertext is the ONNX model sealed with
+/// AES-256-GCM under the key identified by `key_id`, with `key_id` as
+/// additional data.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct EncryptedModelBundle {
+    pub key_id: String,
+    #[serde(with = "serde_bytes")]
+    pub nonce: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub ciphertext: Vec<u8>,
+}
+
+pub(crate) struct KeyBroker {
+    url: String,
+    evidence: AttestationEvidence,
+    enclave_key: Arc<EnclaveKey>,
+}
+
+impl KeyBroker {
+    pub fn new(url: String, evidence: AttestationEvidence, enclave_key: Arc<EnclaveKey>) -> Self {
+        KeyBroker {
+            url,
+            evidence,
+            enclave_key,
+        }
+    }
+
+    /// Whether a key broker is configured through `BLINDAI_KEY_BROKER_URL`
+    pub fn is_configured() -> bool {
+        std::env::var("BLINDAI_KEY_BROKER_URL").is_ok()
+    }
+
+    /// Build a key broker client if `BLINDAI_KEY_BROKER_URL` is set
+    pub fn from_env(evidence: AttestationEvidence, enclave_key: Arc<EnclaveKey>) -> Option<Self> {
+        let url = std::env::var("BLINDAI_KEY_BROKER_URL").ok()?;
+        info!("Encrypted models will be decrypted with keys from {}", url);
+        Some(Self::new(url, evidence, enclave_key))
+    }
+
+    /// Request the key `key_id` from the key broker and unwrap it
+    pub fn fetch_key(&self, key_id: &str) -> Result<[u8; 32]> {
+        debug!("Requesting key {:?} from the key broker", key_id);
+        let response: KeyResponse = ureq::post(&format!("{}/key", self.url))
+            .send_json(KeyRequest {
+                key_id: key_id.to_string(),
+                quote: self.evidence.quote.clone(),
+                collateral: self
+                    .evidence
+                    .collateral
+                    .as_ref()
+                    .map(|store| store.current().collateral.clone()),
+                certificate: self.evidence.certificate.clone(),
+                enclave_public_key: self.enclave_key.public_key().to_vec(),
+            })?
+            .into_json()?;
+
+        let ephemeral_public_key: [u8; 32] = response
+            .ephemeral_public_key
+            .as_slice()
+            .try_into()
+            .map_err(|_| anyhow!("Invalid ephemeral public key from the key broker"))?;
+        let shared_secret = self.enclave_key.diffie_hellman(ephemeral_public_key);
+        let wrapping_key = wrapping_key(
+            shared_secret.as_bytes(),
+            &ephemeral_public_key,
+            self.enclave_key.public_key(),
+        )?;
+
+        let mut wrapped_key = response.wrapped_key;
+        let key = open(&wrapping_key, &response.nonce, key_id, &mut wrapped_key)
+            .map_err(|_| anyhow!("Could not unwrap the key sent by the key broker"))?;
+        key.try_into()
+            .map_err(|_| anyhow!("The key broker sent a key of the wrong size"))
+    }
+
+    /// Decrypt a CBOR encoded [`EncryptedModelBundle`] in enclave memory
+    pub fn decrypt_model(&self, bundle: &[u8]) -> Result<Vec<u8>> {
+        let bundle: EncryptedModelBundle = serde_cbor::from_slice(bundle)?;
+        let key = self.fetch_key(&bundle.key_id)?;
+        let key = LessSafeKey::new(
+            UnboundKey::new(&aead::AES_256_GCM, &key).map_err(|_| anyhow!("Invalid model key"))?,
+        );
+
+        let mut model = bundle.ciphertext;
+        let len = open(&key, &bundle.nonce, &bundle.key_id, &mut model)
+            .map_err(|_| anyhow!("Could not decrypt the model, wrong key or corrupted bundle"))?
+            .len();
+        model.truncate(len);
+        Ok(model)
+    }
+}
+
+/// Derive the AES-256-GCM key used to wrap model keys
+pub(crate) fn wrapping_key(
+    shared_secret: &[u8],
+    ephemeral_public_key: &[u8],
+    enclave_public_key: &[u8],
+) -> Result<LessSafeKey> {
+    let salt = [ephemeral_public_key, enclave_public_key].concat();
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, &salt).extract(shared_secret);
+    let okm = prk
+        .expand(&[KEY_WRAPPING_INFO], &aead::AES_256_GCM)
+        .map_err(|_| anyhow!("HKDF expansion failed"))?;
+    Ok(LessSafeKey::new(UnboundKey::from(okm)))
+}
+
+fn open<'a>(
+    key: &LessSafeKey,
+    nonce: &[u8],
+    aad: &str,
+    in_out: &'a mut [u8],
+) -> Result<&'a mut [u8]> {
+    ensure!(nonce.len() == aead::NONCE_LEN, "Invalid nonce size");
+    let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| anyhow!("Invalid nonce"))?;
+    key.open_in_place(nonce, Aad::from(aad.as_bytes()), in_out)
+        .map_err(|_| anyhow!("Authenticated decryption failed"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::{SecureRandom, SystemRandom};
+    use std::io::Read;
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    const KEY_ID: &str = "mobilenet-v2";
+
+    fn seal(key: &LessSafeKey, aad: &str, plaintext: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let mut nonce = [0u8; aead::NONCE_LEN];
+        SystemRandom::new().fill(&mut nonce).unwrap();
+        let mut in_out = plaintext.to_vec();
+        key.seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(aad.as_bytes()),
+            &mut in_out,
+        )
+        .unwrap();
+        (nonce.to_vec(), in_out)
+    }
+
+    /// Minimal stand-in for a key broker: it trusts any evidence and serves
+    /// a single key.
+    fn start_key_server(model_key: [u8; 32]) -> String {
+        let server = rouille::Server::new("127.0.0.1:0", move |request| {
+            let mut body = vec![];
+            request.data().unwrap().read_to_end(&mut body).unwrap();
+            let key_request: KeyRequest = serde_json::from_slice(&body).unwrap();
+            if key_request.key_id != KEY_ID {
+                return rouille::Response::empty_404();
+            }
+
+            let ephemeral_secret = StaticSecret::random_from_rng(rand::rngs::OsRng);
+            let ephemeral_public_key = PublicKey::from(&ephemeral_secret);
+            let enclave_public_key: [u8; 32] = key_request.enclave_public_key.try_into().unwrap();
+            let shared_secret =
+                ephemeral_secret.diffie_hellman(&PublicKey::from(enclave_public_key));
+            let wrapping_key = wrapping_key(
+                shared_secret.as_bytes(),
+                ephemeral_public_key.as_bytes(),
+                &enclave_public_key,
+            )
+            .unwrap();
+            let (nonce, wrapped_key) = seal(&wrapping_key, KEY_ID, &model_key);
+
+            rouille::Response::json(&KeyResponse {
+                ephemeral_public_key: ephemeral_public_key.as_bytes().to_vec(),
+                nonce,
+                wrapped_key,
+            })
+        })
+        .unwrap();
+        let url = format!("http://{}", server.server_addr());
+        std::thread::spawn(move || server.run());
+        url
+    }
+
+    fn encrypt_model(model_key: &[u8; 32], key_id: &str, model: &[u8]) -> Vec<u8> {
+        let key = LessSafeKey::new(UnboundKey::new(&aead::AES_256_GCM, model_key).unwrap());
+        let (nonce, ciphertext) = seal(&key, key_id, model);
+        serde_cbor::to_vec(&EncryptedModelBundle {
+            key_id: key_id.to_string(),
+            nonce,
+            ciphertext,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn decrypt_model_with_key_from_broker() {
+        let mut model_key = [0u8; 32];
+        SystemRandom::new().fill(&mut model_key).unwrap();
+        let url = start_key_server(model_key);
+
+        let key_broker = KeyBroker::new(
+            url,
+            AttestationEvidence::default(),
+            Arc::new(EnclaveKey::generate()),
+        );
+        let model = b"not quite an onnx model".to_vec();
+        let bundle = encrypt_model(&model_key, KEY_ID, &model);
+        assert_eq!(key_broker.decrypt_model(&bundle).unwrap(), model);
+    }
+
+    #[test]
+    fn decrypt_model_with_wrong_key() {
+        let mut model_key = [0u8; 32];
+        SystemRandom::new().fill(&mut model_key).unwrap();
+        let url = start_key_server(model_key);
+
+        let key_broker = KeyBroker::new(
+            url,
+            AttestationEvidence::default(),
+            Arc::new(EnclaveKey::generate()),
+        );
+        let bundle = encrypt_model(&[0u8; 32], KEY_ID, b"model");
+        assert!(key_broker.decrypt_model(&bundle).is_err());
+    }
+
+    #[test]
+    fn unknown_key_id() {
+        let url = start_key_server([0u8; 32]);
+
+        let key_broker = KeyBroker::new(
+            url,
+            AttestationEvidence::default(),
+            Arc::new(EnclaveKey::generate()),
+        );
+        let bundle = encrypt_model(&[0u8; 32], "unknown", b"model");
+        assert!(key_broker.decrypt_model(&bundle).is_err());
+    }
+}
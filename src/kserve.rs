@@ -0,0 +1,421 @@
+// Copyright 2022 Mithril Security. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Open Inference Protocol (KServe v2) REST endpoints.
+//!
+//! Models are designated by their id, or by their name when it is unique.
+//! Only the JSON tensor representation is supported (no binary data
+//! extension), with the numeric and boolean datatypes of [`ModelDatumType`].
+
+use crate::client_communication::{
+    caller_identity, Exchanger, ModelNotFound, SerializedTensor, TensorInfo,
+};
+use crate::compute::Workload;
+use crate::health::ServerStatus;
+use crate::metrics::labelled_router;
+use crate::model::{ModelDatumType, TensorMetadata};
+use anyhow::{anyhow, bail, Result};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Serialize)]
+struct ServerMetadata {
+    name: &'static str,
+    version: &'static str,
+    extensions: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct MetadataTensor {
+    name: String,
+    datatype: &'static str,
+    shape: Vec<i64>,
+}
+
+#[derive(Serialize)]
+struct ModelMetadata {
+    name: String,
+    platform: &'static str,
+    inputs: Vec<MetadataTensor>,
+    outputs: Vec<MetadataTensor>,
+}
+
+#[derive(Deserialize)]
+struct RequestInput {
+    name: String,
+    shape: Vec<usize>,
+    datatype: String,
+    data: Value,
+}
+
+#[derive(Deserialize)]
+struct RequestOutput {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct InferenceRequest {
+    #[serde(default)]
+    id: Option<String>,
+    inputs: Vec<RequestInput>,
+    #[serde(default)]
+    outputs: Option<Vec<RequestOutput>>,
+}
+
+#[derive(Serialize)]
+struct ResponseOutput {
+    name: String,
+    shape: Vec<usize>,
+    datatype: &'static str,
+    data: Vec<Value>,
+}
+
+#[derive(Serialize)]
+struct InferenceResponse {
+    model_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    outputs: Vec<ResponseOutput>,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+fn datatype(datum_type: ModelDatumType) -> &'static str {
+    match datum_type {
+        ModelDatumType::Bool => "BOOL",
+        ModelDatumType::U8 => "UINT8",
+        ModelDatumType::U16 => "UINT16",
+        ModelDatumType::U32 => "UINT32",
+        ModelDatumType::U64 => "UINT64",
+        ModelDatumType::I8 => "INT8",
+        ModelDatumType::I16 => "INT16",
+        ModelDatumType::I32 => "INT32",
+        ModelDatumType::I64 => "INT64",
+        ModelDatumType::F32 => "FP32",
+        ModelDatumType::F64 => "FP64",
+    }
+}
+
+fn datum_type(datatype: &str) -> Result<ModelDatumType> {
+    Ok(match datatype {
+        "BOOL" => ModelDatumType::Bool,
+        "UINT8" => ModelDatumType::U8,
+        "UINT16" => ModelDatumType::U16,
+        "UINT32" => ModelDatumType::U32,
+        "UINT64" => ModelDatumType::U64,
+        "INT8" => ModelDatumType::I8,
+        "INT16" => ModelDatumType::I16,
+        "INT32" => ModelDatumType::I32,
+        "INT64" => ModelDatumType::I64,
+        "FP32" => ModelDatumType::F32,
+        "FP64" => ModelDatumType::F64,
+        _ => bail!("Unsupported datatype {:?}", datatype),
+    })
+}
+
+/// Flatten the (possibly nested) tensor data in row-major order
+fn flatten<'a>(data: &'a Value, values: &mut Vec<&'a Value>) {
+    match data {
+        Value::Array(elements) => elements.iter().for_each(|e| flatten(e, values)),
+        value => values.push(value),
+    }
+}
+
+macro_rules! integer_to_le_bytes {
+    ($t:ident, $value:expr, $bytes:expr) => {{
+        let value = $value
+            .as_i64()
+            .map(i128::from)
+            .or_else(|| $value.as_u64().map(i128::from))
+            .ok_or_else(|| anyhow!("Expected an integer, got {}", $value))?;
+        let value = $t::try_from(value)
+            .map_err(|_| anyhow!("{} is out of range for {}", value, stringify!($t)))?;
+        $bytes.extend_from_slice(&value.to_le_bytes());
+    }};
+}
+
+/// Convert JSON tensor data to the little-endian bytes used by [`SerializedTensor`]
+fn to_le_bytes(datum_type: ModelDatumType, data: &Value) -> Result<Vec<u8>> {
+    let mut values = vec![];
+    flatten(data, &mut values);
+
+    let mut bytes = vec![];
+    for value in values {
+        match datum_type {
+            ModelDatumType::Bool => {
+                let value = value
+                    .as_bool()
+                    .ok_or_else(|| anyhow!("Expected a boolean, got {}", value))?;
+                bytes.push(value as u8);
+            }
+            ModelDatumType::F32 | ModelDatumType::F64 => {
+                let value = value
+                    .as_f64()
+                    .ok_or_else(|| anyhow!("Expected a number, got {}", value))?;
+                if datum_type == ModelDatumType::F32 {
+                    bytes.extend_from_slice(&(value as f32).to_le_bytes());
+                } else {
+                    bytes.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+            ModelDatumType::U8 => integer_to_le_bytes!(u8, value, bytes),
+            ModelDatumType::U16 => integer_to_le_bytes!(u16, value, bytes),
+            ModelDatumType::U32 => integer_to_le_bytes!(u32, value, bytes),
+            ModelDatumType::U64 => integer_to_le_bytes!(u64, value, bytes),
+            ModelDatumType::I8 => integer_to_le_bytes!(i8, value, bytes),
+            ModelDatumType::I16 => integer_to_le_bytes!(i16, value, bytes),
+            ModelDatumType::I32 => integer_to_le_bytes!(i32, value, bytes),
+            ModelDatumType::I64 => integer_to_le_bytes!(i64, value, bytes),
+        }
+    }
+    Ok(bytes)
+}
+
+macro_rules! from_le_bytes {
+    ($t:ident, $bytes:expr) => {
+        $bytes
+            .chunks_exact(std::mem::size_of::<$t>())
+            .map(|chunk| Value::from($t::from_le_bytes(chunk.try_into().unwrap())))
+            .collect()
+    };
+}
+
+/// Convert the little-endian bytes of a [`SerializedTensor`] to flat JSON data
+fn from_le_bytes(datum_type: ModelDatumType, bytes: &[u8]) -> Vec<Value> {
+    match datum_type {
+        ModelDatumType::Bool => bytes.iter().map(|b| Value::from(*b != 0)).collect(),
+        ModelDatumType::U8 => from_le_bytes!(u8, bytes),
+        ModelDatumType::U16 => from_le_bytes!(u16, bytes),
+        ModelDatumType::U32 => from_le_bytes!(u32, bytes),
+        ModelDatumType::U64 => from_le_bytes!(u64, bytes),
+        ModelDatumType::I8 => from_le_bytes!(i8, bytes),
+        ModelDatumType::I16 => from_le_bytes!(i16, bytes),
+        ModelDatumType::I32 => from_le_bytes!(i32, bytes),
+        ModelDatumType::I64 => from_le_bytes!(i64, bytes),
+        ModelDatumType::F32 => from_le_bytes!(f32, bytes),
+        ModelDatumType::F64 => from_le_bytes!(f64, bytes),
+    }
+}
+
+fn metadata_tensor(tensor: TensorMetadata) -> MetadataTensor {
+    MetadataTensor {
+        name: tensor.name,
+        datatype: datatype(tensor.datum_type),
+        shape: tensor.shape,
+    }
+}
+
+fn model_metadata(exchanger: &Exchanger, name: String) -> Result<ModelMetadata> {
+    let uuid = exchanger.find_model(&name)?;
+    let (inputs, outputs) = exchanger.with_model(uuid, |model| -> Result<_> {
+        let policy = model.policy();
+        let outputs = model
+            .outputs_metadata()?
+            .into_iter()
+            .filter(|output| policy.allows_output(&output.name))
+            .map(metadata_tensor)
+            .collect();
+        let inputs = model
+            .inputs_metadata()?
+            .into_iter()
+            .map(metadata_tensor)
+            .collect();
+        Ok((inputs, outputs))
+    })??;
+
+    Ok(ModelMetadata {
+        name,
+        platform: "onnx",
+        inputs,
+        outputs,
+    })
+}
+
+fn infer(
+    exchanger: &Exchanger,
+    request: &rouille::Request,
+    name: String,
+) -> Result<InferenceResponse> {
+    let inference_request: InferenceRequest =
+        serde_json::from_slice(&exchanger.read_input_body(request)?)?;
+
+    let inputs = inference_request
+        .inputs
+        .into_iter()
+        .map(|input| {
+            let datum_type = datum_type(&input.datatype)?;
+            Ok(SerializedTensor {
+                bytes_data: to_le_bytes(datum_type, &input.data)?,
+                info: TensorInfo {
+                    fact: input.shape,
+                    datum_type,
+                    node_name: Some(input.name),
+                },
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let uuid = exchanger.find_model(&name)?;
+    let mut outputs = exchanger.infer(
+        Workload::Query,
+        caller_identity(request).as_deref(),
+        uuid,
+        inputs,
+    )?;
+    if let Some(requested) = inference_request.outputs {
+        outputs.retain(|output| {
+            requested
+                .iter()
+                .any(|r| output.info.node_name.as_deref() == Some(r.name.as_str()))
+        });
+    }
+
+    Ok(InferenceResponse {
+        model_name: name,
+        id: inference_request.id,
+        outputs: outputs
+            .into_iter()
+            .map(|output| ResponseOutput {
+                name: output.info.node_name.unwrap_or_default(),
+                data: from_le_bytes(output.info.datum_type, &output.bytes_data),
+                datatype: datatype(output.info.datum_type),
+                shape: output.info.fact,
+            })
+            .collect(),
+    })
+}
+
+fn respond<T: serde::Serialize>(reply: Result<T>) -> rouille::Response {
+    match reply {
+        Ok(reply) => rouille::Response::json(&reply),
+        Err(e) => {
+            let status_code = if e.is::<ModelNotFound>() { 404 } else { 400 };
+            rouille::Response::json(&ErrorResponse {
+                error: format!("{}", e),
+            })
+            .with_status_code(status_code)
+        }
+    }
+}
+
+/// Routes of the Open Inference Protocol, served next to `/run` on the
+/// attested port
+pub(crate) fn router(
+    exchanger: &Exchanger,
+    status: &ServerStatus,
+    request: &rouille::Request,
+) -> rouille::Response {
+    labelled_router!(request,
+        (GET) (/v2) => {
+            respond(Ok(ServerMetadata {
+                name: "blindai",
+                version: env!("CARGO_PKG_VERSION"),
+                extensions: vec![],
+            }))
+        },
+        (GET) (/v2/health/live) => {
+            rouille::Response::text("")
+        },
+        (GET) (/v2/health/ready) => {
+            let status_code = if status.readiness().ready { 200 } else { 503 };
+            rouille::Response::text("").with_status_code(status_code)
+        },
+        (GET) (/v2/models/{name: String}) => {
+            respond(model_metadata(exchanger, name))
+        },
+        (GET) (/v2/models/{name: String}/ready) => {
+            match exchanger.find_model(&name).and_then(|uuid| exchanger.with_model(uuid, |_| ())) {
+                Ok(()) => rouille::Response::text(""),
+                Err(_) => rouille::Response::empty_404(),
+            }
+        },
+        (POST) (/v2/models/{name: String}/infer) => {
+            respond(infer(exchanger, request, name))
+        },
+        _ => rouille::Response::empty_404()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn datatypes() {
+        for model_datum_type in [
+            ModelDatumType::Bool,
+            ModelDatumType::U8,
+            ModelDatumType::U16,
+            ModelDatumType::U32,
+            ModelDatumType::U64,
+            ModelDatumType::I8,
+            ModelDatumType::I16,
+            ModelDatumType::I32,
+            ModelDatumType::I64,
+            ModelDatumType::F32,
+            ModelDatumType::F64,
+        ] {
+            assert_eq!(
+                datum_type(datatype(model_datum_type)).unwrap(),
+                model_datum_type
+            );
+        }
+        assert!(datum_type("BYTES").is_err());
+    }
+
+    #[test]
+    fn unknown_models_are_not_found() {
+        let not_found = respond::<()>(Err(ModelNotFound.into()));
+        assert_eq!(not_found.status_code, 404);
+        let bad_request = respond::<()>(Err(anyhow!("Unsupported datatype")));
+        assert_eq!(bad_request.status_code, 400);
+        // The error may be wrapped with some context
+        let wrapped = respond::<()>(Err(anyhow::Error::from(ModelNotFound).context("infer")));
+        assert_eq!(wrapped.status_code, 404);
+    }
+
+    #[test]
+    fn tensor_data() {
+        let bytes = to_le_bytes(ModelDatumType::I32, &json!([[-50, 10], [1000, 0]])).unwrap();
+        assert_eq!(
+            bytes,
+            b"\xce\xff\xff\xff\n\x00\x00\x00\xe8\x03\x00\x00\x00\x00\x00\x00"
+        );
+        assert_eq!(
+            from_le_bytes(ModelDatumType::I32, &bytes),
+            vec![json!(-50), json!(10), json!(1000), json!(0)]
+        );
+
+        let bytes = to_le_bytes(ModelDatumType::F32, &json!([1.0, 2.0, 3.0])).unwrap();
+        assert_eq!(bytes, b"\x00\x00\x80?\x00\x00\x00@\x00\x00@@");
+        assert_eq!(
+            from_le_bytes(ModelDatumType::F32, &bytes),
+            vec![json!(1.0), json!(2.0), json!(3.0)]
+        );
+
+        let bytes = to_le_bytes(ModelDatumType::Bool, &json!([true, false])).unwrap();
+        assert_eq!(
+            from_le_bytes(ModelDatumType::Bool, &bytes),
+            vec![json!(true), json!(false)]
+        );
+
+        assert!(to_le_bytes(ModelDatumType::U8, &json!([256])).is_err());
+        assert!(to_le_bytes(ModelDatumType::I64, &json!([1.5])).is_err());
+        assert!(to_le_bytes(ModelDatumType::Bool, &json!([1])).is_err());
+    }
+}
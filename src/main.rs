@@ -16,25 +16,56 @@
 
 use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
+mod audit_log;
+mod collateral;
+mod compute;
+mod encoding;
+mod health;
 mod identity;
+mod jobs;
+mod key_broker;
+mod kserve;
+mod metrics;
 mod model;
 mod model_store;
-use crate::client_communication::Exchanger;
+mod policy;
+mod protocol;
+mod quoting;
+mod sealing;
+#[cfg(not(target_env = "sgx"))]
+mod simulation;
+mod streaming;
+mod tcb_status;
+mod threads;
+use crate::client_communication::{caller_identity, Exchanger};
 use anyhow::Result;
 use model_store::ModelStore;
 mod client_communication;
 use lazy_static::lazy_static;
-use log::debug;
+use log::{debug, warn};
 mod telemetry;
 mod ureq_dns_resolver;
+mod websocket;
 use telemetry::Telemetry;
 
 // ra
+use audit_log::AuditLog;
+use collateral::CollateralStore;
+use compute::{ComputePool, Workload};
 use env_logger::Env;
-use ring::digest;
-use serde::{Deserialize, Serialize};
+use health::{ServerStatus, UnattestedRoutes};
+use jobs::JobQueue;
+use key_broker::{AttestationEvidence, KeyBroker};
+use metrics::{labelled_router, Gauges, Metrics};
+use quote_verification::Verifier;
+use quoting::FreshQuotes;
+use serde::Serialize;
 use serde_bytes::Bytes;
 use sgx_isa::{Report, Targetinfo};
+use streaming::Streams;
+use tcb_status::TcbPolicy;
+use threads::{ThreadBudget, ENCLAVE_THREADS};
 
 #[derive(Serialize)]
 struct GetQuoteRequest {
@@ -47,31 +78,18 @@ struct GetCollateralRequest {
 }
 
 lazy_static! {
-    static ref EXCHANGER: Arc<Exchanger> = Arc::new(Exchanger::new(
-        Arc::new(ModelStore::new()),
-        1_000_000_000,
-        1_000_000,
-    ));
     pub static ref TELEMETRY_CHANNEL: Arc<Telemetry> = Arc::new(Telemetry::new().unwrap());
 }
 
-// "Native" Rust type for sgx_ql_qve_collateral_t
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct SgxCollateral {
-    pub version: u32,                  // version = 1.  PCK Cert chain is in the Quote.
-    pub pck_crl_issuer_chain: String,  // PCK CRL Issuer Chain in PEM format
-    pub root_ca_crl: String,           // Root CA CRL in PEM format
-    pub pck_crl: String,               // PCK Cert CRL in PEM format
-    pub tcb_info_issuer_chain: String, // PEM
-    pub tcb_info: String,              // TCB Info structure
-    pub qe_identity_issuer_chain: String, // PEM
-    pub qe_identity: String,           // QE Identity Structure
-    pub pck_certificate: String,       // PCK certificate in PEM format
-    pub pck_signing_chain: String,     // PCK signing chain in PEM format
-}
+// "Native" Rust type for sgx_ql_qve_collateral_t, shared with the verifier
+pub use quote_verification::SgxCollateral;
 
 const RUNNER_ADDRESS: &str = "http://127.0.0.1:11000";
 
+/// Threads handling the requests of the unattested, management and metrics
+/// servers, the attested one being sized by `BLINDAI_HTTP_THREADS`
+const SIDE_SERVER_THREADS: usize = 2;
+
 fn get_target_info() -> Result<Targetinfo> {
     Ok(ureq::post(&format!("{RUNNER_ADDRESS}/get_target_info"))
         .call()?
@@ -123,9 +141,46 @@ fn main() -> Result<()> {
     #[cfg(not(debug_assertions))]
     env_logger::Builder::from_env(Env::default().default_filter_or("error")).init();
 
+    // Connection threads only wait for the compute pool during inferences, so
+    // there may be more of them than compute threads
+    let http_threads = match std::env::var("BLINDAI_HTTP_THREADS") {
+        Ok(value) => value.parse()?,
+        Err(_) => 8,
+    };
+    // Connections open at once on each port, as bounded by the runner
+    let max_connections = match std::env::var("BLINDAI_MAX_CONNECTIONS") {
+        Ok(value) => value.parse()?,
+        Err(_) => 16,
+    };
+    let metrics_port = Metrics::port_from_env()?;
+
+    let mut threads = ThreadBudget::new();
+    threads.add_server("unattested server", max_connections, SIDE_SERVER_THREADS);
+    threads.add_server("management server", max_connections, SIDE_SERVER_THREADS);
+    threads.add_server("attested server", max_connections, http_threads);
+    if metrics_port.is_some() {
+        threads.add_server("metrics server", max_connections, SIDE_SERVER_THREADS);
+    }
+    // The management and attested servers are started from threads of their own
+    threads.add("server launchers", 2);
+    threads.add("compute workers", ComputePool::threads_from_env()?);
+    threads.add("job workers", jobs::workers_from_env()?);
+    threads.add("streams", Streams::max_streams_from_env()?);
+    // Collateral refresher, expiry loop and telemetry
+    threads.add("background tasks", 3);
+    threads.check(ENCLAVE_THREADS)?;
+
     let certificate_with_secret = identity::create_tls_certificate()?;
     let enclave_cert_der = Arc::new(certificate_with_secret.serialize_der()?);
     let enclave_private_key_der = certificate_with_secret.serialize_private_key_der();
+    let enclave_key = Arc::new(identity::EnclaveKey::generate());
+
+    let model_store = Arc::new(ModelStore::new());
+    let metrics = Arc::new(Metrics::from_env());
+    let status = Arc::new(ServerStatus::new(
+        Arc::clone(&model_store),
+        !telemetry_disabled,
+    ));
 
     fn respond(x: &(impl Serialize + ?Sized)) -> rouille::Response {
         match serde_cbor::to_vec(&x) {
@@ -139,41 +194,123 @@ fn main() -> Result<()> {
         .with_additional_header("Server", SERVER_NAME)
     }
 
+    let ra_tls = identity::RaTls::from_env()?;
+    let tcb_policy = TcbPolicy::from_env()?;
+    let capabilities = Arc::new(protocol::capabilities(
+        KeyBroker::is_configured(),
+        ra_tls.is_enabled(),
+    ));
+
+    // The unattested port is served during the attestation, for `/ready`
+    let unattested_routes = Arc::new(UnattestedRoutes::new(Arc::clone(&status)));
+    let unattested_server = rouille::Server::new("0.0.0.0:9923", {
+        let unattested_routes = Arc::clone(&unattested_routes);
+        let metrics = Arc::clone(&metrics);
+        move |request: &rouille::Request| {
+            metrics.handle(request, || unattested_routes.handle(request))
+        }
+    })
+    .expect("Failed to start unattested server")
+    .pool_size(SIDE_SERVER_THREADS);
+    let (_unattested_handle, _unattested_sender) = unattested_server.stoppable();
+
     // Remote attestation
     // Connecting to the runner
 
-    // Enclave held data hash
-    let report_binding = digest::digest(&digest::SHA256, &enclave_cert_der);
-    let mut report_data = [0u8; 64];
-    report_data[0..32].copy_from_slice(report_binding.as_ref());
-
     cfg_if::cfg_if! {
         if #[cfg(target_env = "sgx")] {
+            // Enclave held data hash
+            let enclave_held_data = if ra_tls.is_enabled() {
+                Arc::new(certificate_with_secret.get_key_pair().public_key_der())
+            } else {
+                Arc::clone(&enclave_cert_der)
+            };
+            let report_data = identity::report_data(&enclave_held_data, &enclave_key, &[]);
+
             let target_info = get_target_info()?;
             debug!("target info = {:?} ", &target_info);
             let report = Report::for_target(&target_info, &report_data);
 
             let quote = get_quote(report)?;
             debug!("Attestation : Quote is {:?} ", &quote);
+            status.set_quote_obtained();
+
+            let collateral = Arc::new(CollateralStore::from_env(Box::new({
+                let quote = quote.clone();
+                move || get_collateral(&quote)
+            }))?);
+            debug!("Attestation : Collateral is {:?} ", collateral.current().collateral);
+            status.set_collateral_obtained();
+            collateral.spawn_refresher();
+
+            let (platform, tcb_evaluation) = tcb_status::evaluate(
+                &Verifier::new(),
+                &quote,
+                &collateral.current().collateral,
+            );
+            let platform = Arc::new(platform);
+            tcb_policy.check(tcb_evaluation.as_ref())?;
+            let tcb_evaluation = Arc::new(tcb_evaluation);
+
+            // With RA-TLS, the quote is embedded in the certificate served on
+            // every port
+            let enclave_cert_der = if ra_tls.is_enabled() {
+                let certificate = ra_tls.create_certificate(&certificate_with_secret, &quote)?;
+                Arc::new(certificate.serialize_der()?)
+            } else {
+                enclave_cert_der
+            };
 
-            let collateral = get_collateral(&quote)?;
-            debug!("Attestation : Collateral is {:?} ", collateral);
+            let evidence = AttestationEvidence {
+                quote: quote.clone(),
+                collateral: Some(Arc::clone(&collateral)),
+                certificate: enclave_cert_der.to_vec(),
+            };
+
+            let fresh_quotes = FreshQuotes::from_env(
+                Box::new(move |report_data| {
+                    get_quote(Report::for_target(&target_info, report_data))
+                }),
+                enclave_held_data,
+                Arc::clone(&enclave_key),
+            )?;
 
             let router = {
                 let enclave_cert_der = Arc::clone(&enclave_cert_der);
+                let capabilities = Arc::clone(&capabilities);
+                let enclave_key = Arc::clone(&enclave_key);
                 move |request: &rouille::Request| {
-                    rouille::router!(request,
+                    labelled_router!(request,
                         (GET)(/) => {
                             debug!("Requested enclave TLS certificate");
                             respond(Bytes::new(&enclave_cert_der))
                         },
+                        (GET)(/capabilities) => {
+                            rouille::Response::json(&*capabilities)
+                        },
+                        (GET)(/enclave_key) => {
+                            respond(Bytes::new(enclave_key.public_key()))
+                        },
                         (GET)(/quote) => {
                             debug!("Attestation : Sending quote to client.");
-                            respond(Bytes::new(&quote))
+                            fresh_quotes.respond(request, &quote, |quote| respond(Bytes::new(quote)))
+                        },
+                        (POST)(/quote) => {
+                            debug!("Attestation : Sending fresh quote to client.");
+                            fresh_quotes.respond(request, &quote, |quote| respond(Bytes::new(quote)))
                         },
                         (GET)(/collateral) => {
                             debug!("Attestation : Sending collateral to client.");
-                            respond(&collateral)
+                            respond(&collateral.current().collateral)
+                        },
+                        (GET)(/collateral/status) => {
+                            collateral.respond_status()
+                        },
+                        (GET)(/platform) => {
+                            rouille::Response::json(&*platform)
+                        },
+                        (GET)(/tcb_status) => {
+                            rouille::Response::json(&*tcb_evaluation)
                         },
                         _ => {
                             rouille::Response::empty_404()
@@ -182,14 +319,85 @@ fn main() -> Result<()> {
                 }
             };
         } else {
+            // Simulated attestation, the quotes can only be verified by
+            // verifiers accepting simulated quotes
+            if ra_tls.is_enabled() {
+                warn!(
+                    "Attestation : BLINDAI_RA_TLS is ignored with simulated attestation, \
+                     the certificate carries no quote"
+                );
+            }
+            let quoting_enclave = Arc::new(simulation::SimulatedQuotingEnclave::generate()?);
+            let report_data = identity::report_data(&enclave_cert_der, &enclave_key, &[]);
+            let quote = quoting_enclave.quote(&report_data)?;
+            status.set_quote_obtained();
+
+            let collateral = Arc::new(CollateralStore::from_env(Box::new({
+                let quoting_enclave = Arc::clone(&quoting_enclave);
+                move || quoting_enclave.collateral()
+            }))?);
+            status.set_collateral_obtained();
+            collateral.spawn_refresher();
+
+            // Evaluated like the Intel collateral, trusting the simulated PKI
+            let (platform, tcb_evaluation) = tcb_status::evaluate(
+                &Verifier::new().allow_simulated(),
+                &quote,
+                &collateral.current().collateral,
+            );
+            let platform = Arc::new(platform);
+            tcb_policy.check(tcb_evaluation.as_ref())?;
+            let tcb_evaluation = Arc::new(tcb_evaluation);
+
+            let evidence = AttestationEvidence {
+                quote: quote.clone(),
+                collateral: Some(Arc::clone(&collateral)),
+                certificate: enclave_cert_der.to_vec(),
+            };
+
+            let fresh_quotes = FreshQuotes::from_env(
+                Box::new(move |report_data| quoting_enclave.quote(report_data)),
+                Arc::clone(&enclave_cert_der),
+                Arc::clone(&enclave_key),
+            )?;
+
             let router = {
                 let enclave_cert_der = Arc::clone(&enclave_cert_der);
+                let capabilities = Arc::clone(&capabilities);
+                let enclave_key = Arc::clone(&enclave_key);
                 move |request: &rouille::Request| {
-                    rouille::router!(request,
+                    labelled_router!(request,
                         (GET)(/) => {
                             debug!("Requested enclave TLS certificate");
                             respond(Bytes::new(&enclave_cert_der))
                         },
+                        (GET)(/capabilities) => {
+                            rouille::Response::json(&*capabilities)
+                        },
+                        (GET)(/enclave_key) => {
+                            respond(Bytes::new(enclave_key.public_key()))
+                        },
+                        (GET)(/quote) => {
+                            debug!("Attestation : Sending simulated quote to client.");
+                            fresh_quotes.respond(request, &quote, |quote| respond(Bytes::new(quote)))
+                        },
+                        (POST)(/quote) => {
+                            debug!("Attestation : Sending fresh simulated quote to client.");
+                            fresh_quotes.respond(request, &quote, |quote| respond(Bytes::new(quote)))
+                        },
+                        (GET)(/collateral) => {
+                            debug!("Attestation : Sending simulated collateral to client.");
+                            respond(&collateral.current().collateral)
+                        },
+                        (GET)(/collateral/status) => {
+                            collateral.respond_status()
+                        },
+                        (GET)(/platform) => {
+                            rouille::Response::json(&*platform)
+                        },
+                        (GET)(/tcb_status) => {
+                            rouille::Response::json(&*tcb_evaluation)
+                        },
                         _ => {
                             rouille::Response::empty_404()
                         },
@@ -199,27 +407,102 @@ fn main() -> Result<()> {
         }
     };
 
-    let unattested_server =
-        rouille::Server::new("0.0.0.0:9923", router).expect("Failed to start unattested server");
+    unattested_routes.set_attestation_routes(Box::new(router));
 
-    let (_unattested_handle, _unattested_sender) = unattested_server.stoppable();
+    let key_broker = KeyBroker::from_env(evidence, Arc::clone(&enclave_key)).map(Arc::new);
+    let audit_log = Arc::new(AuditLog::from_env(&enclave_private_key_der)?);
+    let compute = Arc::new(ComputePool::from_env()?);
+    let exchanger = Arc::new(Exchanger::new(
+        Arc::clone(&model_store),
+        1_000_000_000,
+        1_000_000,
+        key_broker,
+        audit_log,
+        Arc::clone(&metrics),
+        Arc::clone(&enclave_key),
+        Arc::clone(&compute),
+    ));
+    let jobs = JobQueue::from_env(Arc::new({
+        let exchanger = Arc::clone(&exchanger);
+        move |run_request| exchanger.run(Workload::Background, run_request)
+    }))?;
+    let streams = Streams::from_env(Arc::clone(&exchanger))?;
+
+    if let Some(port) = metrics_port {
+        let model_store = Arc::clone(&model_store);
+        let metrics = Arc::clone(&metrics);
+        let compute = Arc::clone(&compute);
+        let jobs = Arc::clone(&jobs);
+        let metrics_server = rouille::Server::new(format!("0.0.0.0:{port}"), move |request| {
+            rouille::router!(request,
+                (GET) (/metrics) => {
+                    let text = metrics.render(&Gauges {
+                        models_loaded: model_store.model_count(),
+                        model_store_bytes: model_store.weights_size(),
+                        queued_inferences: compute.queued(),
+                        pending_jobs: jobs.pending(),
+                    });
+                    rouille::Response::from_data("text/plain; version=0.0.4", text)
+                },
+                _ => rouille::Response::empty_404()
+            )
+        })
+        .expect("Failed to start metrics server")
+        .pool_size(SIDE_SERVER_THREADS);
+        thread::spawn(move || metrics_server.run());
+        println!("Metrics are exposed on 0.0.0.0:{port}/metrics");
+    }
 
-    let router_management = |request: &rouille::Request| {
-        rouille::router!(request,
-            (POST) (/upload) => {
-                let reply = EXCHANGER.send_model(request);
-                EXCHANGER.respond(request, reply)
-            },
-
-            (POST) (/delete) => {
-                let reply = EXCHANGER.delete_model(request);
-                EXCHANGER.respond(request, reply)
-            },
-            _ => rouille::Response::empty_404()
-        )
+    // Remove the models whose time-to-live has elapsed and the job results
+    // that were not fetched in time
+    thread::spawn({
+        let exchanger = Arc::clone(&exchanger);
+        let jobs = Arc::clone(&jobs);
+        move || loop {
+            thread::sleep(Duration::from_secs(10));
+            exchanger.delete_expired_models();
+            jobs.delete_expired_jobs();
+        }
+    });
+
+    let router_management = {
+        let exchanger = Arc::clone(&exchanger);
+        let metrics = Arc::clone(&metrics);
+        move |request: &rouille::Request| {
+            metrics.handle(request, || {
+                labelled_router!(request,
+                    (POST) (/upload) => {
+                        let reply = exchanger.send_model(request);
+                        exchanger.respond(request, reply)
+                    },
+
+                    (POST) (/delete) => {
+                        let reply = exchanger.delete_model(request);
+                        exchanger.respond(request, reply)
+                    },
+
+                    (POST) (/replace) => {
+                        let reply = exchanger.replace_model(request);
+                        exchanger.respond(request, reply)
+                    },
+
+                    (POST) (/set_policy) => {
+                        let reply = exchanger.set_policy(request);
+                        exchanger.respond(request, reply)
+                    },
+
+                    (POST) (/audit_log) => {
+                        let reply = exchanger.get_audit_log(request);
+                        exchanger.respond(request, reply)
+                    },
+                    _ => rouille::Response::empty_404()
+                )
+            })
+        }
     };
 
     thread::spawn({
+        let status = Arc::clone(&status);
         let enclave_cert_der_s = enclave_cert_der.to_vec();
         let priv_der = enclave_private_key_der.clone();
         move || {
@@ -231,7 +514,9 @@ fn main() -> Result<()> {
                     private_key: priv_der.clone(),
                 }),
             )
-            .expect("Failed to start management server");
+            .expect("Failed to start management server")
+            .pool_size(SIDE_SERVER_THREADS);
+            status.set_management_server_started();
 
             let (_management_handle, _management_sender) = management_server.stoppable();
             _management_handle.join().unwrap();
@@ -240,18 +525,49 @@ fn main() -> Result<()> {
 
     println!("Models can be managed on 0.0.0.0:9925");
 
-    let router = move |request: &rouille::Request| {
-        rouille::router!(request,
-            (POST) (/run) => {
-                let reply = EXCHANGER.run_model(request);
-                EXCHANGER.respond(request, reply)
-            },
-            _ => rouille::Response::empty_404()
-        )
+    let router = {
+        let exchanger = Arc::clone(&exchanger);
+        let status = Arc::clone(&status);
+        let jobs = Arc::clone(&jobs);
+        let streams = Arc::clone(&streams);
+        let metrics = Arc::clone(&metrics);
+        move |request: &rouille::Request| {
+            metrics.handle(request, || {
+                labelled_router!(request,
+                    (POST) (/run) => {
+                        let reply = exchanger.run_model(request);
+                        exchanger.respond(request, reply)
+                    },
+                    (POST) (/jobs) => {
+                        let reply = exchanger
+                            .read_run_request(request)
+                            .and_then(|run_request| jobs.submit(caller_identity(request), run_request));
+                        exchanger.respond(request, reply)
+                    },
+                    (GET) (/jobs/{id: String}) => {
+                        let reply = jobs.status(caller_identity(request).as_deref(), &id);
+                        exchanger.respond(request, reply)
+                    },
+                    (GET) (/stream) => {
+                        streams.start(request)
+                    },
+                    (POST) (/policy) => {
+                        let reply = exchanger.get_policy(request);
+                        exchanger.respond(request, reply)
+                    },
+                    (GET) (/audit_log/head) => {
+                        let reply = exchanger.get_audit_log_head();
+                        exchanger.respond(request, reply)
+                    },
+                    _ => kserve::router(&exchanger, &status, request)
+                )
+            })
+        }
     };
 
     thread::spawn({
         let enclave_cert_der = Arc::clone(&enclave_cert_der);
+        let status = Arc::clone(&status);
         move || {
             let attested_server = rouille::Server::new_ssl(
                 "0.0.0.0:9924",
@@ -262,7 +578,8 @@ fn main() -> Result<()> {
                 }),
             )
             .expect("Failed to start trusted server")
-            .pool_size(8);
+            .pool_size(http_threads);
+            status.set_attested_server_started();
             let (_trusted_handle, _trusted_sender) = attested_server.stoppable();
             _trusted_handle.join().unwrap();
         }
@@ -0,0 +1,384 @@
+// Copyright 2022 Mithril Security. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Operational metrics in the Prometheus text format.
+//!
+//! The metrics server is enabled by setting `BLINDAI_METRICS_PORT`. Only
+//! aggregate numbers are exposed: request counts by endpoint and status code,
+//! requests in flight, inference latencies, the size of the model store and
+//! the depth of the inference and job queues. Endpoints are labelled with the
+//! pattern of the route they matched in a [`labelled_router`].
+//! Nothing derived from the inputs or outputs is recorded. Inference latencies
+//! are labelled with the model id only if `BLINDAI_METRICS_MODEL_LABELS` is
+//! set, model names are never used as labels.
+
+use anyhow::{anyhow, Result};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds (in seconds) of the inference latency histogram buckets
+const LATENCY_BUCKETS: [f64; 13] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if value <= bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+pub(crate) struct Metrics {
+    model_labels: bool,
+    requests: Mutex<BTreeMap<(String, u16), u64>>,
+    in_flight: AtomicI64,
+    inference_latency: Mutex<BTreeMap<Option<String>, Histogram>>,
+}
+
+thread_local! {
+    /// Route matched by the request being handled on this thread
+    static ROUTE: Cell<Option<&'static str>> = const { Cell::new(None) };
+}
+
+/// Record `route` as the route matched by the request being handled, see
+/// [`labelled_router`]
+pub(crate) fn set_route(route: &'static str) {
+    ROUTE.with(|matched| matched.set(Some(route)));
+}
+
+/// `rouille::router!`, also recording the pattern of the matched route as the
+/// endpoint label of the request
+macro_rules! labelled_router {
+    ($request:expr, $(($method:ident) ($($pattern:tt)+) => $handler:block,)* _ => $default:expr $(,)*) => {
+        rouille::router!($request,
+            $(($method) ($($pattern)+) => {
+                $crate::metrics::set_route(stringify!($($pattern)+));
+                $handler
+            },)*
+            _ => $default
+        )
+    };
+}
+pub(crate) use labelled_router;
+
+/// Endpoint label of a route pattern, `/v2/models/{name}/infer` for
+/// `(/v2/models/{name: String}/infer)`. Requests that matched no route are
+/// labelled `other`, so that the label never contains a model name or id.
+fn endpoint_label(route: Option<&str>) -> String {
+    let Some(route) = route else {
+        return "other".to_string();
+    };
+    let mut label = String::new();
+    let mut in_type = false;
+    for c in route.chars().filter(|c| !c.is_whitespace()) {
+        match c {
+            ':' => in_type = true,
+            '}' => {
+                in_type = false;
+                label.push(c);
+            }
+            _ if !in_type => label.push(c),
+            _ => (),
+        }
+    }
+    label
+}
+
+fn labels<'a>(labels: impl Iterator<Item = &'a String>) -> String {
+    let labels: Vec<&str> = labels.map(String::as_str).collect();
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels.join(","))
+    }
+}
+
+/// Gauges sampled when the metrics are rendered
+pub(crate) struct Gauges {
+    pub models_loaded: usize,
+    pub model_store_bytes: usize,
+    /// Inferences waiting for a compute thread
+    pub queued_inferences: usize,
+    /// Jobs waiting for a job worker
+    pub pending_jobs: usize,
+}
+
+/// Keeps a request counted as in flight until dropped, even if the handler
+/// panics
+struct InFlight<'a>(&'a AtomicI64);
+
+impl<'a> InFlight<'a> {
+    fn new(counter: &'a AtomicI64) -> Self {
+        counter.fetch_add(1, Ordering::SeqCst);
+        InFlight(counter)
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Metrics {
+    pub fn new(model_labels: bool) -> Self {
+        Metrics {
+            model_labels,
+            requests: Mutex::new(BTreeMap::new()),
+            in_flight: AtomicI64::new(0),
+            inference_latency: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        Self::new(std::env::var("BLINDAI_METRICS_MODEL_LABELS").is_ok())
+    }
+
+    /// Port of the metrics server, if enabled
+    pub fn port_from_env() -> Result<Option<u16>> {
+        match std::env::var("BLINDAI_METRICS_PORT") {
+            Ok(port) => Self::parse_port(&port).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    fn parse_port(port: &str) -> Result<u16> {
+        port.parse()
+            .map_err(|_| anyhow!("BLINDAI_METRICS_PORT is not a valid port: {:?}", port))
+    }
+
+    /// Handle a request with `handler`, counting it and its status code under
+    /// the route it matched in a [`labelled_router`]
+    pub fn handle(
+        &self,
+        request: &rouille::Request,
+        handler: impl FnOnce() -> rouille::Response,
+    ) -> rouille::Response {
+        ROUTE.with(|matched| matched.set(None));
+        let response = {
+            let _in_flight = InFlight::new(&self.in_flight);
+            handler()
+        };
+
+        let route = ROUTE.with(Cell::take);
+        let key = (endpoint_label(route), response.status_code);
+        *self.requests.lock().unwrap().entry(key).or_insert(0) += 1;
+        response
+    }
+
+    pub fn observe_inference(&self, model_id: &str, duration: Duration) {
+        let label = self.model_labels.then(|| model_id.to_string());
+        self.inference_latency
+            .lock()
+            .unwrap()
+            .entry(label)
+            .or_default()
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn render(&self, gauges: &Gauges) -> String {
+        let mut out = String::new();
+
+        out.push_str(
+            "# HELP blindai_requests_total Requests handled, by endpoint and status code\n",
+        );
+        out.push_str("# TYPE blindai_requests_total counter\n");
+        for ((endpoint, code), count) in self.requests.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "blindai_requests_total{{endpoint=\"{endpoint}\",code=\"{code}\"}} {count}"
+            );
+        }
+
+        out.push_str("# HELP blindai_requests_in_flight Requests being handled\n");
+        out.push_str("# TYPE blindai_requests_in_flight gauge\n");
+        let _ = writeln!(
+            out,
+            "blindai_requests_in_flight {}",
+            self.in_flight.load(Ordering::SeqCst)
+        );
+
+        out.push_str("# HELP blindai_inference_duration_seconds Duration of the inferences\n");
+        out.push_str("# TYPE blindai_inference_duration_seconds histogram\n");
+        for (model, histogram) in self.inference_latency.lock().unwrap().iter() {
+            let model_label = model.as_ref().map(|model| format!("model=\"{model}\""));
+            let bounds = LATENCY_BUCKETS.iter().map(|bound| bound.to_string());
+            let counts = histogram.buckets.iter();
+            for (bound, count) in bounds
+                .chain(["+Inf".to_string()])
+                .zip(counts.chain([&histogram.count]))
+            {
+                let le_label = format!("le=\"{bound}\"");
+                let _ = writeln!(
+                    out,
+                    "blindai_inference_duration_seconds_bucket{} {count}",
+                    labels(model_label.iter().chain([&le_label]))
+                );
+            }
+            let _ = writeln!(
+                out,
+                "blindai_inference_duration_seconds_sum{} {}",
+                labels(model_label.iter()),
+                histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "blindai_inference_duration_seconds_count{} {}",
+                labels(model_label.iter()),
+                histogram.count
+            );
+        }
+
+        for (name, help, value) in [
+            (
+                "blindai_models_loaded",
+                "Models in the model store",
+                gauges.models_loaded,
+            ),
+            (
+                "blindai_model_store_bytes",
+                "Memory held by the weights of the loaded models",
+                gauges.model_store_bytes,
+            ),
+            (
+                "blindai_queued_inferences",
+                "Inferences waiting for a compute thread",
+                gauges.queued_inferences,
+            ),
+            (
+                "blindai_pending_jobs",
+                "Jobs waiting for a job worker",
+                gauges.pending_jobs,
+            ),
+        ] {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} gauge");
+            let _ = writeln!(out, "{name} {value}");
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gauges() -> Gauges {
+        Gauges {
+            models_loaded: 2,
+            model_store_bytes: 1000,
+            queued_inferences: 3,
+            pending_jobs: 4,
+        }
+    }
+
+    #[test]
+    fn endpoint_labels_hide_model_names() {
+        let metrics = Metrics::new(false);
+        for (method, url) in [
+            ("POST", "/run"),
+            ("POST", "/run?x=1"),
+            ("POST", "/v2/models/secret-model/infer"),
+            ("GET", "/v2/models/secret-model"),
+            ("GET", "/jobs/some-job-id"),
+            ("GET", "/secret-model"),
+        ] {
+            let request = rouille::Request::fake_http(method, url, vec![], vec![]);
+            metrics.handle(&request, || {
+                labelled_router!(request,
+                    (POST) (/run) => { rouille::Response::text("") },
+                    (GET) (/jobs/{id: String}) => { rouille::Response::text(id) },
+                    (GET) (/v2/models/{name: String}) => { rouille::Response::text(name) },
+                    (POST) (/v2/models/{name: String}/infer) => { rouille::Response::text(name) },
+                    _ => rouille::Response::empty_404()
+                )
+            });
+        }
+
+        let text = metrics.render(&gauges());
+        for label in [
+            "endpoint=\"/run\",code=\"200\"} 2\n",
+            "endpoint=\"/v2/models/{name}/infer\",code=\"200\"} 1\n",
+            "endpoint=\"/v2/models/{name}\",code=\"200\"} 1\n",
+            "endpoint=\"/jobs/{id}\",code=\"200\"} 1\n",
+            "endpoint=\"other\",code=\"404\"} 1\n",
+        ] {
+            assert!(text.contains(label), "{label} not in {text}");
+        }
+        assert!(!text.contains("secret-model"));
+        assert!(!text.contains("some-job-id"));
+    }
+
+    #[test]
+    fn unparsable_ports_are_errors() {
+        assert_eq!(Metrics::parse_port("9090").unwrap(), 9090);
+        assert!(Metrics::parse_port("90900").is_err());
+        assert!(Metrics::parse_port("metrics").is_err());
+    }
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::new(false);
+        let request = rouille::Request::fake_http("POST", "/run", vec![], vec![]);
+        metrics.handle(&request, || {
+            set_route("/run");
+            rouille::Response::text("")
+        });
+        metrics.handle(&request, || {
+            set_route("/run");
+            rouille::Response::text("").with_status_code(500)
+        });
+        metrics.observe_inference("a", Duration::from_millis(30));
+        metrics.observe_inference("b", Duration::from_secs(2));
+
+        let text = metrics.render(&gauges());
+        assert!(text.contains("blindai_requests_total{endpoint=\"/run\",code=\"200\"} 1\n"));
+        assert!(text.contains("blindai_requests_total{endpoint=\"/run\",code=\"500\"} 1\n"));
+        assert!(text.contains("blindai_requests_in_flight 0\n"));
+        assert!(text.contains("blindai_inference_duration_seconds_bucket{le=\"0.05\"} 1\n"));
+        assert!(text.contains("blindai_inference_duration_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(text.contains("blindai_inference_duration_seconds_count 2\n"));
+        assert!(text.contains("blindai_models_loaded 2\n"));
+        assert!(text.contains("blindai_model_store_bytes 1000\n"));
+        assert!(text.contains("# TYPE blindai_queued_inferences gauge\n"));
+        assert!(text.contains("blindai_queued_inferences 3\n"));
+        assert!(text.contains("blindai_pending_jobs 4\n"));
+        assert!(!text.contains("model="));
+    }
+
+    #[test]
+    fn model_labels() {
+        let metrics = Metrics::new(true);
+        metrics.observe_inference("a", Duration::from_millis(30));
+        let text = metrics.render(&gauges());
+        assert!(text.contains("blindai_inference_duration_seconds_count{model=\"a\"} 1\n"));
+    }
+}
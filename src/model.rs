@@ -12,9 +12,12 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::sync::RwLock;
+use std::time::SystemTime;
 use std::vec::Vec;
 
 use crate::client_communication::{SerializedTensor, TensorInfo};
+use crate::policy::{ModelPolicy, PolicyUsage};
 use anyhow::{anyhow, bail, Result};
 use core::hash::Hash;
 use num_derive::FromPrimitive;
@@ -59,6 +62,11 @@ impl ModelDatumType {
             ModelDatumType::Bool => bool::datum_type(),
         }
     }
+
+    /// Size in bytes of one element
+    pub fn size_of(self) -> usize {
+        self.get_datum_type().size_of()
+    }
 }
 
 impl TryFrom<DatumType> for ModelDatumType {
@@ -247,13 +255,34 @@ where
     Ok(slice.to_le_bytes())
 }
 
+/// Memory held by the constant tensors (mostly the weights) of a model
+pub fn weights_size(onnx: &OnnxModel) -> usize {
+    onnx.model
+        .nodes()
+        .iter()
+        .filter_map(|node| node.op_as::<tract_core::ops::konst::Const>())
+        .map(|konst| konst.0.len() * konst.0.datum_type().size_of())
+        .sum()
+}
+
+#[derive(Clone, Debug)]
+pub struct TensorMetadata {
+    pub name: String,
+    pub datum_type: ModelDatumType,
+    pub shape: Vec<i64>,
+}
+
 #[derive(Debug)]
 pub struct InferenceModel {
     pub onnx: Arc<OnnxModel>,
-    #[allow(unused)]
     model_id: Uuid,
     model_name: Option<String>,
     model_hash: Digest,
+    policy: RwLock<ModelPolicy>,
+    usage: Arc<PolicyUsage>,
+    /// Identity of the caller who uploaded the model
+    owner: Option<String>,
+    expires_at: Option<SystemTime>,
 }
 
 impl InferenceModel {
@@ -278,12 +307,19 @@ impl InferenceModel {
             model_name,
             model_id,
             model_hash,
+            policy: RwLock::new(ModelPolicy::default()),
+            usage: Arc::new(PolicyUsage::default()),
+            owner: None,
+            expires_at: None,
         })
     }
 
     pub fn run_inference(&self, inputs: &[SerializedTensor]) -> Result<Vec<SerializedTensor>> {
-        let mut tensors: Vec<_> = vec![];
         let outlets = self.onnx.model.input_outlets()?;
+        // Named inputs go to the outlet of the same name, the other ones fill
+        // the remaining outlets in order
+        let mut slots: Vec<Option<Tensor>> = outlets.iter().map(|_| None).collect();
+        let mut unnamed = vec![];
         for tensor in inputs {
             let tract_tensor = convert_datum!(create_tensor(
                 tensor.info.datum_type.get_datum_type()
@@ -296,12 +332,23 @@ impl InferenceModel {
                     .iter()
                     .position(|&outlet| outlet.node == node_id)
                     .ok_or_else(|| anyhow!("no node with name {}", node_name))?;
-                tensors.insert(rank, tract_tensor);
+                if slots[rank].replace(tract_tensor).is_some() {
+                    bail!("input {} was given more than once", node_name);
+                }
             } else {
-                tensors.push(tract_tensor);
+                unnamed.push(tract_tensor);
             }
         }
-        let mut result = self.onnx.run(TVec::from_vec(tensors.clone()))?;
+        let mut unnamed = unnamed.into_iter();
+        let tensors = slots
+            .into_iter()
+            .map(|slot| slot.or_else(|| unnamed.next()))
+            .collect::<Option<TVec<_>>>()
+            .ok_or_else(|| anyhow!("expected {} inputs", outlets.len()))?;
+        if unnamed.next().is_some() {
+            bail!("expected {} inputs", outlets.len());
+        }
+        let mut result = self.onnx.run(tensors)?;
         result = result
             .into_iter()
             .map(|tensor| {
@@ -338,9 +385,17 @@ impl InferenceModel {
             model_id,
             model_name,
             model_hash,
+            policy: RwLock::new(ModelPolicy::default()),
+            usage: Arc::new(PolicyUsage::default()),
+            owner: None,
+            expires_at: None,
         }
     }
 
+    pub fn model_id(&self) -> Uuid {
+        self.model_id
+    }
+
     pub fn model_name(&self) -> Option<&str> {
         self.model_name.as_deref()
     }
@@ -349,6 +404,89 @@ impl InferenceModel {
         self.model_hash
     }
 
+    pub fn policy(&self) -> ModelPolicy {
+        self.policy.read().unwrap().clone()
+    }
+
+    pub fn set_policy(&self, policy: ModelPolicy) {
+        *self.policy.write().unwrap() = policy;
+    }
+
+    pub fn owner(&self) -> Option<&str> {
+        self.owner.as_deref()
+    }
+
+    /// Fail unless `caller` uploaded the model, when it has an owner
+    pub fn check_owner(&self, caller: Option<&str>) -> Result<()> {
+        check_owner(self.owner(), caller)
+    }
+
+    pub fn set_lifetime(&mut self, owner: Option<String>, expires_at: Option<SystemTime>) {
+        self.owner = owner;
+        self.expires_at = expires_at;
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at.is_some_and(|expires_at| now >= expires_at)
+    }
+
+    /// Carry the usage policy, the query counters, the owner and the expiry
+    /// of the model version being replaced over to this one
+    pub fn inherit_from(&mut self, previous: &InferenceModel) {
+        self.policy = RwLock::new(previous.policy());
+        self.usage = Arc::clone(&previous.usage);
+        self.owner = previous.owner.clone();
+        self.expires_at = previous.expires_at;
+    }
+
+    /// Enforce the usage policy of the model for a query from `client`
+    pub fn admit(&self, client: Option<&str>) -> Result<()> {
+        self.policy.read().unwrap().admit(&self.usage, client)
+    }
+
+    /// Name, datum type and shape of the inputs, dynamic dimensions being -1
+    pub fn inputs_metadata(&self) -> Result<Vec<TensorMetadata>> {
+        let outlets = self.onnx.model.input_outlets()?;
+        let names = outlets
+            .iter()
+            .map(|outlet| self.onnx.model.node(outlet.node).name.clone())
+            .collect();
+        self.tensors_metadata(outlets, names)
+    }
+
+    /// Name, datum type and shape of the outputs, dynamic dimensions being -1
+    pub fn outputs_metadata(&self) -> Result<Vec<TensorMetadata>> {
+        self.tensors_metadata(&self.onnx.outputs, self.get_output_names())
+    }
+
+    fn tensors_metadata(
+        &self,
+        outlets: &[OutletId],
+        names: Vec<String>,
+    ) -> Result<Vec<TensorMetadata>> {
+        outlets
+            .iter()
+            .zip(names)
+            .map(|(outlet, name)| {
+                let fact = self.onnx.model.outlet_fact(*outlet)?;
+                // TDim outputs are cast to i64 by run_inference
+                let datum_type = match fact.datum_type {
+                    DatumType::TDim => ModelDatumType::I64,
+                    datum_type => ModelDatumType::try_from(datum_type)?,
+                };
+                Ok(TensorMetadata {
+                    name,
+                    datum_type,
+                    shape: fact
+                        .shape
+                        .iter()
+                        .map(|dim| dim.to_i64().unwrap_or(-1))
+                        .collect(),
+                })
+            })
+            .collect()
+    }
+
     pub fn get_output_names(&self) -> Vec<String> {
         self.onnx
             .outputs
@@ -365,13 +503,24 @@ impl InferenceModel {
     }
 }
 
+/// Fail unless `caller` is `owner`, if any: only the identity that uploaded a
+/// model can change or delete it
+pub fn check_owner(owner: Option<&str>, caller: Option<&str>) -> Result<()> {
+    match owner {
+        Some(owner) if caller != Some(owner) => bail!("Only the owner of the model can do this"),
+        _ => Ok(()),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::model_store::ModelStore;
     use anyhow::Result;
 
+    use ring::digest;
     use std::str::FromStr;
+    use std::sync::mpsc;
     use std::{collections::HashMap, sync::Mutex};
 
     use lazy_static::lazy_static;
@@ -380,6 +529,10 @@ mod tests {
         env!("CARGO_MANIFEST_DIR"),
         "/tests/mobilenet/mobilenetv2-7.onnx"
     ));
+    static SIMPLE: &[u8] = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/simple/simple.onnx"
+    ));
     static GRACE_HOPPER_JPG: &[u8] = include_bytes!(concat!(
         env!("CARGO_MANIFEST_DIR"),
         "/tests/mobilenet/grace_hopper.jpg"
@@ -407,10 +560,14 @@ mod tests {
     }
 
     fn add_model(model_bytes: &[u8], model_name: String, optimize: bool) -> Result<(Uuid, Digest)> {
-        MODELSTORE
-            .lock()
-            .unwrap()
-            .add_model(model_bytes, Some(model_name), optimize)
+        MODELSTORE.lock().unwrap().add_model(
+            model_bytes,
+            Some(model_name),
+            optimize,
+            ModelPolicy::default(),
+            None,
+            None,
+        )
     }
 
     #[test]
@@ -427,6 +584,152 @@ mod tests {
         add_uuid("non_optimized".into(), res.unwrap().0.to_string())
     }
 
+    #[test]
+    fn replace_mobilenet() {
+        let store = ModelStore::new();
+        let (model_id, _) = store
+            .add_model(
+                MOBILENET,
+                Some("replaced".into()),
+                false,
+                ModelPolicy::default(),
+                None,
+                None,
+            )
+            .unwrap();
+        let (model_hash, previous) = store.replace_model(model_id, MOBILENET, false).unwrap();
+        assert_eq!(previous.model_hash().as_ref(), model_hash.as_ref());
+
+        // Same bytes: the new version shares the ONNX plan of the previous one
+        let onnx = store
+            .use_model(model_id, |model| {
+                assert_eq!(model.model_name(), Some("replaced"));
+                Arc::clone(&model.onnx)
+            })
+            .unwrap();
+        assert!(Arc::ptr_eq(&onnx, &previous.onnx));
+    }
+
+    #[test]
+    fn replace_with_a_different_model() {
+        let store = ModelStore::new();
+        let (model_id, mobilenet_hash) = store
+            .add_model(
+                MOBILENET,
+                Some("replaced".into()),
+                false,
+                ModelPolicy::default(),
+                None,
+                None,
+            )
+            .unwrap();
+
+        let (started_tx, started_rx) = mpsc::channel();
+        let (resume_tx, resume_rx) = mpsc::channel();
+        std::thread::scope(|scope| {
+            // A query started before the replacement keeps its model
+            let in_flight = scope.spawn(|| {
+                store.use_model(model_id, move |model| {
+                    started_tx.send(()).unwrap();
+                    resume_rx.recv().unwrap();
+                    (model.model_hash(), model.get_output_names())
+                })
+            });
+            started_rx.recv().unwrap();
+
+            let (simple_hash, previous) = store.replace_model(model_id, SIMPLE, false).unwrap();
+            assert_eq!(previous.model_hash().as_ref(), mobilenet_hash.as_ref());
+            assert_ne!(simple_hash.as_ref(), mobilenet_hash.as_ref());
+            let (hash, outputs, onnx) = store
+                .use_model(model_id, |model| {
+                    assert_eq!(model.model_name(), Some("replaced"));
+                    (
+                        model.model_hash(),
+                        model.get_output_names(),
+                        Arc::clone(&model.onnx),
+                    )
+                })
+                .unwrap();
+            assert_eq!(hash.as_ref(), simple_hash.as_ref());
+            assert_eq!(outputs, ["result"]);
+            assert!(!Arc::ptr_eq(&onnx, &previous.onnx));
+
+            resume_tx.send(()).unwrap();
+            let (hash, outputs) = in_flight.join().unwrap().unwrap();
+            assert_eq!(hash.as_ref(), mobilenet_hash.as_ref());
+            assert_eq!(outputs, previous.get_output_names());
+        });
+
+        let simple_hash = hex::encode(digest::digest(&digest::SHA256, SIMPLE));
+        assert_eq!(
+            store.get_uuid_from_hash(&simple_hash).unwrap(),
+            Some(model_id)
+        );
+        assert_eq!(
+            store
+                .get_uuid_from_hash(&hex::encode(mobilenet_hash))
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn only_owners_change_the_policy() {
+        let store = ModelStore::new();
+        let (owned, _) = store
+            .add_model(
+                SIMPLE,
+                None,
+                false,
+                ModelPolicy::default(),
+                Some("alice".into()),
+                None,
+            )
+            .unwrap();
+        let policy = ModelPolicy {
+            max_queries: Some(1),
+            ..Default::default()
+        };
+
+        assert!(store
+            .set_policy(owned, policy.clone(), Some("mallory"))
+            .is_err());
+        assert!(store.set_policy(owned, policy.clone(), None).is_err());
+        assert_eq!(
+            store.use_model(owned, |model| model.policy()),
+            Some(ModelPolicy::default())
+        );
+        assert!(store
+            .set_policy(owned, policy.clone(), Some("alice"))
+            .unwrap()
+            .is_some());
+        assert_eq!(
+            store.use_model(owned, |model| model.policy()),
+            Some(policy.clone())
+        );
+
+        // Models uploaded anonymously have no owner
+        let (ownerless, _) = store
+            .add_model(SIMPLE, None, false, ModelPolicy::default(), None, None)
+            .unwrap();
+        assert!(store
+            .set_policy(ownerless, policy, Some("mallory"))
+            .unwrap()
+            .is_some());
+
+        // Deleting the models of a tenant
+        assert!(check_owner(Some("alice"), Some("mallory")).is_err());
+        assert!(check_owner(Some("alice"), Some("alice")).is_ok());
+    }
+
+    #[test]
+    fn malformed_model_hashes_are_errors() {
+        let store = ModelStore::new();
+        assert!(store.get_uuid_from_hash("not hex").is_err());
+        assert!(store.get_uuid_from_hash("abc").is_err());
+        assert_eq!(store.get_uuid_from_hash("00ff").unwrap(), None);
+    }
+
     #[test]
     fn run_mobilenet_optimized() {
         let uuid = get_uuid("optimized".into());
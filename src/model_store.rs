@@ -12,11 +12,12 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 use log::*;
 use ring::digest::{self, Digest};
 
 use std::sync::RwLock;
+use std::time::{Duration, SystemTime};
 
 use std::{
     collections::{hash_map::Entry, HashMap},
@@ -24,10 +25,11 @@ use std::{
 };
 use uuid::Uuid;
 
-use crate::model::{InferenceModel, OnnxModel};
+use crate::model::{weights_size, InferenceModel, OnnxModel};
+use crate::policy::ModelPolicy;
 
 struct InnerModelStore {
-    models_by_id: HashMap<Uuid, InferenceModel>,
+    models_by_id: HashMap<Uuid, Arc<InferenceModel>>,
     onnx_by_hash: HashMap<Vec<u8>, (usize, Arc<OnnxModel>)>,
 }
 
@@ -51,6 +53,9 @@ impl ModelStore {
         model_bytes: &[u8],
         model_name: Option<String>,
         optimize: bool,
+        policy: ModelPolicy,
+        owner: Option<String>,
+        ttl: Option<Duration>,
     ) -> Result<(Uuid, Digest)> {
         let model_id = Uuid::new_v4();
         let model_hash = digest::digest(&digest::SHA256, model_bytes);
@@ -66,7 +71,7 @@ impl ModelStore {
             // followed with .insert()
 
             // deduplication support
-            let model = match models.onnx_by_hash.entry(model_hash_vec) {
+            let mut model = match models.onnx_by_hash.entry(model_hash_vec) {
                 Entry::Occupied(mut entry) => {
                     let (num, onnx) = entry.get_mut();
                     *num += 1;
@@ -93,6 +98,8 @@ impl ModelStore {
                     model
                 }
             };
+            model.set_policy(policy);
+            model.set_lifetime(owner, ttl.map(|ttl| SystemTime::now() + ttl));
 
             // actual hashmap insertion
             match models.models_by_id.entry(model_id) {
@@ -103,31 +110,147 @@ impl ModelStore {
                     );
                     return Err(anyhow!("UUID collision"));
                 }
-                Entry::Vacant(entry) => entry.insert(model),
+                Entry::Vacant(entry) => entry.insert(Arc::new(model)),
             };
         }
 
         Ok((model_id, model_hash))
     }
 
-    pub fn get_uuid_from_hash(&self, model_hash: &str) -> Option<Uuid> {
+    pub fn get_uuid_from_hash(&self, model_hash: &str) -> Result<Option<Uuid>> {
+        let digest =
+            hex::decode(model_hash).map_err(|_| anyhow!("The model hash must be hex encoded"))?;
         let read_guard = self.inner.read().unwrap();
-        let digest = ring::test::from_hex(model_hash).unwrap();
         for val in read_guard.models_by_id.iter() {
             if val.1.model_hash().as_ref() == &digest[..] {
-                return Some(val.0.to_owned());
+                return Ok(Some(val.0.to_owned()));
             }
         }
-        None
+        Ok(None)
+    }
+
+    pub fn model_count(&self) -> usize {
+        self.inner.read().unwrap().models_by_id.len()
+    }
+
+    /// Memory held by the weights of the loaded models, shared models being
+    /// counted once
+    pub fn weights_size(&self) -> usize {
+        let read_guard = self.inner.read().unwrap();
+        read_guard
+            .onnx_by_hash
+            .values()
+            .map(|(_, onnx)| weights_size(onnx))
+            .sum()
+    }
+
+    pub fn get_uuids_from_name(&self, model_name: &str) -> Vec<Uuid> {
+        let read_guard = self.inner.read().unwrap();
+        read_guard
+            .models_by_id
+            .iter()
+            .filter(|(_, model)| model.model_name() == Some(model_name))
+            .map(|(model_id, _)| *model_id)
+            .collect()
     }
 
     pub fn use_model<U>(&self, model_id: Uuid, fun: impl Fn(&InferenceModel) -> U) -> Option<U> {
-        // take a read lock
+        // The read lock is only held while cloning the model handle, so that
+        // a model replaced in the meantime keeps serving the queries that
+        // already started on it
+        let model = {
+            let read_guard = self.inner.read().unwrap();
+            Arc::clone(read_guard.models_by_id.get(&model_id)?)
+        };
+        // Expired models may not have been removed yet
+        if model.is_expired(SystemTime::now()) {
+            return None;
+        }
+        Some(fun(&model))
+    }
+
+    /// Change the usage policy of a model on behalf of `caller`, who must be
+    /// its owner if it has one
+    pub fn set_policy(
+        &self,
+        model_id: Uuid,
+        policy: ModelPolicy,
+        caller: Option<&str>,
+    ) -> Result<Option<Digest>> {
         let read_guard = self.inner.read().unwrap();
-        read_guard.models_by_id.get(&model_id).map(fun)
+        let Some(model) = read_guard.models_by_id.get(&model_id) else {
+            return Ok(None);
+        };
+        model.check_owner(caller)?;
+        info!("Updating the usage policy of model {}", model_id);
+        model.set_policy(policy);
+        Ok(Some(model.model_hash()))
     }
 
-    pub fn delete_model(&self, model_id: Uuid) -> Option<InferenceModel> {
+    /// Replace the model behind `model_id` with a new version
+    ///
+    /// The new model is loaded before any lock is taken for writing, then
+    /// swapped atomically. Its name, usage policy and query counters are the
+    /// ones of the previous version, which is returned.
+    pub fn replace_model(
+        &self,
+        model_id: Uuid,
+        model_bytes: &[u8],
+        optimize: bool,
+    ) -> Result<(Digest, Arc<InferenceModel>)> {
+        let model_hash = digest::digest(&digest::SHA256, model_bytes);
+        let model_hash_vec = model_hash.as_ref().to_vec();
+
+        let (model_name, loaded_onnx) = {
+            let read_guard = self.inner.read().unwrap();
+            let previous = match read_guard.models_by_id.get(&model_id) {
+                Some(previous) => previous,
+                None => bail!("Model doesn't exist"),
+            };
+            (
+                previous.model_name().map(|s| s.to_string()),
+                read_guard
+                    .onnx_by_hash
+                    .get(&model_hash_vec)
+                    .map(|(_, onnx)| Arc::clone(onnx)),
+            )
+        };
+
+        let mut model = match loaded_onnx {
+            Some(onnx) => {
+                info!("Reusing an existing ONNX entry for model.");
+                InferenceModel::from_onnx_loaded(onnx, model_id, model_name, model_hash)
+            }
+            None => {
+                info!("Creating a new ONNX entry for model.");
+                InferenceModel::load_model(model_bytes, model_id, model_name, model_hash, optimize)?
+            }
+        };
+
+        let mut write_guard = self.inner.write().unwrap();
+        let models = &mut *write_guard;
+
+        // The model may have been deleted while the new version was loading
+        let mut entry = match models.models_by_id.entry(model_id) {
+            Entry::Occupied(entry) => entry,
+            Entry::Vacant(_) => bail!("Model doesn't exist"),
+        };
+        model.inherit_from(entry.get());
+
+        match models.onnx_by_hash.entry(model_hash_vec) {
+            Entry::Occupied(mut entry) => entry.get_mut().0 += 1,
+            Entry::Vacant(entry) => {
+                entry.insert((1, Arc::clone(&model.onnx)));
+            }
+        }
+        let previous = entry.insert(Arc::new(model));
+        release_onnx(&mut models.onnx_by_hash, previous.model_hash());
+        info!("Model {} has been replaced", model_id);
+
+        Ok((model_hash, previous))
+    }
+
+    pub fn delete_model(&self, model_id: Uuid) -> Option<Arc<InferenceModel>> {
         let mut write_guard = self.inner.write().unwrap();
 
         let model = match write_guard.models_by_id.entry(model_id) {
@@ -135,17 +258,51 @@ impl ModelStore {
             Entry::Vacant(_) => return None,
         };
 
-        if let Entry::Occupied(mut entry) = write_guard
-            .onnx_by_hash
-            .entry(model.model_hash().as_ref().to_vec())
-        {
-            let (i, _) = entry.get_mut();
-            *i -= 1;
-            if *i == 0 {
-                entry.remove();
+        release_onnx(&mut write_guard.onnx_by_hash, model.model_hash());
+
+        Some(model)
+    }
+
+    /// Delete all the models matching `filter`, and return them
+    pub fn delete_models(
+        &self,
+        filter: impl Fn(&InferenceModel) -> bool,
+    ) -> Vec<Arc<InferenceModel>> {
+        let mut write_guard = self.inner.write().unwrap();
+        let models = &mut *write_guard;
+
+        let model_ids: Vec<Uuid> = models
+            .models_by_id
+            .iter()
+            .filter(|(_, model)| filter(model))
+            .map(|(model_id, _)| *model_id)
+            .collect();
+
+        let mut deleted = Vec::with_capacity(model_ids.len());
+        for model_id in model_ids {
+            if let Some(model) = models.models_by_id.remove(&model_id) {
+                release_onnx(&mut models.onnx_by_hash, model.model_hash());
+                deleted.push(model);
             }
         }
+        deleted
+    }
 
-        Some(model)
+    /// Remove the models whose time-to-live has elapsed
+    pub fn delete_expired_models(&self) -> Vec<Arc<InferenceModel>> {
+        let now = SystemTime::now();
+        self.delete_models(|model| model.is_expired(now))
+    }
+}
+
+/// Decrement the reference count of a shared ONNX plan, and drop it once it
+/// is no longer used
+fn release_onnx(onnx_by_hash: &mut HashMap<Vec<u8>, (usize, Arc<OnnxModel>)>, model_hash: Digest) {
+    if let Entry::Occupied(mut entry) = onnx_by_hash.entry(model_hash.as_ref().to_vec()) {
+        let (i, _) = entry.get_mut();
+        *i -= 1;
+        if *i == 0 {
+            entry.remove();
+        }
     }
 }
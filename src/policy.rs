@@ -0,0 +1,218 @@
+// Copyright 2022 Mithril Security. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use anyhow::{bail, Result};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::client_communication::SerializedTensor;
+
+/// Usage policy attached to a model by its owner
+///
+/// Every field is optional, an empty policy puts no restriction on the model.
+/// Clients are identified by the hex encoded SHA-256 of the bearer token they
+/// send in the `Authorization` header, so that the policy never contains any
+/// secret.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModelPolicy {
+    /// Identities allowed to query the model
+    #[serde(default)]
+    pub allowed_clients: Option<Vec<String>>,
+    /// Maximum number of queries, all clients included
+    #[serde(default)]
+    pub max_queries: Option<u64>,
+    /// Maximum number of queries for each client
+    #[serde(default)]
+    pub max_queries_per_client: Option<u64>,
+    /// UNIX timestamp (in seconds) after which the model can't be queried
+    /// anymore. Note that the time is provided by the host.
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    /// Names of the outputs that may be returned to the clients
+    #[serde(default)]
+    pub allowed_outputs: Option<Vec<String>>,
+}
+
+/// Maximum number of clients whose queries are counted for a per-client
+/// budget, further clients are refused
+const MAX_TRACKED_CLIENTS: usize = 100_000;
+
+#[derive(Debug, Default)]
+struct Usage {
+    total: u64,
+    per_client: HashMap<String, u64>,
+}
+
+/// Query counters used to enforce the budgets of a [`ModelPolicy`]
+#[derive(Debug, Default)]
+pub struct PolicyUsage(Mutex<Usage>);
+
+impl ModelPolicy {
+    /// Check that `client` may query the model and count the query
+    ///
+    /// Anonymous clients share the same per-client budget.
+    pub fn admit(&self, usage: &PolicyUsage, client: Option<&str>) -> Result<()> {
+        if let Some(expires_at) = self.expires_at {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            if now >= expires_at {
+                bail!("The usage policy of this model has expired");
+            }
+        }
+
+        if let Some(allowed_clients) = &self.allowed_clients {
+            match client {
+                Some(client) if allowed_clients.iter().any(|c| c == client) => (),
+                _ => bail!("You are not allowed to query this model"),
+            }
+        }
+
+        let mut usage = usage.0.lock().unwrap();
+        if let Some(max_queries) = self.max_queries {
+            if usage.total >= max_queries {
+                bail!("The query budget of this model is exhausted");
+            }
+        }
+        // Per-client counts are only kept when there is a per-client budget,
+        // and for a bounded number of clients
+        if let Some(max_queries_per_client) = self.max_queries_per_client {
+            let client = client.unwrap_or_default();
+            let client_queries = match usage.per_client.get(client) {
+                Some(&client_queries) => client_queries,
+                None if usage.per_client.len() >= MAX_TRACKED_CLIENTS => {
+                    bail!("Too many clients have queried this model")
+                }
+                None => 0,
+            };
+            if client_queries >= max_queries_per_client {
+                bail!("Your query budget for this model is exhausted");
+            }
+            usage
+                .per_client
+                .insert(client.to_string(), client_queries + 1);
+        }
+        usage.total += 1;
+        Ok(())
+    }
+
+    pub fn allows_output(&self, name: &str) -> bool {
+        match &self.allowed_outputs {
+            None => true,
+            Some(allowed_outputs) => allowed_outputs.iter().any(|output| output == name),
+        }
+    }
+
+    /// Drop the outputs the clients are not allowed to see
+    pub fn filter_outputs(&self, outputs: Vec<SerializedTensor>) -> Vec<SerializedTensor> {
+        if self.allowed_outputs.is_none() {
+            return outputs;
+        }
+        outputs
+            .into_iter()
+            .filter(|output| {
+                output
+                    .info
+                    .node_name
+                    .as_deref()
+                    .is_some_and(|name| self.allows_output(name))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_policy_admits_everyone() {
+        let usage = PolicyUsage::default();
+        let policy = ModelPolicy::default();
+        for _ in 0..10 {
+            policy.admit(&usage, None).unwrap();
+            policy.admit(&usage, Some("a")).unwrap();
+        }
+    }
+
+    #[test]
+    fn allowed_clients() {
+        let usage = PolicyUsage::default();
+        let policy = ModelPolicy {
+            allowed_clients: Some(vec!["a".into()]),
+            ..Default::default()
+        };
+        policy.admit(&usage, Some("a")).unwrap();
+        assert!(policy.admit(&usage, Some("b")).is_err());
+        assert!(policy.admit(&usage, None).is_err());
+    }
+
+    #[test]
+    fn query_budgets() {
+        let usage = PolicyUsage::default();
+        let policy = ModelPolicy {
+            max_queries: Some(3),
+            max_queries_per_client: Some(2),
+            ..Default::default()
+        };
+        policy.admit(&usage, Some("a")).unwrap();
+        policy.admit(&usage, Some("a")).unwrap();
+        assert!(policy.admit(&usage, Some("a")).is_err());
+        policy.admit(&usage, Some("b")).unwrap();
+        assert!(policy.admit(&usage, Some("c")).is_err());
+    }
+
+    #[test]
+    fn clients_are_only_tracked_with_a_per_client_budget() {
+        let usage = PolicyUsage::default();
+        let policy = ModelPolicy {
+            max_queries: Some(1000),
+            ..Default::default()
+        };
+        for i in 0..100 {
+            policy.admit(&usage, Some(&i.to_string())).unwrap();
+        }
+        let tracked = usage.0.lock().unwrap();
+        assert_eq!(tracked.total, 100);
+        assert!(tracked.per_client.is_empty());
+    }
+
+    #[test]
+    fn tracked_clients_are_bounded() {
+        let usage = PolicyUsage::default();
+        let policy = ModelPolicy {
+            max_queries_per_client: Some(1),
+            ..Default::default()
+        };
+        for i in 0..MAX_TRACKED_CLIENTS {
+            policy.admit(&usage, Some(&i.to_string())).unwrap();
+        }
+        assert!(policy.admit(&usage, Some("new")).is_err());
+        assert!(policy.admit(&usage, Some("0")).is_err());
+        assert_eq!(
+            usage.0.lock().unwrap().per_client.len(),
+            MAX_TRACKED_CLIENTS
+        );
+    }
+
+    #[test]
+    fn expired_policy() {
+        let usage = PolicyUsage::default();
+        let policy = ModelPolicy {
+            expires_at: Some(1),
+            ..Default::default()
+        };
+        assert!(policy.admit(&usage, None).is_err());
+    }
+}
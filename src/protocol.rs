@@ -0,0 +1,273 @@
+// Copyright 2022 Mithril Security. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Versioning of the wire protocol.
+//!
+//! Clients give the version of the protocol they speak in the
+//! `X-BlindAI-Protocol-Version` header, or in the `protocol_version` field of
+//! the request body. Requests without a version come from clients predating
+//! versioning and are handled as version 1, and fields introduced by a later
+//! version are rejected in them. The versions and features the
+//! server supports are advertised on `/capabilities` of the unattested port.
+
+use crate::encoding::Encoding;
+use crate::model::ModelDatumType;
+use anyhow::{bail, Context, Result};
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use std::fmt;
+
+/// Version of the protocol spoken by this server
+pub(crate) const PROTOCOL_VERSION: u32 = 2;
+
+/// Oldest version of the protocol still accepted
+pub(crate) const MIN_PROTOCOL_VERSION: u32 = 1;
+
+pub(crate) const PROTOCOL_VERSION_HEADER: &str = "X-BlindAI-Protocol-Version";
+
+#[derive(Debug)]
+pub(crate) struct UnsupportedVersion(pub u32);
+
+impl fmt::Display for UnsupportedVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let advice = if self.0 > PROTOCOL_VERSION {
+            "upgrade the server or downgrade the client"
+        } else {
+            "upgrade the client"
+        };
+        write!(
+            f,
+            "Unsupported protocol version {}: this server speaks versions {} to {}, {} to a \
+             version speaking protocol version {}",
+            self.0, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, advice, PROTOCOL_VERSION
+        )
+    }
+}
+
+impl std::error::Error for UnsupportedVersion {}
+
+/// A request body, which may give the version of the protocol it was written
+/// for in its `protocol_version` field
+pub(crate) trait Versioned {
+    fn protocol_version(&self) -> Option<u32>;
+
+    /// Check that the body only uses fields defined in protocol `version`
+    fn check_fields(&self, _version: u32) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Fail if one of the `fields` set in a request of protocol `version` was
+/// only introduced in protocol version `since`
+pub(crate) fn reject_newer_fields(version: u32, since: u32, fields: &[(&str, bool)]) -> Result<()> {
+    if version >= since {
+        return Ok(());
+    }
+    match fields.iter().find(|(_, set)| *set) {
+        Some((field, _)) => bail!(
+            "The {} field requires protocol version {}, this request is version {}",
+            field,
+            since,
+            version
+        ),
+        None => Ok(()),
+    }
+}
+
+fn check_supported(version: u32) -> Result<u32> {
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+        return Err(UnsupportedVersion(version).into());
+    }
+    Ok(version)
+}
+
+/// Protocol version given in the header of a request, if supported
+fn header_version(request: &rouille::Request) -> Result<Option<u32>> {
+    match request.header(PROTOCOL_VERSION_HEADER) {
+        Some(version) => match version.trim().parse() {
+            Ok(version) => check_supported(version).map(Some),
+            Err(_) => bail!("Invalid {} header {:?}", PROTOCOL_VERSION_HEADER, version),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Check the protocol version of a request, given in its header or else in
+/// its body as `body_version`, returning it
+pub(crate) fn check_version(request: &rouille::Request, body_version: Option<u32>) -> Result<u32> {
+    match header_version(request)? {
+        Some(version) => Ok(version),
+        None => check_supported(body_version.unwrap_or(1)),
+    }
+}
+
+/// Decode the body of a request and check that it is valid in its protocol
+/// version
+pub(crate) fn parse_request<T: DeserializeOwned + Versioned>(
+    request: &rouille::Request,
+    encoding: Encoding,
+    body: &[u8],
+) -> Result<T> {
+    // A client speaking an unsupported version must be told so rather than
+    // get a deserialization error
+    let header_version = header_version(request)?;
+    let parsed: T = encoding.deserialize(body).with_context(|| {
+        format!(
+            "Invalid request for protocol version {}",
+            header_version.unwrap_or(1)
+        )
+    })?;
+    let version = check_version(request, parsed.protocol_version())?;
+    parsed.check_fields(version)?;
+    Ok(parsed)
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Capabilities {
+    protocol_version: u32,
+    min_protocol_version: u32,
+    datum_types: Vec<ModelDatumType>,
+    /// Encodings of the request and response bodies
+    encodings: Vec<&'static str>,
+    /// Compressions of the bodies and of the tensor data
+    compressions: Vec<&'static str>,
+    upload_formats: Vec<&'static str>,
+    features: Vec<&'static str>,
+}
+
+/// Capabilities of this server, `encrypted_models` depending on whether a key
+/// broker is configured and `ra_tls` on whether the attestation evidence is
+/// embedded in the enclave certificate
+///
+/// They are served unattested and only meant to pick a compatible client, the
+/// attested endpoints enforce them anyway.
+pub(crate) fn capabilities(encrypted_models: bool, ra_tls: bool) -> Capabilities {
+    let mut features = vec![
+        "model_ttl",
+        "model_policies",
+        "audit_log",
+        "jobs",
+        "streaming",
+        "kserve_v2",
+        "sealed_inputs",
+        "sealed_outputs",
+    ];
+    if encrypted_models {
+        features.push("encrypted_models");
+    }
+    if cfg!(target_env = "sgx") {
+        features.push("fresh_quotes");
+        if ra_tls {
+            features.push("ra_tls");
+        }
+    } else {
+        features.extend(["fresh_quotes", "simulated_attestation"]);
+    }
+
+    Capabilities {
+        protocol_version: PROTOCOL_VERSION,
+        min_protocol_version: MIN_PROTOCOL_VERSION,
+        datum_types: vec![
+            ModelDatumType::F32,
+            ModelDatumType::F64,
+            ModelDatumType::I32,
+            ModelDatumType::I64,
+            ModelDatumType::U32,
+            ModelDatumType::U64,
+            ModelDatumType::U8,
+            ModelDatumType::U16,
+            ModelDatumType::I8,
+            ModelDatumType::I16,
+            ModelDatumType::Bool,
+        ],
+        encodings: vec![Encoding::Cbor.content_type(), Encoding::Json.content_type()],
+        compressions: vec!["identity", "gzip"],
+        upload_formats: vec!["cbor", "json", "multipart"],
+        features,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)]) -> rouille::Request {
+        let headers = headers
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        rouille::Request::fake_http("POST", "/run", headers, vec![])
+    }
+
+    #[derive(Deserialize)]
+    struct Body {
+        #[serde(default)]
+        protocol_version: Option<u32>,
+        #[serde(default)]
+        new_field: Option<u32>,
+    }
+
+    impl Versioned for Body {
+        fn protocol_version(&self) -> Option<u32> {
+            self.protocol_version
+        }
+
+        fn check_fields(&self, version: u32) -> Result<()> {
+            reject_newer_fields(version, 2, &[("new_field", self.new_field.is_some())])
+        }
+    }
+
+    fn version(headers: &[(&str, &str)], body: &[u8]) -> Result<Option<u32>> {
+        parse_request::<Body>(&request(headers), Encoding::Json, body)
+            .map(|body| body.protocol_version)
+    }
+
+    #[test]
+    fn versions() {
+        assert_eq!(check_version(&request(&[]), None).unwrap(), 1);
+        assert_eq!(check_version(&request(&[]), Some(2)).unwrap(), 2);
+        assert_eq!(
+            check_version(&request(&[(PROTOCOL_VERSION_HEADER, "2")]), None).unwrap(),
+            2
+        );
+
+        let e = check_version(&request(&[]), Some(3)).unwrap_err();
+        assert!(e.is::<UnsupportedVersion>());
+        assert!(e.to_string().contains("speaking protocol version 2"));
+        assert!(check_version(&request(&[(PROTOCOL_VERSION_HEADER, "0")]), None).is_err());
+        assert!(check_version(&request(&[(PROTOCOL_VERSION_HEADER, "x")]), None).is_err());
+    }
+
+    #[test]
+    fn parsed_requests() {
+        assert_eq!(version(&[], b"{}").unwrap(), None);
+        assert_eq!(version(&[], br#"{"protocol_version":2}"#).unwrap(), Some(2));
+        let e = version(&[], br#"{"protocol_version":3}"#).unwrap_err();
+        assert!(e.is::<UnsupportedVersion>());
+
+        // An unsupported header version is reported even if the body is not
+        // understood
+        let e = version(&[(PROTOCOL_VERSION_HEADER, "3")], b"1").unwrap_err();
+        assert!(e.is::<UnsupportedVersion>());
+        assert!(version(&[], b"1").is_err());
+
+        // Fields of version 2 are rejected in version 1 requests
+        let new_field = br#"{"new_field":1}"#;
+        let e = version(&[], new_field).unwrap_err();
+        assert!(e.to_string().contains("requires protocol version 2"));
+        assert!(version(&[(PROTOCOL_VERSION_HEADER, "1")], new_field).is_err());
+        assert!(version(&[(PROTOCOL_VERSION_HEADER, "2")], new_field).is_ok());
+        assert!(version(&[], br#"{"protocol_version":2,"new_field":1}"#).is_ok());
+    }
+}
@@ -0,0 +1,267 @@
+// Copyright 2022 Mithril Security. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Fresh quotes bound to data chosen by the client.
+//!
+//! The quote served by default on `/quote` is generated once at startup and
+//! replayed to every client. A client wanting freshness guarantees sends up
+//! to 32 bytes of its own, either hex encoded in `GET /quote?nonce=...` or as
+//! the raw body of `POST /quote`. A new report is then created with the client
+//! data hashed into the second half of the report data, together with the
+//! enclave key (see [`crate::identity::report_data`]), and quoted by the
+//! runner. Verifiers can't find the client data as is in the report data.
+//!
+//! Quoting goes through the quoting enclave of the host, so fresh quotes are
+//! rate limited with a token bucket: `BLINDAI_FRESH_QUOTES_PER_MINUTE` quotes
+//! per minute (30 by default), with bursts of up to
+//! `BLINDAI_FRESH_QUOTES_BURST` quotes (5 by default).
+
+use crate::encoding::read_raw_body;
+use crate::identity::{self, EnclaveKey};
+use anyhow::{anyhow, Result};
+use log::error;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Maximum size of the client data bound to a fresh quote
+pub(crate) const MAX_CLIENT_DATA: usize = 32;
+
+type GetQuote = dyn Fn(&[u8; 64]) -> Result<Vec<u8>> + Send + Sync;
+
+#[derive(Debug)]
+pub(crate) struct RateLimited;
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Too many fresh quotes requested, retry later")
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+/// Token bucket
+struct RateLimiter {
+    burst: f64,
+    per_second: f64,
+    /// Tokens left, and when they were counted
+    tokens: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    fn new(per_minute: u32, burst: u32) -> Self {
+        RateLimiter {
+            burst: burst as f64,
+            per_second: per_minute as f64 / 60.0,
+            tokens: Mutex::new((burst as f64, Instant::now())),
+        }
+    }
+
+    fn acquire_at(&self, now: Instant) -> bool {
+        let mut tokens = self.tokens.lock().unwrap();
+        let (available, counted_at) = *tokens;
+        let elapsed = now.saturating_duration_since(counted_at).as_secs_f64();
+        let available = (available + elapsed * self.per_second).min(self.burst);
+        if available < 1.0 {
+            *tokens = (available, now);
+            return false;
+        }
+        *tokens = (available - 1.0, now);
+        true
+    }
+
+    fn acquire(&self) -> bool {
+        self.acquire_at(Instant::now())
+    }
+}
+
+pub(crate) struct FreshQuotes {
+    get_quote: Box<GetQuote>,
+    enclave_held_data: Arc<Vec<u8>>,
+    enclave_key: Arc<EnclaveKey>,
+    limiter: RateLimiter,
+}
+
+impl FreshQuotes {
+    pub fn new(
+        get_quote: Box<GetQuote>,
+        enclave_held_data: Arc<Vec<u8>>,
+        enclave_key: Arc<EnclaveKey>,
+        per_minute: u32,
+        burst: u32,
+    ) -> Self {
+        FreshQuotes {
+            get_quote,
+            enclave_held_data,
+            enclave_key,
+            limiter: RateLimiter::new(per_minute, burst),
+        }
+    }
+
+    /// Rate limit fresh quotes with `BLINDAI_FRESH_QUOTES_PER_MINUTE` (30 by
+    /// default) and `BLINDAI_FRESH_QUOTES_BURST` (5 by default)
+    pub fn from_env(
+        get_quote: Box<GetQuote>,
+        enclave_held_data: Arc<Vec<u8>>,
+        enclave_key: Arc<EnclaveKey>,
+    ) -> Result<Self> {
+        fn var(name: &str, default: u32) -> Result<u32> {
+            match std::env::var(name) {
+                Ok(value) => Ok(value.parse()?),
+                Err(_) => Ok(default),
+            }
+        }
+        Ok(Self::new(
+            get_quote,
+            enclave_held_data,
+            enclave_key,
+            var("BLINDAI_FRESH_QUOTES_PER_MINUTE", 30)?,
+            var("BLINDAI_FRESH_QUOTES_BURST", 5)?,
+        ))
+    }
+
+    /// Client data of a fresh quote request, `None` if the default quote is
+    /// requested
+    pub fn client_data(request: &rouille::Request) -> Result<Option<Vec<u8>>> {
+        let client_data = match request.method() {
+            "POST" => read_raw_body(request, MAX_CLIENT_DATA)?,
+            _ => match request.get_param("nonce") {
+                Some(nonce) => hex::decode(nonce.trim())
+                    .map_err(|_| anyhow!("The nonce must be hex encoded"))?,
+                None => return Ok(None),
+            },
+        };
+        if client_data.len() > MAX_CLIENT_DATA {
+            return Err(anyhow!(
+                "At most {} bytes of client data can be bound to a quote",
+                MAX_CLIENT_DATA
+            ));
+        }
+        Ok(Some(client_data))
+    }
+
+    /// Quote a new report binding the enclave identity and `client_data`
+    pub fn quote(&self, client_data: &[u8]) -> Result<Vec<u8>> {
+        if !self.limiter.acquire() {
+            return Err(RateLimited.into());
+        }
+        let report_data =
+            identity::report_data(&self.enclave_held_data, &self.enclave_key, client_data);
+        (self.get_quote)(&report_data)
+    }
+
+    /// Serve `default_quote`, or a fresh quote if client data is given, with
+    /// `respond`
+    pub fn respond(
+        &self,
+        request: &rouille::Request,
+        default_quote: &[u8],
+        respond: impl Fn(&[u8]) -> rouille::Response,
+    ) -> rouille::Response {
+        let client_data = match Self::client_data(request) {
+            Ok(Some(client_data)) => client_data,
+            Ok(None) => return respond(default_quote),
+            Err(e) => return rouille::Response::text(e.to_string()).with_status_code(400),
+        };
+        match self.quote(&client_data) {
+            Ok(quote) => respond(&quote),
+            Err(e) if e.is::<RateLimited>() => rouille::Response::text(e.to_string())
+                .with_status_code(429)
+                .with_additional_header("Retry-After", retry_after(&self.limiter)),
+            Err(e) => {
+                error!("Attestation : Could not create a fresh quote: {:?}", e);
+                rouille::Response::text("Could not create a fresh quote").with_status_code(500)
+            }
+        }
+    }
+}
+
+/// Seconds until the next token, rounded up
+fn retry_after(limiter: &RateLimiter) -> String {
+    if limiter.per_second <= 0.0 {
+        return "60".to_string();
+    }
+    ((1.0 / limiter.per_second).ceil() as u64).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn fresh_quotes(per_minute: u32, burst: u32) -> FreshQuotes {
+        // The "quote" is the report data itself
+        FreshQuotes::new(
+            Box::new(|report_data| Ok(report_data.to_vec())),
+            Arc::new(b"certificate".to_vec()),
+            Arc::new(EnclaveKey::generate()),
+            per_minute,
+            burst,
+        )
+    }
+
+    #[test]
+    fn quotes_bind_the_client_data() {
+        let quotes = fresh_quotes(60, 3);
+        let default_data = identity::report_data(b"certificate", &quotes.enclave_key, &[]);
+
+        let first = quotes.quote(b"nonce 1").unwrap();
+        let second = quotes.quote(b"nonce 2").unwrap();
+        assert_eq!(first[..32], default_data[..32]);
+        assert_ne!(first[32..], default_data[32..]);
+        assert_ne!(first[32..], second[32..]);
+        assert_eq!(quotes.quote(&[]).unwrap(), default_data);
+    }
+
+    #[test]
+    fn client_data_is_read_from_the_request() {
+        let get = |url: &str| rouille::Request::fake_http("GET", url, vec![], vec![]);
+        let post = |body: Vec<u8>| rouille::Request::fake_http("POST", "/quote", vec![], body);
+
+        assert_eq!(FreshQuotes::client_data(&get("/quote")).unwrap(), None);
+        assert_eq!(
+            FreshQuotes::client_data(&get("/quote?nonce=00ff")).unwrap(),
+            Some(vec![0, 255])
+        );
+        assert!(FreshQuotes::client_data(&get("/quote?nonce=xyz")).is_err());
+        assert!(
+            FreshQuotes::client_data(&get(&format!("/quote?nonce={}", "00".repeat(33)))).is_err()
+        );
+        assert_eq!(
+            FreshQuotes::client_data(&post(vec![1; 32])).unwrap(),
+            Some(vec![1; 32])
+        );
+        assert!(FreshQuotes::client_data(&post(vec![1; 33])).is_err());
+    }
+
+    #[test]
+    fn fresh_quotes_are_rate_limited() {
+        let limiter = RateLimiter::new(60, 2);
+        let start = Instant::now();
+        assert!(limiter.acquire_at(start));
+        assert!(limiter.acquire_at(start));
+        assert!(!limiter.acquire_at(start));
+        assert!(!limiter.acquire_at(start + Duration::from_millis(500)));
+        assert!(limiter.acquire_at(start + Duration::from_secs(1)));
+        // The bucket never holds more than the burst
+        let later = start + Duration::from_secs(3600);
+        assert!(limiter.acquire_at(later));
+        assert!(limiter.acquire_at(later));
+        assert!(!limiter.acquire_at(later));
+
+        let quotes = fresh_quotes(1, 1);
+        assert!(quotes.quote(b"nonce").is_ok());
+        assert!(quotes.quote(b"nonce").unwrap_err().is::<RateLimited>());
+    }
+}
@@ -0,0 +1,302 @@
+// Copyright 2022 Mithril Security. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! End-to-end encryption of inputs and outputs, in the spirit of HPKE base
+//! mode.
+//!
+//! A payload is sealed to a recipient public key (X25519 or P-256) with a
+//! fresh ephemeral key pair:
+//!
+//! * `encapsulated_key` : the ephemeral public key, 32 bytes for X25519 and
+//!   65 bytes (uncompressed point) for P-256
+//! * `ciphertext` : the CBOR encoded payload sealed with AES-256-GCM
+//!
+//! The AES-256-GCM key is derived with HKDF-SHA256 from the shared secret,
+//! using `encapsulated_key || recipient_public_key` as salt and
+//! [`OUTPUTS_INFO`] or [`INPUTS_INFO`] as info. Every key seals a single
+//! payload, so the nonce is all zeros.
+//!
+//! Outputs are sealed to the key given by the data owner, so that a relay
+//! forwarding the queries never sees them. Inputs may be sealed to the
+//! enclave X25519 key, which is bound to the quote through the report data.
+//!
+//! The recipient of the outputs travels in the clear next to the sealed
+//! inputs, so its public key is the associated data of the inputs seal (see
+//! [`recipient_binding`]): a relay swapping the recipient for its own key, or
+//! adding or removing one, makes the inputs impossible to open.
+
+use crate::encoding::binary;
+use crate::identity::EnclaveKey;
+use anyhow::{anyhow, bail, Result};
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey};
+use ring::agreement::{self, EphemeralPrivateKey, UnparsedPublicKey};
+use ring::hkdf;
+use ring::rand::SystemRandom;
+use serde_derive::{Deserialize, Serialize};
+
+pub(crate) const OUTPUTS_INFO: &[u8] = b"blindai sealed outputs v1";
+pub(crate) const INPUTS_INFO: &[u8] = b"blindai sealed inputs v1";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Kem {
+    X25519,
+    P256,
+}
+
+impl Kem {
+    fn algorithm(self) -> &'static agreement::Algorithm {
+        match self {
+            Kem::X25519 => &agreement::X25519,
+            Kem::P256 => &agreement::ECDH_P256,
+        }
+    }
+}
+
+/// Public key the outputs are sealed to
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Recipient {
+    pub kem: Kem,
+    #[serde(with = "binary")]
+    pub public_key: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct SealedPayload {
+    pub kem: Kem,
+    #[serde(with = "binary")]
+    pub encapsulated_key: Vec<u8>,
+    #[serde(with = "binary")]
+    pub ciphertext: Vec<u8>,
+}
+
+fn payload_key(
+    shared_secret: &[u8],
+    encapsulated_key: &[u8],
+    recipient_public_key: &[u8],
+    info: &[u8],
+) -> Result<LessSafeKey> {
+    let salt = [encapsulated_key, recipient_public_key].concat();
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, &salt).extract(shared_secret);
+    let info = [info];
+    let okm = prk
+        .expand(&info, &aead::AES_256_GCM)
+        .map_err(|_| anyhow!("HKDF expansion failed"))?;
+    Ok(LessSafeKey::new(UnboundKey::from(okm)))
+}
+
+/// Associated data binding sealed inputs to the recipient of the outputs: its
+/// public key, or nothing when the outputs are sent in the clear
+pub(crate) fn recipient_binding(recipient: Option<&Recipient>) -> &[u8] {
+    recipient.map_or(&[], |recipient| &recipient.public_key)
+}
+
+/// Seal `plaintext` so that only the holder of the recipient private key can
+/// open it, authenticating `aad` along with it
+pub(crate) fn seal(
+    recipient: &Recipient,
+    info: &[u8],
+    aad: &[u8],
+    plaintext: Vec<u8>,
+) -> Result<SealedPayload> {
+    let algorithm = recipient.kem.algorithm();
+    let ephemeral_key = EphemeralPrivateKey::generate(algorithm, &SystemRandom::new())
+        .map_err(|_| anyhow!("Could not generate an ephemeral key"))?;
+    let encapsulated_key = ephemeral_key
+        .compute_public_key()
+        .map_err(|_| anyhow!("Could not compute the ephemeral public key"))?
+        .as_ref()
+        .to_vec();
+
+    let key = agreement::agree_ephemeral(
+        ephemeral_key,
+        &UnparsedPublicKey::new(algorithm, &recipient.public_key),
+        anyhow!("Invalid recipient public key"),
+        |shared_secret| {
+            payload_key(
+                shared_secret,
+                &encapsulated_key,
+                &recipient.public_key,
+                info,
+            )
+        },
+    )?;
+
+    let mut ciphertext = plaintext;
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key([0u8; aead::NONCE_LEN]),
+        Aad::from(aad),
+        &mut ciphertext,
+    )
+    .map_err(|_| anyhow!("Could not seal the payload"))?;
+
+    Ok(SealedPayload {
+        kem: recipient.kem,
+        encapsulated_key,
+        ciphertext,
+    })
+}
+
+/// Open a payload sealed to the enclave key with `aad` as associated data
+pub(crate) fn open(
+    enclave_key: &EnclaveKey,
+    info: &[u8],
+    aad: &[u8],
+    sealed: SealedPayload,
+) -> Result<Vec<u8>> {
+    if sealed.kem != Kem::X25519 {
+        bail!("Payloads sealed to the enclave must use X25519");
+    }
+    let encapsulated_key: [u8; 32] = sealed
+        .encapsulated_key
+        .as_slice()
+        .try_into()
+        .map_err(|_| anyhow!("Invalid encapsulated key"))?;
+
+    let shared_secret = enclave_key.diffie_hellman(encapsulated_key);
+    let key = payload_key(
+        shared_secret.as_bytes(),
+        &encapsulated_key,
+        enclave_key.public_key(),
+        info,
+    )?;
+
+    let mut plaintext = sealed.ciphertext;
+    let len = key
+        .open_in_place(
+            Nonce::assume_unique_for_key([0u8; aead::NONCE_LEN]),
+            Aad::from(aad),
+            &mut plaintext,
+        )
+        .map_err(|_| anyhow!("Could not open the sealed payload"))?
+        .len();
+    plaintext.truncate(len);
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Open a payload with an ephemeral recipient key, as a data owner would
+    fn open_as_recipient(
+        recipient_key: EphemeralPrivateKey,
+        recipient: &Recipient,
+        sealed: SealedPayload,
+    ) -> Result<Vec<u8>> {
+        let key = agreement::agree_ephemeral(
+            recipient_key,
+            &UnparsedPublicKey::new(sealed.kem.algorithm(), &sealed.encapsulated_key),
+            anyhow!("Invalid encapsulated key"),
+            |shared_secret| {
+                payload_key(
+                    shared_secret,
+                    &sealed.encapsulated_key,
+                    &recipient.public_key,
+                    OUTPUTS_INFO,
+                )
+            },
+        )?;
+        let mut plaintext = sealed.ciphertext;
+        let len = key
+            .open_in_place(
+                Nonce::assume_unique_for_key([0u8; aead::NONCE_LEN]),
+                Aad::empty(),
+                &mut plaintext,
+            )
+            .map_err(|_| anyhow!("Could not open the sealed payload"))?
+            .len();
+        plaintext.truncate(len);
+        Ok(plaintext)
+    }
+
+    #[test]
+    fn outputs_are_sealed_to_the_recipient() {
+        for kem in [Kem::X25519, Kem::P256] {
+            let rng = SystemRandom::new();
+            let recipient_key = EphemeralPrivateKey::generate(kem.algorithm(), &rng).unwrap();
+            let recipient = Recipient {
+                kem,
+                public_key: recipient_key
+                    .compute_public_key()
+                    .unwrap()
+                    .as_ref()
+                    .to_vec(),
+            };
+
+            let sealed = seal(&recipient, OUTPUTS_INFO, &[], b"outputs".to_vec()).unwrap();
+            assert_eq!(sealed.kem, kem);
+            assert!(!sealed.ciphertext.starts_with(b"outputs"));
+            let opened = open_as_recipient(recipient_key, &recipient, sealed).unwrap();
+            assert_eq!(opened, b"outputs");
+        }
+
+        let invalid = Recipient {
+            kem: Kem::P256,
+            public_key: vec![4; 65],
+        };
+        assert!(seal(&invalid, OUTPUTS_INFO, &[], vec![]).is_err());
+    }
+
+    #[test]
+    fn inputs_are_opened_by_the_enclave() {
+        let enclave_key = EnclaveKey::generate();
+        let recipient = Recipient {
+            kem: Kem::X25519,
+            public_key: enclave_key.public_key().to_vec(),
+        };
+
+        let sealed = seal(&recipient, INPUTS_INFO, &[], b"inputs".to_vec()).unwrap();
+        assert_eq!(
+            open(&enclave_key, INPUTS_INFO, &[], sealed.clone()).unwrap(),
+            b"inputs"
+        );
+        assert!(open(&enclave_key, OUTPUTS_INFO, &[], sealed.clone()).is_err());
+        assert!(open(&EnclaveKey::generate(), INPUTS_INFO, &[], sealed).is_err());
+    }
+
+    #[test]
+    fn substituted_recipients_are_rejected() {
+        let enclave_key = EnclaveKey::generate();
+        let enclave = Recipient {
+            kem: Kem::X25519,
+            public_key: enclave_key.public_key().to_vec(),
+        };
+        let recipient = |public_key: Vec<u8>| Recipient {
+            kem: Kem::X25519,
+            public_key,
+        };
+        let owner = recipient(vec![1; 32]);
+        let relay = recipient(vec![2; 32]);
+
+        let binding = recipient_binding(Some(&owner));
+        let sealed = seal(&enclave, INPUTS_INFO, binding, b"inputs".to_vec()).unwrap();
+        let open_for = |recipient: Option<&Recipient>| {
+            open(
+                &enclave_key,
+                INPUTS_INFO,
+                recipient_binding(recipient),
+                sealed.clone(),
+            )
+        };
+        assert_eq!(open_for(Some(&owner)).unwrap(), b"inputs");
+        assert!(open_for(Some(&relay)).is_err());
+        assert!(open_for(None).is_err());
+
+        // Nor can a recipient be added to inputs sealed without one
+        let sealed = seal(&enclave, INPUTS_INFO, recipient_binding(None), vec![]).unwrap();
+        let binding = recipient_binding(Some(&relay));
+        assert!(open(&enclave_key, INPUTS_INFO, binding, sealed).is_err());
+    }
+}
@@ -0,0 +1,488 @@
+// Copyright 2022 Mithril Security. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Simulated attestation, for servers built without SGX.
+//!
+//! Outside of an enclave there is no report to quote and no quoting enclave,
+//! so the attestation flow could not be exercised in CI or on a laptop. The
+//! simulated quoting enclave produces version 3 DCAP quotes in software:
+//!
+//! * a throwaway PKI is generated at startup: a root CA, a PCK CA issuing a
+//!   PCK certificate with the SGX extensions, and a TCB signing certificate;
+//! * the collateral (CRLs, TCB info and QE identity) is signed with it, and
+//!   is valid for [`COLLATERAL_VALIDITY`];
+//! * the enclave report is built in software around the requested report
+//!   data, and signed by a software attestation key certified by a QE report.
+//!
+//! Simulated quotes say so: their QE vendor id is [`SIMULATED_QE_VENDOR_ID`]
+//! instead of the Intel one, their certificates are named "BlindAI Simulated
+//! ...", and the enclave report has a zero MRENCLAVE and the DEBUG attribute.
+//! Nothing chains up to the Intel root CA, so verifiers reject them unless
+//! explicitly told to accept simulated quotes.
+
+use crate::SgxCollateral;
+use anyhow::{anyhow, Result};
+use quote_verification::pki::parse_certificate;
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, CustomExtension, DistinguishedName, DnType,
+    IsCa,
+};
+use ring::digest::{digest, SHA256};
+use ring::rand::SystemRandom;
+use ring::signature::{
+    EcdsaKeyPair, EcdsaSigningAlgorithm, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING,
+    ECDSA_P256_SHA256_FIXED_SIGNING,
+};
+use std::time::Duration;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use yasna::models::{GeneralizedTime, ObjectIdentifier, UTCTime};
+use yasna::DERWriter;
+
+/// QE vendor id of simulated quotes, in place of the Intel one
+pub(crate) const SIMULATED_QE_VENDOR_ID: [u8; 16] = *b"BLINDAISIMULATED";
+
+/// Validity of the simulated collateral from the time it is generated
+pub(crate) const COLLATERAL_VALIDITY: Duration = Duration::from_secs(30 * 86400);
+
+const SGX_EXTENSIONS_OID: &[u64] = &[1, 2, 840, 113741, 1, 13, 1];
+
+const FMSPC: [u8; 6] = [0; 6];
+const PCE_ID: [u8; 2] = [0; 2];
+const PPID: [u8; 16] = [0; 16];
+const CPU_SVN: [u8; 16] = [1; 16];
+const PCE_SVN: u16 = 1;
+const QE_SVN: u16 = 1;
+const QE_PROD_ID: u16 = 1;
+
+/// INIT | DEBUG | MODE64BIT, XFRM 0x3
+const ENCLAVE_ATTRIBUTES: [u8; 16] = [7, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0];
+/// INIT | PROVISION_KEY, XFRM 0x3
+const QE_ATTRIBUTES: [u8; 16] = [0x11, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0];
+
+/// A certificate of the simulated PKI, serialized once as chains must repeat
+/// the exact same bytes
+struct Issued {
+    certificate: Certificate,
+    der: Vec<u8>,
+}
+
+impl Issued {
+    fn new(common_name: &str, ca: bool, issuer: Option<&Issued>) -> Result<Self> {
+        Self::with_extensions(common_name, ca, issuer, vec![])
+    }
+
+    fn with_extensions(
+        common_name: &str,
+        ca: bool,
+        issuer: Option<&Issued>,
+        extensions: Vec<CustomExtension>,
+    ) -> Result<Self> {
+        let mut params = CertificateParams::default();
+        params.distinguished_name = DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, common_name);
+        params
+            .distinguished_name
+            .push(DnType::OrganizationName, "BlindAI simulated attestation");
+        if ca {
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        }
+        params.custom_extensions = extensions;
+
+        let certificate = Certificate::from_params(params)?;
+        let der = match issuer {
+            Some(issuer) => certificate.serialize_der_with_signer(&issuer.certificate)?,
+            None => certificate.serialize_der()?,
+        };
+        Ok(Issued { certificate, der })
+    }
+
+    fn signing_key(&self, algorithm: &'static EcdsaSigningAlgorithm) -> Result<EcdsaKeyPair> {
+        EcdsaKeyPair::from_pkcs8(algorithm, &self.certificate.serialize_private_key_der())
+            .map_err(|_| anyhow!("Invalid simulated key"))
+    }
+
+    fn pem(&self) -> String {
+        pem("CERTIFICATE", &self.der)
+    }
+
+    /// DER encoded subject, the issuer name of what this certificate signs
+    fn subject(&self) -> Result<Vec<u8>> {
+        Ok(parse_certificate(&self.der)?.subject().as_raw().to_vec())
+    }
+}
+
+/// Software quoting enclave, along with the PKI certifying it
+pub(crate) struct SimulatedQuotingEnclave {
+    root_ca: Issued,
+    pck_ca: Issued,
+    pck: Issued,
+    tcb_signing: Issued,
+    attestation_key: EcdsaKeyPair,
+    rng: SystemRandom,
+}
+
+impl SimulatedQuotingEnclave {
+    pub fn generate() -> Result<Self> {
+        let root_ca = Issued::new("BlindAI Simulated SGX Root CA", true, None)?;
+        let pck_ca = Issued::new(
+            "BlindAI Simulated SGX PCK Processor CA",
+            true,
+            Some(&root_ca),
+        )?;
+        let pck = Issued::with_extensions(
+            "BlindAI Simulated SGX PCK Certificate",
+            false,
+            Some(&pck_ca),
+            vec![CustomExtension::from_oid_content(
+                SGX_EXTENSIONS_OID,
+                sgx_extensions(),
+            )],
+        )?;
+        let tcb_signing = Issued::new("BlindAI Simulated SGX TCB Signing", false, Some(&root_ca))?;
+
+        let rng = SystemRandom::new();
+        let attestation_key = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+            .ok()
+            .and_then(|pkcs8| {
+                EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref()).ok()
+            })
+            .ok_or_else(|| anyhow!("Could not generate the simulated attestation key"))?;
+
+        Ok(SimulatedQuotingEnclave {
+            root_ca,
+            pck_ca,
+            pck,
+            tcb_signing,
+            attestation_key,
+            rng,
+        })
+    }
+
+    fn sign(&self, key: &EcdsaKeyPair, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(key
+            .sign(&self.rng, data)
+            .map_err(|_| anyhow!("Could not sign simulated attestation data"))?
+            .as_ref()
+            .to_vec())
+    }
+
+    /// Quote of a simulated enclave report carrying `report_data`
+    pub fn quote(&self, report_data: &[u8; 64]) -> Result<Vec<u8>> {
+        let mut header = Vec::with_capacity(48);
+        header.extend_from_slice(&3u16.to_le_bytes()); // version
+        header.extend_from_slice(&2u16.to_le_bytes()); // ECDSA-256-with-P-256 key
+        header.extend_from_slice(&[0; 4]);
+        header.extend_from_slice(&QE_SVN.to_le_bytes());
+        header.extend_from_slice(&PCE_SVN.to_le_bytes());
+        header.extend_from_slice(&SIMULATED_QE_VENDOR_ID);
+        header.extend_from_slice(&[0; 20]);
+
+        let enclave_report = report_body(ENCLAVE_ATTRIBUTES, [0; 32], 0, 0, report_data);
+        let mut signed_data = header;
+        signed_data.extend_from_slice(&enclave_report);
+        let signature = self.sign(&self.attestation_key, &signed_data)?;
+
+        // The QE report binds the attestation key and the QE authentication data
+        let attestation_key = &self.attestation_key.public_key().as_ref()[1..];
+        let qe_auth_data = [0u8; 32];
+        let mut qe_report_data = [0u8; 64];
+        qe_report_data[..32]
+            .copy_from_slice(digest(&SHA256, &[attestation_key, &qe_auth_data].concat()).as_ref());
+        let qe_report = report_body(QE_ATTRIBUTES, [0; 32], QE_PROD_ID, QE_SVN, &qe_report_data);
+        let pck_key = self.pck.signing_key(&ECDSA_P256_SHA256_FIXED_SIGNING)?;
+        let qe_report_signature = self.sign(&pck_key, &qe_report)?;
+
+        let certification_data = [self.pck.pem(), self.pck_ca.pem(), self.root_ca.pem()].concat();
+        let mut signature_data = signature;
+        signature_data.extend_from_slice(attestation_key);
+        signature_data.extend_from_slice(&qe_report);
+        signature_data.extend_from_slice(&qe_report_signature);
+        signature_data.extend_from_slice(&(qe_auth_data.len() as u16).to_le_bytes());
+        signature_data.extend_from_slice(&qe_auth_data);
+        signature_data.extend_from_slice(&5u16.to_le_bytes()); // PCK certificate chain
+        signature_data.extend_from_slice(&(certification_data.len() as u32).to_le_bytes());
+        signature_data.extend_from_slice(certification_data.as_bytes());
+
+        let mut quote = signed_data;
+        quote.extend_from_slice(&(signature_data.len() as u32).to_le_bytes());
+        quote.extend_from_slice(&signature_data);
+        Ok(quote)
+    }
+
+    /// Collateral of the simulated quotes, valid from now for
+    /// [`COLLATERAL_VALIDITY`]
+    pub fn collateral(&self) -> Result<SgxCollateral> {
+        let issue_date = OffsetDateTime::now_utc().replace_nanosecond(0)?;
+        let next_update = issue_date + COLLATERAL_VALIDITY;
+
+        let root_ca_crl = self.crl(&self.root_ca, issue_date, next_update)?;
+        let pck_crl = self.crl(&self.pck_ca, issue_date, next_update)?;
+
+        let tcb_components = CPU_SVN
+            .iter()
+            .map(|svn| serde_json::json!({ "svn": svn }))
+            .collect::<Vec<_>>();
+        let tcb_info = serde_json::json!({
+            "id": "SGX",
+            "version": 3,
+            "issueDate": rfc3339(issue_date)?,
+            "nextUpdate": rfc3339(next_update)?,
+            "fmspc": hex::encode_upper(FMSPC),
+            "pceId": hex::encode_upper(PCE_ID),
+            "tcbType": 0,
+            "tcbEvaluationDataNumber": 1,
+            "tcbLevels": [{
+                "tcb": { "sgxtcbcomponents": tcb_components, "pcesvn": PCE_SVN },
+                "tcbDate": rfc3339(issue_date)?,
+                "tcbStatus": "UpToDate",
+            }],
+        });
+        let qe_identity = serde_json::json!({
+            "id": "QE",
+            "version": 2,
+            "issueDate": rfc3339(issue_date)?,
+            "nextUpdate": rfc3339(next_update)?,
+            "tcbEvaluationDataNumber": 1,
+            "miscselect": "00000000",
+            "miscselectMask": "FFFFFFFF",
+            "attributes": hex::encode_upper(QE_ATTRIBUTES),
+            "attributesMask": "FBFFFFFFFFFFFFFF0000000000000000",
+            "mrsigner": hex::encode_upper([0; 32]),
+            "isvprodid": QE_PROD_ID,
+            "tcbLevels": [{
+                "tcb": { "isvsvn": QE_SVN },
+                "tcbDate": rfc3339(issue_date)?,
+                "tcbStatus": "UpToDate",
+            }],
+        });
+
+        let pck_ca_chain = [self.pck_ca.pem(), self.root_ca.pem()].concat();
+        let tcb_signing_chain = [self.tcb_signing.pem(), self.root_ca.pem()].concat();
+        Ok(SgxCollateral {
+            version: 3,
+            pck_crl_issuer_chain: pck_ca_chain.clone(),
+            root_ca_crl: pem("X509 CRL", &root_ca_crl),
+            pck_crl: pem("X509 CRL", &pck_crl),
+            tcb_info_issuer_chain: tcb_signing_chain.clone(),
+            tcb_info: self.signed_json("tcbInfo", &tcb_info)?,
+            qe_identity_issuer_chain: tcb_signing_chain,
+            qe_identity: self.signed_json("enclaveIdentity", &qe_identity)?,
+            pck_certificate: self.pck.pem(),
+            pck_signing_chain: pck_ca_chain,
+        })
+    }
+
+    /// `{"<body_name>": <body>, "signature": "<hex>"}`, the signature covering
+    /// the exact bytes of the body as for the Intel PCS
+    fn signed_json(&self, body_name: &str, body: &serde_json::Value) -> Result<String> {
+        let body = serde_json::to_string(body)?;
+        let key = self
+            .tcb_signing
+            .signing_key(&ECDSA_P256_SHA256_FIXED_SIGNING)?;
+        let signature = hex::encode(self.sign(&key, body.as_bytes())?);
+        Ok(format!(
+            r#"{{"{}":{},"signature":"{}"}}"#,
+            body_name, body, signature
+        ))
+    }
+
+    /// Empty CRL of `issuer` (RFC 5280, section 5.1)
+    fn crl(
+        &self,
+        issuer: &Issued,
+        this_update: OffsetDateTime,
+        next_update: OffsetDateTime,
+    ) -> Result<Vec<u8>> {
+        let ecdsa_with_sha256 = ObjectIdentifier::from_slice(&[1, 2, 840, 10045, 4, 3, 2]);
+        let signature_algorithm = |writer: DERWriter| {
+            writer.write_sequence(|writer| writer.next().write_oid(&ecdsa_with_sha256))
+        };
+        let issuer_name = issuer.subject()?;
+        let tbs = yasna::construct_der(|writer| {
+            writer.write_sequence(|writer| {
+                writer.next().write_u8(1);
+                signature_algorithm(writer.next());
+                writer.next().write_der(&issuer_name);
+                write_time(writer.next(), this_update);
+                write_time(writer.next(), next_update);
+            })
+        });
+        let key = issuer.signing_key(&ECDSA_P256_SHA256_ASN1_SIGNING)?;
+        let signature = self.sign(&key, &tbs)?;
+        Ok(yasna::construct_der(|writer| {
+            writer.write_sequence(|writer| {
+                writer.next().write_der(&tbs);
+                signature_algorithm(writer.next());
+                writer
+                    .next()
+                    .write_bitvec_bytes(&signature, signature.len() * 8);
+            })
+        }))
+    }
+}
+
+/// SGX report body (`sgx_report_body_t`)
+fn report_body(
+    attributes: [u8; 16],
+    mr_signer: [u8; 32],
+    isv_prod_id: u16,
+    isv_svn: u16,
+    report_data: &[u8; 64],
+) -> Vec<u8> {
+    let mut body = Vec::with_capacity(384);
+    body.extend_from_slice(&CPU_SVN);
+    body.extend_from_slice(&[0; 4]); // MISCSELECT
+    body.extend_from_slice(&[0; 28]); // reserved, ISV extended product id
+    body.extend_from_slice(&attributes);
+    body.extend_from_slice(&[0; 32]); // MRENCLAVE
+    body.extend_from_slice(&[0; 32]); // reserved
+    body.extend_from_slice(&mr_signer);
+    body.extend_from_slice(&[0; 96]); // reserved, config id
+    body.extend_from_slice(&isv_prod_id.to_le_bytes());
+    body.extend_from_slice(&isv_svn.to_le_bytes());
+    body.extend_from_slice(&[0; 60]); // config SVN, reserved, ISV family id
+    body.extend_from_slice(report_data);
+    body
+}
+
+/// SGX extensions of the PCK certificate
+fn sgx_extensions() -> Vec<u8> {
+    yasna::construct_der(|writer| {
+        writer.write_sequence(|writer| {
+            sgx_extension(writer.next(), &[1], |writer| writer.write_bytes(&PPID));
+            sgx_extension(writer.next(), &[2], |writer| {
+                writer.write_sequence(|writer| {
+                    for (i, &svn) in CPU_SVN.iter().enumerate() {
+                        sgx_extension(writer.next(), &[2, i as u64 + 1], |writer| {
+                            writer.write_u8(svn)
+                        });
+                    }
+                    sgx_extension(writer.next(), &[2, 17], |writer| writer.write_u16(PCE_SVN));
+                    sgx_extension(writer.next(), &[2, 18], |writer| {
+                        writer.write_bytes(&CPU_SVN)
+                    });
+                })
+            });
+            sgx_extension(writer.next(), &[3], |writer| writer.write_bytes(&PCE_ID));
+            sgx_extension(writer.next(), &[4], |writer| writer.write_bytes(&FMSPC));
+            // SGX type: Standard
+            sgx_extension(writer.next(), &[5], |writer| writer.write_enum(0));
+        })
+    })
+}
+
+/// `SEQUENCE { 1.2.840.113741.1.13.1.<arcs>, value }`
+fn sgx_extension(writer: DERWriter, arcs: &[u64], value: impl FnOnce(DERWriter)) {
+    let oid = ObjectIdentifier::from_slice(&[SGX_EXTENSIONS_OID, arcs].concat());
+    writer.write_sequence(|writer| {
+        writer.next().write_oid(&oid);
+        value(writer.next());
+    })
+}
+
+/// UTCTime until 2049, GeneralizedTime after that (RFC 5280, section 4.1.2.5)
+fn write_time(writer: DERWriter, time: OffsetDateTime) {
+    if time.year() < 2050 {
+        writer.write_utctime(&UTCTime::from_datetime(time))
+    } else {
+        writer.write_generalized_time(&GeneralizedTime::from_datetime(time))
+    }
+}
+
+fn rfc3339(time: OffsetDateTime) -> Result<String> {
+    Ok(time.format(&Rfc3339)?)
+}
+
+/// The certificates and CRLs of the simulated PKI are public, unlike the keys
+/// of the enclave which must not go through the `pem` crate
+fn pem(tag: &str, der: &[u8]) -> String {
+    let pem = pem::Pem::new(tag, der);
+    pem::encode_config(
+        &pem,
+        pem::EncodeConfig {
+            line_ending: pem::LineEnding::LF,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collateral;
+    use crate::tcb_status::{self, TcbStatus};
+    use quote_verification::Verifier;
+    use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED};
+    use std::time::SystemTime;
+
+    #[test]
+    fn dates_are_formatted() {
+        let time = |secs| OffsetDateTime::from_unix_timestamp(secs).unwrap();
+        assert_eq!(rfc3339(time(0)).unwrap(), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc3339(time(1709251199)).unwrap(), "2024-02-29T23:59:59Z");
+        let der_time = |secs| yasna::construct_der(|writer| write_time(writer, time(secs)));
+        assert_eq!(der_time(1706659200), b"\x17\x0d240131000000Z");
+        assert_eq!(der_time(2556144000), b"\x18\x0f20510101000000Z");
+    }
+
+    #[test]
+    fn simulated_quotes_are_dcap_quotes() {
+        let quoting_enclave = SimulatedQuotingEnclave::generate().unwrap();
+        let report_data = [7; 64];
+        let quote = quoting_enclave.quote(&report_data).unwrap();
+
+        assert_eq!(quote[..2], 3u16.to_le_bytes());
+        assert_eq!(quote[12..28], SIMULATED_QE_VENDOR_ID);
+        assert_eq!(quote[48 + 320..48 + 384], report_data);
+        // The quote is signed by the attestation key following it
+        let attestation_key = [&[4][..], &quote[436 + 64..436 + 128]].concat();
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, attestation_key)
+            .verify(&quote[..432], &quote[436..436 + 64])
+            .unwrap();
+        let chain = std::str::from_utf8(&quote[436 + 64 + 64 + 384 + 64 + 2 + 32 + 6..]).unwrap();
+        assert_eq!(chain.matches("BEGIN CERTIFICATE").count(), 3);
+    }
+
+    #[test]
+    fn simulated_collateral_is_valid_for_a_while() {
+        let quoting_enclave = SimulatedQuotingEnclave::generate().unwrap();
+        let collateral = quoting_enclave.collateral().unwrap();
+        let expiry = collateral::expiry(&collateral).unwrap().unwrap();
+        let validity = expiry.duration_since(SystemTime::now()).unwrap();
+        assert!(validity > COLLATERAL_VALIDITY - Duration::from_secs(60));
+        assert!(validity <= COLLATERAL_VALIDITY);
+        assert!(collateral.tcb_info.starts_with(r#"{"tcbInfo":{"#));
+    }
+
+    #[test]
+    fn simulated_platform_is_up_to_date() {
+        let quoting_enclave = SimulatedQuotingEnclave::generate().unwrap();
+        let quote = quoting_enclave.quote(&[7; 64]).unwrap();
+        let collateral = quoting_enclave.collateral().unwrap();
+
+        // Evaluated as the enclave evaluates the Intel collateral, matching
+        // the PCK certificate against the TCB levels
+        let verifier = Verifier::new().allow_simulated();
+        let (platform, evaluation) = tcb_status::evaluate(&verifier, &quote, &collateral);
+        let platform = platform.unwrap();
+        assert_eq!(platform.fmspc, FMSPC);
+        assert_eq!(platform.tcb.sgx_components, CPU_SVN);
+        assert_eq!(platform.tcb.pce_svn, PCE_SVN);
+        let evaluation = evaluation.unwrap();
+        assert_eq!(evaluation.status, TcbStatus::UpToDate);
+        assert!(evaluation.advisory_ids.is_empty());
+    }
+}
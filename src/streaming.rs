@@ -0,0 +1,341 @@
+// Copyright 2022 Mithril Security. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Streaming inference over a WebSocket.
+//!
+//! Clients open a WebSocket on `/stream` of the attested port with the
+//! `blindai-stream` subprotocol. The first message is a [`StreamStart`]
+//! describing the model and the window it is run on. Every following binary
+//! message is a frame of little-endian elements appended to the window held by
+//! the server. The model is run as soon as the window is full and then every
+//! `hop` elements, each run being sent back as a [`StreamMessage::Output`].
+//! Messages are CBOR in binary messages, or JSON in text messages if the start
+//! message was sent as text.
+
+use crate::client_communication::{caller_identity, Exchanger, SerializedTensor, TensorInfo};
+use crate::compute::Workload;
+use crate::encoding::Encoding;
+use crate::model::ModelDatumType;
+use crate::protocol::check_version;
+use crate::websocket::{self, Message, WebSocket};
+use anyhow::{anyhow, bail, Result};
+use log::{error, info};
+use serde_derive::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use uuid::Uuid;
+
+pub(crate) const PROTOCOL: &str = "blindai-stream";
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct StreamStart {
+    /// Id of the model, or its name if only one model has this name
+    model_id: String,
+    #[serde(default)]
+    node_name: Option<String>,
+    datum_type: ModelDatumType,
+    /// Shape of the window the model is run on
+    shape: Vec<usize>,
+    /// Number of elements received between two runs, the size of the window
+    /// by default
+    #[serde(default)]
+    hop: Option<usize>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum StreamMessage {
+    Output {
+        sequence: u64,
+        outputs: Vec<SerializedTensor>,
+    },
+    Error {
+        error: String,
+    },
+}
+
+/// Elements received so far, up to the size of the window
+struct Window {
+    info: TensorInfo,
+    buffer: Vec<u8>,
+    window_size: usize,
+    hop_size: usize,
+}
+
+impl Window {
+    fn new(start: &StreamStart, max_size: usize) -> Result<Self> {
+        let element_size = start.datum_type.size_of();
+        let elements = start
+            .shape
+            .iter()
+            .try_fold(1usize, |elements, &dimension| {
+                elements.checked_mul(dimension)
+            })
+            .ok_or_else(|| anyhow!("The window is too big"))?;
+        let hop = start.hop.unwrap_or(elements);
+        if elements == 0 {
+            bail!("The window can't be empty");
+        }
+        if hop == 0 || hop > elements {
+            bail!("The hop must be between 1 and the size of the window");
+        }
+        let window_size = match elements.checked_mul(element_size) {
+            Some(window_size) if window_size <= max_size => window_size,
+            _ => bail!("The window is too big"),
+        };
+
+        Ok(Window {
+            info: TensorInfo {
+                fact: start.shape.clone(),
+                datum_type: start.datum_type,
+                node_name: start.node_name.clone(),
+            },
+            buffer: Vec::with_capacity(window_size),
+            window_size,
+            hop_size: hop * element_size,
+        })
+    }
+
+    /// Append a frame, returning the windows that are ready to be run on
+    fn push(&mut self, frame: &[u8]) -> Result<Vec<SerializedTensor>> {
+        if frame.len() % self.info.datum_type.size_of() != 0 {
+            bail!("The frame doesn't contain a whole number of elements");
+        }
+        if frame.len() > self.window_size {
+            bail!("The frame is bigger than the window");
+        }
+
+        self.buffer.extend_from_slice(frame);
+        let mut windows = vec![];
+        while self.buffer.len() >= self.window_size {
+            windows.push(SerializedTensor {
+                info: self.info.clone(),
+                bytes_data: self.buffer[..self.window_size].to_vec(),
+            });
+            self.buffer.drain(..self.hop_size);
+        }
+        Ok(windows)
+    }
+}
+
+/// An open stream, counted until dropped
+struct Slot(Arc<Streams>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.open_streams.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub(crate) struct Streams {
+    exchanger: Arc<Exchanger>,
+    open_streams: AtomicUsize,
+    max_streams: usize,
+}
+
+impl Streams {
+    pub fn new(exchanger: Arc<Exchanger>, max_streams: usize) -> Arc<Self> {
+        Arc::new(Streams {
+            exchanger,
+            open_streams: AtomicUsize::new(0),
+            max_streams,
+        })
+    }
+
+    /// At most `BLINDAI_MAX_STREAMS` streams are open at once (8 by default),
+    /// each one holding a thread of its own which its inferences run on
+    pub fn from_env(exchanger: Arc<Exchanger>) -> Result<Arc<Self>> {
+        Ok(Self::new(exchanger, Self::max_streams_from_env()?))
+    }
+
+    /// Number of streams, and thus of stream threads, configured
+    pub fn max_streams_from_env() -> Result<usize> {
+        match std::env::var("BLINDAI_MAX_STREAMS") {
+            Ok(value) => Ok(value.parse()?),
+            Err(_) => Ok(8),
+        }
+    }
+
+    /// Upgrade the request to a WebSocket served by a new thread
+    pub fn start(self: &Arc<Self>, request: &rouille::Request) -> rouille::Response {
+        if self.open_streams.fetch_add(1, Ordering::SeqCst) >= self.max_streams {
+            self.open_streams.fetch_sub(1, Ordering::SeqCst);
+            return rouille::Response::text("Too many open streams, retry later")
+                .with_status_code(503);
+        }
+        let slot = Slot(Arc::clone(self));
+
+        // The version of a stream is given in the header of the upgrade request
+        if let Err(e) = check_version(request, None) {
+            return rouille::Response::text(e.to_string()).with_status_code(400);
+        }
+
+        // Until the window is known, messages are bounded by the maximum input size
+        let max_message_size = self.exchanger.max_input_size();
+        let (response, websocket) = match websocket::start(request, PROTOCOL, max_message_size) {
+            Ok(upgrade) => upgrade,
+            Err(e) => return rouille::Response::text(e.to_string()).with_status_code(400),
+        };
+
+        let caller = caller_identity(request);
+        thread::spawn(move || {
+            // The WebSocket is available once the upgrade response is sent
+            if let Ok(websocket) = websocket.recv() {
+                slot.0.serve(caller.as_deref(), websocket);
+            }
+        });
+        response
+    }
+
+    fn serve(&self, caller: Option<&str>, mut websocket: WebSocket) {
+        let (encoding, start) = match websocket.read_message() {
+            Ok(Some(Message::Binary(data))) => (Encoding::Cbor, data),
+            Ok(Some(Message::Text(text))) => (Encoding::Json, text.into_bytes()),
+            Ok(None) => return,
+            Err(e) => {
+                error!("Stream error: {}", e);
+                return;
+            }
+        };
+        let (model, mut window) = match self.open(&start, encoding) {
+            Ok(stream) => stream,
+            Err(e) => {
+                send(&mut websocket, encoding, &error_message(e));
+                return;
+            }
+        };
+        info!("Stream opened on model {}", model);
+        // Frames bigger than the window are refused before being read
+        websocket.set_max_message_size(window.window_size);
+
+        let mut sequence = 0;
+        loop {
+            let frame = match websocket.read_message() {
+                Ok(Some(Message::Binary(frame))) => frame,
+                Ok(None) => break,
+                Err(e) => {
+                    send(&mut websocket, encoding, &error_message(e));
+                    return;
+                }
+                Ok(Some(Message::Text(_))) => {
+                    let e = anyhow!("Frames must be sent as binary messages");
+                    send(&mut websocket, encoding, &error_message(e));
+                    return;
+                }
+            };
+
+            let outputs = window.push(&frame).and_then(|windows| {
+                windows
+                    .into_iter()
+                    .map(|input| {
+                        self.exchanger
+                            .infer(Workload::Background, caller, model, vec![input])
+                    })
+                    .collect::<Result<Vec<_>>>()
+            });
+            let outputs = match outputs {
+                Ok(outputs) => outputs,
+                Err(e) => {
+                    send(&mut websocket, encoding, &error_message(e));
+                    return;
+                }
+            };
+
+            for outputs in outputs {
+                let message = StreamMessage::Output { sequence, outputs };
+                if !send(&mut websocket, encoding, &message) {
+                    return;
+                }
+                sequence += 1;
+            }
+        }
+        info!("Stream closed on model {}", model);
+    }
+
+    fn open(&self, start: &[u8], encoding: Encoding) -> Result<(Uuid, Window)> {
+        let start: StreamStart = encoding.deserialize(start)?;
+        let model = self.exchanger.find_model(&start.model_id)?;
+        let window = Window::new(&start, self.exchanger.max_input_size())?;
+        Ok((model, window))
+    }
+}
+
+fn error_message(e: anyhow::Error) -> StreamMessage {
+    error!("Stream error: {}", e);
+    StreamMessage::Error {
+        error: format!("{:?}", e),
+    }
+}
+
+/// Send `message`, returning whether the WebSocket is still open
+fn send(websocket: &mut WebSocket, encoding: Encoding, message: &StreamMessage) -> bool {
+    let data = match encoding.serialize(message) {
+        Ok(data) => data,
+        Err(e) => {
+            error!("Could not serialize a stream message: {}", e);
+            return false;
+        }
+    };
+    let sent = match encoding {
+        Encoding::Cbor => websocket.send_binary(&data),
+        Encoding::Json => websocket.send_text(&String::from_utf8_lossy(&data)),
+    };
+    sent.is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start(shape: Vec<usize>, hop: Option<usize>) -> StreamStart {
+        StreamStart {
+            model_id: String::new(),
+            node_name: None,
+            datum_type: ModelDatumType::I16,
+            shape,
+            hop,
+        }
+    }
+
+    #[test]
+    fn window_slides_by_hop() {
+        let mut window = Window::new(&start(vec![1, 4], Some(2)), 100).unwrap();
+        assert!(window.push(&[1, 0, 2, 0]).unwrap().is_empty());
+        let windows = window.push(&[3, 0, 4, 0, 5, 0, 6, 0]).unwrap();
+        let data: Vec<_> = windows.iter().map(|w| w.bytes_data.clone()).collect();
+        assert_eq!(
+            data,
+            vec![vec![1, 0, 2, 0, 3, 0, 4, 0], vec![3, 0, 4, 0, 5, 0, 6, 0]]
+        );
+        assert_eq!(windows[0].info.fact, vec![1, 4]);
+        assert_eq!(window.buffer, vec![5, 0, 6, 0]);
+    }
+
+    #[test]
+    fn invalid_windows_and_frames() {
+        assert!(Window::new(&start(vec![0], None), 100).is_err());
+        assert!(Window::new(&start(vec![4], Some(5)), 100).is_err());
+        assert!(Window::new(&start(vec![100], None), 100).is_err());
+        // Sizes overflowing a usize are rejected, not wrapped
+        assert!(Window::new(&start(vec![usize::MAX, 2], None), usize::MAX).is_err());
+        assert!(Window::new(&start(vec![usize::MAX / 2 + 1], None), usize::MAX).is_err());
+
+        let mut window = Window::new(&start(vec![4], None), 100).unwrap();
+        assert!(window.push(&[1, 0, 2]).is_err());
+        assert!(window.push(&[0; 10]).is_err());
+        assert_eq!(window.push(&[0; 8]).unwrap().len(), 1);
+        assert!(window.buffer.is_empty());
+    }
+}
@@ -0,0 +1,215 @@
+// Copyright 2022 Mithril Security. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! TCB status of the platform, evaluated at startup.
+//!
+//! The enclave verifies its own quote against the collateral, with the
+//! verifier the runner and the clients use: the certificates and signatures
+//! of the collateral are checked up to the Intel root CA embedded in the
+//! enclave, so the host can't make up a better status. The TCB of the PCK
+//! certificate is then matched against the TCB levels of the TCB info, and
+//! the status of the first level reached is combined with the one of the
+//! quoting enclave, with the advisory IDs of the vulnerabilities the platform
+//! is exposed to. The evaluation is logged and served on `/tcb_status`, and
+//! the SGX extensions of the PCK certificate on `/platform`.
+//!
+//! By default the server starts whatever the status. With
+//! `BLINDAI_ACCEPTED_TCB_STATUSES`, a comma separated list such as
+//! `UpToDate,SWHardeningNeeded`, it refuses to start on any other status, or
+//! when the status could not be evaluated.
+
+use crate::SgxCollateral;
+use anyhow::{anyhow, bail, Result};
+use log::{error, info, warn};
+use quote_verification::pki::PckExtensions;
+pub(crate) use quote_verification::tcb::{TcbEvaluation, TcbStatus};
+use quote_verification::Verifier;
+
+/// Statuses of the TCB levels defined by Intel
+const TCB_STATUSES: [TcbStatus; 7] = [
+    TcbStatus::UpToDate,
+    TcbStatus::SWHardeningNeeded,
+    TcbStatus::ConfigurationNeeded,
+    TcbStatus::ConfigurationAndSWHardeningNeeded,
+    TcbStatus::OutOfDate,
+    TcbStatus::OutOfDateConfigurationNeeded,
+    TcbStatus::Revoked,
+];
+
+/// Verify the quote of the enclave against its collateral, returning the SGX
+/// extensions of its PCK certificate and its TCB status. Both are logged, and
+/// unknown if the quote doesn't verify.
+pub(crate) fn evaluate(
+    verifier: &Verifier,
+    quote: &[u8],
+    collateral: &SgxCollateral,
+) -> (Option<PckExtensions>, Option<TcbEvaluation>) {
+    let verified = match verifier.verify(quote, collateral) {
+        Ok(verified) => verified,
+        Err(e) => {
+            error!("Attestation : Failed to evaluate the TCB status: {:?}", e);
+            return (None, None);
+        }
+    };
+    match serde_json::to_string(&verified.platform) {
+        Ok(platform) => info!("Attestation : Platform is {}", platform),
+        Err(e) => error!("Attestation : Failed to serialize the platform: {:?}", e),
+    }
+    let evaluation = verified.tcb_evaluation;
+    if evaluation.status == TcbStatus::UpToDate {
+        info!("Attestation : TCB status is UpToDate");
+    } else {
+        warn!(
+            "Attestation : TCB status is {:?} since {}, advisories {:?}",
+            evaluation.status, evaluation.tcb_date, evaluation.advisory_ids
+        );
+    }
+    (Some(verified.platform), Some(evaluation))
+}
+
+fn names(statuses: &[TcbStatus]) -> String {
+    statuses
+        .iter()
+        .map(|status| format!("{:?}", status))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// TCB statuses the server accepts to serve on, all of them if `None`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct TcbPolicy(Option<Vec<TcbStatus>>);
+
+impl TcbPolicy {
+    pub fn from_env() -> Result<Self> {
+        match std::env::var("BLINDAI_ACCEPTED_TCB_STATUSES") {
+            Ok(statuses) => Self::parse(&statuses),
+            Err(_) => Ok(Self(None)),
+        }
+    }
+
+    fn parse(statuses: &str) -> Result<Self> {
+        let statuses = statuses
+            .split(',')
+            .map(str::trim)
+            .filter(|status| !status.is_empty())
+            .map(|status| {
+                TCB_STATUSES
+                    .into_iter()
+                    .find(|known| format!("{:?}", known) == status)
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "Unknown TCB status {:?}, expected one of {}",
+                            status,
+                            names(&TCB_STATUSES)
+                        )
+                    })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if statuses.is_empty() {
+            bail!("BLINDAI_ACCEPTED_TCB_STATUSES doesn't contain any TCB status");
+        }
+        Ok(Self(Some(statuses)))
+    }
+
+    /// Check that the server may serve with the TCB status of the platform,
+    /// `None` if it could not be evaluated
+    pub fn check(&self, evaluation: Option<&TcbEvaluation>) -> Result<()> {
+        let Some(accepted) = &self.0 else {
+            return Ok(());
+        };
+        match evaluation {
+            Some(evaluation) if accepted.contains(&evaluation.status) => Ok(()),
+            Some(evaluation) => bail!(
+                "The TCB status of the platform is {:?}, the server only accepts {}",
+                evaluation.status,
+                names(accepted)
+            ),
+            None => bail!(
+                "The TCB status of the platform is unknown, the server only accepts {}",
+                names(accepted)
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUOTE: &[u8] = include_bytes!("../runner/quote_verification/tests/fixtures/quote.bin");
+    const COLLATERAL: &str =
+        include_str!("../runner/quote_verification/tests/fixtures/collateral.json");
+    const ROOT_CA: &str = include_str!("../runner/quote_verification/tests/fixtures/root_ca.pem");
+    /// 2024-01-15, while the collateral is valid
+    const TIME: i64 = 1705276800;
+
+    fn evaluation(status: TcbStatus) -> TcbEvaluation {
+        TcbEvaluation {
+            status,
+            tcb_date: "2023-08-09T00:00:00Z".to_string(),
+            advisory_ids: vec!["INTEL-SA-00615".to_string()],
+        }
+    }
+
+    #[test]
+    fn every_status_is_accepted_by_default() {
+        let policy = TcbPolicy(None);
+        for status in TCB_STATUSES {
+            assert!(policy.check(Some(&evaluation(status))).is_ok());
+        }
+        assert!(policy.check(None).is_ok());
+    }
+
+    #[test]
+    fn only_accepted_statuses_are_served() {
+        let policy = TcbPolicy::parse("UpToDate, SWHardeningNeeded").unwrap();
+        assert!(policy.check(Some(&evaluation(TcbStatus::UpToDate))).is_ok());
+        assert!(policy
+            .check(Some(&evaluation(TcbStatus::SWHardeningNeeded)))
+            .is_ok());
+        assert!(policy
+            .check(Some(&evaluation(TcbStatus::OutOfDate)))
+            .is_err());
+        assert!(policy.check(Some(&evaluation(TcbStatus::Revoked))).is_err());
+        // An unknown status can't be accepted
+        assert!(policy.check(None).is_err());
+    }
+
+    #[test]
+    fn unknown_statuses_are_rejected() {
+        assert!(TcbPolicy::parse("UpToDate,Uptodate").is_err());
+        assert!(TcbPolicy::parse(" , ").is_err());
+    }
+
+    #[test]
+    fn the_status_is_evaluated_from_the_verified_collateral() {
+        let verifier = Verifier::with_root_ca_pem(ROOT_CA).unwrap().at(TIME);
+        let collateral: SgxCollateral = serde_json::from_str(COLLATERAL).unwrap();
+        let (platform, evaluation) = evaluate(&verifier, QUOTE, &collateral);
+        assert_eq!(
+            platform.unwrap().fmspc,
+            [0x00, 0x90, 0x6e, 0xd5, 0x00, 0x00]
+        );
+        assert_eq!(evaluation.unwrap().status, TcbStatus::UpToDate);
+
+        // A host rewriting the TCB levels breaks their signature
+        let mut tampered = collateral.clone();
+        tampered.tcb_info = tampered.tcb_info.replace("OutOfDate", "UpToDate");
+        assert_eq!(evaluate(&verifier, QUOTE, &tampered), (None, None));
+
+        // The test PKI doesn't chain up to the Intel root CA
+        let intel = Verifier::new().at(TIME);
+        assert_eq!(evaluate(&intel, QUOTE, &collateral), (None, None));
+    }
+}
@@ -0,0 +1,110 @@
+// Copyright 2022 Mithril Security. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Budget of the threads of the enclave.
+//!
+//! An enclave has the fixed number of threads set by `threads` in its
+//! manifest, and spawning one more panics. The threads the configuration
+//! calls for are thus summed at startup, the server refusing to start when
+//! they don't fit instead of failing under load.
+
+use anyhow::{bail, Result};
+
+/// `threads` of `[package.metadata.fortanix-sgx]` in Cargo.toml
+pub(crate) const ENCLAVE_THREADS: usize = 128;
+
+pub(crate) struct ThreadBudget {
+    threads: Vec<(&'static str, usize)>,
+}
+
+impl ThreadBudget {
+    pub fn new() -> Self {
+        ThreadBudget {
+            threads: vec![("main", 1)],
+        }
+    }
+
+    pub fn add(&mut self, name: &'static str, threads: usize) {
+        self.threads.push((name, threads));
+    }
+
+    /// Count an HTTP server: tiny-http accepts connections on a thread and
+    /// reads each of them on its own thread, keeping at least 4 of them, and
+    /// rouille runs on a thread handing the requests to its pool
+    pub fn add_server(&mut self, name: &'static str, max_connections: usize, pool_size: usize) {
+        let threads = max_connections
+            .max(4)
+            .saturating_add(pool_size)
+            .saturating_add(2);
+        self.add(name, threads);
+    }
+
+    pub fn total(&self) -> usize {
+        self.threads
+            .iter()
+            .fold(0, |total, (_, threads)| total.saturating_add(*threads))
+    }
+
+    /// Fail unless the threads fit in the `available` ones
+    pub fn check(&self, available: usize) -> Result<()> {
+        let total = self.total();
+        if total > available {
+            let threads = self
+                .threads
+                .iter()
+                .map(|(name, threads)| format!("{name}: {threads}"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            bail!(
+                "The configuration needs {} threads but the enclave has {} ({}). \
+                 Lower these settings or raise `threads` in the enclave manifest",
+                total,
+                available,
+                threads
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn budget_matches_the_manifest() {
+        let threads = include_str!("../Cargo.toml")
+            .split("[package.metadata.fortanix-sgx]")
+            .nth(1)
+            .and_then(|metadata| {
+                metadata
+                    .lines()
+                    .find_map(|line| line.trim().strip_prefix("threads="))
+            });
+        assert_eq!(threads, Some(ENCLAVE_THREADS.to_string().as_str()));
+    }
+
+    #[test]
+    fn threads_over_the_budget_are_errors() {
+        let mut budget = ThreadBudget::new();
+        budget.add_server("attested server", 16, 8);
+        budget.add("compute", 4);
+        assert_eq!(budget.total(), 1 + 16 + 8 + 2 + 4);
+        assert!(budget.check(31).is_ok());
+
+        budget.add("streams", usize::MAX);
+        let e = budget.check(ENCLAVE_THREADS).unwrap_err().to_string();
+        assert!(e.contains("streams: 18446744073709551615"), "{}", e);
+    }
+}
@@ -0,0 +1,351 @@
+// Copyright 2022 Mithril Security. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Server side of WebSocket connections whose messages are bounded in size.
+//!
+//! `rouille::websocket` buffers every message whole before handing it out,
+//! whatever its size. The handshake is still done by rouille, but the
+//! upgraded connection is read here, frame by frame (RFC 6455): a message is
+//! rejected as soon as its frame headers announce more than the maximum
+//! message size, before anything is allocated for it. The connection is then
+//! closed with the 1009 (message too big) status.
+
+use anyhow::{anyhow, bail, Result};
+use rouille::{ReadWrite, Upgrade};
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{self, Receiver, Sender};
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+pub(crate) enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// Upgrade the request to a WebSocket with the `protocol` subprotocol. The
+/// WebSocket is received once the upgrade response is sent.
+pub(crate) fn start(
+    request: &rouille::Request,
+    protocol: &'static str,
+    max_message_size: usize,
+) -> Result<(rouille::Response, Receiver<WebSocket>)> {
+    let (mut response, _) =
+        rouille::websocket::start(request, Some(protocol)).map_err(|e| anyhow!("{}", e))?;
+    let (sender, receiver) = mpsc::channel();
+    response.upgrade = Some(Box::new(Upgraded {
+        sender,
+        max_message_size,
+    }));
+    Ok((response, receiver))
+}
+
+struct Upgraded {
+    sender: Sender<WebSocket>,
+    max_message_size: usize,
+}
+
+impl Upgrade for Upgraded {
+    fn build(&mut self, socket: Box<dyn ReadWrite + Send>) {
+        let _ = self
+            .sender
+            .send(WebSocket::new(socket, self.max_message_size));
+    }
+}
+
+pub(crate) struct WebSocket {
+    socket: Box<dyn ReadWrite + Send>,
+    max_message_size: usize,
+    /// Status sent in the close frame, once the connection is being closed
+    close_status: Option<u16>,
+}
+
+impl WebSocket {
+    pub fn new(socket: Box<dyn ReadWrite + Send>, max_message_size: usize) -> Self {
+        WebSocket {
+            socket,
+            max_message_size,
+            close_status: None,
+        }
+    }
+
+    pub fn set_max_message_size(&mut self, max_message_size: usize) {
+        self.max_message_size = max_message_size;
+    }
+
+    /// Next message, `None` once the connection is closed
+    ///
+    /// Pings are answered along the way. Messages bigger than the maximum
+    /// size and protocol errors are errors, the connection being closed once
+    /// the WebSocket is dropped.
+    pub fn read_message(&mut self) -> Result<Option<Message>> {
+        if self.close_status.is_some() {
+            return Ok(None);
+        }
+        match self.read_frames() {
+            Ok(message) => Ok(message),
+            Err(e) => {
+                self.close_status.get_or_insert(CLOSE_PROTOCOL_ERROR);
+                Err(e)
+            }
+        }
+    }
+
+    fn read_frames(&mut self) -> Result<Option<Message>> {
+        let mut message_opcode = None;
+        let mut payload = Vec::new();
+        loop {
+            let mut header = [0u8; 2];
+            match self.socket.read_exact(&mut header) {
+                Ok(()) => (),
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    self.close_status = Some(CLOSE_NORMAL);
+                    return Ok(None);
+                }
+                Err(e) => return Err(e.into()),
+            }
+            let fin = header[0] & 0x80 != 0;
+            let opcode = header[0] & 0x0f;
+            if header[0] & 0x70 != 0 {
+                bail!("Unexpected WebSocket extension bits");
+            }
+            if header[1] & 0x80 == 0 {
+                bail!("Client WebSocket frames must be masked");
+            }
+            let length = match header[1] & 0x7f {
+                126 => {
+                    let mut length = [0u8; 2];
+                    self.socket.read_exact(&mut length)?;
+                    u16::from_be_bytes(length) as u64
+                }
+                127 => {
+                    let mut length = [0u8; 8];
+                    self.socket.read_exact(&mut length)?;
+                    u64::from_be_bytes(length)
+                }
+                length => length as u64,
+            };
+            let mut mask = [0u8; 4];
+            self.socket.read_exact(&mut mask)?;
+
+            if opcode >= OPCODE_CLOSE {
+                if !fin || length > 125 {
+                    bail!("Invalid WebSocket control frame");
+                }
+                let control = self.read_payload(length as usize, mask)?;
+                match opcode {
+                    OPCODE_CLOSE => {
+                        self.close_status = Some(match control[..] {
+                            [high, low, ..] => u16::from_be_bytes([high, low]),
+                            _ => CLOSE_NORMAL,
+                        });
+                        return Ok(None);
+                    }
+                    OPCODE_PING => self.send_frame(OPCODE_PONG, &control)?,
+                    OPCODE_PONG => (),
+                    _ => bail!("Unknown WebSocket opcode {}", opcode),
+                }
+                continue;
+            }
+
+            match (opcode, message_opcode) {
+                (OPCODE_CONTINUATION, Some(_)) => (),
+                (OPCODE_TEXT | OPCODE_BINARY, None) => message_opcode = Some(opcode),
+                _ => bail!("Unexpected WebSocket frame"),
+            }
+            let size = (payload.len() as u64).saturating_add(length);
+            if size > self.max_message_size as u64 {
+                self.close_status = Some(CLOSE_TOO_BIG);
+                bail!(
+                    "The WebSocket message is bigger than {} bytes",
+                    self.max_message_size
+                );
+            }
+            payload.extend(self.read_payload(length as usize, mask)?);
+
+            if fin {
+                return Ok(Some(match message_opcode {
+                    Some(OPCODE_TEXT) => Message::Text(String::from_utf8(payload)?),
+                    _ => Message::Binary(payload),
+                }));
+            }
+        }
+    }
+
+    fn read_payload(&mut self, length: usize, mask: [u8; 4]) -> Result<Vec<u8>> {
+        let mut payload = vec![0u8; length];
+        self.socket.read_exact(&mut payload)?;
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        Ok(payload)
+    }
+
+    pub fn send_text(&mut self, text: &str) -> io::Result<()> {
+        self.send_frame(OPCODE_TEXT, text.as_bytes())
+    }
+
+    pub fn send_binary(&mut self, data: &[u8]) -> io::Result<()> {
+        self.send_frame(OPCODE_BINARY, data)
+    }
+
+    /// Send an unfragmented, unmasked frame, as servers do
+    fn send_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = vec![0x80 | opcode];
+        match payload.len() {
+            length if length < 126 => frame.push(length as u8),
+            length if length <= u16::MAX as usize => {
+                frame.push(126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                frame.push(127);
+                frame.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+        self.socket.write_all(&frame)?;
+        self.socket.flush()
+    }
+}
+
+impl Drop for WebSocket {
+    fn drop(&mut self) {
+        let status = self.close_status.unwrap_or(CLOSE_NORMAL);
+        let _ = self.send_frame(OPCODE_CLOSE, &status.to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    /// Reads the frames sent by the client, records the ones sent back
+    struct Connection {
+        input: Cursor<Vec<u8>>,
+        output: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Read for Connection {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Connection {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn connect(frames: &[Vec<u8>], max_message_size: usize) -> (WebSocket, Arc<Mutex<Vec<u8>>>) {
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let connection = Connection {
+            input: Cursor::new(frames.concat()),
+            output: Arc::clone(&output),
+        };
+        (
+            WebSocket::new(Box::new(connection), max_message_size),
+            output,
+        )
+    }
+
+    /// Masked client frame
+    fn frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [1, 2, 3, 4];
+        let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+        if payload.len() < 126 {
+            frame.push(0x80 | payload.len() as u8);
+        } else {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn messages_are_reassembled() {
+        let (mut websocket, output) = connect(
+            &[
+                frame(true, OPCODE_TEXT, b"start"),
+                frame(false, OPCODE_BINARY, &[1, 2]),
+                frame(true, OPCODE_PING, b"ping"),
+                frame(true, OPCODE_CONTINUATION, &[3; 200]),
+                frame(true, OPCODE_CLOSE, &1000u16.to_be_bytes()),
+            ],
+            1000,
+        );
+        assert!(matches!(
+            websocket.read_message().unwrap(),
+            Some(Message::Text(text)) if text == "start"
+        ));
+        match websocket.read_message().unwrap() {
+            Some(Message::Binary(data)) => {
+                assert_eq!(data[..2], [1, 2]);
+                assert_eq!(data[2..], [3; 200]);
+            }
+            _ => panic!("expected a binary message"),
+        }
+        assert!(websocket.read_message().unwrap().is_none());
+
+        websocket.send_binary(&[5; 300]).unwrap();
+        drop(websocket);
+        let output = output.lock().unwrap();
+        // Pong, the binary message and the close frame
+        assert_eq!(output[..6], [0x8a, 4, b'p', b'i', b'n', b'g']);
+        assert_eq!(output[6..10], [0x82, 126, 1, 44]);
+        assert_eq!(output[10..310], [5; 300]);
+        assert_eq!(output[310..], [0x88, 2, 3, 232]);
+    }
+
+    #[test]
+    fn big_messages_are_rejected_before_being_read() {
+        let (mut websocket, output) = connect(
+            &[
+                frame(false, OPCODE_BINARY, &[0; 60]),
+                frame(true, OPCODE_CONTINUATION, &[0; 60]),
+            ],
+            100,
+        );
+        assert!(websocket.read_message().is_err());
+        assert!(websocket.read_message().unwrap().is_none());
+        drop(websocket);
+        // Closed with 1009
+        assert_eq!(output.lock().unwrap()[..], [0x88, 2, 3, 241]);
+
+        // Unmasked frames break the protocol
+        let mut unmasked = frame(true, OPCODE_BINARY, &[0; 4]);
+        unmasked[1] &= 0x7f;
+        let (mut websocket, output) = connect(&[unmasked], 100);
+        assert!(websocket.read_message().is_err());
+        drop(websocket);
+        assert_eq!(output.lock().unwrap()[..], [0x88, 2, 3, 234]);
+    }
+}